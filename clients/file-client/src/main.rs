@@ -4,25 +4,65 @@ extern crate file_protocol;
 extern crate log;
 #[macro_use]
 extern crate failure;
+extern crate rand;
 extern crate simplelog;
 
+mod progress;
+
 use clap::{App, Arg};
-use file_protocol::{FileProtocol, FileProtocolConfig, State};
+use file_protocol::{
+    manifest, Compression, FileInfo, FileProtocol, FileProtocolConfig, ManifestEntry,
+    ProtocolError, State, TransactionInfo,
+};
+use progress::ProgressMode;
+use rand::Rng;
 use simplelog::*;
-use std::path::Path;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+// Environment variable holding the key to sign messages with, if no key file is given
+const AUTH_KEY_VAR: &str = "FILE_CLIENT_AUTH_KEY";
+
+// Set up a file protocol instance which displays transfer progress in the requested format
+fn connect(
+    host_ip: &str,
+    remote_addr: &str,
+    f_config: FileProtocolConfig,
+    progress: ProgressMode,
+) -> FileProtocol {
+    let f_protocol = FileProtocol::new(host_ip, remote_addr, f_config);
+
+    // Find out what the remote target supports, so that requests it can't handle fail
+    // up front. Older targets don't reply, in which case we carry on regardless
+    match f_protocol.hello(Duration::from_secs(2)) {
+        Ok(remote) => info!(
+            "Remote target speaks version {} with features {:?}",
+            remote.version, remote.features
+        ),
+        Err(ProtocolError::ReceiveTimeout) => {
+            warn!("No hello reply from remote target. Unable to check its capabilities")
+        }
+        Err(e) => warn!("Failed to exchange hello messages: {}", e),
+    }
+
+    match progress.reporter() {
+        Some(reporter) => f_protocol.with_progress(reporter),
+        None => f_protocol,
+    }
+}
+
 fn upload(
     host_ip: &str,
     remote_addr: &str,
     source_path: &str,
     target_path: &str,
-    prefix: Option<String>,
-    chunk_size: usize,
-    hold_count: u16,
+    f_config: FileProtocolConfig,
+    progress: ProgressMode,
 ) -> Result<(), failure::Error> {
-    let f_config = FileProtocolConfig::new(prefix, chunk_size, hold_count);
-    let f_protocol = FileProtocol::new(host_ip, remote_addr, f_config);
+    let f_protocol = connect(host_ip, remote_addr, f_config, progress);
 
     info!(
         "Uploading local:{} to remote:{}",
@@ -32,11 +72,23 @@ fn upload(
     // Copy file to upload to temp storage. Calculate the hash and chunk info
     let (hash, num_chunks, mode) = f_protocol.initialize_file(&source_path)?;
 
+    // Let the user know how to pick the transfer back up if it's interrupted
+    info!("Transfer hash: {}", hash);
+
     // Generate channel id for transaction
     let channel = f_protocol.generate_channel()?;
 
-    // Tell our destination the hash and number of chunks to expect
-    f_protocol.send_metadata(channel, &hash, num_chunks)?;
+    // If our destination already has a copy of the file, it can be
+    // reassembled there without us sending any of the chunks
+    if f_protocol.remote_has_file(&hash, Duration::from_secs(2))? {
+        info!(
+            "Remote target already has {}, skipping chunk transfer",
+            hash
+        );
+    } else {
+        // Tell our destination the hash and number of chunks to expect
+        f_protocol.send_metadata(channel, &hash, num_chunks)?;
+    }
 
     // Send export command for file
     f_protocol.send_export(channel, &hash, &target_path, mode)?;
@@ -49,17 +101,30 @@ fn upload(
     )?)
 }
 
+// Which part of a file to download, and how to report the download's progress
+#[derive(Clone, Copy, Debug)]
+struct DownloadOptions {
+    // Where the part of the file to download starts
+    offset: u64,
+    // How much of the file to download. `None` downloads the rest of it
+    length: Option<u64>,
+    progress: ProgressMode,
+}
+
 fn download(
     host_ip: &str,
     remote_addr: &str,
     source_path: &str,
     target_path: &str,
-    prefix: Option<String>,
-    chunk_size: usize,
-    hold_count: u16,
+    f_config: FileProtocolConfig,
+    options: DownloadOptions,
 ) -> Result<(), failure::Error> {
-    let f_config = FileProtocolConfig::new(prefix, chunk_size, hold_count);
-    let f_protocol = FileProtocol::new(host_ip, remote_addr, f_config);
+    let DownloadOptions {
+        offset,
+        length,
+        progress,
+    } = options;
+    let f_protocol = connect(host_ip, remote_addr, f_config, progress);
 
     info!(
         "Downloading remote: {} to local: {}",
@@ -70,8 +135,9 @@ fn download(
     let channel = f_protocol.generate_channel()?;
 
     // Send our file request to the remote addr and verify that it's
-    // going to be able to send it
-    f_protocol.send_import(channel, source_path)?;
+    // going to be able to send it.
+    // The whole file is requested, unless only part of it was asked for
+    f_protocol.send_import_range(channel, source_path, offset, length)?;
 
     // Wait for the request reply.
     // Note/TODO: We don't use a timeout here because we don't know how long it will
@@ -89,9 +155,370 @@ fn download(
         },
     )?;
 
+    // Let the user know how to pick the transfer back up if it's interrupted
+    if let State::Receiving { hash, .. } = &state {
+        info!("Transfer hash: {}", hash);
+    }
+
     Ok(f_protocol.message_engine(|d| f_protocol.recv(Some(d)), Duration::from_secs(2), state)?)
 }
 
+fn upload_dir(
+    host_ip: &str,
+    remote_addr: &str,
+    source_path: &str,
+    target_path: &str,
+    f_config: FileProtocolConfig,
+    progress: ProgressMode,
+) -> Result<(), failure::Error> {
+    let f_protocol = FileProtocol::new(host_ip, remote_addr, f_config.clone());
+
+    info!(
+        "Uploading local directory:{} to remote:{}",
+        &source_path, &target_path
+    );
+
+    // Walk the directory tree and copy its manifest to temp storage
+    let (hash, num_chunks, entries) = f_protocol.initialize_directory(source_path)?;
+
+    // Generate channel id for transaction
+    let channel = f_protocol.generate_channel()?;
+
+    // Tell our destination the hash and number of chunks to expect
+    f_protocol.send_metadata(channel, &hash, num_chunks)?;
+
+    // Send the manifest so the destination can recreate the directory tree
+    f_protocol.send_export_dir(channel, &hash, target_path)?;
+
+    f_protocol.message_engine(
+        |d| f_protocol.recv(Some(d)),
+        Duration::from_secs(2),
+        State::Transmitting,
+    )?;
+
+    // Now that the directories exist, send each file individually
+    transfer_files(&entries, |entry| {
+        let source = manifest::entry_path(source_path, entry)?;
+        let target = manifest::entry_path(target_path, entry)?;
+
+        upload(
+            host_ip,
+            remote_addr,
+            &source.to_string_lossy(),
+            &target.to_string_lossy(),
+            f_config.clone(),
+            progress,
+        )
+    })
+}
+
+fn download_dir(
+    host_ip: &str,
+    remote_addr: &str,
+    source_path: &str,
+    target_path: &str,
+    f_config: FileProtocolConfig,
+    progress: ProgressMode,
+) -> Result<(), failure::Error> {
+    let f_protocol = FileProtocol::new(host_ip, remote_addr, f_config.clone());
+
+    info!(
+        "Downloading remote directory: {} to local: {}",
+        source_path, target_path
+    );
+
+    // Generate channel id for transaction
+    let channel = f_protocol.generate_channel()?;
+
+    // Ask the remote addr for the manifest of the directory tree
+    f_protocol.send_import_dir(channel, source_path)?;
+
+    let reply = match f_protocol.recv(None) {
+        Ok(message) => message,
+        Err(error) => bail!("Failed to import directory: {}", error),
+    };
+
+    // Receive the manifest into a scratch file so we can read it back
+    let manifest_path = manifest_file()?;
+
+    let entries = f_protocol
+        .process_message(
+            reply,
+            State::StartReceive {
+                path: manifest_path.to_string_lossy().into_owned(),
+            },
+        ).and_then(|state| {
+            f_protocol.message_engine(|d| f_protocol.recv(Some(d)), Duration::from_secs(2), state)
+        }).and_then(|_| manifest::load(&manifest_path));
+    let _ = fs::remove_file(&manifest_path);
+    let entries = entries?;
+
+    // Recreate the directory tree, then fetch each file individually
+    manifest::create_directories(target_path, &entries)?;
+
+    transfer_files(&entries, |entry| {
+        let source = manifest::entry_path(source_path, entry)?;
+        let target = manifest::entry_path(target_path, entry)?;
+
+        download(
+            host_ip,
+            remote_addr,
+            &source.to_string_lossy(),
+            &target.to_string_lossy(),
+            f_config.clone(),
+            DownloadOptions {
+                offset: 0,
+                length: None,
+                progress,
+            },
+        )
+    })
+}
+
+// Create an empty file in the temporary directory for a manifest to be received into.
+// It has a random name and mustn't already exist, so nobody else can have put
+// anything in its place
+fn manifest_file() -> Result<PathBuf, failure::Error> {
+    let path = env::temp_dir().join(format!(
+        ".{:016x}.manifest",
+        rand::thread_rng().gen::<u64>()
+    ));
+
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&path)?;
+
+    Ok(path)
+}
+
+// Transfer each file listed in a directory manifest, reporting the result of each one.
+// A failed file doesn't stop the remaining files from being transferred
+fn transfer_files<F>(entries: &[ManifestEntry], transfer: F) -> Result<(), failure::Error>
+where
+    F: Fn(&ManifestEntry) -> Result<(), failure::Error>,
+{
+    let files: Vec<&ManifestEntry> = entries.iter().filter(|entry| !entry.is_dir).collect();
+    let mut failures = 0;
+
+    for entry in files.iter() {
+        match transfer(entry) {
+            Ok(()) => info!("Transferred {}", entry.path),
+            Err(err) => {
+                error!("Failed to transfer {}: {}", entry.path, err);
+                failures += 1;
+            }
+        }
+    }
+
+    if failures > 0 {
+        bail!("{} of {} files failed to transfer", failures, files.len());
+    }
+
+    Ok(())
+}
+
+fn resume(
+    host_ip: &str,
+    remote_addr: &str,
+    hash: &str,
+    f_config: FileProtocolConfig,
+    progress: ProgressMode,
+) -> Result<(), failure::Error> {
+    let f_protocol = connect(host_ip, remote_addr, f_config, progress);
+
+    // Figure out which side of the transfer we were on
+    let state = f_protocol.load_state(hash)?;
+
+    info!("Resuming transfer of {}", hash);
+
+    // Generate channel id for transaction
+    let channel = f_protocol.generate_channel()?;
+
+    // Ask the remote target to pick up where it left off
+    f_protocol.send_resume(channel, hash)?;
+
+    let state = match state {
+        State::StartReceive { .. } => {
+            // We were downloading, so wait for the file info from the remote target.
+            // It will then be told which chunks we're still missing
+            let reply = match f_protocol.recv(None) {
+                Ok(message) => message,
+                Err(error) => bail!("Failed to resume transfer: {}", error),
+            };
+
+            f_protocol.process_message(reply, state)?
+        }
+        // We were uploading, so wait for the remote target to tell us which
+        // chunks it still needs
+        other => other,
+    };
+
+    Ok(f_protocol.message_engine(|d| f_protocol.recv(Some(d)), Duration::from_secs(2), state)?)
+}
+
+// Log a file's details in a format similar to `ls -l`
+fn print_info(info: &FileInfo) {
+    info!(
+        "{}{:o}\t{}\t{}\t{}",
+        if info.is_dir { "d" } else { "-" },
+        info.mode & 0o7777,
+        info.size,
+        info.mtime,
+        info.name
+    );
+}
+
+fn list(
+    host_ip: &str,
+    remote_addr: &str,
+    path: &str,
+    f_config: FileProtocolConfig,
+) -> Result<(), failure::Error> {
+    let f_protocol = FileProtocol::new(host_ip, remote_addr, f_config);
+
+    info!("Listing remote: {}", path);
+
+    for entry in f_protocol.remote_list(path)? {
+        print_info(&entry);
+    }
+
+    Ok(())
+}
+
+fn stat(
+    host_ip: &str,
+    remote_addr: &str,
+    path: &str,
+    f_config: FileProtocolConfig,
+) -> Result<(), failure::Error> {
+    let f_protocol = FileProtocol::new(host_ip, remote_addr, f_config);
+
+    print_info(&f_protocol.remote_stat(path)?);
+
+    Ok(())
+}
+
+fn delete(
+    host_ip: &str,
+    remote_addr: &str,
+    path: &str,
+    recursive: bool,
+    f_config: FileProtocolConfig,
+) -> Result<(), failure::Error> {
+    let f_protocol = FileProtocol::new(host_ip, remote_addr, f_config);
+
+    info!("Deleting remote: {}", path);
+
+    Ok(f_protocol.remote_delete(path, recursive)?)
+}
+
+fn mkdir(
+    host_ip: &str,
+    remote_addr: &str,
+    path: &str,
+    f_config: FileProtocolConfig,
+) -> Result<(), failure::Error> {
+    let f_protocol = FileProtocol::new(host_ip, remote_addr, f_config);
+
+    info!("Creating remote directory: {}", path);
+
+    Ok(f_protocol.remote_mkdir(path)?)
+}
+
+fn rename(
+    host_ip: &str,
+    remote_addr: &str,
+    source_path: &str,
+    target_path: Option<&str>,
+    f_config: FileProtocolConfig,
+) -> Result<(), failure::Error> {
+    let target_path = match target_path {
+        Some(path) => path,
+        None => bail!("A target path is required when moving a file"),
+    };
+
+    let f_protocol = FileProtocol::new(host_ip, remote_addr, f_config);
+
+    info!("Moving remote: {} to remote: {}", source_path, target_path);
+
+    Ok(f_protocol.remote_rename(source_path, target_path)?)
+}
+
+// Log a transaction's details, one per line
+fn print_transaction(info: &TransactionInfo) {
+    let status = match info.error {
+        Some(ref error) => format!("\t({})", error),
+        None => String::new(),
+    };
+
+    info!(
+        "{}\t{}\t{}/{}\t{}s\t{}\t{}{}",
+        info.channel_id,
+        info.direction.name(),
+        info.chunks_done,
+        info.num_chunks,
+        info.age,
+        if info.hash.is_empty() { "-" } else { &info.hash },
+        info.path,
+        status
+    );
+}
+
+fn transactions(
+    host_ip: &str,
+    remote_addr: &str,
+    f_config: FileProtocolConfig,
+) -> Result<(), failure::Error> {
+    let f_protocol = FileProtocol::new(host_ip, remote_addr, f_config);
+
+    info!("Listing active remote transactions");
+
+    for transaction in f_protocol.remote_transactions()? {
+        print_transaction(&transaction);
+    }
+
+    Ok(())
+}
+
+fn history(
+    host_ip: &str,
+    remote_addr: &str,
+    count: Option<&str>,
+    f_config: FileProtocolConfig,
+) -> Result<(), failure::Error> {
+    let count: u32 = match count {
+        Some(count) => count.parse()?,
+        None => 10,
+    };
+
+    let f_protocol = FileProtocol::new(host_ip, remote_addr, f_config);
+
+    info!("Listing finished remote transactions");
+
+    for transaction in f_protocol.remote_history(count)? {
+        print_transaction(&transaction);
+    }
+
+    Ok(())
+}
+
+fn cancel(
+    host_ip: &str,
+    remote_addr: &str,
+    channel: &str,
+    f_config: FileProtocolConfig,
+) -> Result<(), failure::Error> {
+    let channel: u32 = channel.parse()?;
+
+    let f_protocol = FileProtocol::new(host_ip, remote_addr, f_config);
+
+    info!("Cancelling remote transaction on channel {}", channel);
+
+    Ok(f_protocol.remote_cancel(channel)?)
+}
+
 fn main() {
     CombinedLogger::init(vec![
         TermLogger::new(LevelFilter::Info, Config::default()).unwrap(),
@@ -104,9 +531,21 @@ fn main() {
             Arg::with_name("operation")
                 .index(1)
                 .required(true)
-                .possible_values(&["upload", "download"])
+                .possible_values(&[
+                    "upload",
+                    "download",
+                    "resume",
+                    "list",
+                    "stat",
+                    "delete",
+                    "mkdir",
+                    "rename",
+                    "transactions",
+                    "history",
+                    "cancel",
+                ])
                 .case_insensitive(true),
-        ).arg(Arg::with_name("source_file").index(2).required(true))
+        ).arg(Arg::with_name("source_file").index(2))
         .arg(Arg::with_name("target_file").index(3))
         .arg(
             Arg::with_name("host_ip")
@@ -138,22 +577,81 @@ fn main() {
                 .short("-t")
                 .takes_value(true)
                 .default_value("6"),
+        ).arg(
+            Arg::with_name("window_size")
+                .short("-w")
+                .takes_value(true)
+                .default_value("0"),
+        ).arg(
+            Arg::with_name("max_rate")
+                .short("-b")
+                .takes_value(true)
+                .default_value("0"),
+        ).arg(
+            Arg::with_name("compression")
+                .short("-z")
+                .takes_value(true)
+                .possible_values(&["none", "deflate"])
+                .default_value("none"),
+        ).arg(
+            Arg::with_name("recursive")
+                .short("-R"),
+        ).arg(
+            Arg::with_name("streaming")
+                .short("-S"),
+        ).arg(
+            Arg::with_name("offset")
+                .long("offset")
+                .takes_value(true)
+                .default_value("0"),
+        ).arg(Arg::with_name("length").long("length").takes_value(true))
+        .arg(
+            Arg::with_name("fec_block")
+                .long("fec-block")
+                .takes_value(true)
+                .default_value("16"),
+        ).arg(
+            Arg::with_name("fec_parity")
+                .long("fec-parity")
+                .takes_value(true)
+                .default_value("0"),
+        ).arg(
+            Arg::with_name("progress")
+                .long("progress")
+                .takes_value(true)
+                .possible_values(&["line", "json", "none"])
+                .default_value("line"),
+        ).arg(
+            Arg::with_name("auth_key_file")
+                .long("auth-key-file")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("auth_window")
+                .long("auth-window")
+                .takes_value(true)
+                .default_value("30"),
         ).get_matches();
 
     // Get upload vs download (required)
     let command = args.value_of("operation").unwrap();
 
-    // Get source file (required)
-    let source_path = args.value_of("source_file").unwrap();
+    // Get source file. Only needed when the operation acts on a file
+    let source_path = match args.value_of("source_file") {
+        Some(path) => path,
+        None if command == "transactions" || command == "history" => "",
+        None => {
+            error!("A source path is required for {}", command);
+            return;
+        }
+    };
 
     // Get target file. If not present, just copy the filename from the source path
     let target_path: String = match args.value_of("target_file") {
         Some(path) => path.to_owned(),
         None => Path::new(&source_path)
             .file_name()
-            .unwrap()
-            .to_string_lossy()
-            .into_owned(),
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
     };
 
     let host_ip = args.value_of("host_ip").unwrap();
@@ -167,25 +665,107 @@ fn main() {
     let hold_count: u16 = args.value_of("hold_count").unwrap().parse().unwrap();
     let storage_prefix = args.value_of("storage_prefix").unwrap().to_string();
 
+    let window_size: u32 = args.value_of("window_size").unwrap().parse().unwrap();
+    let max_rate: u32 = args.value_of("max_rate").unwrap().parse().unwrap();
+
+    let compression: Compression = args.value_of("compression").unwrap().parse().unwrap();
+
+    let fec_block: u32 = args.value_of("fec_block").unwrap().parse().unwrap();
+    let fec_parity: u32 = args.value_of("fec_parity").unwrap().parse().unwrap();
+
+    let mut f_config = FileProtocolConfig::new(Some(storage_prefix), chunk_size, hold_count)
+        .with_window_size(window_size)
+        .with_max_rate(max_rate)
+        .with_compression(compression)
+        .with_streaming(args.is_present("streaming"))
+        .with_fec(fec_block, fec_parity);
+
+    // The key is kept out of the command line, where anyone could see it
+    let auth_key = match args.value_of("auth_key_file") {
+        Some(path) => match fs::read_to_string(path) {
+            Ok(key) => Some(key.trim_end().to_owned()),
+            Err(e) => {
+                error!("Failed to read key file {}: {}", path, e);
+                return;
+            }
+        },
+        None => env::var(AUTH_KEY_VAR).ok(),
+    };
+    if let Some(key) = auth_key {
+        let auth_window: u64 = args.value_of("auth_window").unwrap().parse().unwrap();
+        f_config = f_config.with_auth(key.as_bytes(), Duration::from_secs(auth_window));
+    }
+
+    let recursive = args.is_present("recursive");
+
+    // Downloads can be limited to part of the remote file
+    let offset: u64 = args.value_of("offset").unwrap().parse().unwrap();
+    let length: Option<u64> = args.value_of("length").map(|val| val.parse().unwrap());
+
+    let progress: ProgressMode = args.value_of("progress").unwrap().parse().unwrap();
+
     let result = match command.as_ref() {
+        "upload" if recursive => upload_dir(
+            host_ip,
+            &remote_addr,
+            source_path,
+            &target_path,
+            f_config,
+            progress,
+        ),
+        "download" if recursive => download_dir(
+            host_ip,
+            &remote_addr,
+            source_path,
+            &target_path,
+            f_config,
+            progress,
+        ),
         "upload" => upload(
             host_ip,
             &remote_addr,
-            &source_path,
+            source_path,
             &target_path,
-            Some(storage_prefix),
-            chunk_size,
-            hold_count,
+            f_config,
+            progress,
         ),
         "download" => download(
             host_ip,
             &remote_addr,
-            &source_path,
+            source_path,
             &target_path,
-            Some(storage_prefix),
-            chunk_size,
-            hold_count,
+            f_config,
+            DownloadOptions {
+                offset,
+                length,
+                progress,
+            },
+        ),
+        // The source argument is the hash of the interrupted transfer
+        "resume" => resume(host_ip, &remote_addr, source_path, f_config, progress),
+        // Remote file system operations only act on the source path,
+        // apart from rename, which moves it to the target path
+        "list" => list(host_ip, &remote_addr, source_path, f_config),
+        "stat" => stat(host_ip, &remote_addr, source_path, f_config),
+        "delete" => delete(host_ip, &remote_addr, source_path, recursive, f_config),
+        "mkdir" => mkdir(host_ip, &remote_addr, source_path, f_config),
+        "rename" => rename(
+            host_ip,
+            &remote_addr,
+            source_path,
+            args.value_of("target_file"),
+            f_config,
+        ),
+        // Transaction management operations act on the remote target's transactions.
+        // The source argument is the number of transactions or the channel
+        "transactions" => transactions(host_ip, &remote_addr, f_config),
+        "history" => history(
+            host_ip,
+            &remote_addr,
+            args.value_of("source_file"),
+            f_config,
         ),
+        "cancel" => cancel(host_ip, &remote_addr, source_path, f_config),
         // This shouldn't be possible, since we checked the string earlier
         _ => {
             error!("Unknown command given");
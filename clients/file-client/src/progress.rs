@@ -0,0 +1,135 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Display of file transfer progress
+
+use file_protocol::{Progress, ProgressCallback};
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// Minimum time between progress updates, so we don't flood the terminal
+const INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProgressMode {
+    // Don't display anything
+    None,
+    // Keep rewriting a single status line on stderr
+    Line,
+    // Write a JSON object to stdout for each update
+    Json,
+}
+
+impl FromStr for ProgressMode {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "none" => Ok(ProgressMode::None),
+            "line" => Ok(ProgressMode::Line),
+            "json" => Ok(ProgressMode::Json),
+            other => Err(format!("Unknown progress display: {}", other)),
+        }
+    }
+}
+
+impl ProgressMode {
+    // Create a callback which displays progress updates in this format.
+    // Updates are throttled, apart from the one for the end of the transfer
+    pub fn reporter(self) -> Option<ProgressCallback> {
+        if self == ProgressMode::None {
+            return None;
+        }
+
+        let last_shown: Cell<Option<Instant>> = Cell::new(None);
+
+        Some(Box::new(move |progress: &Progress| {
+            let finished = progress.remaining == 0;
+            if let Some(shown) = last_shown.get() {
+                if !finished && shown.elapsed() < INTERVAL {
+                    return;
+                }
+            }
+            last_shown.set(Some(Instant::now()));
+
+            match self {
+                ProgressMode::Line => {
+                    let mut stderr = io::stderr();
+                    let _ = write!(stderr, "\r{}", format_line(progress));
+                    if finished {
+                        let _ = writeln!(stderr);
+                    }
+                    let _ = stderr.flush();
+                }
+                ProgressMode::Json => println!("{}", format_json(progress)),
+                ProgressMode::None => {}
+            }
+        }))
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_millis()) / 1000.0
+}
+
+// Human-readable summary, eg. `12/40 chunks (30%)  1 NAK  5120 B/s  ETA 22s`
+fn format_line(progress: &Progress) -> String {
+    let percent = (progress.chunks_done() * 100)
+        .checked_div(progress.num_chunks)
+        .unwrap_or(100);
+
+    let eta = match progress.eta {
+        Some(eta) => format!("{}s", eta.as_secs()),
+        None => "--".to_owned(),
+    };
+
+    format!(
+        "{}/{} chunks ({}%)  {} NAK  {} B/s  ETA {}  ",
+        progress.chunks_done(),
+        progress.num_chunks,
+        percent,
+        progress.nak_rounds,
+        progress.rate,
+        eta
+    )
+}
+
+// One JSON object per line, for scripts to consume.
+// Times are given in seconds, and the ETA is `null` until it can be estimated
+fn format_json(progress: &Progress) -> String {
+    let eta = match progress.eta {
+        Some(eta) => format!("{:.3}", seconds(eta)),
+        None => "null".to_owned(),
+    };
+
+    format!(
+        "{{\"hash\":\"{}\",\"num_chunks\":{},\"remaining\":{},\"chunks_sent\":{},\
+         \"chunks_received\":{},\"nak_rounds\":{},\"bytes\":{},\"elapsed\":{:.3},\
+         \"rate\":{},\"eta\":{}}}",
+        progress.hash,
+        progress.num_chunks,
+        progress.remaining,
+        progress.chunks_sent,
+        progress.chunks_received,
+        progress.nak_rounds,
+        progress.bytes,
+        seconds(progress.elapsed),
+        progress.rate,
+        eta
+    )
+}
@@ -0,0 +1,307 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Message signing and verification
+//
+// Signed messages are framed as:
+//
+//   [ SIGNED ][ timestamp (8 bytes) ][ sequence (8 bytes) ][ CBOR body ][ HMAC (32 bytes) ]
+//
+// The timestamp is the number of milliseconds since the UNIX epoch when the message was sent,
+// and the sequence number counts up with each message, so that no two messages are the same.
+// The HMAC (using BLAKE2s) covers a byte giving the sender's role (0 for a client and
+// 1 for a service), followed by everything before it. The role isn't sent, so a message
+// only verifies at the other end of the link, and can't be sent back to where it came from.
+//
+// Messages are only accepted if their timestamp is within the replay window of the
+// receiver's clock, and if an identical message hasn't already been received within the window.
+
+use blake2_rfc::blake2s::{blake2s, Blake2s};
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ProtocolError;
+
+/// Frame type of a signed message
+pub const SIGNED: u8 = 3;
+
+const BLOCK_SIZE: usize = 64;
+const TAG_SIZE: usize = 32;
+const HEADER_SIZE: usize = 17;
+
+/// Number of bytes signing adds to a message
+pub const OVERHEAD: usize = HEADER_SIZE + TAG_SIZE;
+
+type Tag = [u8; TAG_SIZE];
+
+/// Which end of a link a signer is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Client,
+    Service,
+}
+
+impl Role {
+    fn byte(self) -> u8 {
+        match self {
+            Role::Client => 0,
+            Role::Service => 1,
+        }
+    }
+
+    // Who we expect messages from
+    fn peer(self) -> Role {
+        match self {
+            Role::Client => Role::Service,
+            Role::Service => Role::Client,
+        }
+    }
+}
+
+// Tags of the messages received within the replay window, in the order they were
+// received along with their timestamps, and as a set to look them up in
+type Seen = (VecDeque<(u64, Tag)>, HashSet<Tag>);
+
+pub struct Auth {
+    key: Vec<u8>,
+    window: Duration,
+    role: Role,
+    sequence: AtomicUsize,
+    // Tags of the messages received within the replay window, oldest first
+    seen: Mutex<Seen>,
+}
+
+impl Auth {
+    pub fn new(key: &[u8], window: Duration, role: Role) -> Self {
+        // Keys longer than a block are hashed down to size, as usual for HMAC
+        let key = if key.len() > BLOCK_SIZE {
+            blake2s(TAG_SIZE, &[], key).as_bytes().to_vec()
+        } else {
+            key.to_vec()
+        };
+
+        Auth {
+            key,
+            window,
+            role,
+            // Don't start from the same place every time we restart
+            sequence: AtomicUsize::new(now_millis() as usize),
+            seen: Mutex::new((VecDeque::new(), HashSet::new())),
+        }
+    }
+
+    // Wrap a message in a signed frame
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed) as u64;
+
+        let mut frame = Vec::with_capacity(message.len() + OVERHEAD);
+        frame.push(SIGNED);
+        frame.extend_from_slice(&to_bytes(now_millis()));
+        frame.extend_from_slice(&to_bytes(sequence));
+        frame.extend_from_slice(message);
+
+        let tag = self.hmac(self.role, &frame);
+        frame.extend_from_slice(&tag);
+        frame
+    }
+
+    // Check a signed frame, and get the message out of it
+    pub fn verify<'a>(&self, frame: &'a [u8]) -> Result<&'a [u8], ProtocolError> {
+        if frame.len() < OVERHEAD || frame[0] != SIGNED {
+            return Err(rejected("message is not signed"));
+        }
+
+        let (signed, received_tag) = frame.split_at(frame.len() - TAG_SIZE);
+        let tag = self.hmac(self.role.peer(), signed);
+        if !constant_time_eq(&tag, received_tag) {
+            return Err(rejected("signature doesn't match"));
+        }
+
+        let timestamp = from_bytes(&signed[1..9]);
+        let now = now_millis();
+        let window = self.window.as_secs() * 1000 + u64::from(self.window.subsec_millis());
+        if timestamp.saturating_sub(now) > window || now.saturating_sub(timestamp) > window {
+            return Err(rejected("timestamp is outside of the replay window"));
+        }
+
+        let mut seen = self.seen.lock().unwrap();
+        let (ref mut order, ref mut tags) = *seen;
+
+        // Anything older than the window would be rejected anyway, so can be forgotten
+        while order
+            .front()
+            .is_some_and(|&(time, _)| now.saturating_sub(time) > window)
+        {
+            if let Some((_, old)) = order.pop_front() {
+                tags.remove(&old);
+            }
+        }
+
+        if !tags.insert(tag) {
+            return Err(rejected("message has already been received"));
+        }
+        order.push_back((timestamp, tag));
+
+        Ok(&signed[HEADER_SIZE..])
+    }
+
+    fn hmac(&self, signer: Role, data: &[u8]) -> Tag {
+        let mut inner_key = [0x36; BLOCK_SIZE];
+        let mut outer_key = [0x5c; BLOCK_SIZE];
+        for (index, byte) in self.key.iter().enumerate() {
+            inner_key[index] ^= byte;
+            outer_key[index] ^= byte;
+        }
+
+        let mut inner = Blake2s::new(TAG_SIZE);
+        inner.update(&inner_key);
+        inner.update(&[signer.byte()]);
+        inner.update(data);
+
+        let mut outer = Blake2s::new(TAG_SIZE);
+        outer.update(&outer_key);
+        outer.update(inner.finalize().as_bytes());
+
+        let mut tag = [0; TAG_SIZE];
+        tag.copy_from_slice(outer.finalize().as_bytes());
+        tag
+    }
+}
+
+// Get the contents of a signed frame without checking it
+pub fn unwrap(frame: &[u8]) -> Result<&[u8], ProtocolError> {
+    if frame.len() < OVERHEAD {
+        return Err(rejected("signed message is too short"));
+    }
+
+    Ok(&frame[HEADER_SIZE..frame.len() - TAG_SIZE])
+}
+
+fn rejected(reason: &str) -> ProtocolError {
+    ProtocolError::AuthenticationFailed {
+        reason: reason.to_owned(),
+    }
+}
+
+fn now_millis() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+    now.as_secs() * 1000 + u64::from(now.subsec_millis())
+}
+
+fn to_bytes(value: u64) -> [u8; 8] {
+    let mut bytes = [0; 8];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = (value >> (56 - index * 8)) as u8;
+    }
+    bytes
+}
+
+fn from_bytes(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0, |value, byte| (value << 8) | u64::from(*byte))
+}
+
+// Compare tags without giving away how much of them matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(key: &[u8]) -> (Auth, Auth) {
+        (
+            Auth::new(key, Duration::from_secs(30), Role::Client),
+            Auth::new(key, Duration::from_secs(30), Role::Service),
+        )
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let (client, service) = pair(b"key");
+        let frame = client.sign(b"message");
+
+        assert_eq!(frame.len(), 7 + OVERHEAD);
+        assert_eq!(service.verify(&frame).unwrap(), b"message");
+        assert_eq!(unwrap(&frame).unwrap(), b"message");
+
+        // Each message gets a new sequence number, so it's signed differently
+        assert_ne!(client.sign(b"message"), frame);
+
+        assert_eq!(client.verify(&service.sign(b"reply")).unwrap(), b"reply");
+    }
+
+    #[test]
+    fn reject_replay() {
+        let (client, service) = pair(b"key");
+        let frame = client.sign(b"message");
+
+        assert!(service.verify(&frame).is_ok());
+        assert!(service.verify(&frame).is_err());
+    }
+
+    #[test]
+    fn reject_reflection() {
+        let (client, service) = pair(b"key");
+
+        // A message sent back to the end which signed it hasn't been seen there,
+        // but still isn't accepted
+        assert!(client.verify(&client.sign(b"message")).is_err());
+        assert!(service.verify(&service.sign(b"message")).is_err());
+    }
+
+    #[test]
+    fn reject_tampering() {
+        let (client, service) = pair(b"key");
+        let (_, other) = pair(b"other key");
+
+        let mut frame = client.sign(b"message");
+        assert!(other.verify(&frame).is_err());
+
+        frame[HEADER_SIZE] ^= 1;
+        assert!(service.verify(&frame).is_err());
+
+        assert!(service.verify(b"message").is_err());
+    }
+
+    #[test]
+    fn reject_old_message() {
+        let (client, service) = pair(b"key");
+
+        // Move the timestamp back a minute, and sign it again
+        let mut frame = client.sign(b"message");
+        let timestamp = from_bytes(&frame[1..9]) - 60_000;
+        frame[1..9].copy_from_slice(&to_bytes(timestamp));
+        frame.truncate(frame.len() - TAG_SIZE);
+        let tag = client.hmac(Role::Client, &frame);
+        frame.extend_from_slice(&tag);
+
+        assert!(service.verify(&frame).is_err());
+    }
+
+    #[test]
+    fn long_key() {
+        let (client, service) = pair(&[7; 100]);
+        let frame = client.sign(b"message");
+
+        assert_eq!(service.verify(&frame).unwrap(), b"message");
+    }
+}
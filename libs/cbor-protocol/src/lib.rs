@@ -16,6 +16,9 @@
 
 //! Kubos CBOR over UDP communication crate
 //!
+//! Messages are sent as UDP packets by default. Other links can be used
+//! by creating the protocol with a [`Transport`](trait.Transport.html)
+//!
 //! # Examples
 //!
 //! ```no_run
@@ -44,14 +47,55 @@
 #![deny(missing_docs)]
 #![deny(warnings)]
 
+extern crate blake2_rfc;
 #[macro_use]
 extern crate failure;
+extern crate rust_uart;
 extern crate serde_cbor;
+extern crate serial;
+
+mod auth;
+mod transport;
+
+pub use transport::{IntoTransport, SlipTransport, Transport, UdpTransport, UnixTransport};
 
+use transport::unspecified;
+
+use auth::{Auth, Role};
 use serde_cbor::de;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
 use std::io;
-use std::net::{SocketAddr, UdpSocket};
-use std::time::Duration;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Frame types
+const MESSAGE: u8 = 0;
+const PAUSE: u8 = 1;
+const RESUME: u8 = 2;
+const FRAGMENT: u8 = 4;
+
+// A fragment's message ID, index and fragment count, after the frame type
+const FRAGMENT_HEADER: usize = 8;
+
+/// Default number of messages which can be queued up for paused destinations
+pub const DEFAULT_QUEUE_LIMIT: usize = 64;
+
+/// Default number of seconds a destination stays paused if it never sends a resume frame
+pub const DEFAULT_MAX_PAUSE: u64 = 10;
+
+/// Most fragments a message can be split into
+pub const MAX_FRAGMENTS: usize = 256;
+
+/// Default number of seconds to wait for the rest of a fragmented message
+pub const DEFAULT_REASSEMBLY_TIMEOUT: u64 = 5;
+
+/// Default number of fragmented messages which can be put back together at once
+pub const DEFAULT_REASSEMBLY_MESSAGES: usize = 32;
+
+/// Default number of fragmented messages which can be put back together at once
+/// for any one sender
+pub const DEFAULT_REASSEMBLY_PEER_MESSAGES: usize = 4;
 
 /// An error generated during protocol execution
 #[derive(Debug, Fail)]
@@ -88,12 +132,67 @@ pub enum ProtocolError {
         /// Cause of parsing failure
         err: String,
     },
+    /// Indicates a message which couldn't be authenticated
+    #[fail(display = "Rejected message: {}", reason)]
+    AuthenticationFailed {
+        /// Why the message was rejected
+        reason: String,
+    },
+    /// Not a failure. The remote end asked for messages to it to be held back
+    #[fail(display = "Remote end paused")]
+    Paused,
+    /// Not a failure. The remote end is ready for messages again. Any held back messages
+    /// have been sent
+    #[fail(display = "Remote end resumed")]
+    Resumed,
+    /// A message couldn't be queued for a paused destination, because the queue is full
+    #[fail(display = "Send queue for {} is full", dest)]
+    QueueFull {
+        /// The paused destination
+        dest: SocketAddr,
+    },
+    /// A message needs more than `MAX_FRAGMENTS` fragments to send
+    #[fail(display = "Message of {} bytes is too large to send", size)]
+    MessageTooLarge {
+        /// Size of the message
+        size: usize,
+    },
 }
 
 /// CBOR protocol communication structure
 pub struct Protocol {
-    handle: UdpSocket,
+    handle: Box<dyn Transport + Send>,
+    // Largest frame we send, before signing. Larger messages are split into fragments
+    frame_size: usize,
     msg_size: usize,
+    // Signs and checks messages, if a key has been given
+    auth: Option<Auth>,
+    // Destinations which have asked us to stop sending, and when we start again anyway,
+    // in case their resume frame is lost
+    paused: RefCell<HashMap<SocketAddr, Instant>>,
+    max_pause: Duration,
+    // Messages waiting for their destination to resume
+    queue: RefCell<VecDeque<(SocketAddr, Vec<u8>)>>,
+    queue_limit: usize,
+    // ID for the next fragmented message we send
+    next_id: Cell<u32>,
+    // Fragmented messages which are still being received, by sender and message ID
+    partial: RefCell<HashMap<(SocketAddr, u32), Partial>>,
+    reassembly_timeout: Duration,
+    // Most fragmented messages which can be put back together at once, overall and
+    // for each sender, and the most fragment data which can be held on to for them
+    reassembly_messages: usize,
+    reassembly_peer_messages: usize,
+    reassembly_bytes: usize,
+}
+
+// The fragments of a message received so far
+struct Partial {
+    fragments: Vec<Option<Vec<u8>>>,
+    missing: usize,
+    started: Instant,
+    // Size of the fragments received so far
+    bytes: usize,
 }
 
 impl Protocol {
@@ -102,7 +201,8 @@ impl Protocol {
     /// # Arguments
     ///
     /// * host_url - The IP address and port to bind
-    /// * data_size - Expected max size of payload in messages
+    /// * data_size - Expected max size of payload in messages. Larger messages are split
+    ///   into fragments, and put back together by the receiver
     ///
     /// # Errors
     ///
@@ -117,12 +217,223 @@ impl Protocol {
     /// ```
     ///
     pub fn new(host_url: String, data_size: usize) -> Self {
+        Self::from_transport(
+            UdpTransport::bind(host_url.parse::<SocketAddr>().unwrap()).unwrap(),
+            data_size,
+        )
+    }
+
+    /// Creates a new protocol instance which sends and receives messages over the given transport
+    ///
+    /// # Arguments
+    ///
+    /// * transport - The link to use, or an IP address (with or without a port) to bind a UDP socket to
+    /// * data_size - Expected max size of payload in messages. Larger messages are split
+    ///   into fragments, and put back together by the receiver
+    ///
+    /// # Errors
+    ///
+    /// If an IP address is given and it can't be bound, this function will panic
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cbor_protocol::*;
+    ///
+    /// let (local, _remote) = UnixTransport::pair().unwrap();
+    /// let cbor_connection = Protocol::from_transport(local, 4096);
+    /// ```
+    ///
+    pub fn from_transport<T: IntoTransport>(transport: T, data_size: usize) -> Self {
+        // Leave room for the rest of the message around the payload
+        // (ex. a file chunk's channel ID, hash, index and checksum,
+        // or the position of a parity chunk), and for a signature
+        let frame_size = data_size + 96;
+
+        // Don't start from the same place every time we restart, so that fragments
+        // of a message sent before a restart don't get mixed up with new ones
+        let first_id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.subsec_nanos())
+            .unwrap_or(0);
+
         Self {
-            handle: UdpSocket::bind(host_url.parse::<SocketAddr>().unwrap()).unwrap(),
-            msg_size: data_size + 50,
+            handle: transport.into_transport(),
+            frame_size,
+            msg_size: frame_size + auth::OVERHEAD,
+            auth: None,
+            paused: RefCell::new(HashMap::new()),
+            max_pause: Duration::from_secs(DEFAULT_MAX_PAUSE),
+            queue: RefCell::new(VecDeque::new()),
+            queue_limit: DEFAULT_QUEUE_LIMIT,
+            next_id: Cell::new(first_id),
+            partial: RefCell::new(HashMap::new()),
+            reassembly_timeout: Duration::from_secs(DEFAULT_REASSEMBLY_TIMEOUT),
+            reassembly_messages: DEFAULT_REASSEMBLY_MESSAGES,
+            reassembly_peer_messages: DEFAULT_REASSEMBLY_PEER_MESSAGES,
+            // Enough for each sender to send its largest possible messages
+            reassembly_bytes: DEFAULT_REASSEMBLY_PEER_MESSAGES * MAX_FRAGMENTS * frame_size,
         }
     }
 
+    /// Set how many messages can be held back while destinations are paused
+    ///
+    /// # Arguments
+    ///
+    /// * limit - Maximum number of queued messages, across all destinations
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cbor_protocol::*;
+    ///
+    /// let cbor_connection = Protocol::new("0.0.0.0:8003".to_owned(), 4096).with_queue_limit(16);
+    /// ```
+    ///
+    pub fn with_queue_limit(mut self, limit: usize) -> Self {
+        self.queue_limit = limit;
+        self
+    }
+
+    /// Set how long a destination stays paused without sending a resume frame
+    ///
+    /// Once this has passed since the destination's last pause frame, its queued messages
+    /// are sent along with the next message, as though it had resumed. This stops a lost
+    /// resume frame from holding messages back forever
+    ///
+    /// # Arguments
+    ///
+    /// * max_pause - Longest time to hold messages back for a paused destination
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cbor_protocol::*;
+    /// use std::time::Duration;
+    ///
+    /// let cbor_connection = Protocol::new("0.0.0.0:8006".to_owned(), 4096)
+    ///     .with_max_pause(Duration::from_secs(30));
+    /// ```
+    ///
+    pub fn with_max_pause(mut self, max_pause: Duration) -> Self {
+        self.max_pause = max_pause;
+        self
+    }
+
+    /// Set how long to wait for the rest of a fragmented message
+    ///
+    /// If any of a message's fragments haven't arrived by the time this has passed since
+    /// the first one did, the message is dropped
+    ///
+    /// # Arguments
+    ///
+    /// * timeout - Maximum time to wait for all of a message's fragments
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cbor_protocol::*;
+    /// use std::time::Duration;
+    ///
+    /// let cbor_connection = Protocol::new("0.0.0.0:8004".to_owned(), 4096)
+    ///     .with_reassembly_timeout(Duration::from_secs(1));
+    /// ```
+    ///
+    pub fn with_reassembly_timeout(mut self, timeout: Duration) -> Self {
+        self.reassembly_timeout = timeout;
+        self
+    }
+
+    /// Limit how many fragmented messages can be put back together at once
+    ///
+    /// Once a limit is reached, the oldest incomplete message is dropped to make room
+    /// for the next one
+    ///
+    /// # Arguments
+    ///
+    /// * messages - Maximum number of incomplete messages, across all senders
+    /// * peer_messages - Maximum number of incomplete messages from any one sender
+    /// * bytes - Maximum size of the fragments held for incomplete messages, across all senders
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cbor_protocol::*;
+    ///
+    /// let cbor_connection = Protocol::new("0.0.0.0:8005".to_owned(), 4096)
+    ///     .with_reassembly_limits(8, 2, 1 << 20);
+    /// ```
+    ///
+    pub fn with_reassembly_limits(
+        mut self,
+        messages: usize,
+        peer_messages: usize,
+        bytes: usize,
+    ) -> Self {
+        self.reassembly_messages = messages;
+        self.reassembly_peer_messages = peer_messages;
+        self.reassembly_bytes = bytes;
+        self
+    }
+
+    /// Sign all sent messages, and only accept received messages which are signed
+    ///
+    /// Messages are signed with an HMAC, using a key which both sides share. They also carry
+    /// the time they were sent, and are rejected if that's further from the receiver's clock
+    /// than the replay window, or if the same message has already been received.
+    ///
+    /// Messages are signed as a client, so only messages signed by a service are accepted.
+    /// Services should use [`with_service_auth`](#method.with_service_auth) instead
+    ///
+    /// # Arguments
+    ///
+    /// * key - Shared secret key
+    /// * window - How far apart the sender's and receiver's clocks can be, including
+    ///   the time it takes for messages to arrive
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cbor_protocol::*;
+    /// use std::time::Duration;
+    ///
+    /// let cbor_connection = Protocol::new("0.0.0.0:8002".to_owned(), 4096)
+    ///     .with_auth(b"shared secret", Duration::from_secs(30));
+    /// ```
+    ///
+    pub fn with_auth(mut self, key: &[u8], window: Duration) -> Self {
+        self.auth = Some(Auth::new(key, window, Role::Client));
+        self
+    }
+
+    /// Sign and check messages as a service, rather than as a client
+    ///
+    /// Works the same way as [`with_auth`](#method.with_auth). Which end of the link signed
+    /// a message is part of its signature, so services only accept messages signed by
+    /// clients and clients only accept messages signed by services. This stops a message
+    /// from being sent back to where it came from
+    ///
+    /// # Arguments
+    ///
+    /// * key - Shared secret key
+    /// * window - How far apart the sender's and receiver's clocks can be, including
+    ///   the time it takes for messages to arrive
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use cbor_protocol::*;
+    /// use std::time::Duration;
+    ///
+    /// let cbor_connection = Protocol::new("0.0.0.0:8007".to_owned(), 4096)
+    ///     .with_service_auth(b"shared secret", Duration::from_secs(30));
+    /// ```
+    ///
+    pub fn with_service_auth(mut self, key: &[u8], window: Duration) -> Self {
+        self.auth = Some(Auth::new(key, window, Role::Service));
+        self
+    }
+
     /// Send a CBOR packet to a specified UDP socket destination
     ///
     /// # Arguments
@@ -163,16 +474,20 @@ impl Protocol {
     /// ```
     ///
     pub fn send_message(&self, message: &[u8], dest: SocketAddr) -> Result<(), ProtocolError> {
-        // TODO: If paused, just queue up the message
+        self.poll_control();
+        self.expire_pauses()?;
 
-        let mut payload = vec![];
-        payload.extend(message);
-        payload.insert(0, 0);
+        // Hold on to the message until the destination is ready for it
+        if self.is_paused(&dest) {
+            let mut queue = self.queue.borrow_mut();
+            if queue.len() >= self.queue_limit {
+                return Err(ProtocolError::QueueFull { dest });
+            }
+            queue.push_back((dest, message.to_vec()));
+            return Ok(());
+        }
 
-        self.handle
-            .send_to(&payload, &dest)
-            .map_err(|err| ProtocolError::SendFailed { dest, err })?;
-        Ok(())
+        self.send_frame(MESSAGE, message, dest)
     }
 
     /// Send a pause message to a specified UDP socket destination
@@ -198,11 +513,7 @@ impl Protocol {
     pub fn send_pause(&self, dest: SocketAddr) -> Result<(), ProtocolError> {
         println!("-> pause");
 
-        let payload = vec![1];
-        self.handle
-            .send_to(&payload, &dest)
-            .map_err(|err| ProtocolError::SendFailed { dest, err })?;
-        Ok(())
+        self.send_frame(PAUSE, &[], dest)
     }
 
     /// Send a resume message to a specified UDP socket destination
@@ -228,13 +539,135 @@ impl Protocol {
     pub fn send_resume(&self, dest: SocketAddr) -> Result<(), ProtocolError> {
         println!("-> resume");
 
-        let payload = vec![2];
+        self.send_frame(RESUME, &[], dest)
+    }
+
+    // Send a frame, signing it if we have a key.
+    // Signed pause and resume frames carry their frame type as their body
+    fn send_frame(&self, kind: u8, body: &[u8], dest: SocketAddr) -> Result<(), ProtocolError> {
+        if kind == MESSAGE && body.len() + 1 > self.frame_size {
+            return self.send_fragments(body, dest);
+        }
+
+        let payload = match (self.auth.as_ref(), kind) {
+            (Some(auth), MESSAGE) => auth.sign(body),
+            (Some(auth), kind) => auth.sign(&[kind]),
+            (None, kind) => {
+                let mut payload = vec![kind];
+                payload.extend(body);
+                payload
+            }
+        };
+
+        self.send_raw(&payload, dest)
+    }
+
+    // Split a message which is too large for one frame into fragments.
+    // Signed fragments carry the whole fragment frame as their body
+    fn send_fragments(&self, body: &[u8], dest: SocketAddr) -> Result<(), ProtocolError> {
+        let fragment_size = self.frame_size - 1 - FRAGMENT_HEADER;
+        let count = body.len().div_ceil(fragment_size);
+        if count > MAX_FRAGMENTS {
+            return Err(ProtocolError::MessageTooLarge { size: body.len() });
+        }
+
+        let id = self.next_id.get();
+        self.next_id.set(id.wrapping_add(1));
+
+        for (index, data) in body.chunks(fragment_size).enumerate() {
+            let mut fragment = Vec::with_capacity(1 + FRAGMENT_HEADER + data.len());
+            fragment.push(FRAGMENT);
+            fragment.extend_from_slice(&to_bytes(u64::from(id), 4));
+            fragment.extend_from_slice(&to_bytes(index as u64, 2));
+            fragment.extend_from_slice(&to_bytes(count as u64, 2));
+            fragment.extend_from_slice(data);
+
+            let payload = match self.auth {
+                Some(ref auth) => auth.sign(&fragment),
+                None => fragment,
+            };
+            self.send_raw(&payload, dest)?;
+        }
+        Ok(())
+    }
+
+    fn send_raw(&self, payload: &[u8], dest: SocketAddr) -> Result<(), ProtocolError> {
         self.handle
-            .send_to(&payload, &dest)
+            .send_to(payload, &dest)
             .map_err(|err| ProtocolError::SendFailed { dest, err })?;
         Ok(())
     }
 
+    // Whether messages to a destination should be held back.
+    // Links without addresses only have one peer, so a pause from it covers everything
+    fn is_paused(&self, dest: &SocketAddr) -> bool {
+        let paused = self.paused.borrow();
+        paused.contains_key(dest) || paused.contains_key(&unspecified())
+    }
+
+    // Stop waiting for destinations which paused too long ago,
+    // and send what was held back for them
+    fn expire_pauses(&self) -> Result<(), ProtocolError> {
+        let now = Instant::now();
+        let expired: Vec<SocketAddr> = self
+            .paused
+            .borrow()
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(peer, _)| *peer)
+            .collect();
+
+        for peer in expired {
+            eprintln!("Pause from {} expired without a resume", peer);
+            self.paused.borrow_mut().remove(&peer);
+            self.flush(peer)?;
+        }
+        Ok(())
+    }
+
+    // Send everything which was held back for a destination,
+    // other than messages for destinations which are still paused
+    fn flush(&self, peer: SocketAddr) -> Result<(), ProtocolError> {
+        let everything = peer == unspecified();
+        let (ready, waiting) = self
+            .queue
+            .borrow_mut()
+            .drain(..)
+            .partition(|&(dest, _)| (everything || dest == peer) && !self.is_paused(&dest));
+        *self.queue.borrow_mut() = waiting;
+
+        let ready: VecDeque<(SocketAddr, Vec<u8>)> = ready;
+        for (dest, message) in ready {
+            self.send_frame(MESSAGE, &message, dest)?;
+        }
+        Ok(())
+    }
+
+    // Pick up any pause or resume frame which arrived while we were busy sending,
+    // so that we notice without having to wait for a reply.
+    // Only possible with transports which can check for messages without waiting
+    fn poll_control(&self) {
+        // One byte more than the largest control frame, so that we can tell them
+        // apart from longer messages
+        let mut buf = [0; auth::OVERHEAD + 2];
+        let is_control = match self.handle.try_peek_from(&mut buf) {
+            Ok(Some((size, _))) => match &buf[..size] {
+                &[PAUSE] | &[RESUME] => true,
+                frame if size == auth::OVERHEAD + 1 && frame[0] == auth::SIGNED => {
+                    matches!(auth::unwrap(frame), Ok(&[PAUSE]) | Ok(&[RESUME]))
+                }
+                _ => false,
+            },
+            _ => false,
+        };
+
+        if is_control {
+            if let Ok((peer, data)) = self.recv_raw(None) {
+                let _ = self.recv_start(peer, &data);
+            }
+        }
+    }
+
     /// Receive a UDP message (no timeout)
     ///
     /// # Errors
@@ -252,13 +685,9 @@ impl Protocol {
     /// ```
     ///
     pub fn recv_message(&self) -> Result<serde_cbor::Value, ProtocolError> {
-        let mut buf = vec![0; self.msg_size];
-        let (size, _peer) = self
-            .handle
-            .recv_from(&mut buf)
-            .map_err(|err| ProtocolError::ReceiveFailed { err })?;
+        let (_peer, message) = self.recv_next(None)?;
 
-        self.recv_start(&buf[0..size])
+        Ok(message)
     }
 
     /// Peek at the sender information for the next message in the UDP receive buffer
@@ -305,14 +734,7 @@ impl Protocol {
     /// ```
     ///
     pub fn recv_message_peer(&self) -> Result<(SocketAddr, serde_cbor::Value), ProtocolError> {
-        let mut buf = vec![0; self.msg_size];
-        let (size, peer) = self
-            .handle
-            .recv_from(&mut buf)
-            .map_err(|err| ProtocolError::ReceiveFailed { err })?;
-
-        let message = self.recv_start(&buf[0..size])?;
-        Ok((peer, message))
+        self.recv_next(None)
     }
 
     /// Receive a UDP message and take note of the sender (with timeout)
@@ -350,30 +772,7 @@ impl Protocol {
         &self,
         timeout: Duration,
     ) -> Result<(SocketAddr, serde_cbor::Value), ProtocolError> {
-        // Set the timeout for this particular receive
-        self.handle
-            .set_read_timeout(Some(timeout))
-            .map_err(|err| ProtocolError::IoError { err })?;
-
-        let mut buf = vec![0; self.msg_size];
-
-        let result = self.handle.recv_from(&mut buf);
-
-        // Reset the timeout for future calls
-        // TODO: Decide what should happen if this fails...
-        let _ = self.handle.set_read_timeout(None);
-
-        let (size, peer) = match result {
-            Ok(data) => data,
-            Err(err) => match err.kind() {
-                // For some reason, UDP recv returns WouldBlock for timeouts
-                io::ErrorKind::WouldBlock => return Err(ProtocolError::Timeout),
-                _ => return Err(ProtocolError::ReceiveFailed { err }),
-            },
-        };
-
-        let message = self.recv_start(&buf[0..size])?;
-        Ok((peer, message))
+        self.recv_next(Some(timeout))
     }
 
     /// Receive a UDP message (with timeout)
@@ -411,69 +810,519 @@ impl Protocol {
         &self,
         timeout: Duration,
     ) -> Result<serde_cbor::Value, ProtocolError> {
-        // Set the timeout for this particular receive
-        self.handle
-            .set_read_timeout(Some(timeout))
-            .map_err(|err| ProtocolError::IoError { err })?;
+        let (_peer, message) = self.recv_next(Some(timeout))?;
 
-        let mut buf = vec![0; self.msg_size];
+        Ok(message)
+    }
+
+    // Receive the next whole message, waiting for all of its fragments if it was split up
+    fn recv_next(
+        &self,
+        timeout: Option<Duration>,
+    ) -> Result<(SocketAddr, serde_cbor::Value), ProtocolError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        // Don't hold on to messages which will never be finished, even if no more
+        // fragments arrive to make us look
+        self.prune_partial();
 
-        let result = self.handle.recv_from(&mut buf);
+        loop {
+            let remaining = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(ProtocolError::Timeout);
+                    }
+                    Some(deadline - now)
+                }
+                None => None,
+            };
+
+            let (peer, data) = self.recv_raw(remaining)?;
+            if let Some(message) = self.recv_start(peer, &data)? {
+                return Ok((peer, message));
+            }
+        }
+    }
 
-        // Reset the timeout for future calls
-        // TODO: Decide what should happen if this fails...
-        let _ = self.handle.set_read_timeout(None);
+    // Receive the raw bytes of a message from the transport
+    fn recv_raw(&self, timeout: Option<Duration>) -> Result<(SocketAddr, Vec<u8>), ProtocolError> {
+        let mut buf = vec![0; self.msg_size];
 
-        let (size, _peer) = match result {
+        let (size, peer) = match self.handle.recv_from(&mut buf, timeout) {
             Ok(data) => data,
             Err(err) => match err.kind() {
                 // For some reason, UDP recv returns WouldBlock for timeouts
-                ::std::io::ErrorKind::WouldBlock => return Err(ProtocolError::Timeout),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut if timeout.is_some() => {
+                    return Err(ProtocolError::Timeout)
+                }
                 _ => return Err(ProtocolError::ReceiveFailed { err }),
             },
         };
 
-        Ok(self.recv_start(&buf[0..size])?)
+        buf.truncate(size);
+        Ok((peer, buf))
     }
 
-    // Parse the received CBOR message
-    fn recv_start(&self, data: &[u8]) -> Result<serde_cbor::Value, ProtocolError> {
+    // Parse the received CBOR message, or act on a control frame.
+    // Returns nothing for fragments of a message which hasn't been completely received yet
+    fn recv_start(
+        &self,
+        peer: SocketAddr,
+        data: &[u8],
+    ) -> Result<Option<serde_cbor::Value>, ProtocolError> {
         if data.len() == 0 {
             return Err(ProtocolError::NoDataReceived);
         }
 
-        let result = match data[0] {
-            0 => {
-                let message: serde_cbor::Value =
-                    de::from_slice(&data[1..]).map_err(|err| ProtocolError::ParseFail {
-                        err: format!("{:?}", err),
-                    })?;
+        let frame = match self.auth {
+            // Once we have a key, anything which isn't properly signed is turned away
+            Some(ref auth) => signed_frame(auth.verify(data)?),
+            None => match data[0] {
+                MESSAGE => Frame::Message(&data[1..]),
+                PAUSE => Frame::Pause,
+                RESUME => Frame::Resume,
+                FRAGMENT => Frame::Fragment(&data[1..]),
+                // We can't check the signature without a key, but we can still use what's inside
+                auth::SIGNED => signed_frame(auth::unwrap(data)?),
+                x => {
+                    eprintln!("Ignoring unknown control frame: {}", x);
+                    return Err(ProtocolError::NoDataReceived);
+                }
+            },
+        };
 
-                if message.is_array() {
-                    message
+        match frame {
+            Frame::Message(body) => parse_body(body).map(Some),
+            Frame::Fragment(fragment) => match self.reassemble(peer, fragment)? {
+                Some(body) => parse_body(&body).map(Some),
+                None => Ok(None),
+            },
+            Frame::Pause => {
+                println!("<- pause");
+                // Pausing again puts off the expiry
+                self.paused
+                    .borrow_mut()
+                    .insert(peer, Instant::now() + self.max_pause);
+                Err(ProtocolError::Paused)
+            }
+            Frame::Resume => {
+                println!("<- resume");
+                if peer == unspecified() {
+                    self.paused.borrow_mut().clear();
                 } else {
-                    return Err(ProtocolError::ParseFail {
-                        err: "Body is not an array".to_owned(),
-                    });
+                    self.paused.borrow_mut().remove(&peer);
                 }
+                self.flush(peer)?;
+                Err(ProtocolError::Resumed)
             }
-            1 => {
-                println!("<- pause");
-                //TODO: Evaluate whether to keep/use pause & resume
-                //TODO: self.pause()?;
-                return Err(ProtocolError::NoDataReceived);
+        }
+    }
+
+    // Store a received fragment, and put its message back together once
+    // all of the fragments are here
+    fn reassemble(
+        &self,
+        peer: SocketAddr,
+        fragment: &[u8],
+    ) -> Result<Option<Vec<u8>>, ProtocolError> {
+        if fragment.len() < FRAGMENT_HEADER {
+            return Err(ProtocolError::ParseFail {
+                err: "Fragment is too short".to_owned(),
+            });
+        }
+
+        let id = from_bytes(&fragment[0..4]) as u32;
+        let index = from_bytes(&fragment[4..6]) as usize;
+        let count = from_bytes(&fragment[6..8]) as usize;
+        if index >= count || count > MAX_FRAGMENTS {
+            return Err(ProtocolError::ParseFail {
+                err: format!("Invalid fragment {} of {}", index, count),
+            });
+        }
+
+        self.prune_partial();
+        let mut partial = self.partial.borrow_mut();
+
+        // Make room for a new message by dropping the oldest ones
+        if !partial.contains_key(&(peer, id)) {
+            while partial.keys().filter(|&&(sender, _)| sender == peer).count()
+                >= self.reassembly_peer_messages
+                && drop_oldest(&mut partial, Some(peer))
+            {}
+            while partial.len() >= self.reassembly_messages && drop_oldest(&mut partial, None) {}
+        }
+
+        let complete = {
+            let message = partial.entry((peer, id)).or_insert_with(|| Partial {
+                fragments: vec![None; count],
+                missing: count,
+                started: Instant::now(),
+                bytes: 0,
+            });
+
+            if message.fragments.len() != count {
+                return Err(ProtocolError::ParseFail {
+                    err: format!("Fragment count of message {} changed", id),
+                });
             }
-            2 => {
-                println!("<- resume");
-                // TODO: self.resume()?;
-                return Err(ProtocolError::NoDataReceived);
+
+            let data = fragment[FRAGMENT_HEADER..].to_vec();
+            match message.fragments[index] {
+                Some(ref old) => message.bytes -= old.len(),
+                None => message.missing -= 1,
             }
-            x => {
-                eprintln!("Ignoring unknown control frame: {}", x);
-                return Err(ProtocolError::NoDataReceived);
+            message.bytes += data.len();
+            message.fragments[index] = Some(data);
+            message.missing == 0
+        };
+
+        // Keep the fragments we're holding on to within bounds, which may mean
+        // giving up on this message too
+        while partial.values().map(|message| message.bytes).sum::<usize>() > self.reassembly_bytes
+            && drop_oldest(&mut partial, None)
+        {}
+
+        if !complete || !partial.contains_key(&(peer, id)) {
+            return Ok(None);
+        }
+
+        let mut body = vec![];
+        if let Some(message) = partial.remove(&(peer, id)) {
+            for data in message.fragments {
+                body.extend(data.unwrap_or_default());
             }
+        }
+        Ok(Some(body))
+    }
+
+    // Give up on messages whose other fragments haven't turned up in time
+    fn prune_partial(&self) {
+        let timeout = self.reassembly_timeout;
+        self.partial.borrow_mut().retain(|&(sender, id), message| {
+            let expired = message.started.elapsed() > timeout;
+            if expired {
+                eprintln!("Dropping incomplete message {} from {}", id, sender);
+            }
+            !expired
+        });
+    }
+}
+
+// Drop the incomplete message which started arriving first, either from
+// a particular sender or from anyone. Returns false if there wasn't one to drop
+fn drop_oldest(
+    partial: &mut HashMap<(SocketAddr, u32), Partial>,
+    peer: Option<SocketAddr>,
+) -> bool {
+    let oldest = partial
+        .iter()
+        .filter(|(&(sender, _), _)| peer.is_none_or(|peer| peer == sender))
+        .min_by_key(|(_, message)| message.started)
+        .map(|(&key, _)| key);
+
+    match oldest {
+        Some((sender, id)) => {
+            eprintln!("Dropping incomplete message {} from {} to make room", id, sender);
+            partial.remove(&(sender, id));
+            true
+        }
+        None => false,
+    }
+}
+
+// The contents of a received frame
+enum Frame<'a> {
+    Message(&'a [u8]),
+    Pause,
+    Resume,
+    // A piece of a larger message, starting with its header
+    Fragment(&'a [u8]),
+}
+
+// Work out what the body of a signed frame holds.
+// CBOR messages are always arrays, so can't start with any of the frame types
+fn signed_frame<'a>(body: &'a [u8]) -> Frame<'a> {
+    match body {
+        &[PAUSE] => Frame::Pause,
+        &[RESUME] => Frame::Resume,
+        body if body.first() == Some(&FRAGMENT) => Frame::Fragment(&body[1..]),
+        body => Frame::Message(body),
+    }
+}
+
+// Big-endian conversions for fragment headers
+fn to_bytes(value: u64, size: usize) -> Vec<u8> {
+    (0..size)
+        .rev()
+        .map(|index| (value >> (index * 8)) as u8)
+        .collect()
+}
+
+fn from_bytes(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0, |value, byte| (value << 8) | u64::from(*byte))
+}
+
+// Parse the CBOR body of a message
+fn parse_body(body: &[u8]) -> Result<serde_cbor::Value, ProtocolError> {
+    let message: serde_cbor::Value =
+        de::from_slice(body).map_err(|err| ProtocolError::ParseFail {
+            err: format!("{:?}", err),
+        })?;
+
+    if message.is_array() {
+        Ok(message)
+    } else {
+        Err(ProtocolError::ParseFail {
+            err: "Body is not an array".to_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_cbor::Value;
+    use std::thread;
+
+    fn expect_message(protocol: &Protocol, value: u64) {
+        assert_eq!(
+            protocol
+                .recv_message_timeout(Duration::from_millis(100))
+                .unwrap(),
+            Value::Array(vec![Value::U64(value)])
+        );
+    }
+
+    #[test]
+    fn send_from_another_thread() {
+        let (first, second) = UnixTransport::pair().unwrap();
+        let sender = Protocol::from_transport(first, 4096);
+        let receiver = Protocol::from_transport(second, 4096);
+
+        thread::spawn(move || sender.send_message(&[0x81, 0x01], unspecified()).unwrap())
+            .join()
+            .unwrap();
+        expect_message(&receiver, 1);
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let (first, second) = UnixTransport::pair().unwrap();
+        let sender = Protocol::from_transport(first, 4096).with_queue_limit(2);
+        let receiver = Protocol::from_transport(second, 4096);
+
+        receiver.send_pause(unspecified()).unwrap();
+        match sender.recv_message_timeout(Duration::from_millis(100)) {
+            Err(ProtocolError::Paused) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        // Messages are held back until the queue fills up
+        sender.send_message(&[0x81, 0x01], unspecified()).unwrap();
+        sender.send_message(&[0x81, 0x02], unspecified()).unwrap();
+        match sender.send_message(&[0x81, 0x03], unspecified()) {
+            Err(ProtocolError::QueueFull { .. }) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        match receiver.recv_message_timeout(Duration::from_millis(10)) {
+            Err(ProtocolError::Timeout) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        // Then sent in order once the receiver is ready for them
+        receiver.send_resume(unspecified()).unwrap();
+        match sender.recv_message_timeout(Duration::from_millis(100)) {
+            Err(ProtocolError::Resumed) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        expect_message(&receiver, 1);
+        expect_message(&receiver, 2);
+
+        sender.send_message(&[0x81, 0x04], unspecified()).unwrap();
+        expect_message(&receiver, 4);
+    }
+
+    #[test]
+    fn pause_expires_without_resume() {
+        let (first, second) = UnixTransport::pair().unwrap();
+        let sender =
+            Protocol::from_transport(first, 4096).with_max_pause(Duration::from_millis(100));
+        let receiver = Protocol::from_transport(second, 4096);
+
+        receiver.send_pause(unspecified()).unwrap();
+        match sender.recv_message_timeout(Duration::from_millis(100)) {
+            Err(ProtocolError::Paused) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        sender.send_message(&[0x81, 0x01], unspecified()).unwrap();
+        match receiver.recv_message_timeout(Duration::from_millis(10)) {
+            Err(ProtocolError::Timeout) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        // The resume frame is lost, so the sender carries on by itself
+        thread::sleep(Duration::from_millis(150));
+        sender.send_message(&[0x81, 0x02], unspecified()).unwrap();
+        expect_message(&receiver, 1);
+        expect_message(&receiver, 2);
+    }
+
+    #[test]
+    fn pause_while_sending() {
+        let sender = Protocol::new("127.0.0.1:8010".to_owned(), 4096)
+            .with_auth(b"key", Duration::from_secs(30));
+        let receiver = Protocol::new("127.0.0.1:8011".to_owned(), 4096)
+            .with_service_auth(b"key", Duration::from_secs(30));
+        let sender_addr = "127.0.0.1:8010".parse().unwrap();
+        let receiver_addr = "127.0.0.1:8011".parse().unwrap();
+
+        // The sender notices the pause without having to receive anything itself
+        receiver.send_pause(sender_addr).unwrap();
+        sender.send_message(&[0x81, 0x01], receiver_addr).unwrap();
+        match receiver.recv_message_timeout(Duration::from_millis(10)) {
+            Err(ProtocolError::Timeout) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        receiver.send_resume(sender_addr).unwrap();
+        sender.send_message(&[0x81, 0x02], receiver_addr).unwrap();
+        expect_message(&receiver, 1);
+        expect_message(&receiver, 2);
+    }
+
+    // A CBOR array holding a single byte string
+    fn large_message(size: usize) -> Vec<u8> {
+        let mut message = vec![0x81, 0x59];
+        message.extend(to_bytes(size as u64, 2));
+        message.extend((0..size).map(|index| index as u8));
+        message
+    }
+
+    fn expect_large_message(protocol: &Protocol, size: usize) {
+        assert_eq!(
+            protocol
+                .recv_message_timeout(Duration::from_millis(100))
+                .unwrap(),
+            Value::Array(vec![Value::Bytes(
+                (0..size).map(|index| index as u8).collect(),
+            )])
+        );
+    }
+
+    #[test]
+    fn fragmented_message() {
+        let (first, second) = UnixTransport::pair().unwrap();
+        let sender = Protocol::from_transport(first, 64);
+        let receiver = Protocol::from_transport(second, 64);
+
+        sender
+            .send_message(&large_message(1000), unspecified())
+            .unwrap();
+        sender.send_message(&[0x81, 0x01], unspecified()).unwrap();
+
+        expect_large_message(&receiver, 1000);
+        expect_message(&receiver, 1);
+    }
+
+    #[test]
+    fn fragmented_signed_message() {
+        let (first, second) = UnixTransport::pair().unwrap();
+        let sender = Protocol::from_transport(first, 64).with_auth(b"key", Duration::from_secs(30));
+        let receiver =
+            Protocol::from_transport(second, 64).with_service_auth(b"key", Duration::from_secs(30));
+
+        sender
+            .send_message(&large_message(1000), unspecified())
+            .unwrap();
+        expect_large_message(&receiver, 1000);
+    }
+
+    #[test]
+    fn message_too_large() {
+        let (first, _second) = UnixTransport::pair().unwrap();
+        let sender = Protocol::from_transport(first, 64);
+
+        match sender.send_message(&large_message(60000), unspecified()) {
+            Err(ProtocolError::MessageTooLarge { size: 60004 }) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reassembly() {
+        let (first, second) = UnixTransport::pair().unwrap();
+        let receiver =
+            Protocol::from_transport(second, 64).with_reassembly_timeout(Duration::from_millis(50));
+
+        let message = large_message(20);
+        let fragment = |id: u64, index: u64, data: &[u8]| {
+            let mut fragment = vec![FRAGMENT];
+            fragment.extend(to_bytes(id, 4));
+            fragment.extend(to_bytes(index, 2));
+            fragment.extend(to_bytes(2, 2));
+            fragment.extend_from_slice(data);
+            first.send_to(&fragment, &unspecified()).unwrap();
         };
 
-        Ok(result)
+        // Fragments can arrive in any order
+        fragment(1, 1, &message[10..]);
+        fragment(1, 0, &message[..10]);
+        expect_large_message(&receiver, 20);
+
+        // But the rest of the message is dropped if they don't all arrive in time
+        let expect_timeout = || match receiver.recv_message_timeout(Duration::from_millis(10)) {
+            Err(ProtocolError::Timeout) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        };
+        fragment(2, 0, &message[..10]);
+        expect_timeout();
+        thread::sleep(Duration::from_millis(100));
+        fragment(2, 1, &message[10..]);
+        expect_timeout();
+
+        // Expired messages are dropped whenever we receive, even if no more fragments arrive
+        thread::sleep(Duration::from_millis(100));
+        expect_timeout();
+        assert!(receiver.partial.borrow().is_empty());
+    }
+
+    #[test]
+    fn reassembly_limits() {
+        let (first, second) = UnixTransport::pair().unwrap();
+        let receiver = Protocol::from_transport(second, 64).with_reassembly_limits(8, 2, 35);
+
+        let message = large_message(20);
+        let fragment = |id: u64, index: u64, data: &[u8]| {
+            let mut fragment = vec![FRAGMENT];
+            fragment.extend(to_bytes(id, 4));
+            fragment.extend(to_bytes(index, 2));
+            fragment.extend(to_bytes(2, 2));
+            fragment.extend_from_slice(data);
+            first.send_to(&fragment, &unspecified()).unwrap();
+        };
+        let expect_timeout = || match receiver.recv_message_timeout(Duration::from_millis(10)) {
+            Err(ProtocolError::Timeout) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        };
+
+        // Starting a third message from the same sender drops the oldest one
+        fragment(1, 0, &message[..10]);
+        fragment(2, 0, &message[..10]);
+        fragment(3, 0, &message[..10]);
+        fragment(1, 1, &message[10..]);
+        expect_timeout();
+        assert_eq!(receiver.partial.borrow().len(), 2);
+
+        // Going over the size limit drops the oldest message too,
+        // even if it's the one which would have been finished
+        fragment(3, 1, &message[10..]);
+        expect_timeout();
+        assert_eq!(receiver.partial.borrow().len(), 1);
+        fragment(1, 0, &message[..10]);
+        expect_large_message(&receiver, 20);
     }
 }
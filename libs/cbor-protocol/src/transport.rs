@@ -0,0 +1,555 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Links which CBOR messages can be carried over
+
+use rust_uart::{Connection, UartError};
+use serial::{BaudRate, CharSize, FlowControl, Parity, PortSettings, StopBits};
+use std::cell::RefCell;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// A way of sending and receiving whole messages
+///
+/// Messages are addressed with socket addresses. Links which only ever connect two
+/// points (a pair of Unix sockets, or a serial line) ignore the destination of sent
+/// messages, and report the unspecified address (`0.0.0.0:0`) as the source of
+/// received ones.
+///
+/// Transports must be `Send`, so that a protocol can be moved onto another thread
+pub trait Transport: Send {
+    /// Send a message
+    fn send_to(&self, data: &[u8], dest: &SocketAddr) -> io::Result<()>;
+
+    /// Receive a message into `buf`, returning its length and where it came from
+    ///
+    /// Gives up with an error of kind `WouldBlock` or `TimedOut` if nothing arrives
+    /// within `timeout`. Waits forever if no timeout is given
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<(usize, SocketAddr)>;
+
+    /// Find out where the next message came from, without removing it
+    fn peek_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)>;
+
+    /// Look at the next message without removing it, if one has already arrived
+    ///
+    /// Used to notice flow control frames while sending. Transports which can't check
+    /// without waiting can leave this out, and flow control frames will be picked up
+    /// the next time a message is received instead
+    fn try_peek_from(&self, _buf: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        Ok(None)
+    }
+}
+
+/// Something which can be turned into a transport
+///
+/// Lets protocol constructors take either a ready-made transport, or an IP address
+/// to bind a new UDP socket to. An address without a port is given a free one
+pub trait IntoTransport {
+    /// Create the transport
+    fn into_transport(self) -> Box<dyn Transport + Send>;
+}
+
+impl<T: Transport + 'static> IntoTransport for T {
+    fn into_transport(self) -> Box<dyn Transport + Send> {
+        Box::new(self)
+    }
+}
+
+impl IntoTransport for &str {
+    fn into_transport(self) -> Box<dyn Transport + Send> {
+        let addr = self
+            .parse::<SocketAddr>()
+            .or_else(|_| self.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 0)))
+            .unwrap();
+        Box::new(UdpTransport::bind(addr).unwrap())
+    }
+}
+
+/// Messages sent as UDP packets
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    /// Bind a UDP socket to the given address
+    pub fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Ok(UdpTransport {
+            socket: UdpSocket::bind(addr)?,
+        })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send_to(&self, data: &[u8], dest: &SocketAddr) -> io::Result<()> {
+        self.socket.send_to(data, dest).map(|_| ())
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<(usize, SocketAddr)> {
+        // Set the timeout for this particular receive
+        self.socket.set_read_timeout(timeout)?;
+        let result = self.socket.recv_from(buf);
+
+        // Reset the timeout for future calls
+        // TODO: Decide what should happen if this fails...
+        let _ = self.socket.set_read_timeout(None);
+
+        result
+    }
+
+    fn peek_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.peek_from(buf)
+    }
+
+    fn try_peek_from(&self, buf: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        self.socket.set_nonblocking(true)?;
+        let result = self.socket.peek_from(buf);
+        let _ = self.socket.set_nonblocking(false);
+
+        match result {
+            Ok(peeked) => Ok(Some(peeked)),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Messages sent over a Unix datagram socket, to a single peer
+pub struct UnixTransport {
+    socket: UnixDatagram,
+    // Where to send messages, if the socket isn't connected
+    peer: Option<PathBuf>,
+}
+
+impl UnixTransport {
+    /// Bind a socket to `path`, which sends messages to the socket bound to `peer`
+    ///
+    /// The peer doesn't need to exist yet
+    pub fn bind<P: AsRef<Path>, Q: AsRef<Path>>(path: P, peer: Q) -> io::Result<Self> {
+        Ok(UnixTransport {
+            socket: UnixDatagram::bind(path)?,
+            peer: Some(peer.as_ref().to_path_buf()),
+        })
+    }
+
+    /// Create a pair of connected sockets
+    pub fn pair() -> io::Result<(Self, Self)> {
+        let (first, second) = UnixDatagram::pair()?;
+        Ok((
+            UnixTransport {
+                socket: first,
+                peer: None,
+            },
+            UnixTransport {
+                socket: second,
+                peer: None,
+            },
+        ))
+    }
+}
+
+impl Transport for UnixTransport {
+    fn send_to(&self, data: &[u8], _dest: &SocketAddr) -> io::Result<()> {
+        let result = match self.peer {
+            Some(ref peer) => self.socket.send_to(data, peer),
+            None => self.socket.send(data),
+        };
+        result.map(|_| ())
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<(usize, SocketAddr)> {
+        self.socket.set_read_timeout(timeout)?;
+        let result = self.socket.recv(buf);
+        let _ = self.socket.set_read_timeout(None);
+
+        result.map(|size| (size, unspecified()))
+    }
+
+    fn peek_from(&self, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        // There's only ever one peer, so there's nothing to look at
+        Ok((0, unspecified()))
+    }
+}
+
+// SLIP special characters. KISS uses the same ones
+const END: u8 = 0xC0;
+const ESC: u8 = 0xDB;
+const ESC_END: u8 = 0xDC;
+const ESC_ESC: u8 = 0xDD;
+
+// KISS command for a data frame on the first port
+const KISS_DATA: u8 = 0x00;
+
+// How long to wait for each read when there's no timeout
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Messages framed with SLIP (RFC 1055) over a serial link
+///
+/// With KISS enabled, each frame also starts with a KISS command byte, as expected by
+/// TNCs and many packet radios. Only data frames for the first port are sent and received
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate cbor_protocol;
+/// extern crate rust_uart;
+///
+/// use cbor_protocol::*;
+/// use rust_uart::Connection;
+///
+/// # fn main() {}
+/// // Talk to a radio which expects KISS frames
+/// fn radio_link(connection: Connection) -> Protocol {
+///     Protocol::from_transport(SlipTransport::new(connection).with_kiss(), 200)
+/// }
+/// ```
+pub struct SlipTransport {
+    connection: Connection,
+    kiss: bool,
+    // A frame we've started receiving, whether its last byte was an escape,
+    // and whether it's grown too large and is being skipped until its END
+    partial: RefCell<(Vec<u8>, bool, bool)>,
+}
+
+impl SlipTransport {
+    /// Send and receive messages over a serial connection
+    pub fn new(connection: Connection) -> Self {
+        SlipTransport {
+            connection,
+            kiss: false,
+            partial: RefCell::new((vec![], false, false)),
+        }
+    }
+
+    /// Open a serial port, such as `/dev/ttyS1`, at the given baud rate
+    ///
+    /// The port is set up for 8 data bits, no parity, one stop bit and no flow control
+    pub fn open(path: &str, baud_rate: usize) -> io::Result<Self> {
+        let settings = PortSettings {
+            baud_rate: BaudRate::from_speed(baud_rate),
+            char_size: CharSize::Bits8,
+            parity: Parity::ParityNone,
+            stop_bits: StopBits::Stop1,
+            flow_control: FlowControl::FlowNone,
+        };
+        let connection =
+            Connection::from_path(path, settings, POLL_INTERVAL).map_err(uart_error)?;
+        Ok(SlipTransport::new(connection))
+    }
+
+    /// Add KISS command bytes to each frame
+    pub fn with_kiss(mut self) -> Self {
+        self.kiss = true;
+        self
+    }
+
+    // Read a single byte, waiting until the deadline if there is one
+    fn read_byte(&self, deadline: Option<Instant>) -> io::Result<u8> {
+        loop {
+            let wait = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out"));
+                    }
+                    deadline - now
+                }
+                None => POLL_INTERVAL,
+            };
+
+            match self.connection.read(1, wait) {
+                Ok(data) => return Ok(data[0]),
+                Err(UartError::IoError {
+                    cause: io::ErrorKind::TimedOut,
+                    ..
+                }) if deadline.is_none() => {}
+                Err(err) => return Err(uart_error(err)),
+            }
+        }
+    }
+}
+
+impl Transport for SlipTransport {
+    fn send_to(&self, data: &[u8], _dest: &SocketAddr) -> io::Result<()> {
+        self.connection
+            .write(&encode(data, self.kiss))
+            .map_err(uart_error)
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> io::Result<(usize, SocketAddr)> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let byte = self.read_byte(deadline)?;
+
+            // Anything read so far is kept for next time if we time out mid-frame
+            let mut partial = self.partial.borrow_mut();
+            let (ref mut frame, ref mut escaped, ref mut oversized) = *partial;
+            if *oversized {
+                if byte != END {
+                    continue;
+                }
+                *oversized = false;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Received frame is too large",
+                ));
+            }
+
+            let decoded = decode_byte(frame, escaped, byte);
+            // Don't keep buffering a frame which could never fit,
+            // in case its END got lost or the line is just noise
+            if frame.len() > buf.len() + self.kiss as usize {
+                frame.clear();
+                *escaped = false;
+                *oversized = true;
+                continue;
+            }
+
+            if let Some(frame) = decoded {
+                let body = match (self.kiss, frame.split_first()) {
+                    (false, _) => &frame[..],
+                    (true, Some((&KISS_DATA, body))) => body,
+                    // Ignore other KISS commands
+                    (true, _) => continue,
+                };
+
+                // Empty frames are just the gaps between messages
+                if body.is_empty() {
+                    continue;
+                }
+                if body.len() > buf.len() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Received frame is too large",
+                    ));
+                }
+
+                buf[..body.len()].copy_from_slice(body);
+                return Ok((body.len(), unspecified()));
+            }
+        }
+    }
+
+    fn peek_from(&self, _buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        // There's only ever one peer, so there's nothing to look at
+        Ok((0, unspecified()))
+    }
+}
+
+// Wrap a message in a SLIP frame. Starting with END flushes out any line noise
+fn encode(data: &[u8], kiss: bool) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(data.len() + 3);
+    frame.push(END);
+    if kiss {
+        frame.push(KISS_DATA);
+    }
+
+    for &byte in data {
+        match byte {
+            END => frame.extend_from_slice(&[ESC, ESC_END]),
+            ESC => frame.extend_from_slice(&[ESC, ESC_ESC]),
+            byte => frame.push(byte),
+        }
+    }
+
+    frame.push(END);
+    frame
+}
+
+// Add a received byte to the frame being built up.
+// Returns the whole frame once its END arrives
+fn decode_byte(frame: &mut Vec<u8>, escaped: &mut bool, byte: u8) -> Option<Vec<u8>> {
+    if *escaped {
+        *escaped = false;
+        frame.push(match byte {
+            ESC_END => END,
+            ESC_ESC => ESC,
+            // Not a valid escape, but keep the byte rather than guess
+            byte => byte,
+        });
+        return None;
+    }
+
+    match byte {
+        END => Some(mem::take(frame)),
+        ESC => {
+            *escaped = true;
+            None
+        }
+        byte => {
+            frame.push(byte);
+            None
+        }
+    }
+}
+
+fn uart_error(err: UartError) -> io::Error {
+    match err {
+        UartError::IoError { cause, description } => io::Error::new(cause, description),
+        err => io::Error::other(format!("{}", err)),
+    }
+}
+
+// The address given for messages on links which don't have addresses
+pub(crate) fn unspecified() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_uart::mock::MockStream;
+    use Protocol;
+    use ProtocolError;
+
+    fn slip(output: Vec<u8>) -> SlipTransport {
+        let mut mock = MockStream::default();
+        mock.read.set_output(output);
+        SlipTransport::new(Connection::new(Box::new(mock)))
+    }
+
+    #[test]
+    fn slip_framing() {
+        let data = [1, END, 2, ESC, 3];
+        let frame = vec![END, 1, ESC, ESC_END, 2, ESC, ESC_ESC, 3, END];
+
+        assert_eq!(encode(&data, false), frame);
+        assert_eq!(
+            encode(&data, true),
+            vec![END, KISS_DATA, 1, ESC, ESC_END, 2, ESC, ESC_ESC, 3, END]
+        );
+
+        let mut mock = MockStream::default();
+        mock.write.set_input(frame);
+        let transport = SlipTransport::new(Connection::new(Box::new(mock)));
+        assert!(transport.send_to(&data, &unspecified()).is_ok());
+    }
+
+    #[test]
+    fn slip_receive() {
+        let mut output = encode(&[1, END, 2], false);
+        output.extend_from_slice(&encode(&[ESC, 3], false));
+        // Half a frame, which never gets finished
+        output.extend_from_slice(&[END, 4, 5]);
+        let transport = slip(output);
+
+        let mut buf = [0; 16];
+        let (size, peer) = transport
+            .recv_from(&mut buf, Some(Duration::from_millis(10)))
+            .unwrap();
+        assert_eq!(&buf[..size], &[1, END, 2]);
+        assert_eq!(peer, unspecified());
+
+        let (size, _) = transport
+            .recv_from(&mut buf, Some(Duration::from_millis(10)))
+            .unwrap();
+        assert_eq!(&buf[..size], &[ESC, 3]);
+
+        let err = transport
+            .recv_from(&mut buf, Some(Duration::from_millis(10)))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn slip_frame_too_large() {
+        let transport = slip(encode(&[1, 2, 3, 4], false));
+
+        let mut buf = [0; 2];
+        let err = transport
+            .recv_from(&mut buf, Some(Duration::from_millis(10)))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn slip_skip_oversized_frame() {
+        // A frame with no END, followed by one which does fit
+        let mut output = vec![END];
+        output.extend_from_slice(&[9; 64]);
+        output.extend_from_slice(&encode(&[1, 2], false));
+        let transport = slip(output);
+
+        let mut buf = [0; 4];
+        let err = transport
+            .recv_from(&mut buf, Some(Duration::from_millis(10)))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(transport.partial.borrow().0.len() <= buf.len());
+
+        let (size, _) = transport
+            .recv_from(&mut buf, Some(Duration::from_millis(10)))
+            .unwrap();
+        assert_eq!(&buf[..size], &[1, 2]);
+    }
+
+    #[test]
+    fn kiss_receive() {
+        // A frame which sets the TX delay, rather than carrying data
+        let mut output = vec![END, 0x01, 50, END];
+        output.extend_from_slice(&encode(&[6, 7], true));
+        let transport = slip(output).with_kiss();
+
+        let mut buf = [0; 16];
+        let (size, _) = transport
+            .recv_from(&mut buf, Some(Duration::from_millis(10)))
+            .unwrap();
+        assert_eq!(&buf[..size], &[6, 7]);
+    }
+
+    #[test]
+    fn unix_pair() {
+        let (first, second) = UnixTransport::pair().unwrap();
+        let first = Protocol::from_transport(first, 4096);
+        let second = Protocol::from_transport(second, 4096);
+
+        // Point-to-point links ignore where messages are sent
+        first.send_message(&[0x81, 0x01], unspecified()).unwrap();
+        let (peer, message) = second
+            .recv_message_peer_timeout(Duration::from_millis(100))
+            .unwrap();
+        assert_eq!(peer, unspecified());
+        assert_eq!(
+            message,
+            ::serde_cbor::Value::Array(vec![::serde_cbor::Value::U64(1)])
+        );
+
+        match second.recv_message_timeout(Duration::from_millis(10)) {
+            Err(ProtocolError::Timeout) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+}
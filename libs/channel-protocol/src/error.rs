@@ -15,6 +15,7 @@
 //
 
 use cbor_protocol;
+use serde_cbor;
 
 /// Errors which occur when using ChannelProtocol
 #[derive(Debug, Fail)]
@@ -39,6 +40,20 @@ pub enum ProtocolError {
         /// Underlying error encountered
         err: String,
     },
+    /// An error was encountered when creating a message
+    #[fail(display = "Unable to create {} message: {}", message, err)]
+    MessageCreationError {
+        /// Message which was being created
+        message: String,
+        /// Underlying serialization error
+        err: serde_cbor::error::Error,
+    },
+    /// The remote end doesn't support something we need
+    #[fail(display = "Remote end doesn't support {}", feature)]
+    Unsupported {
+        /// The missing feature
+        feature: String,
+    },
 }
 
 impl From<cbor_protocol::ProtocolError> for ProtocolError {
@@ -0,0 +1,188 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Hello messages, which let each end find out what the other supports
+//
+// { channel_id, "hello", version, [ feature, ... ], max_chunk_size }
+
+use error::ProtocolError;
+use serde_cbor::{ser, Value};
+
+/// What one end of a channel supports
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Capabilities {
+    /// Version of the protocol spoken over the channel
+    pub version: u32,
+    /// Names of the optional features which are supported
+    pub features: Vec<String>,
+    /// Largest chunk of data which can be received in a single message
+    pub max_chunk_size: u32,
+}
+
+impl Capabilities {
+    /// Create a description of a protocol version, without any optional features
+    ///
+    /// # Arguments
+    ///
+    /// * version - Version of the protocol
+    /// * max_chunk_size - Largest chunk of data which can be received in a single message
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use channel_protocol::*;
+    ///
+    /// let capabilities = Capabilities::new(1, 4096)
+    ///     .with_feature("compression")
+    ///     .with_feature("ranges");
+    ///
+    /// assert!(capabilities.supports("ranges"));
+    /// ```
+    ///
+    pub fn new(version: u32, max_chunk_size: u32) -> Self {
+        Capabilities {
+            version,
+            features: vec![],
+            max_chunk_size,
+        }
+    }
+
+    /// Add an optional feature to the supported list
+    pub fn with_feature(mut self, feature: &str) -> Self {
+        self.features.push(feature.to_owned());
+        self
+    }
+
+    /// Whether an optional feature is supported
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|name| name == feature)
+    }
+
+    /// Check that an optional feature is supported
+    ///
+    /// # Errors
+    ///
+    /// Returns `ProtocolError::Unsupported` if it isn't
+    pub fn require(&self, feature: &str) -> Result<(), ProtocolError> {
+        if self.supports(feature) {
+            Ok(())
+        } else {
+            Err(ProtocolError::Unsupported {
+                feature: feature.to_owned(),
+            })
+        }
+    }
+
+    /// Create a hello message describing these capabilities
+    pub fn to_cbor(&self, channel_id: u32) -> Result<Vec<u8>, ProtocolError> {
+        let features: Vec<Value> = self
+            .features
+            .iter()
+            .map(|feature| Value::String(feature.to_owned()))
+            .collect();
+
+        ser::to_vec_packed(&(
+            channel_id,
+            "hello",
+            self.version,
+            features,
+            self.max_chunk_size,
+        )).map_err(|err| ProtocolError::MessageCreationError {
+            message: "hello".to_owned(),
+            err,
+        })
+    }
+
+    /// Read the capabilities out of a hello message's payload
+    ///
+    /// Anything after the known fields is ignored, so that later versions can add more
+    pub fn from_payload(payload: &[Value]) -> Result<Self, ProtocolError> {
+        let version = match payload.first() {
+            Some(Value::U64(version)) => *version as u32,
+            _ => {
+                return Err(ProtocolError::MessageParseError {
+                    err: "No hello version found".to_owned(),
+                })
+            }
+        };
+
+        let features = match payload.get(1) {
+            Some(Value::Array(features)) => features
+                .iter()
+                .filter_map(|feature| feature.as_string().map(|name| name.to_owned()))
+                .collect(),
+            _ => {
+                return Err(ProtocolError::MessageParseError {
+                    err: "No hello features found".to_owned(),
+                })
+            }
+        };
+
+        let max_chunk_size = match payload.get(2) {
+            Some(Value::U64(size)) => *size as u32,
+            _ => {
+                return Err(ProtocolError::MessageParseError {
+                    err: "No hello chunk size found".to_owned(),
+                })
+            }
+        };
+
+        Ok(Capabilities {
+            version,
+            features,
+            max_chunk_size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parsers::parse_message;
+    use serde_cbor::de;
+
+    #[test]
+    fn create_parse_hello() {
+        let capabilities = Capabilities::new(2, 1024).with_feature("fec");
+
+        let raw = capabilities.to_cbor(10).unwrap();
+        let message = parse_message(de::from_slice(&raw).unwrap()).unwrap();
+
+        assert_eq!(message.channel_id, 10);
+        assert_eq!(message.name, "hello");
+        assert_eq!(
+            Capabilities::from_payload(&message.payload).unwrap(),
+            capabilities
+        );
+    }
+
+    #[test]
+    fn require_feature() {
+        let capabilities = Capabilities::new(1, 4096).with_feature("compression");
+
+        assert!(capabilities.require("compression").is_ok());
+        match capabilities.require("ranges") {
+            Err(ProtocolError::Unsupported { feature }) => assert_eq!(feature, "ranges"),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_bad_hello() {
+        assert!(Capabilities::from_payload(&[]).is_err());
+        assert!(Capabilities::from_payload(&[Value::U64(1), Value::U64(2)]).is_err());
+    }
+}
@@ -22,10 +22,12 @@ extern crate rand;
 extern crate serde_cbor;
 
 mod error;
+mod hello;
 mod parsers;
 mod protocol;
 
 pub use error::ProtocolError;
+pub use hello::Capabilities;
 pub use parsers::*;
 pub use protocol::Message as ChannelMessage;
 pub use protocol::Protocol as ChannelProtocol;
@@ -14,13 +14,17 @@
 // limitations under the License.
 //
 
-use cbor_protocol::Protocol as CborProtocol;
+use cbor_protocol;
+use cbor_protocol::{IntoTransport, Protocol as CborProtocol};
 use error::ProtocolError;
+use generate_channel;
+use hello::Capabilities;
 use parsers::parse_message;
 use serde_cbor::Value;
 use std::cell::Cell;
+use std::cmp;
 use std::net::SocketAddr;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct Message {
@@ -35,12 +39,14 @@ pub struct Protocol {
 }
 
 impl Protocol {
-    /// Create a new channel protocol instance using an automatically assigned UDP socket
+    /// Create a new channel protocol instance
     ///
     /// # Arguments
     ///
-    /// * host_ip - The local IP address
-    /// * remote_addr - The remote IP and port to communicate with
+    /// * transport - The link to communicate over, or the local IP address to bind
+    ///   an automatically assigned UDP socket to
+    /// * remote_addr - The remote IP and port to communicate with. Ignored by links
+    ///   which only connect two points, such as a serial line
     /// * data_len - Max payload length
     ///
     /// # Errors
@@ -55,9 +61,8 @@ impl Protocol {
     /// let channel_protocol = ChannelProtocol::new("0.0.0.0", "192.168.0.1:7000", 4096);
     /// ```
     ///
-    pub fn new(host_ip: &str, remote_addr: &str, data_len: u32) -> Self {
-        // Get a local UDP socket (Bind)
-        let c_protocol = CborProtocol::new(format!("{}:0", host_ip), data_len as usize);
+    pub fn new<T: IntoTransport>(transport: T, remote_addr: &str, data_len: u32) -> Self {
+        let c_protocol = CborProtocol::from_transport(transport, data_len as usize);
 
         // Set up the full connection info
         Protocol {
@@ -66,6 +71,55 @@ impl Protocol {
         }
     }
 
+    /// Sign all sent messages, and only accept received messages which are signed
+    ///
+    /// # Arguments
+    ///
+    /// * key - Key shared with the remote side
+    /// * window - How far from the current time a message can have been sent
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use channel_protocol::*;
+    /// use std::time::Duration;
+    ///
+    /// let channel_protocol = ChannelProtocol::new("0.0.0.0", "192.168.0.1:7000", 4096)
+    ///     .with_auth(b"shared secret", Duration::from_secs(30));
+    /// ```
+    ///
+    pub fn with_auth(self, key: &[u8], window: Duration) -> Self {
+        Protocol {
+            cbor_proto: self.cbor_proto.with_auth(key, window),
+            ..self
+        }
+    }
+
+    /// Sign and check messages as a service, so that only messages signed by clients
+    /// (with [`with_auth`](#method.with_auth)) are accepted
+    ///
+    /// # Arguments
+    ///
+    /// * key - Key shared with the remote side
+    /// * window - How far from the current time a message can have been sent
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use channel_protocol::*;
+    /// use std::time::Duration;
+    ///
+    /// let channel_protocol = ChannelProtocol::new("0.0.0.0", "192.168.0.1:7000", 4096)
+    ///     .with_service_auth(b"shared secret", Duration::from_secs(30));
+    /// ```
+    ///
+    pub fn with_service_auth(self, key: &[u8], window: Duration) -> Self {
+        Protocol {
+            cbor_proto: self.cbor_proto.with_service_auth(key, window),
+            ..self
+        }
+    }
+
     /// Send CBOR packet to the destination port
     ///
     /// # Arguments
@@ -128,9 +182,23 @@ impl Protocol {
     /// ```
     ///
     pub fn recv_raw(&self, timeout: Option<Duration>) -> Result<Value, ProtocolError> {
-        match timeout {
-            Some(value) => Ok(self.cbor_proto.recv_message_timeout(value)?),
-            None => Ok(self.cbor_proto.recv_message()?),
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let result = match deadline {
+                Some(deadline) => self.cbor_proto.recv_message_timeout(cmp::max(
+                    deadline.saturating_duration_since(Instant::now()),
+                    Duration::from_millis(1),
+                )),
+                None => self.cbor_proto.recv_message(),
+            };
+
+            match result {
+                // Flow control is taken care of by the CBOR protocol,
+                // so keep waiting for a real message
+                Err(cbor_protocol::ProtocolError::Paused)
+                | Err(cbor_protocol::ProtocolError::Resumed) => continue,
+                result => return Ok(result?),
+            }
         }
     }
 
@@ -169,4 +237,84 @@ impl Protocol {
         let raw = self.recv_raw(timeout)?;
         Ok(parse_message(raw)?)
     }
+
+    /// Tell the remote end what we support
+    ///
+    /// # Arguments
+    ///
+    /// * channel_id - Channel the remote end asked on, or a new channel to ask on
+    /// * capabilities - Our protocol version, optional features and limits
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use channel_protocol::*;
+    ///
+    /// let c_protocol = ChannelProtocol::new("0.0.0.0", "0.0.0.0:7000", 4096);
+    ///
+    /// c_protocol.send_hello(generate_channel(), &Capabilities::new(1, 4096));
+    /// ```
+    ///
+    pub fn send_hello(
+        &self,
+        channel_id: u32,
+        capabilities: &Capabilities,
+    ) -> Result<(), ProtocolError> {
+        self.send(capabilities.to_cbor(channel_id)?)
+    }
+
+    /// Exchange hello messages with the remote end, to find out what it supports
+    ///
+    /// # Arguments
+    ///
+    /// * capabilities - Our protocol version, optional features and limits
+    /// * timeout - Maximum time to wait for the reply
+    ///
+    /// # Errors
+    ///
+    /// - If the remote end doesn't reply in time, it will return
+    ///   `Err(ProtocolError::ReceiveTimeout)`. Remote ends which are too old to know
+    ///   about hello messages won't reply at all
+    /// - If this function encounters any other errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use channel_protocol::*;
+    /// use std::time::Duration;
+    ///
+    /// let c_protocol = ChannelProtocol::new("0.0.0.0", "0.0.0.0:7000", 4096);
+    ///
+    /// let remote = c_protocol
+    ///     .hello(&Capabilities::new(1, 4096), Duration::from_secs(2))
+    ///     .unwrap();
+    /// println!("Remote end speaks version {}", remote.version);
+    /// ```
+    ///
+    pub fn hello(
+        &self,
+        capabilities: &Capabilities,
+        timeout: Duration,
+    ) -> Result<Capabilities, ProtocolError> {
+        let channel_id = generate_channel();
+        self.send_hello(channel_id, capabilities)?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                return Err(ProtocolError::ReceiveTimeout);
+            }
+
+            // Anything else which turns up in the meantime isn't for us
+            let message = self.recv_message(Some(remaining))?;
+            if message.channel_id == channel_id && message.name == "hello" {
+                return Capabilities::from_payload(&message.payload);
+            }
+        }
+    }
 }
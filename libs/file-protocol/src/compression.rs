@@ -0,0 +1,54 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Chunk compression codecs
+
+use error::ProtocolError;
+use std::str::FromStr;
+
+/// Compression codec applied to a file's chunks while it is being transferred
+///
+/// The file's hash is always calculated over the original, uncompressed contents
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Compression {
+    /// Chunks contain the raw file contents
+    #[default]
+    None,
+    /// Chunks contain a deflate stream of the file contents
+    Deflate,
+}
+
+impl Compression {
+    /// The name used for the codec in messages and temporary storage
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Deflate => "deflate",
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = ProtocolError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "none" => Ok(Compression::None),
+            "deflate" => Ok(Compression::Deflate),
+            _ => Err(ProtocolError::UnsupportedCompression(name.to_owned())),
+        }
+    }
+}
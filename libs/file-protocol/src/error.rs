@@ -15,6 +15,7 @@
 //
 
 use cbor_protocol;
+use channel_protocol;
 use serde_cbor;
 use std::io;
 
@@ -24,6 +25,12 @@ pub enum ProtocolError {
     /// A file in storage was corrupt
     #[fail(display = "File was corrupt: {}", _0)]
     CorruptFile(String),
+    /// One or more chunks failed their integrity check and need to be transferred again
+    #[fail(display = "Corrupt chunks: {:?}", _0)]
+    CorruptChunks(Vec<u32>),
+    /// Missing chunks couldn't be rebuilt from the parity chunks which were received
+    #[fail(display = "Unable to recover missing chunks: {}", _0)]
+    RecoveryError(String),
     /// An error was encountered by the cbor protocol
     #[fail(display = "Cbor Error: {}", err)]
     CborError {
@@ -39,6 +46,9 @@ pub enum ProtocolError {
     /// A hash mismatch was found when finalizing the file
     #[fail(display = "File hash mismatch")]
     HashMismatch,
+    /// A hash wasn't in the form of one which we generated
+    #[fail(display = "Invalid file hash: {}", _0)]
+    InvalidHash(String),
     /// An invalid value was found when parsing a message
     #[fail(
         display = "Unable to parse {} message: Invalid {} param",
@@ -83,12 +93,36 @@ pub enum ProtocolError {
         /// The underlying std::io::Error
         err: io::Error,
     },
+    /// Temporary storage doesn't have enough room left for a new transaction
+    #[fail(
+        display = "Not enough temporary storage: {} bytes needed, {} bytes available",
+        needed,
+        available
+    )]
+    StorageFull {
+        /// Number of bytes the transaction needs
+        needed: u64,
+        /// Number of bytes left before the storage limit is reached
+        available: u64,
+    },
     /// An error was encountered when parsing file storage data
     #[fail(display = "{}", _0)]
     StorageParseError(String),
     /// A timeout occurred when receiving data
     #[fail(display = "A receive timeout was encountered")]
     ReceiveTimeout,
+    /// The transaction was cancelled before it could be completed
+    #[fail(display = "Transaction was cancelled")]
+    Cancelled,
+    /// A transaction was requested which isn't currently active
+    #[fail(display = "No active transaction on channel {}", _0)]
+    NoTransaction(u32),
+    /// A new transaction was refused because too many are already in progress
+    #[fail(display = "Too many transactions in progress (limit is {})", _0)]
+    TooManyTransactions(usize),
+    /// A path was requested which is outside of the directories the remote side may use
+    #[fail(display = "Access to {} is not allowed", _0)]
+    PathNotAllowed(String),
     /// An error was encountered when transmitting
     #[fail(
         display = "Transmission failure on channel {}: {}",
@@ -101,6 +135,24 @@ pub enum ProtocolError {
         /// Message from underlying error
         error_message: String,
     },
+    /// A file which was being streamed was changed before the transfer completed
+    #[fail(display = "Source file {} was modified during the transfer", _0)]
+    SourceChanged(String),
+    /// A compression codec was requested which isn't supported
+    #[fail(display = "Unsupported compression codec: {}", _0)]
+    UnsupportedCompression(String),
+    /// An error was encountered by the channel protocol
+    #[fail(display = "Channel protocol error: {}", err)]
+    ChannelError {
+        /// The specific channel protocol error
+        err: channel_protocol::ProtocolError,
+    },
+    /// The remote target doesn't support something the request needs
+    #[fail(display = "Remote target doesn't support {}", _0)]
+    Unsupported(String),
+    /// A request was received which couldn't be understood
+    #[fail(display = "Unsupported request: {}", _0)]
+    UnsupportedRequest(String),
 }
 
 impl From<cbor_protocol::ProtocolError> for ProtocolError {
@@ -112,6 +164,17 @@ impl From<cbor_protocol::ProtocolError> for ProtocolError {
     }
 }
 
+impl From<channel_protocol::ProtocolError> for ProtocolError {
+    fn from(error: channel_protocol::ProtocolError) -> Self {
+        match error {
+            channel_protocol::ProtocolError::Unsupported { feature } => {
+                ProtocolError::Unsupported(feature)
+            }
+            err => ProtocolError::ChannelError { err },
+        }
+    }
+}
+
 impl From<serde_cbor::error::Error> for ProtocolError {
     fn from(error: serde_cbor::error::Error) -> Self {
         ProtocolError::Serialize { err: error }
@@ -0,0 +1,224 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Forward error correction for file chunks
+//!
+//! A block of data chunks can be followed by a number of parity chunks, generated with
+//! a systematic Reed-Solomon code over GF(2^8). The code uses a Cauchy matrix, so a receiver
+//! which is missing some of a block's data chunks can rebuild them from any equal number
+//! of that block's parity chunks.
+
+use error::ProtocolError;
+
+/// Maximum number of data chunks in a block, and of parity chunks generated for a block
+pub const MAX_CHUNKS: u32 = 128;
+
+/// Identifies which block of data chunks a parity chunk protects
+///
+/// Every data chunk in a block, apart from the last, is assumed to be as long as the
+/// block's parity chunks
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Parity {
+    /// Index of the first data chunk in the block
+    pub first_chunk: u32,
+    /// Number of data chunks in the block
+    pub num_chunks: u32,
+    /// Index of this parity chunk within the block's parity chunks
+    pub index: u32,
+    /// Size of the last data chunk in the block
+    pub last_size: u32,
+}
+
+// Log and exponent tables for GF(2^8), using the polynomial x^8 + x^4 + x^3 + x^2 + 1
+struct Field {
+    exp: [u8; 510],
+    log: [u8; 256],
+}
+
+impl Field {
+    fn new() -> Self {
+        let mut exp = [0u8; 510];
+        let mut log = [0u8; 256];
+        let mut val: u16 = 1;
+        for power in 0..255 {
+            exp[power] = val as u8;
+            exp[power + 255] = val as u8;
+            log[val as usize] = power as u8;
+            val <<= 1;
+            if val & 0x100 != 0 {
+                val ^= 0x11d;
+            }
+        }
+
+        Field { exp, log }
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            0
+        } else {
+            self.exp[self.log[a as usize] as usize + self.log[b as usize] as usize]
+        }
+    }
+
+    fn inv(&self, a: u8) -> u8 {
+        self.exp[255 - self.log[a as usize] as usize]
+    }
+
+    // Weight of data chunk `col` in parity chunk `row`.
+    // Rows and columns map to disjoint halves of the field, so the divisor is never zero
+    fn coefficient(&self, row: u32, col: u32) -> u8 {
+        self.inv((MAX_CHUNKS + row) as u8 ^ col as u8)
+    }
+
+    // dest += coef * src
+    fn mul_add(&self, dest: &mut [u8], coef: u8, src: &[u8]) {
+        if coef == 0 {
+            return;
+        }
+
+        let log_coef = self.log[coef as usize] as usize;
+        for (out, &val) in dest.iter_mut().zip(src) {
+            if val != 0 {
+                *out ^= self.exp[log_coef + self.log[val as usize] as usize];
+            }
+        }
+    }
+
+    // Gauss-Jordan elimination. Returns `None` if the matrix is singular
+    fn invert(&self, mut matrix: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+        let size = matrix.len();
+        let mut inverse: Vec<Vec<u8>> = (0..size)
+            .map(|row| (0..size).map(|col| (row == col) as u8).collect())
+            .collect();
+
+        for col in 0..size {
+            let pivot = (col..size).find(|&row| matrix[row][col] != 0)?;
+            matrix.swap(col, pivot);
+            inverse.swap(col, pivot);
+
+            let scale = self.inv(matrix[col][col]);
+            for val in matrix[col].iter_mut().chain(inverse[col].iter_mut()) {
+                *val = self.mul(*val, scale);
+            }
+
+            for row in 0..size {
+                let factor = matrix[row][col];
+                if row == col || factor == 0 {
+                    continue;
+                }
+                for idx in 0..size {
+                    let val = self.mul(factor, matrix[col][idx]);
+                    matrix[row][idx] ^= val;
+                    let val = self.mul(factor, inverse[col][idx]);
+                    inverse[row][idx] ^= val;
+                }
+            }
+        }
+
+        Some(inverse)
+    }
+}
+
+/// Generate parity chunks for a block of data chunks
+///
+/// Each parity chunk is as long as the longest data chunk. Shorter data chunks are
+/// treated as if they were padded with zeros.
+pub fn encode(data: &[Vec<u8>], num_parity: u32) -> Vec<Vec<u8>> {
+    let field = Field::new();
+    let size = data.iter().map(|chunk| chunk.len()).max().unwrap_or(0);
+
+    (0..num_parity)
+        .map(|row| {
+            let mut parity = vec![0u8; size];
+            for (col, chunk) in data.iter().enumerate() {
+                field.mul_add(&mut parity, field.coefficient(row, col as u32), chunk);
+            }
+            parity
+        }).collect()
+}
+
+/// Rebuild the missing data chunks of a block
+///
+/// `data` holds each of the block's data chunks, or `None` for the ones which were lost.
+/// `parity` holds the index and contents of each parity chunk which was received.
+/// Rebuilt chunks are as long as the parity chunks, so they may need to be truncated.
+pub fn decode(
+    data: &mut [Option<Vec<u8>>],
+    parity: &[(u32, Vec<u8>)],
+) -> Result<(), ProtocolError> {
+    let missing: Vec<usize> = data
+        .iter()
+        .enumerate()
+        .filter(|(_, chunk)| chunk.is_none())
+        .map(|(col, _)| col)
+        .collect();
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    if parity.len() < missing.len() {
+        return Err(ProtocolError::RecoveryError(format!(
+            "{} chunks are missing, but only {} parity chunks were received",
+            missing.len(),
+            parity.len()
+        )));
+    }
+
+    let field = Field::new();
+    let parity = &parity[0..missing.len()];
+    let size = parity[0].1.len();
+
+    // Remove the chunks we do have from each parity chunk,
+    // leaving a combination of just the missing ones
+    let remainders: Vec<Vec<u8>> = parity
+        .iter()
+        .map(|(row, chunk)| {
+            let mut remainder = chunk.clone();
+            remainder.resize(size, 0);
+            for (col, chunk) in data.iter().enumerate() {
+                if let Some(chunk) = chunk {
+                    field.mul_add(&mut remainder, field.coefficient(*row, col as u32), chunk);
+                }
+            }
+            remainder
+        }).collect();
+
+    // Then solve for the missing chunks
+    let matrix = parity
+        .iter()
+        .map(|(row, _)| {
+            missing
+                .iter()
+                .map(|&col| field.coefficient(*row, col as u32))
+                .collect()
+        }).collect();
+
+    let inverse = field.invert(matrix).ok_or_else(|| {
+        ProtocolError::RecoveryError("Parity chunks are not independent".to_owned())
+    })?;
+
+    for (idx, &col) in missing.iter().enumerate() {
+        let mut chunk = vec![0u8; size];
+        for (weights, remainder) in inverse[idx].iter().zip(&remainders) {
+            field.mul_add(&mut chunk, *weights, remainder);
+        }
+        data[col] = Some(chunk);
+    }
+
+    Ok(())
+}
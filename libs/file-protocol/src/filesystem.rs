@@ -0,0 +1,180 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Remote file system operations
+//!
+//! These are performed on behalf of the remote side of a transaction,
+//! so that it can inspect and manage files without needing a shell.
+
+use error::ProtocolError;
+use serde_cbor::Value;
+use std::fs::{self, Metadata};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+/// Information about a single file or directory
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FileInfo {
+    /// File name. For directory listings this is the name of the entry within the directory,
+    /// otherwise it is the requested path
+    pub name: String,
+    /// Size of the file, in bytes
+    pub size: u64,
+    /// Last modification time, in seconds since the UNIX epoch
+    pub mtime: u64,
+    /// File mode
+    pub mode: u32,
+    /// Whether the entry is a directory
+    pub is_dir: bool,
+}
+
+impl FileInfo {
+    fn new(name: String, meta: &Metadata) -> Self {
+        FileInfo {
+            name,
+            size: meta.len(),
+            mtime: meta.mtime().max(0) as u64,
+            mode: meta.mode(),
+            is_dir: meta.is_dir(),
+        }
+    }
+
+    // Messages carry file information as `[ name, size, mtime, mode, is_dir ]`
+    pub(crate) fn to_value(&self) -> Value {
+        Value::Array(vec![
+            Value::String(self.name.clone()),
+            Value::U64(self.size),
+            Value::U64(self.mtime),
+            Value::U64(self.mode.into()),
+            Value::Bool(self.is_dir),
+        ])
+    }
+
+    pub(crate) fn from_value(value: &Value) -> Option<Self> {
+        let fields = value.as_array()?;
+
+        match (
+            fields.first(),
+            fields.get(1),
+            fields.get(2),
+            fields.get(3),
+            fields.get(4),
+        ) {
+            (
+                Some(Value::String(name)),
+                Some(Value::U64(size)),
+                Some(Value::U64(mtime)),
+                Some(Value::U64(mode)),
+                Some(Value::Bool(is_dir)),
+            ) => Some(FileInfo {
+                name: name.to_owned(),
+                size: *size,
+                mtime: *mtime,
+                mode: *mode as u32,
+                is_dir: *is_dir,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// List the contents of a directory, sorted by name
+pub fn list(path: &str) -> Result<Vec<FileInfo>, ProtocolError> {
+    let entries = fs::read_dir(path).map_err(|err| ProtocolError::StorageError {
+        action: format!("read directory {}", path),
+        err,
+    })?;
+
+    let mut listing = vec![];
+    for entry in entries {
+        let entry = entry.map_err(|err| ProtocolError::StorageError {
+            action: format!("read directory {}", path),
+            err,
+        })?;
+
+        // Don't follow symlinks, so broken links still show up
+        let meta =
+            fs::symlink_metadata(entry.path()).map_err(|err| ProtocolError::StorageError {
+                action: format!("stat {:?}", entry.path()),
+                err,
+            })?;
+
+        listing.push(FileInfo::new(
+            entry.file_name().to_string_lossy().into_owned(),
+            &meta,
+        ));
+    }
+
+    listing.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(listing)
+}
+
+/// Get information about a single file or directory
+pub fn stat(path: &str) -> Result<FileInfo, ProtocolError> {
+    let meta = fs::symlink_metadata(path).map_err(|err| ProtocolError::StorageError {
+        action: format!("stat {}", path),
+        err,
+    })?;
+
+    Ok(FileInfo::new(path.to_owned(), &meta))
+}
+
+/// Delete a file or directory
+///
+/// Directories must be empty, unless `recursive` is set
+pub fn delete(path: &str, recursive: bool) -> Result<(), ProtocolError> {
+    let meta = fs::symlink_metadata(path).map_err(|err| ProtocolError::StorageError {
+        action: format!("stat {}", path),
+        err,
+    })?;
+
+    let result = if !meta.is_dir() {
+        fs::remove_file(path)
+    } else if recursive {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_dir(path)
+    };
+
+    result.map_err(|err| ProtocolError::StorageError {
+        action: format!("delete {}", path),
+        err,
+    })
+}
+
+/// Create a directory, along with any missing parent directories
+pub fn mkdir(path: &str) -> Result<(), ProtocolError> {
+    fs::create_dir_all(path).map_err(|err| ProtocolError::StorageError {
+        action: format!("create directory {}", path),
+        err,
+    })
+}
+
+/// Move a file or directory to a new location
+pub fn rename(source_path: &str, target_path: &str) -> Result<(), ProtocolError> {
+    if Path::new(target_path).exists() {
+        return Err(ProtocolError::StorageParseError(format!(
+            "Unable to move {} to {}: destination already exists",
+            source_path, target_path
+        )));
+    }
+
+    fs::rename(source_path, target_path).map_err(|err| ProtocolError::StorageError {
+        action: format!("move {} to {}", source_path, target_path),
+        err,
+    })
+}
@@ -90,8 +90,11 @@
 
 extern crate blake2_rfc;
 extern crate cbor_protocol;
+extern crate channel_protocol;
 #[macro_use]
 extern crate failure;
+extern crate flate2;
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate rand;
@@ -99,48 +102,118 @@ extern crate serde;
 extern crate serde_cbor;
 extern crate time;
 
+mod compression;
 mod error;
+mod fec;
+pub mod filesystem;
+pub mod manifest;
 mod messages;
 mod parsers;
+mod progress;
 pub mod protocol;
+mod sandbox;
 mod storage;
+mod task;
+mod transaction;
 
+pub use channel_protocol::Capabilities;
+pub use compression::Compression;
 pub use error::ProtocolError;
+pub use fec::Parity;
+pub use filesystem::FileInfo;
+pub use manifest::ManifestEntry;
+pub use progress::{Progress, ProgressCallback};
 pub use protocol::Protocol as FileProtocol;
 pub use protocol::ProtocolConfig as FileProtocolConfig;
 pub use protocol::State;
+pub use protocol::PROTOCOL_VERSION;
+pub use task::{Task, TaskOutput};
+pub use transaction::{Direction, TransactionInfo};
 
-pub use parsers::parse_channel_id;
+pub use parsers::{parse_channel_id, parse_message};
+pub use storage::collect_garbage;
 
 /// File protocol message types
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Message {
-    /// TODO: Decide whether or not to keep this
+    /// Request to find out whether the receiver already holds all of a file's chunks
     Sync(u32, String),
     /// Receiver should prepare a new temporary storage folder with the specified metadata
-    Metadata(u32, String, u32),
-    /// File data chunk message
-    ReceiveChunk(u32, String, u32, Vec<u8>),
+    /// and chunk compression codec
+    Metadata(u32, String, u32, Compression),
+    /// File data chunk message, along with the chunk's checksum (if one was sent)
+    ReceiveChunk(u32, String, u32, Vec<u8>, Option<u32>),
+    /// Parity chunk which can be used to rebuild lost data chunks, along with its checksum
+    ReceiveParity(u32, String, Parity, Vec<u8>, u32),
     /// Receiver has successfully gotten all data chunks of the requested file
     ACK(u32, String),
     /// Receiver is missing the specified file data chunks
     NAK(u32, String, Option<Vec<(u32, u32)>>),
     /// (Client Only) Message requesting the recipient to receive the specified file
     ReqReceive(u32, String, String, Option<u32>),
-    /// (Client Only) Message requesting the recipient to transmit the specified file
-    ReqTransmit(u32, String),
+    /// (Client Only) Message requesting the recipient to transmit the specified file,
+    /// compressing its chunks with the given codec if possible.
+    /// Only the bytes from the given offset are sent, up to the length (if one was given)
+    ReqTransmit(u32, String, Compression, u64, Option<u64>),
+    /// (Client Only) Message requesting the recipient to receive the specified directory manifest
+    /// and recreate its directory tree
+    ReqReceiveDir(u32, String, String),
+    /// (Client Only) Message requesting the recipient to transmit the manifest of the
+    /// specified directory
+    ReqTransmitDir(u32, String),
+    /// (Client Only) Message requesting the recipient to resume an interrupted transaction
+    Resume(u32, String),
+    /// (Client Only) Message requesting the contents of the specified directory
+    ReqList(u32, String),
+    /// (Client Only) Message requesting information about the specified file
+    ReqStat(u32, String),
+    /// (Client Only) Message requesting the recipient to delete the specified file or directory,
+    /// optionally including the directory's contents
+    ReqDelete(u32, String, bool),
+    /// (Client Only) Message requesting the recipient to create the specified directory
+    ReqMkdir(u32, String),
+    /// (Client Only) Message requesting the recipient to move a file from the first path
+    /// to the second
+    ReqRename(u32, String, String),
+    /// (Client Only) Message requesting the list of the recipient's active transactions
+    ReqTransactions(u32),
+    /// (Client Only) Message requesting up to the specified number of the recipient's
+    /// most recently finished transactions
+    ReqHistory(u32, u32),
+    /// (Client Only) Message requesting the recipient to cancel the transaction on the
+    /// specified channel
+    ReqCancel(u32, u32),
     /// (Server Only) Recipient has successfully processed a request to receive a file
     SuccessReceive(u32),
     /// (Server Only) Recipient has successfully prepared to transmit a file
-    SuccessTransmit(u32, String, u32, Option<u32>),
+    SuccessTransmit(u32, String, u32, Option<u32>, Compression),
+    /// (Server Only) Contents of the requested directory
+    SuccessList(u32, Vec<FileInfo>),
+    /// (Server Only) Information about the requested file
+    SuccessStat(u32, FileInfo),
+    /// (Server Only) List of the requested transactions
+    SuccessTransactions(u32, Vec<TransactionInfo>),
     /// (Server Only) The transmit or receive request has failed to be completed
     Failure(u32, String),
+    /// The sender's protocol version, optional features and limits.
+    /// Sent by clients to find out what the remote target supports, and sent back in reply
+    Hello(u32, Capabilities),
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{messages, parsers, Message};
-    use serde_cbor::de;
+    use super::{
+        fec, messages, parsers, progress, sandbox, storage, Capabilities, Compression, Direction,
+        FileInfo, FileProtocol, FileProtocolConfig, Message, Parity, ProtocolError, State,
+        TransactionInfo,
+    };
+    use blake2_rfc::blake2s::blake2s;
+    use cbor_protocol::UnixTransport;
+    use serde_cbor::{de, ser, Value};
+    use std::os::unix::fs::symlink;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+    use std::{env, fs, process, thread};
 
     #[test]
     fn create_parse_export_request() {
@@ -159,6 +232,178 @@ mod tests {
         );
     }
 
+    #[test]
+    fn create_parse_export_dir_request() {
+        let channel_id = 10;
+        let hash = "abcdedf".to_owned();
+        let target_path = "/path/to/dir".to_owned();
+
+        let raw = messages::export_dir_request(channel_id, &hash, &target_path).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::ReqReceiveDir(channel_id, hash, target_path)
+        );
+    }
+
+    #[test]
+    fn create_parse_import_dir_request() {
+        let channel_id = 10;
+        let source_path = "/path/to/dir".to_owned();
+
+        let raw = messages::import_dir_request(channel_id, &source_path).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::ReqTransmitDir(channel_id, source_path)
+        );
+    }
+
+    #[test]
+    fn create_parse_resume_request() {
+        let channel_id = 12;
+        let hash = "abcdefg".to_owned();
+
+        let raw = messages::resume_request(channel_id, &hash).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(msg.unwrap(), Message::Resume(channel_id, hash));
+    }
+
+    #[test]
+    fn create_parse_list_request() {
+        let channel_id = 13;
+        let path = "/path/to/dir".to_owned();
+
+        let raw = messages::list_request(channel_id, &path).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(msg.unwrap(), Message::ReqList(channel_id, path));
+    }
+
+    #[test]
+    fn create_parse_delete_request() {
+        let channel_id = 13;
+        let path = "/path/to/dir".to_owned();
+
+        let raw = messages::delete_request(channel_id, &path, true).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(msg.unwrap(), Message::ReqDelete(channel_id, path, true));
+    }
+
+    #[test]
+    fn create_parse_rename_request() {
+        let channel_id = 13;
+        let source_path = "/path/to/old".to_owned();
+        let target_path = "/path/to/new".to_owned();
+
+        let raw = messages::rename_request(channel_id, &source_path, &target_path).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::ReqRename(channel_id, source_path, target_path)
+        );
+    }
+
+    #[test]
+    fn create_parse_list_success() {
+        let channel_id = 13;
+        let entries = vec![
+            FileInfo {
+                name: "file.txt".to_owned(),
+                size: 1234,
+                mtime: 1533081600,
+                mode: 0o100644,
+                is_dir: false,
+            },
+            FileInfo {
+                name: "logs".to_owned(),
+                size: 4096,
+                mtime: 1533081601,
+                mode: 0o40755,
+                is_dir: true,
+            },
+        ];
+
+        let raw = messages::list_success(channel_id, &entries).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(msg.unwrap(), Message::SuccessList(channel_id, entries));
+    }
+
+    #[test]
+    fn create_parse_transaction_requests() {
+        let channel_id = 15;
+
+        let raw = messages::transactions_request(channel_id).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+        assert_eq!(msg.unwrap(), Message::ReqTransactions(channel_id));
+
+        let raw = messages::history_request(channel_id, 10).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+        assert_eq!(msg.unwrap(), Message::ReqHistory(channel_id, 10));
+
+        let raw = messages::cancel_request(channel_id, 1234).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+        assert_eq!(msg.unwrap(), Message::ReqCancel(channel_id, 1234));
+    }
+
+    #[test]
+    fn create_parse_transactions_success() {
+        let channel_id = 16;
+        let transactions = vec![
+            TransactionInfo {
+                channel_id: 1234,
+                direction: Direction::Download,
+                path: "/home/system/logs/app.log".to_owned(),
+                hash: "abcdefg".to_owned(),
+                num_chunks: 10,
+                chunks_done: 4,
+                age: 30,
+                error: None,
+            },
+            TransactionInfo {
+                channel_id: 5678,
+                direction: Direction::Upload,
+                path: "/home/system/bin/app".to_owned(),
+                hash: "hijklmn".to_owned(),
+                num_chunks: 20,
+                chunks_done: 2,
+                age: 5,
+                error: Some("Transaction was cancelled".to_owned()),
+            },
+        ];
+
+        let raw = messages::transactions_success(channel_id, &transactions).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::SuccessTransactions(channel_id, transactions)
+        );
+    }
+
+    #[test]
+    fn create_parse_stat_success() {
+        let channel_id = 13;
+        let info = FileInfo {
+            name: "/path/to/file.txt".to_owned(),
+            size: 1234,
+            mtime: 1533081600,
+            mode: 0o100644,
+            is_dir: false,
+        };
+
+        let raw = messages::stat_success(channel_id, &info).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(msg.unwrap(), Message::SuccessStat(channel_id, info));
+    }
+
     #[test]
     fn create_parse_sync() {
         let channel_id = 10;
@@ -176,12 +421,102 @@ mod tests {
         let hash = "abcdefg".to_owned();
         let num_chunks = 100;
 
-        let raw = messages::metadata(channel_id, &hash, num_chunks).unwrap();
+        let raw = messages::metadata(channel_id, &hash, num_chunks, Compression::None).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::Metadata(channel_id, hash, num_chunks, Compression::None)
+        );
+    }
+
+    #[test]
+    fn create_parse_metadata_compressed() {
+        let channel_id = 10;
+        let hash = "abcdefg".to_owned();
+        let num_chunks = 100;
+
+        let raw = messages::metadata(channel_id, &hash, num_chunks, Compression::Deflate).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::Metadata(channel_id, hash, num_chunks, Compression::Deflate)
+        );
+    }
+
+    #[test]
+    fn create_parse_import_request() {
+        let channel_id = 10;
+        let source_path = "/path/to/file".to_owned();
+
+        let raw =
+            messages::import_request(channel_id, &source_path, Compression::Deflate, 0, None)
+                .unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::ReqTransmit(channel_id, source_path, Compression::Deflate, 0, None)
+        );
+    }
+
+    #[test]
+    fn create_parse_import_request_range() {
+        let channel_id = 10;
+        let source_path = "/path/to/file".to_owned();
+
+        let raw =
+            messages::import_request(channel_id, &source_path, Compression::None, 4096, Some(100))
+                .unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::ReqTransmit(channel_id, source_path, Compression::None, 4096, Some(100))
+        );
+    }
+
+    #[test]
+    fn create_parse_import_request_tail() {
+        let channel_id = 10;
+        let source_path = "/path/to/file".to_owned();
+
+        let raw = messages::import_request(channel_id, &source_path, Compression::None, 4096, None)
+            .unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::ReqTransmit(channel_id, source_path, Compression::None, 4096, None)
+        );
+    }
+
+    #[test]
+    fn create_parse_import_success_compressed() {
+        let channel_id = 10;
+        let hash = "abcdefg".to_owned();
+        let num_chunks = 100;
+        let mode = 0o644;
+
+        let raw = messages::import_setup_success(
+            channel_id,
+            &hash,
+            num_chunks,
+            mode,
+            Compression::Deflate,
+        ).unwrap();
         let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
 
         assert_eq!(
             msg.unwrap(),
-            Message::Metadata(channel_id, hash, num_chunks)
+            Message::SuccessTransmit(
+                channel_id,
+                hash,
+                num_chunks,
+                Some(mode),
+                Compression::Deflate
+            )
         );
     }
 
@@ -195,9 +530,187 @@ mod tests {
         let raw = messages::chunk(channel_id, &hash, chunk_num, &chunk_data).unwrap();
         let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
 
+        let checksum = storage::chunk_checksum(&chunk_data);
+        assert_eq!(
+            msg.unwrap(),
+            Message::ReceiveChunk(channel_id, hash, chunk_num, chunk_data, Some(checksum))
+        );
+    }
+
+    #[test]
+    fn create_parse_parity() {
+        let channel_id = 10;
+        let hash = "abcdefg".to_owned();
+        let parity = Parity {
+            first_chunk: 32,
+            num_chunks: 16,
+            index: 1,
+            last_size: 100,
+        };
+        let parity_data: Vec<u8> = vec![6, 5, 4, 3, 2, 1];
+
+        let raw = messages::parity(channel_id, &hash, &parity, &parity_data).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        let checksum = storage::chunk_checksum(&parity_data);
+        assert_eq!(
+            msg.unwrap(),
+            Message::ReceiveParity(channel_id, hash, parity, parity_data, checksum)
+        );
+    }
+
+    #[test]
+    fn parse_parity_out_of_range() {
+        let hash = "abcdefg".to_owned();
+        let parity_data: Vec<u8> = vec![6, 5, 4, 3, 2, 1];
+
+        for &(num_chunks, index) in &[(0, 0), (fec::MAX_CHUNKS + 1, 0), (4, fec::MAX_CHUNKS)] {
+            let parity = Parity {
+                first_chunk: 0,
+                num_chunks,
+                index,
+                last_size: 100,
+            };
+            let raw = messages::parity(10, &hash, &parity, &parity_data).unwrap();
+
+            assert!(parsers::parse_message(de::from_slice(&raw).unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn parity_outside_file_dropped() {
+        let dir = env::temp_dir().join(format!("file-protocol-parity-{}", process::id()));
+        let prefix = dir.to_string_lossy().into_owned();
+        let hash = "0123456789abcdef0123456789abcdef";
+        storage::store_meta(&prefix, hash, 4, Compression::None).unwrap();
+
+        let (transport, _peer) = UnixTransport::pair().unwrap();
+        let config = FileProtocolConfig::new(Some(prefix.clone()), 4096, 5);
+        let f_protocol = FileProtocol::new(transport, "0.0.0.0:0", config);
+
+        let parity_data: Vec<u8> = vec![6, 5, 4, 3, 2, 1];
+        let mut results = vec![];
+        // Past the end of the file, then far enough past it to overflow, then a block which fits
+        for &first_chunk in &[2, u32::MAX - 1, 0] {
+            let parity = Parity {
+                first_chunk,
+                num_chunks: 4,
+                index: 0,
+                last_size: 6,
+            };
+            let raw = messages::parity(10, hash, &parity, &parity_data).unwrap();
+            let result = f_protocol.process_message(de::from_slice(&raw).unwrap(), State::Done);
+            results.push(result.is_ok());
+        }
+
+        let stored: Vec<String> = fs::read_dir(dir.join("storage").join(hash).join("parity"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(results, vec![true, true, true]);
+        assert_eq!(stored, vec!["0.0".to_owned()]);
+    }
+
+    #[test]
+    fn recover_block_checks_parity() {
+        let dir = env::temp_dir().join(format!("file-protocol-recover-{}", process::id()));
+        let prefix = dir.to_string_lossy().into_owned();
+        let hash = "0123456789abcdef0123456789abcdef";
+        storage::store_meta(&prefix, hash, 4, Compression::None).unwrap();
+        let parity_dir = dir.join("storage").join(hash).join("parity");
+        let parity_data: Vec<u8> = vec![6, 5, 4, 3, 2, 1];
+
+        // Parity chunks which disagree about how big the block is
+        for &(index, num_chunks) in &[(0, 4), (1, 3)] {
+            let parity = Parity {
+                first_chunk: 0,
+                num_chunks,
+                index,
+                last_size: 6,
+            };
+            storage::store_parity(&prefix, hash, &parity, &parity_data).unwrap();
+        }
+        let disagree = storage::recover_block(&prefix, hash, 0);
+        let left = fs::read_dir(&parity_dir).unwrap().count();
+
+        // A block which runs past the end of the file, and one whose last chunk
+        // is bigger than its parity chunks
+        let mut results = vec![];
+        for &(first_chunk, last_size) in &[(2, 6), (0, 7)] {
+            let parity = Parity {
+                first_chunk,
+                num_chunks: 4,
+                index: 0,
+                last_size,
+            };
+            storage::store_parity(&prefix, hash, &parity, &parity_data).unwrap();
+            results.push(storage::recover_block(&prefix, hash, first_chunk).is_err());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(disagree.is_err());
+        assert_eq!(left, 0);
+        assert_eq!(results, vec![true, true]);
+    }
+
+    #[test]
+    fn fec_rebuild_chunks() {
+        let data: Vec<Vec<u8>> = (0..10u8)
+            .map(|num| (0..100u8).map(|val| val.wrapping_mul(num + 1)).collect())
+            .collect();
+        let mut short = data.clone();
+        short[9].truncate(40);
+
+        let parity = fec::encode(&short, 3);
+        assert_eq!(parity.len(), 3);
+        assert_eq!(parity[0].len(), 100);
+
+        // Lose three of the data chunks, including the short one,
+        // and rebuild them from the last three parity chunks
+        let mut received: Vec<Option<Vec<u8>>> = short.iter().cloned().map(Some).collect();
+        received[0] = None;
+        received[4] = None;
+        received[9] = None;
+        let parity: Vec<(u32, Vec<u8>)> = (0..3u32).zip(parity).collect();
+
+        fec::decode(&mut received, &parity).unwrap();
+
+        assert_eq!(received[0].as_ref().unwrap(), &data[0]);
+        assert_eq!(received[4].as_ref().unwrap(), &data[4]);
+        assert_eq!(&received[9].as_ref().unwrap()[0..40], &short[9][..]);
+    }
+
+    #[test]
+    fn fec_not_enough_parity() {
+        let data: Vec<Vec<u8>> = (0..4u8).map(|num| vec![num; 10]).collect();
+        let parity = fec::encode(&data, 1);
+
+        let mut received: Vec<Option<Vec<u8>>> = vec![None, None, Some(data[2].clone()), None];
+        let parity: Vec<(u32, Vec<u8>)> = vec![(0, parity[0].clone())];
+
+        assert!(fec::decode(&mut received, &parity).is_err());
+    }
+
+    #[test]
+    fn parse_chunk_no_checksum() {
+        let channel_id = 10;
+        let hash = "abcdefg".to_owned();
+        let chunk_num = 10;
+        let chunk_data: Vec<u8> = vec![1, 2, 3, 4, 5, 6];
+
+        let raw = ser::to_vec_packed(&(
+            channel_id,
+            &hash,
+            chunk_num,
+            Value::Bytes(chunk_data.clone()),
+        )).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
         assert_eq!(
             msg.unwrap(),
-            Message::ReceiveChunk(channel_id, hash, chunk_num, chunk_data)
+            Message::ReceiveChunk(channel_id, hash, chunk_num, chunk_data, None)
         );
     }
 
@@ -213,6 +726,19 @@ mod tests {
         assert_eq!(msg.unwrap(), Message::ACK(channel_id, hash));
     }
 
+    #[test]
+    fn create_parse_hello() {
+        let channel_id = 15;
+        let capabilities = Capabilities::new(1, 2048)
+            .with_feature("compression")
+            .with_feature("ranges");
+
+        let raw = messages::hello(channel_id, &capabilities).unwrap();
+        let msg = parsers::parse_message(de::from_slice(&raw).unwrap());
+
+        assert_eq!(msg.unwrap(), Message::Hello(channel_id, capabilities));
+    }
+
     #[test]
     fn create_parse_nak() {
         let channel_id = 11;
@@ -228,4 +754,290 @@ mod tests {
             Message::NAK(channel_id, hash, Some(chunk_ranges))
         );
     }
+
+    #[test]
+    fn progress_tracking() {
+        let mut tracker = progress::Tracker::new("abcdefg", 10);
+
+        // Nothing has been transferred yet, so there's no way to estimate how long it will take
+        let progress = tracker.snapshot();
+        assert_eq!(progress.remaining, 10);
+        assert_eq!(progress.eta, None);
+
+        tracker.nak(4);
+        tracker.chunks_received += 1;
+        tracker.bytes += 4096;
+        tracker.remaining -= 1;
+
+        let progress = tracker.snapshot();
+        assert_eq!(progress.nak_rounds, 1);
+        assert_eq!(progress.remaining, 3);
+        assert_eq!(progress.chunks_done(), 7);
+
+        tracker.remaining = 0;
+        assert_eq!(tracker.snapshot().eta, Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn sandbox_paths() {
+        let roots = vec![PathBuf::from("/sandbox/root")];
+        let check = |path: &str| sandbox::check(Path::new(path), &roots).is_ok();
+
+        assert!(check("/sandbox/root"));
+        assert!(check("/sandbox/root/file"));
+        assert!(check("/sandbox/root/./dir/../file"));
+        assert!(!check("/sandbox/root/../file"));
+        assert!(!check("/sandbox/root/dir/../../file"));
+        assert!(!check("/sandbox/rootless/file"));
+        assert!(!check("/etc/passwd"));
+
+        // Without any roots, everything is allowed
+        assert!(sandbox::check(Path::new("/etc/passwd"), &[]).is_ok());
+
+        // Only what's inside a root can be replaced, removed or moved, not the root itself
+        let check_beneath = |path: &str| sandbox::check_beneath(Path::new(path), &roots).is_ok();
+        assert!(check_beneath("/sandbox/root/file"));
+        assert!(!check_beneath("/sandbox/root"));
+        assert!(!check_beneath("/sandbox/root/"));
+        assert!(!check_beneath("/sandbox/root/dir/.."));
+        assert!(!check_beneath("/sandbox/root/."));
+        assert!(sandbox::check_beneath(Path::new("/sandbox"), &[]).is_ok());
+    }
+
+    #[test]
+    fn transfer_over_unix_socket() {
+        let dir = env::temp_dir().join(format!("file-protocol-unix-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source");
+        let dest = dir.join("dest");
+        let contents: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+        fs::write(&source, &contents).unwrap();
+
+        let (client, service) = UnixTransport::pair().unwrap();
+        let prefix = |name: &str| Some(dir.join(name).to_string_lossy().into_owned());
+
+        // There's only one peer on the other end, so the remote address doesn't matter
+        let config = FileProtocolConfig::new(prefix("service"), 4096, 5);
+        let receiver = thread::spawn(move || {
+            let f_protocol = FileProtocol::new(service, "0.0.0.0:0", config);
+            f_protocol.message_engine(
+                |d| f_protocol.recv(Some(d)),
+                Duration::from_millis(500),
+                State::Holding {
+                    count: 0,
+                    prev_state: Box::new(State::Done),
+                },
+            )
+        });
+
+        let config = FileProtocolConfig::new(prefix("client"), 4096, 5);
+        let f_protocol = FileProtocol::new(client, "0.0.0.0:0", config);
+        let (hash, num_chunks, mode) = f_protocol
+            .initialize_file(source.to_str().unwrap())
+            .unwrap();
+        let channel_id = f_protocol.generate_channel().unwrap();
+        f_protocol
+            .send_metadata(channel_id, &hash, num_chunks)
+            .unwrap();
+        f_protocol
+            .send_export(channel_id, &hash, dest.to_str().unwrap(), mode)
+            .unwrap();
+        let sent = f_protocol.message_engine(
+            |d| f_protocol.recv(Some(d)),
+            Duration::from_millis(500),
+            State::Transmitting,
+        );
+        let received = receiver.join().unwrap();
+
+        let result = fs::read(&dest);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(sent.is_ok() && received.is_ok());
+        assert_eq!(result.unwrap(), contents);
+    }
+
+    #[test]
+    fn request_retried_until_answered() {
+        let (client, service) = UnixTransport::pair().unwrap();
+
+        // Ignore the first request, as though it was lost, then answer the second
+        // after a late reply to some other request
+        let config = FileProtocolConfig::new(None, 4096, 5);
+        let responder = thread::spawn(move || {
+            let f_protocol = FileProtocol::new(service, "0.0.0.0:0", config);
+            let first = f_protocol.recv(Some(Duration::from_secs(1))).unwrap();
+            let second = f_protocol.recv(Some(Duration::from_secs(1))).unwrap();
+            assert_eq!(first, second);
+            let channel_id = parsers::parse_channel_id(&second).unwrap();
+            f_protocol
+                .send(messages::operation_success(channel_id.wrapping_add(1)).unwrap())
+                .unwrap();
+            f_protocol
+                .send(messages::list_success(channel_id, &[]).unwrap())
+                .unwrap();
+
+            // Stop answering, but keep the socket open
+            let mut ignored = 0;
+            while f_protocol.recv(Some(Duration::from_secs(1))).is_ok() {
+                ignored += 1;
+            }
+            ignored
+        });
+
+        let config = FileProtocolConfig::new(None, 4096, 2)
+            .with_request_timeout(Duration::from_millis(200));
+        let f_protocol = FileProtocol::new(client, "0.0.0.0:0", config);
+        assert!(f_protocol.remote_list("/").unwrap().is_empty());
+
+        // Nobody's answering now, so give up after the last retry
+        match f_protocol.remote_list("/") {
+            Err(ProtocolError::ReceiveTimeout) => {}
+            other => panic!("Unexpected result: {:?}", other.map(|_| ())),
+        }
+        assert_eq!(responder.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn finalize_keeps_target_until_complete() {
+        let dir = env::temp_dir().join(format!("file-protocol-finalize-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let prefix = dir.join("storage").to_string_lossy().into_owned();
+        let source = dir.join("source");
+        let target = dir.join("target");
+        let target_str = target.to_str().unwrap();
+        let contents: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+        fs::write(&source, &contents).unwrap();
+        fs::write(&target, b"the good copy").unwrap();
+
+        let (hash, _, _) = storage::initialize_file(
+            &prefix,
+            source.to_str().unwrap(),
+            4096,
+            Compression::None,
+            false,
+        )
+        .unwrap();
+        fs::write(
+            format!("{}/storage/{}/1", prefix, hash),
+            b"not what was sent",
+        )
+        .unwrap();
+
+        // The bad chunk has to be sent again before anything is written
+        let corrupt = storage::finalize_file(&prefix, &hash, target_str, None, &[]);
+        let kept = fs::read(&target).unwrap();
+        let leftovers = fs::read_dir(&dir).unwrap().count();
+
+        storage::initialize_file(
+            &prefix,
+            source.to_str().unwrap(),
+            4096,
+            Compression::None,
+            false,
+        )
+        .unwrap();
+        let finished = storage::finalize_file(&prefix, &hash, target_str, None, &[]);
+        let result = fs::read(&target);
+        fs::remove_dir_all(&dir).unwrap();
+
+        match corrupt {
+            Err(ProtocolError::CorruptChunks(chunks)) => assert_eq!(chunks, vec![1]),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(kept, b"the good copy");
+        // Just the storage, source and target
+        assert_eq!(leftovers, 3);
+        assert!(finished.is_ok());
+        assert_eq!(result.unwrap(), contents);
+    }
+
+    #[test]
+    fn finalize_stays_in_write_roots() {
+        let dir = env::temp_dir().join(format!("file-protocol-finalize-roots-{}", process::id()));
+        let prefix = dir.to_string_lossy().into_owned();
+        let root = dir.join("root");
+        let outside = dir.join("outside");
+        let source = dir.join("source");
+        let contents = b"finalize_stays_in_write_roots";
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(&source, contents).unwrap();
+        fs::write(outside.join("victim"), b"untouched").unwrap();
+        let (hash, _, _) = storage::initialize_file(
+            &prefix,
+            source.to_str().unwrap(),
+            4096,
+            Compression::None,
+            false,
+        )
+        .unwrap();
+        let roots = vec![root.clone()];
+
+        // A directory which leads out of the roots isn't written to
+        symlink(&outside, root.join("link")).unwrap();
+        let escaped = storage::finalize_file(
+            &prefix,
+            &hash,
+            root.join("link/target").to_str().unwrap(),
+            None,
+            &roots,
+        );
+        let outside_entries = fs::read_dir(&outside).unwrap().count();
+
+        // Nor is a link left where the file might be put together
+        symlink(outside.join("victim"), root.join("dir/.target.partial")).unwrap();
+        let target = root.join("dir/target");
+        let finished =
+            storage::finalize_file(&prefix, &hash, target.to_str().unwrap(), None, &roots);
+        let result = fs::read(&target);
+        let victim = fs::read(outside.join("victim"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        match escaped {
+            Err(ProtocolError::PathNotAllowed(_)) => {}
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert_eq!(outside_entries, 1);
+        assert!(finished.is_ok());
+        assert_eq!(result.unwrap(), &contents[..]);
+        assert_eq!(victim.unwrap(), b"untouched");
+    }
+
+    #[test]
+    fn load_chunks_without_checksums() {
+        let dir = env::temp_dir().join(format!("file-protocol-old-chunks-{}", process::id()));
+        let prefix = dir.to_string_lossy().into_owned();
+        let target = dir.join("target");
+        let contents = b"first half, second half";
+        let hash: String = blake2s(16, &[], contents)
+            .as_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let storage = dir.join("storage").join(&hash);
+
+        // Half of a transfer stored before chunks had checksums
+        fs::create_dir_all(&storage).unwrap();
+        let meta = vec![
+            ("num_chunks", Value::U64(2)),
+            ("compression", Value::String("none".to_owned())),
+        ];
+        fs::write(storage.join("meta"), ser::to_vec(&meta).unwrap()).unwrap();
+        fs::write(storage.join("0"), &contents[..12]).unwrap();
+
+        let loaded = storage::load_chunk(&prefix, &hash, 0);
+
+        // Resuming the transfer keeps the file's chunks in the same format
+        storage::store_meta(&prefix, &hash, 2, Compression::None).unwrap();
+        storage::store_chunk(&prefix, &hash, 1, &contents[12..], None).unwrap();
+        let second = fs::read(storage.join("1")).unwrap();
+        let finished = storage::finalize_file(&prefix, &hash, target.to_str().unwrap(), None, &[]);
+        let result = fs::read(&target);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(loaded.unwrap(), &contents[..12]);
+        assert_eq!(second, &contents[12..]);
+        assert!(finished.is_ok());
+        assert_eq!(result.unwrap(), &contents[..]);
+    }
 }
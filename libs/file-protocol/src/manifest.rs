@@ -0,0 +1,209 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Directory transfer manifests
+//!
+//! A manifest describes every directory and file within a directory tree,
+//! using paths relative to the root of the tree. It is transferred like any
+//! other file and then used to rebuild the tree on the far side.
+
+use error::ProtocolError;
+use serde_cbor::{de, ser};
+use std::fs::{self, File, Permissions};
+use std::io::{Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
+
+/// A single directory or file within a directory transfer
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ManifestEntry {
+    /// Path of the entry, relative to the root of the directory tree
+    pub path: String,
+    /// File mode
+    pub mode: u32,
+    /// Whether the entry is a directory
+    pub is_dir: bool,
+}
+
+/// Walk a directory tree and create a manifest describing its contents
+///
+/// The root directory itself is the first entry, with the path `"."`.
+/// Directories are always listed before their contents.
+/// Anything which is neither a regular file nor a directory (ex. symlinks) is skipped.
+pub fn create(root: &str) -> Result<Vec<ManifestEntry>, ProtocolError> {
+    let meta = fs::metadata(root).map_err(|err| ProtocolError::StorageError {
+        action: format!("stat directory {}", root),
+        err,
+    })?;
+
+    if !meta.is_dir() {
+        return Err(ProtocolError::StorageParseError(format!(
+            "{} is not a directory",
+            root
+        )));
+    }
+
+    let mut entries = vec![ManifestEntry {
+        path: ".".to_owned(),
+        mode: meta.mode(),
+        is_dir: true,
+    }];
+
+    walk(Path::new(root), Path::new(""), &mut entries)?;
+
+    Ok(entries)
+}
+
+// Recursively add the contents of `root/relative` to the manifest
+fn walk(
+    root: &Path,
+    relative: &Path,
+    entries: &mut Vec<ManifestEntry>,
+) -> Result<(), ProtocolError> {
+    let dir = root.join(relative);
+    let mut children: Vec<_> = fs::read_dir(&dir)
+        .map_err(|err| ProtocolError::StorageError {
+            action: format!("read {:?} directory", dir),
+            err,
+        })?.filter_map(|entry| entry.ok())
+        .collect();
+
+    // Keep the transfer order predictable
+    children.sort_by_key(|entry| entry.file_name());
+
+    for child in children {
+        let child_path = relative.join(child.file_name());
+        let meta =
+            fs::symlink_metadata(child.path()).map_err(|err| ProtocolError::StorageError {
+                action: format!("stat {:?}", child.path()),
+                err,
+            })?;
+
+        let path = child_path.to_string_lossy().into_owned();
+
+        if meta.is_dir() {
+            entries.push(ManifestEntry {
+                path,
+                mode: meta.mode(),
+                is_dir: true,
+            });
+            walk(root, &child_path, entries)?;
+        } else if meta.is_file() {
+            entries.push(ManifestEntry {
+                path,
+                mode: meta.mode(),
+                is_dir: false,
+            });
+        } else {
+            warn!(
+                "Skipping {:?}: not a regular file or directory",
+                child.path()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Write a manifest to a file
+pub fn store(path: &Path, entries: &[ManifestEntry]) -> Result<(), ProtocolError> {
+    let data: Vec<(&str, u32, bool)> = entries
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry.mode, entry.is_dir))
+        .collect();
+
+    let vec = ser::to_vec_packed(&data)?;
+
+    File::create(path)
+        .map_err(|err| ProtocolError::StorageError {
+            action: format!("create/open {:?} for writing", path),
+            err,
+        })?.write_all(&vec)
+        .map_err(|err| ProtocolError::StorageError {
+            action: format!("write manifest to {:?}", path),
+            err,
+        })?;
+
+    Ok(())
+}
+
+/// Read a manifest from a file
+pub fn load(path: &Path) -> Result<Vec<ManifestEntry>, ProtocolError> {
+    let mut data = vec![];
+
+    File::open(path)
+        .map_err(|err| ProtocolError::StorageError {
+            action: format!("open manifest {:?}", path),
+            err,
+        })?.read_to_end(&mut data)
+        .map_err(|err| ProtocolError::StorageError {
+            action: format!("read manifest {:?}", path),
+            err,
+        })?;
+
+    let raw: Vec<(String, u32, bool)> = de::from_slice(&data).map_err(|err| {
+        ProtocolError::StorageParseError(format!("Unable to parse manifest: {}", err))
+    })?;
+
+    Ok(raw
+        .into_iter()
+        .map(|(path, mode, is_dir)| ManifestEntry { path, mode, is_dir })
+        .collect())
+}
+
+/// Get the full path of a manifest entry beneath the given root directory
+///
+/// Entries which would escape the root directory are rejected
+pub fn entry_path(root: &str, entry: &ManifestEntry) -> Result<PathBuf, ProtocolError> {
+    let mut path = PathBuf::from(root);
+
+    for component in Path::new(&entry.path).components() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            // `create_dir_all` chokes on trailing `.` components, so leave them out
+            Component::CurDir => {}
+            _ => {
+                return Err(ProtocolError::StorageParseError(format!(
+                    "Invalid manifest entry path: {}",
+                    entry.path
+                )))
+            }
+        }
+    }
+
+    Ok(path)
+}
+
+/// Create all of the directories listed in a manifest beneath the given root directory
+pub fn create_directories(root: &str, entries: &[ManifestEntry]) -> Result<(), ProtocolError> {
+    for entry in entries.iter().filter(|entry| entry.is_dir) {
+        let path = entry_path(root, entry)?;
+
+        fs::create_dir_all(&path).map_err(|err| ProtocolError::StorageError {
+            action: format!("create directory {:?}", path),
+            err,
+        })?;
+
+        fs::set_permissions(&path, Permissions::from_mode(entry.mode)).map_err(|err| {
+            ProtocolError::StorageError {
+                action: format!("set mode of directory {:?}", path),
+                err,
+            }
+        })?;
+    }
+
+    Ok(())
+}
@@ -14,8 +14,14 @@
 // limitations under the License.
 //
 
+use channel_protocol::Capabilities;
+use compression::Compression;
 use error::ProtocolError;
+use fec::Parity;
+use filesystem::FileInfo;
 use serde_cbor::{ser, Value};
+use storage;
+use transaction::TransactionInfo;
 
 // Create export message
 pub fn export_request(
@@ -38,27 +44,229 @@ pub fn export_request(
 }
 
 // Create import message
-pub fn import_request(channel_id: u32, source_path: &str) -> Result<Vec<u8>, ProtocolError> {
-    info!("-> {{ import, {} }}", source_path);
-    ser::to_vec_packed(&(channel_id, "import", source_path)).map_err(|err| {
+// The compression codec is only included if one is being requested,
+// or if only part of the file is wanted
+pub fn import_request(
+    channel_id: u32,
+    source_path: &str,
+    compression: Compression,
+    offset: u64,
+    length: Option<u64>,
+) -> Result<Vec<u8>, ProtocolError> {
+    let result = match (compression, offset, length) {
+        (Compression::None, 0, None) => {
+            info!("-> {{ import, {} }}", source_path);
+            ser::to_vec_packed(&(channel_id, "import", source_path))
+        }
+        (codec, 0, None) => {
+            info!("-> {{ import, {}, {} }}", source_path, codec.name());
+            ser::to_vec_packed(&(channel_id, "import", source_path, codec.name()))
+        }
+        (codec, offset, None) => {
+            info!(
+                "-> {{ import, {}, {}, {} }}",
+                source_path,
+                codec.name(),
+                offset
+            );
+            ser::to_vec_packed(&(channel_id, "import", source_path, codec.name(), offset))
+        }
+        (codec, offset, Some(length)) => {
+            info!(
+                "-> {{ import, {}, {}, {}, {} }}",
+                source_path,
+                codec.name(),
+                offset,
+                length
+            );
+            ser::to_vec_packed(&(
+                channel_id,
+                "import",
+                source_path,
+                codec.name(),
+                offset,
+                length,
+            ))
+        }
+    };
+
+    result.map_err(|err| ProtocolError::MessageCreationError {
+        message: "import".to_owned(),
+        err,
+    })
+}
+
+// Create directory export message
+pub fn export_dir_request(
+    channel_id: u32,
+    hash: &str,
+    target_path: &str,
+) -> Result<Vec<u8>, ProtocolError> {
+    info!(
+        "-> {{ {}, export_dir, {}, {} }}",
+        channel_id, hash, target_path
+    );
+
+    ser::to_vec_packed(&(channel_id, "export_dir", hash, target_path)).map_err(|err| {
         ProtocolError::MessageCreationError {
-            message: "import".to_owned(),
+            message: "export_dir".to_owned(),
             err,
         }
     })
 }
 
-// Create sync message
-pub fn metadata(channel_id: u32, hash: &str, num_chunks: u32) -> Result<Vec<u8>, ProtocolError> {
-    info!("-> {{ {}, {}, {} }}", channel_id, hash, num_chunks);
-    ser::to_vec_packed(&(channel_id, hash, num_chunks)).map_err(|err| {
+// Create directory import message
+pub fn import_dir_request(channel_id: u32, source_path: &str) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ import_dir, {} }}", source_path);
+    ser::to_vec_packed(&(channel_id, "import_dir", source_path)).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "import_dir".to_owned(),
+            err,
+        }
+    })
+}
+
+// Create resume message
+pub fn resume_request(channel_id: u32, hash: &str) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ {}, resume, {} }}", channel_id, hash);
+    ser::to_vec_packed(&(channel_id, "resume", hash)).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "resume".to_owned(),
+            err,
+        }
+    })
+}
+
+// Create directory listing request message
+pub fn list_request(channel_id: u32, path: &str) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ {}, list, {} }}", channel_id, path);
+    ser::to_vec_packed(&(channel_id, "list", path)).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "list".to_owned(),
+            err,
+        }
+    })
+}
+
+// Create file information request message
+pub fn stat_request(channel_id: u32, path: &str) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ {}, stat, {} }}", channel_id, path);
+    ser::to_vec_packed(&(channel_id, "stat", path)).map_err(|err| {
         ProtocolError::MessageCreationError {
-            message: "metadata".to_owned(),
+            message: "stat".to_owned(),
             err,
         }
     })
 }
 
+// Create delete request message
+pub fn delete_request(
+    channel_id: u32,
+    path: &str,
+    recursive: bool,
+) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ {}, delete, {}, {} }}", channel_id, path, recursive);
+    ser::to_vec_packed(&(channel_id, "delete", path, recursive)).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "delete".to_owned(),
+            err,
+        }
+    })
+}
+
+// Create directory creation request message
+pub fn mkdir_request(channel_id: u32, path: &str) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ {}, mkdir, {} }}", channel_id, path);
+    ser::to_vec_packed(&(channel_id, "mkdir", path)).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "mkdir".to_owned(),
+            err,
+        }
+    })
+}
+
+// Create rename request message
+pub fn rename_request(
+    channel_id: u32,
+    source_path: &str,
+    target_path: &str,
+) -> Result<Vec<u8>, ProtocolError> {
+    info!(
+        "-> {{ {}, rename, {}, {} }}",
+        channel_id, source_path, target_path
+    );
+    ser::to_vec_packed(&(channel_id, "rename", source_path, target_path)).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "rename".to_owned(),
+            err,
+        }
+    })
+}
+
+// Create active transaction list request message
+pub fn transactions_request(channel_id: u32) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ {}, transactions }}", channel_id);
+    ser::to_vec_packed(&(channel_id, "transactions")).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "transactions".to_owned(),
+            err,
+        }
+    })
+}
+
+// Create finished transaction list request message
+pub fn history_request(channel_id: u32, count: u32) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ {}, history, {} }}", channel_id, count);
+    ser::to_vec_packed(&(channel_id, "history", count)).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "history".to_owned(),
+            err,
+        }
+    })
+}
+
+// Create transaction cancellation request message
+pub fn cancel_request(channel_id: u32, target_channel: u32) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ {}, cancel, {} }}", channel_id, target_channel);
+    ser::to_vec_packed(&(channel_id, "cancel", target_channel)).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "cancel".to_owned(),
+            err,
+        }
+    })
+}
+
+// Create metadata message
+// The compression codec is only included if the chunks are compressed
+pub fn metadata(
+    channel_id: u32,
+    hash: &str,
+    num_chunks: u32,
+    compression: Compression,
+) -> Result<Vec<u8>, ProtocolError> {
+    let result = match compression {
+        Compression::None => {
+            info!("-> {{ {}, {}, {} }}", channel_id, hash, num_chunks);
+            ser::to_vec_packed(&(channel_id, hash, num_chunks))
+        }
+        codec => {
+            info!(
+                "-> {{ {}, {}, {}, {} }}",
+                channel_id,
+                hash,
+                num_chunks,
+                codec.name()
+            );
+            ser::to_vec_packed(&(channel_id, hash, num_chunks, codec.name()))
+        }
+    };
+
+    result.map_err(|err| ProtocolError::MessageCreationError {
+        message: "metadata".to_owned(),
+        err,
+    })
+}
+
 // Send an acknowledge to the remote address
 pub fn ack(channel_id: u32, hash: &str, num_chunks: Option<u32>) -> Result<Vec<u8>, ProtocolError> {
     info!("-> {{ {}, {}, true, {:?} }}", channel_id, hash, num_chunks);
@@ -105,6 +313,7 @@ pub fn nak(channel_id: u32, hash: &str, missing_chunks: &[u32]) -> Result<Vec<u8
 }
 
 // Create chunk message
+// The chunk's checksum is included so the receiver can verify the data
 pub fn chunk(
     channel_id: u32,
     hash: &str,
@@ -112,8 +321,12 @@ pub fn chunk(
     chunk: &[u8],
 ) -> Result<Vec<u8>, ProtocolError> {
     let chunk_bytes = Value::Bytes(chunk.to_vec());
-    info!("-> {{ {}, {}, {}, chunk_data }}", channel_id, hash, index);
-    ser::to_vec_packed(&(channel_id, hash, index, chunk_bytes)).map_err(|err| {
+    let checksum = storage::chunk_checksum(chunk);
+    info!(
+        "-> {{ {}, {}, {}, chunk_data, {:08x} }}",
+        channel_id, hash, index, checksum
+    );
+    ser::to_vec_packed(&(channel_id, hash, index, chunk_bytes, checksum)).map_err(|err| {
         ProtocolError::MessageCreationError {
             message: "chunk".to_owned(),
             err,
@@ -121,21 +334,128 @@ pub fn chunk(
     })
 }
 
+// Create parity chunk message
+// The parity chunk's checksum is included so the receiver can verify the data
+pub fn parity(
+    channel_id: u32,
+    hash: &str,
+    parity: &Parity,
+    data: &[u8],
+) -> Result<Vec<u8>, ProtocolError> {
+    let parity_bytes = Value::Bytes(data.to_vec());
+    let checksum = storage::chunk_checksum(data);
+    info!(
+        "-> {{ {}, parity, {}, {}, {}, {}, {}, parity_data, {:08x} }}",
+        channel_id,
+        hash,
+        parity.first_chunk,
+        parity.num_chunks,
+        parity.index,
+        parity.last_size,
+        checksum
+    );
+    ser::to_vec_packed(&(
+        channel_id,
+        "parity",
+        hash,
+        parity.first_chunk,
+        parity.num_chunks,
+        parity.index,
+        parity.last_size,
+        parity_bytes,
+        checksum,
+    )).map_err(|err| ProtocolError::MessageCreationError {
+        message: "parity".to_owned(),
+        err,
+    })
+}
+
 // Create succesful import request response message
+// The compression codec is only included if the chunks are compressed
 pub fn import_setup_success(
     channel_id: u32,
     hash: &str,
     num_chunks: u32,
     mode: u32,
+    compression: Compression,
 ) -> Result<Vec<u8>, ProtocolError> {
+    let result = match compression {
+        Compression::None => {
+            info!(
+                "-> {{ {}, true, {}, {}, {} }}",
+                channel_id, hash, num_chunks, mode
+            );
+            ser::to_vec_packed(&(channel_id, true, hash, num_chunks, mode))
+        }
+        codec => {
+            info!(
+                "-> {{ {}, true, {}, {}, {}, {} }}",
+                channel_id,
+                hash,
+                num_chunks,
+                mode,
+                codec.name()
+            );
+            ser::to_vec_packed(&(channel_id, true, hash, num_chunks, mode, codec.name()))
+        }
+    };
+
+    result.map_err(|err| ProtocolError::MessageCreationError {
+        message: "import success".to_owned(),
+        err,
+    })
+}
+
+// Create hello message, describing what we support
+pub fn hello(channel_id: u32, capabilities: &Capabilities) -> Result<Vec<u8>, ProtocolError> {
+    info!(
+        "-> {{ {}, hello, {}, {:?}, {} }}",
+        channel_id, capabilities.version, capabilities.features, capabilities.max_chunk_size
+    );
+    Ok(capabilities.to_cbor(channel_id)?)
+}
+
+// Create successful directory listing response message
+pub fn list_success(channel_id: u32, entries: &[FileInfo]) -> Result<Vec<u8>, ProtocolError> {
     info!(
-        "-> {{ {}, true, {}, {}, {} }}",
-        channel_id, hash, num_chunks, mode
+        "-> {{ {}, true, list, {} entries }}",
+        channel_id,
+        entries.len()
     );
+    let entries = Value::Array(entries.iter().map(|entry| entry.to_value()).collect());
+    ser::to_vec_packed(&(channel_id, true, "list", entries)).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "list success".to_owned(),
+            err,
+        }
+    })
+}
+
+// Create successful file information response message
+pub fn stat_success(channel_id: u32, info: &FileInfo) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ {}, true, stat, {:?} }}", channel_id, info);
+    ser::to_vec_packed(&(channel_id, true, "stat", info.to_value())).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "stat success".to_owned(),
+            err,
+        }
+    })
+}
 
-    ser::to_vec_packed(&(channel_id, true, hash, num_chunks, mode)).map_err(|err| {
+// Create successful transaction list response message
+pub fn transactions_success(
+    channel_id: u32,
+    transactions: &[TransactionInfo],
+) -> Result<Vec<u8>, ProtocolError> {
+    info!(
+        "-> {{ {}, true, transactions, {} entries }}",
+        channel_id,
+        transactions.len()
+    );
+    let transactions = Value::Array(transactions.iter().map(|info| info.to_value()).collect());
+    ser::to_vec_packed(&(channel_id, true, "transactions", transactions)).map_err(|err| {
         ProtocolError::MessageCreationError {
-            message: "import success".to_owned(),
+            message: "transactions success".to_owned(),
             err,
         }
     })
@@ -15,9 +15,14 @@
 //
 
 use super::Message;
+use channel_protocol::Capabilities;
+use compression::Compression;
 use error::ProtocolError;
+use fec::{self, Parity};
+use filesystem::FileInfo;
 use serde_cbor::Value;
 use std::slice::Iter;
+use transaction::TransactionInfo;
 
 /// Parse out just the channel ID from a message
 pub fn parse_channel_id(message: &Value) -> Result<u32, ProtocolError> {
@@ -47,6 +52,7 @@ pub fn parse_channel_id(message: &Value) -> Result<u32, ProtocolError> {
     }
 }
 
+/// Parse a file protocol message
 pub fn parse_message(message: Value) -> Result<Message, ProtocolError> {
     let raw = match message {
         Value::Array(val) => val.to_owned(),
@@ -73,9 +79,38 @@ pub fn parse_message(message: Value) -> Result<Message, ProtocolError> {
         if let Some(msg) = parse_import_request(channel_id, pieces.to_owned())? {
             return Ok(msg);
         }
+        if let Some(msg) = parse_export_dir_request(channel_id, pieces.to_owned())? {
+            return Ok(msg);
+        }
+        if let Some(msg) = parse_import_dir_request(channel_id, pieces.to_owned())? {
+            return Ok(msg);
+        }
+        if let Some(msg) = parse_resume_request(channel_id, pieces.to_owned())? {
+            return Ok(msg);
+        }
+        if let Some(msg) = parse_filesystem_request(channel_id, pieces.to_owned())? {
+            return Ok(msg);
+        }
+        if let Some(msg) = parse_transaction_request(channel_id, pieces.to_owned())? {
+            return Ok(msg);
+        }
+        if let Some(msg) = parse_hello(channel_id, pieces.to_owned())? {
+            return Ok(msg);
+        }
+        if let Some(msg) = parse_parity(channel_id, pieces.to_owned())? {
+            return Ok(msg);
+        }
         if let Some(msg) = parse_success_receive(channel_id, pieces.to_owned())? {
             return Ok(msg);
         }
+        // These need to be checked before the import success message, since their
+        // second param is also a string
+        if let Some(msg) = parse_success_filesystem(channel_id, pieces.to_owned())? {
+            return Ok(msg);
+        }
+        if let Some(msg) = parse_success_transactions(channel_id, pieces.to_owned())? {
+            return Ok(msg);
+        }
         if let Some(msg) = parse_success_transmit(channel_id, pieces.to_owned())? {
             return Ok(msg);
         }
@@ -88,10 +123,12 @@ pub fn parse_message(message: Value) -> Result<Message, ProtocolError> {
         if let Some(msg) = parse_nak(channel_id, pieces.to_owned())? {
             return Ok(msg);
         }
-        if let Some(msg) = parse_chunk(channel_id, pieces.to_owned())? {
+        // Metadata messages may also have three params, so they need to be checked
+        // before chunk messages
+        if let Some(msg) = parse_sync(channel_id, pieces.to_owned())? {
             return Ok(msg);
         }
-        if let Some(msg) = parse_sync(channel_id, pieces.to_owned())? {
+        if let Some(msg) = parse_chunk(channel_id, pieces.to_owned())? {
             return Ok(msg);
         }
     }
@@ -153,7 +190,7 @@ pub fn parse_export_request(
 }
 
 // Parse out import request
-// { channel_id, "import", path }
+// { channel_id, "import", path [, compression [, offset [, length]]] }
 pub fn parse_import_request(
     channel_id: u32,
     mut pieces: Iter<Value>,
@@ -172,9 +209,46 @@ pub fn parse_import_request(
                     ))
                 }
             };
+
+            // The codec is only a request. If we don't know it, the file will be
+            // sent uncompressed instead
+            let compression = match pieces.next() {
+                Some(Value::String(name)) => name.parse().unwrap_or_else(|err| {
+                    warn!("{}. Sending uncompressed", err);
+                    Compression::None
+                }),
+                _ => Compression::None,
+            };
+
+            // Only part of the file may be wanted
+            let offset = match pieces.next() {
+                Some(Value::U64(val)) => *val,
+                None => 0,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "import".to_owned(),
+                        "offset".to_owned(),
+                    ))
+                }
+            };
+
+            let length = match pieces.next() {
+                Some(Value::U64(val)) => Some(*val),
+                None => None,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "import".to_owned(),
+                        "length".to_owned(),
+                    ))
+                }
+            };
+
             return Ok(Some(Message::ReqTransmit(
                 channel_id as u32,
                 path.to_owned(),
+                compression,
+                offset,
+                length,
             )));
         }
     }
@@ -182,6 +256,291 @@ pub fn parse_import_request(
     return Ok(None);
 }
 
+// Parse out directory export request
+// { channel_id, "export_dir", hash, path }
+pub fn parse_export_dir_request(
+    channel_id: u32,
+    mut pieces: Iter<Value>,
+) -> Result<Option<Message>, ProtocolError> {
+    if let Some(Value::String(op)) = pieces.next() {
+        if op == "export_dir" {
+            let hash = match pieces.next().ok_or(ProtocolError::MissingParam(
+                "export_dir".to_owned(),
+                "hash".to_owned(),
+            ))? {
+                Value::String(val) => val,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "export_dir".to_owned(),
+                        "hash".to_owned(),
+                    ))
+                }
+            };
+
+            let path = match pieces.next().ok_or(ProtocolError::MissingParam(
+                "export_dir".to_owned(),
+                "path".to_owned(),
+            ))? {
+                Value::String(val) => val,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "export_dir".to_owned(),
+                        "path".to_owned(),
+                    ))
+                }
+            };
+
+            return Ok(Some(Message::ReqReceiveDir(
+                channel_id,
+                hash.to_owned(),
+                path.to_owned(),
+            )));
+        }
+    }
+
+    Ok(None)
+}
+
+// Parse out directory import request
+// { channel_id, "import_dir", path }
+pub fn parse_import_dir_request(
+    channel_id: u32,
+    mut pieces: Iter<Value>,
+) -> Result<Option<Message>, ProtocolError> {
+    if let Some(Value::String(op)) = pieces.next() {
+        if op == "import_dir" {
+            let path = match pieces.next().ok_or(ProtocolError::MissingParam(
+                "import_dir".to_owned(),
+                "path".to_owned(),
+            ))? {
+                Value::String(val) => val,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "import_dir".to_owned(),
+                        "path".to_owned(),
+                    ))
+                }
+            };
+            return Ok(Some(Message::ReqTransmitDir(channel_id, path.to_owned())));
+        }
+    }
+
+    Ok(None)
+}
+
+// Parse out resume request
+// { channel_id, "resume", hash }
+pub fn parse_resume_request(
+    channel_id: u32,
+    mut pieces: Iter<Value>,
+) -> Result<Option<Message>, ProtocolError> {
+    if let Some(Value::String(op)) = pieces.next() {
+        if op == "resume" {
+            let hash = match pieces.next().ok_or(ProtocolError::MissingParam(
+                "resume".to_owned(),
+                "hash".to_owned(),
+            ))? {
+                Value::String(val) => val,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "resume".to_owned(),
+                        "hash".to_owned(),
+                    ))
+                }
+            };
+            return Ok(Some(Message::Resume(channel_id, hash.to_owned())));
+        }
+    }
+
+    Ok(None)
+}
+
+// Get the next string param of a message
+fn parse_string_param(
+    message: &str,
+    param: &str,
+    pieces: &mut Iter<Value>,
+) -> Result<String, ProtocolError> {
+    match pieces.next().ok_or(ProtocolError::MissingParam(
+        message.to_owned(),
+        param.to_owned(),
+    ))? {
+        Value::String(val) => Ok(val.to_owned()),
+        _ => Err(ProtocolError::InvalidParam(
+            message.to_owned(),
+            param.to_owned(),
+        )),
+    }
+}
+
+// Parse out file system requests
+// { channel_id, "list", path }
+// { channel_id, "stat", path }
+// { channel_id, "delete", path [, recursive] }
+// { channel_id, "mkdir", path }
+// { channel_id, "rename", source_path, target_path }
+pub fn parse_filesystem_request(
+    channel_id: u32,
+    mut pieces: Iter<Value>,
+) -> Result<Option<Message>, ProtocolError> {
+    if let Some(Value::String(op)) = pieces.next() {
+        let message = match op.as_str() {
+            "list" => Message::ReqList(channel_id, parse_string_param(op, "path", &mut pieces)?),
+            "stat" => Message::ReqStat(channel_id, parse_string_param(op, "path", &mut pieces)?),
+            "delete" => {
+                let path = parse_string_param(op, "path", &mut pieces)?;
+                let recursive = match pieces.next() {
+                    Some(Value::Bool(val)) => *val,
+                    _ => false,
+                };
+                Message::ReqDelete(channel_id, path, recursive)
+            }
+            "mkdir" => Message::ReqMkdir(channel_id, parse_string_param(op, "path", &mut pieces)?),
+            "rename" => {
+                let source_path = parse_string_param(op, "source path", &mut pieces)?;
+                let target_path = parse_string_param(op, "target path", &mut pieces)?;
+                Message::ReqRename(channel_id, source_path, target_path)
+            }
+            _ => return Ok(None),
+        };
+
+        return Ok(Some(message));
+    }
+
+    Ok(None)
+}
+
+// Get the next integer param of a message
+fn parse_u32_param(
+    message: &str,
+    param: &str,
+    pieces: &mut Iter<Value>,
+) -> Result<u32, ProtocolError> {
+    match pieces.next().ok_or(ProtocolError::MissingParam(
+        message.to_owned(),
+        param.to_owned(),
+    ))? {
+        Value::U64(val) => Ok(*val as u32),
+        _ => Err(ProtocolError::InvalidParam(
+            message.to_owned(),
+            param.to_owned(),
+        )),
+    }
+}
+
+// Parse out transaction management requests
+// { channel_id, "transactions" }
+// { channel_id, "history", count }
+// { channel_id, "cancel", target_channel_id }
+pub fn parse_transaction_request(
+    channel_id: u32,
+    mut pieces: Iter<Value>,
+) -> Result<Option<Message>, ProtocolError> {
+    if let Some(Value::String(op)) = pieces.next() {
+        let message = match op.as_str() {
+            "transactions" => {
+                // Otherwise it's a sync message for a file with a very unlikely hash
+                if pieces.next().is_some() {
+                    return Ok(None);
+                }
+                Message::ReqTransactions(channel_id)
+            }
+            "history" => {
+                Message::ReqHistory(channel_id, parse_u32_param(op, "count", &mut pieces)?)
+            }
+            "cancel" => {
+                Message::ReqCancel(channel_id, parse_u32_param(op, "channel", &mut pieces)?)
+            }
+            _ => return Ok(None),
+        };
+
+        return Ok(Some(message));
+    }
+
+    Ok(None)
+}
+
+// Parse out hello message
+// { channel_id, "hello", version, [ feature, ... ], max_chunk_size }
+pub fn parse_hello(
+    channel_id: u32,
+    mut pieces: Iter<Value>,
+) -> Result<Option<Message>, ProtocolError> {
+    if let Some(Value::String(op)) = pieces.next() {
+        if op == "hello" {
+            let capabilities = Capabilities::from_payload(pieces.as_slice())?;
+            return Ok(Some(Message::Hello(channel_id, capabilities)));
+        }
+    }
+
+    Ok(None)
+}
+
+// Parse out file system request success messages
+// { channel_id, true, "list", [ file_info, ... ] }
+// { channel_id, true, "stat", file_info }
+pub fn parse_success_filesystem(
+    channel_id: u32,
+    mut pieces: Iter<Value>,
+) -> Result<Option<Message>, ProtocolError> {
+    if let Some(Value::Bool(true)) = pieces.next() {
+        if let Some(Value::String(op)) = pieces.next() {
+            match op.as_str() {
+                "list" => {
+                    let entries = match pieces.next() {
+                        Some(Value::Array(entries)) => entries
+                            .iter()
+                            .map(FileInfo::from_value)
+                            .collect::<Option<Vec<FileInfo>>>(),
+                        _ => None,
+                    }.ok_or(ProtocolError::InvalidParam(
+                        "list success".to_owned(),
+                        "entries".to_owned(),
+                    ))?;
+
+                    return Ok(Some(Message::SuccessList(channel_id, entries)));
+                }
+                "stat" => {
+                    let info = pieces.next().and_then(FileInfo::from_value).ok_or(
+                        ProtocolError::InvalidParam("stat success".to_owned(), "info".to_owned()),
+                    )?;
+
+                    return Ok(Some(Message::SuccessStat(channel_id, info)));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+// Parse out transaction list success messages
+// { channel_id, true, "transactions", [ transaction_info, ... ] }
+pub fn parse_success_transactions(
+    channel_id: u32,
+    mut pieces: Iter<Value>,
+) -> Result<Option<Message>, ProtocolError> {
+    if let (Some(Value::Bool(true)), Some(Value::String(op))) = (pieces.next(), pieces.next()) {
+        if op == "transactions" {
+            let transactions = match pieces.next() {
+                Some(Value::Array(entries)) => entries
+                    .iter()
+                    .map(TransactionInfo::from_value)
+                    .collect::<Option<Vec<TransactionInfo>>>(),
+                _ => None,
+            }.ok_or(ProtocolError::InvalidParam(
+                "transactions success".to_owned(),
+                "transactions".to_owned(),
+            ))?;
+
+            return Ok(Some(Message::SuccessTransactions(channel_id, transactions)));
+        }
+    }
+
+    Ok(None)
+}
+
 // Parse out success received message
 // { channel_id, true }
 pub fn parse_success_receive(
@@ -236,12 +595,18 @@ pub fn parse_success_transmit(
                 _ => None,
             };
 
+            let compression = match pieces.next() {
+                Some(Value::String(name)) => name.parse()?,
+                _ => Compression::None,
+            };
+
             // Return the file info
             return Ok(Some(Message::SuccessTransmit(
                 channel_id,
                 hash.to_string(),
                 num_chunks as u32,
                 mode,
+                compression,
             )));
         }
     }
@@ -330,7 +695,7 @@ pub fn parse_nak(
 }
 
 // Parse out chunk
-// { hash, chunk_index, data }
+// { hash, chunk_index, data [, checksum] }
 pub fn parse_chunk(
     channel_id: u32,
     mut pieces: Iter<Value>,
@@ -339,11 +704,24 @@ pub fn parse_chunk(
         if let Some(Value::U64(num)) = pieces.next() {
             if let Some(third_param) = pieces.next() {
                 if let Value::Bytes(data) = third_param {
+                    // Older senders don't include a checksum
+                    let checksum = match pieces.next() {
+                        Some(Value::U64(checksum)) => Some(*checksum as u32),
+                        None => None,
+                        _ => {
+                            return Err(ProtocolError::InvalidParam(
+                                "chunk".to_owned(),
+                                "checksum".to_owned(),
+                            ))
+                        }
+                    };
+
                     return Ok(Some(Message::ReceiveChunk(
                         channel_id,
                         hash.to_owned(),
                         *num as u32,
                         data.to_vec(),
+                        checksum,
                     )));
                 } else {
                     return Err(ProtocolError::InvalidParam(
@@ -358,8 +736,104 @@ pub fn parse_chunk(
     return Ok(None);
 }
 
+// Parse out parity chunk
+// { "parity", hash, first_chunk, num_chunks, parity_index, last_size, data, checksum }
+pub fn parse_parity(
+    channel_id: u32,
+    mut pieces: Iter<Value>,
+) -> Result<Option<Message>, ProtocolError> {
+    if let Some(Value::String(op)) = pieces.next() {
+        if op == "parity" {
+            let hash = match pieces.next().ok_or(ProtocolError::MissingParam(
+                "parity".to_owned(),
+                "hash".to_owned(),
+            ))? {
+                Value::String(val) => val,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "parity".to_owned(),
+                        "hash".to_owned(),
+                    ))
+                }
+            };
+
+            let mut fields = vec![];
+            for name in &["first_chunk", "num_chunks", "parity_index", "last_size"] {
+                match pieces.next().ok_or(ProtocolError::MissingParam(
+                    "parity".to_owned(),
+                    name.to_string(),
+                ))? {
+                    Value::U64(val) if *val <= u64::from(u32::MAX) => fields.push(*val as u32),
+                    _ => {
+                        return Err(ProtocolError::InvalidParam(
+                            "parity".to_owned(),
+                            name.to_string(),
+                        ))
+                    }
+                }
+            }
+
+            // The parity chunk has to fit the code's limits, otherwise the receiver
+            // could be made to rebuild a nonsensical block
+            if fields[1] == 0 || fields[1] > fec::MAX_CHUNKS {
+                return Err(ProtocolError::InvalidParam(
+                    "parity".to_owned(),
+                    "num_chunks".to_owned(),
+                ));
+            }
+            if fields[2] >= fec::MAX_CHUNKS {
+                return Err(ProtocolError::InvalidParam(
+                    "parity".to_owned(),
+                    "parity_index".to_owned(),
+                ));
+            }
+
+            let data = match pieces.next().ok_or(ProtocolError::MissingParam(
+                "parity".to_owned(),
+                "parity data".to_owned(),
+            ))? {
+                Value::Bytes(val) => val,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "parity".to_owned(),
+                        "parity data".to_owned(),
+                    ))
+                }
+            };
+
+            let checksum = match pieces.next().ok_or(ProtocolError::MissingParam(
+                "parity".to_owned(),
+                "checksum".to_owned(),
+            ))? {
+                Value::U64(val) => *val as u32,
+                _ => {
+                    return Err(ProtocolError::InvalidParam(
+                        "parity".to_owned(),
+                        "checksum".to_owned(),
+                    ))
+                }
+            };
+
+            return Ok(Some(Message::ReceiveParity(
+                channel_id,
+                hash.to_owned(),
+                Parity {
+                    first_chunk: fields[0],
+                    num_chunks: fields[1],
+                    index: fields[2],
+                    last_size: fields[3],
+                },
+                data.to_vec(),
+                checksum,
+            )));
+        }
+    }
+
+    Ok(None)
+}
+
 // Parse out sync
-// { hash, num_chunks }
+// { hash, num_chunks [, compression] }
 // or
 // { hash }
 pub fn parse_sync(
@@ -369,12 +843,20 @@ pub fn parse_sync(
     if let Some(Value::String(hash)) = pieces.next() {
         if let Some(second_param) = pieces.next() {
             if let Value::U64(num) = second_param {
+                let compression = match pieces.next() {
+                    None => Compression::None,
+                    Some(Value::String(name)) => name.parse()?,
+                    // Not a metadata message (ex. a chunk)
+                    _ => return Ok(None),
+                };
+
                 if let None = pieces.next() {
-                    // It's a sync message: { hash, num_chunks }
+                    // It's a sync message: { hash, num_chunks [, compression] }
                     return Ok(Some(Message::Metadata(
                         channel_id,
                         hash.to_owned(),
                         *num as u32,
+                        compression,
                     )));
                 }
             }
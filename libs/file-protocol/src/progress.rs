@@ -0,0 +1,122 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Transfer progress reporting
+
+use std::time::{Duration, Instant};
+
+/// Called with each progress update
+pub type ProgressCallback = Box<dyn Fn(&Progress)>;
+
+/// Snapshot of a file transfer's progress
+///
+/// Reported each time chunks are sent or received, and whenever the receiver
+/// acknowledges which chunks it has
+#[derive(Clone, Debug, PartialEq)]
+pub struct Progress {
+    /// Hash of the file being transferred
+    pub hash: String,
+    /// Total number of chunks in the file
+    pub num_chunks: u32,
+    /// Number of chunks which haven't been transferred yet. Corrected each time
+    /// a NAK reports which chunks the receiver is missing
+    pub remaining: u32,
+    /// Number of chunks we have sent, including any which were sent again
+    pub chunks_sent: u32,
+    /// Number of chunks we have received
+    pub chunks_received: u32,
+    /// Number of NAKs which have been sent or received
+    pub nak_rounds: u32,
+    /// Number of chunk data bytes sent and received
+    pub bytes: u64,
+    /// Time since the transfer started
+    pub elapsed: Duration,
+    /// Average throughput since the transfer started, in bytes per second
+    pub rate: u64,
+    /// Estimated time until the receiver has every chunk, once there is enough
+    /// information to calculate it
+    pub eta: Option<Duration>,
+}
+
+impl Progress {
+    /// Number of chunks which the receiver has
+    pub fn chunks_done(&self) -> u32 {
+        self.num_chunks.saturating_sub(self.remaining)
+    }
+}
+
+// Running totals for the transfer which is currently in progress
+pub(crate) struct Tracker {
+    pub hash: String,
+    pub num_chunks: u32,
+    pub remaining: u32,
+    pub chunks_sent: u32,
+    pub chunks_received: u32,
+    pub nak_rounds: u32,
+    pub bytes: u64,
+    start: Instant,
+}
+
+impl Tracker {
+    pub fn new(hash: &str, num_chunks: u32) -> Self {
+        Tracker {
+            hash: hash.to_owned(),
+            num_chunks,
+            remaining: num_chunks,
+            chunks_sent: 0,
+            chunks_received: 0,
+            nak_rounds: 0,
+            bytes: 0,
+            start: Instant::now(),
+        }
+    }
+
+    // Record the chunks which a NAK says are missing
+    pub fn nak(&mut self, missing: u32) {
+        self.nak_rounds += 1;
+        self.remaining = missing;
+    }
+
+    pub fn snapshot(&self) -> Progress {
+        let elapsed = self.start.elapsed();
+        let millis = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_millis());
+        let rate = (self.bytes * 1000).checked_div(millis).unwrap_or(0);
+
+        // Assume the remaining chunks are the same size as the ones handled so far
+        let handled = u64::from(self.chunks_sent + self.chunks_received);
+        let eta = if self.remaining == 0 {
+            Some(Duration::from_secs(0))
+        } else if rate == 0 || handled == 0 {
+            None
+        } else {
+            let remaining_bytes = u64::from(self.remaining) * self.bytes / handled;
+            Some(Duration::from_millis(remaining_bytes * 1000 / rate))
+        };
+
+        Progress {
+            hash: self.hash.clone(),
+            num_chunks: self.num_chunks,
+            remaining: self.remaining,
+            chunks_sent: self.chunks_sent,
+            chunks_received: self.chunks_received,
+            nak_rounds: self.nak_rounds,
+            bytes: self.bytes,
+            elapsed,
+            rate,
+            eta,
+        }
+    }
+}
@@ -16,19 +16,42 @@
 
 //! File transfer protocol module
 
+use super::fec;
+use super::filesystem::{self, FileInfo};
+use super::manifest::{self, ManifestEntry};
 use super::messages;
 use super::parsers;
 use super::storage;
+use super::Compression;
 use super::Message;
-use cbor_protocol::Protocol as CborProtocol;
+use super::Parity;
+use super::Progress;
+use super::TransactionInfo;
+use cbor_protocol::{IntoTransport, Protocol as CborProtocol};
+use channel_protocol::Capabilities;
 use error::ProtocolError;
+use progress::{ProgressCallback, Tracker};
 use rand::{self, Rng};
+use sandbox;
 use serde_cbor::Value;
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::VecDeque;
+use std::fs;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::str;
-use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use task::{Task, TaskOutput};
+
+// Percentage of the previously sent chunks which may be reported missing
+// before the sending window is reduced
+const LOSS_THRESHOLD: usize = 10;
+
+// Shortest time a receiver waits after the last chunk arrived before deciding the
+// sender has paused, and telling it which chunks are still missing
+const FEEDBACK_DELAY: Duration = Duration::from_millis(100);
 
 /// Configuration data for Protocol
 #[derive(Clone)]
@@ -38,6 +61,32 @@ pub struct ProtocolConfig {
     // How many times do we read and timeout
     // while in the Hold state before stopping
     hold_count: u16,
+    // Maximum number of chunks to send in response to a single NAK.
+    // Zero means there is no limit
+    window_size: u32,
+    // Maximum transmission rate, in bytes per second.
+    // Zero means there is no limit
+    max_rate: u32,
+    // Codec used to compress the chunks of files we send
+    compression: Compression,
+    // Whether files we send are read on demand rather than copied into storage
+    streaming: bool,
+    // Number of data chunks in each block which parity chunks are generated for
+    fec_block: u32,
+    // Number of parity chunks sent after each block.
+    // Zero means forward error correction is disabled
+    fec_parity: u32,
+    // Maximum total size of temporary storage, in bytes.
+    // Zero means there is no limit
+    storage_limit: u64,
+    // Directories which remote requests may read from. Empty means anywhere
+    read_roots: Vec<PathBuf>,
+    // Directories which remote requests may write to. Empty means anywhere
+    write_roots: Vec<PathBuf>,
+    // Key for signing and checking messages, along with the replay window
+    auth: Option<(Vec<u8>, Duration)>,
+    // How long to wait for the reply to a file system request before asking again
+    request_timeout: Duration,
 }
 
 impl ProtocolConfig {
@@ -47,15 +96,281 @@ impl ProtocolConfig {
             storage_prefix: storage_prefix.unwrap_or("file-storage".to_owned()),
             chunk_size,
             hold_count,
+            window_size: 0,
+            max_rate: 0,
+            compression: Compression::None,
+            streaming: false,
+            fec_block: 0,
+            fec_parity: 0,
+            storage_limit: 0,
+            read_roots: vec![],
+            write_roots: vec![],
+            auth: None,
+            request_timeout: Duration::from_secs(2),
         }
     }
+
+    /// The directory used for temporary storage
+    pub fn storage_prefix(&self) -> &str {
+        &self.storage_prefix
+    }
+
+    /// Limit the number of chunks which will be sent in response to a single NAK
+    ///
+    /// The window starts at this size and then adapts to the link: it is halved when
+    /// too many of the previously sent chunks are reported missing and grows back
+    /// towards this size when none are. A value of zero disables windowing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5).with_window_size(16);
+    /// ```
+    ///
+    pub fn with_window_size(mut self, window_size: u32) -> Self {
+        self.window_size = window_size;
+        self
+    }
+
+    /// Limit the rate at which chunk data is transmitted, in bytes per second
+    ///
+    /// A value of zero disables rate limiting.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5).with_max_rate(9600);
+    /// ```
+    ///
+    pub fn with_max_rate(mut self, max_rate: u32) -> Self {
+        self.max_rate = max_rate;
+        self
+    }
+
+    /// Compress file chunks with the given codec
+    ///
+    /// Files which we upload are compressed with this codec, and it is requested
+    /// from the remote target for files which we download.
+    /// If the remote target doesn't support the codec, downloaded files are sent uncompressed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5).with_compression(Compression::Deflate);
+    /// ```
+    ///
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Read the chunks of files we send straight from the original file
+    ///
+    /// Files are normally copied into temporary storage before they are sent.
+    /// When streaming, the file is only read to calculate its hash, and each chunk is
+    /// then read from the file when it is sent. This avoids needing space for a second
+    /// copy of the file, but the file must not be changed until the transfer has completed.
+    /// Changes are detected, and cause the transfer to fail.
+    ///
+    /// Compressed files are always copied into storage.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5).with_streaming(true);
+    /// ```
+    ///
+    pub fn with_streaming(mut self, streaming: bool) -> Self {
+        self.streaming = streaming;
+        self
+    }
+
+    /// Follow each block of data chunks we send with parity chunks
+    ///
+    /// The receiver can use the parity chunks to rebuild up to `parity` lost chunks in each
+    /// block of `block_size` chunks without having to request them again.
+    /// This costs extra bandwidth, but saves NAK round trips on lossy links.
+    /// Both values are limited to 128. A value of zero for either disables forward
+    /// error correction.
+    ///
+    /// The remote target must also support parity chunks.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5).with_fec(16, 2);
+    /// ```
+    ///
+    pub fn with_fec(mut self, block_size: u32, parity: u32) -> Self {
+        self.fec_block = cmp::min(block_size, fec::MAX_CHUNKS);
+        self.fec_parity = if block_size == 0 {
+            0
+        } else {
+            cmp::min(parity, fec::MAX_CHUNKS)
+        };
+        self
+    }
+
+    /// Limit the total size of temporary storage, in bytes
+    ///
+    /// Requests to send or receive a file are rejected if its chunks would take
+    /// temporary storage over this size. A value of zero disables the limit.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5).with_storage_limit(64 * 1024 * 1024);
+    /// ```
+    ///
+    pub fn with_storage_limit(mut self, max_bytes: u64) -> Self {
+        self.storage_limit = max_bytes;
+        self
+    }
+
+    /// Only let remote requests read files beneath the given directories
+    ///
+    /// Applies to import, directory import, list and stat requests.
+    /// Requests for anything else are answered with a failure message.
+    /// No directories means any file can be read
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5)
+    ///     .with_read_roots(&["/home/kubos".to_owned(), "/var/log".to_owned()]);
+    /// ```
+    ///
+    pub fn with_read_roots(mut self, roots: &[String]) -> Self {
+        self.read_roots = roots.iter().map(PathBuf::from).collect();
+        self
+    }
+
+    /// Only let remote requests write files beneath the given directories
+    ///
+    /// Applies to export, directory export, delete, mkdir and rename requests.
+    /// Requests for anything else are answered with a failure message.
+    /// No directories means any file can be written
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5)
+    ///     .with_write_roots(&["/home/kubos/uploads".to_owned()]);
+    /// ```
+    ///
+    pub fn with_write_roots(mut self, roots: &[String]) -> Self {
+        self.write_roots = roots.iter().map(PathBuf::from).collect();
+        self
+    }
+
+    /// Sign all sent messages, and only accept received messages which are signed
+    ///
+    /// Both sides of a transaction must use the same key. Messages which were sent
+    /// further than `window` away from the current time, or which have already been
+    /// received, are rejected
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    /// use std::time::Duration;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5)
+    ///     .with_auth(b"shared secret", Duration::from_secs(30));
+    /// ```
+    ///
+    pub fn with_auth(mut self, key: &[u8], window: Duration) -> Self {
+        self.auth = Some((key.to_vec(), window));
+        self
+    }
+
+    /// Wait this long for the reply to a file system or transaction management request
+    ///
+    /// If no reply arrives, the request is sent again, up to `hold_count` more times,
+    /// before giving up. Defaults to two seconds
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    /// use std::time::Duration;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5)
+    ///     .with_request_timeout(Duration::from_secs(10));
+    /// ```
+    ///
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = timeout;
+        self
+    }
 }
 
+/// Version of the file protocol spoken by this library
+pub const PROTOCOL_VERSION: u32 = 1;
+
 /// File protocol information structure
 pub struct Protocol {
-    cbor_proto: CborProtocol,
+    cbor_proto: Rc<CborProtocol>,
     remote_addr: Cell<SocketAddr>,
     config: ProtocolConfig,
+    // What the remote target supports, once it has told us
+    remote_capabilities: RefCell<Option<Capabilities>>,
+    // Current number of chunks we're allowed to send in response to a NAK
+    window: Cell<u32>,
+    // Chunks sent in response to the previous NAK. Used to judge link quality
+    last_sent: RefCell<Vec<u32>>,
+    // When the last chunk arrived
+    last_chunk: Cell<Option<Instant>>,
+    // Whether chunks have arrived since we last told the sender what's missing
+    feedback_due: Cell<bool>,
+    // Typical time between chunks arriving, to tell a pause in sending from a slow link
+    chunk_gap: Cell<Duration>,
+    // Running totals for the transfer currently in progress
+    progress: RefCell<Option<Tracker>>,
+    // Bytes the file being received may still store before storage is measured again
+    reserved: Cell<u64>,
+    // Chunks still to be sent in answer to the last NAK
+    outgoing: RefCell<Option<Outgoing>>,
+    // Called whenever the transfer makes progress
+    on_progress: Option<ProgressCallback>,
+    // Whether slow storage work is left for the caller to run, rather than done straight away
+    background_tasks: bool,
+    // Storage work which the transaction is waiting for the caller to run
+    task: RefCell<Option<Task>>,
+}
+
+// The chunks of a file which are being sent, and how far through them we've got
+struct Outgoing {
+    channel_id: u32,
+    hash: String,
+    // The chunk ranges the receiver asked for
+    requested: Vec<(u32, u32)>,
+    // Chunks which haven't been sent yet
+    chunks: VecDeque<u32>,
+    // Parity chunks of the last block sent, which haven't been sent yet
+    parity: VecDeque<Vec<u8>>,
+    num_chunks: u32,
+    start: Instant,
+    messages_sent: u32,
+    bytes_sent: u64,
+    // When the next chunk can be sent
+    due: Instant,
 }
 
 /// Current state of the file protocol transaction
@@ -83,6 +398,8 @@ pub enum State {
         path: String,
         /// File mode
         mode: Option<u32>,
+        /// Whether the file is a directory manifest whose tree should be recreated at `path`
+        directory: bool,
     },
     /// All file chunks have been received
     ReceivingDone {
@@ -94,6 +411,28 @@ pub enum State {
         path: String,
         /// File mode
         mode: Option<u32>,
+        /// Whether the file is a directory manifest whose tree should be recreated at `path`
+        directory: bool,
+    },
+    /// Waiting for a requested file to be set up for transmission
+    Preparing {
+        /// Transaction identifier
+        channel_id: u32,
+        /// Codec the chunks will be compressed with
+        compression: Compression,
+    },
+    /// Waiting for a received file to be put back together at its destination
+    Finalizing {
+        /// Transaction identifier
+        channel_id: u32,
+        /// File hash
+        hash: String,
+        /// Destination file path
+        path: String,
+        /// File mode
+        mode: Option<u32>,
+        /// Whether the file is a directory manifest whose tree should be recreated at `path`
+        directory: bool,
     },
     /// Currenty transmitting a file
     Transmitting,
@@ -104,12 +443,14 @@ pub enum State {
 }
 
 impl Protocol {
-    /// Create a new file protocol instance using an automatically assigned UDP socket
+    /// Create a new file protocol instance
     ///
     /// # Arguments
     ///
-    /// * host_ip - The local IP address
-    /// * remote_addr - The remote IP and port to communicate with
+    /// * transport - The link to communicate over, or the local IP address to bind
+    ///   an automatically assigned UDP socket to
+    /// * remote_addr - The remote IP and port to communicate with. Ignored by links
+    ///   which only connect two points, such as a serial line
     /// * prefix - Temporary storage directory prefix
     ///
     /// # Errors
@@ -125,19 +466,262 @@ impl Protocol {
     /// let f_protocol = FileProtocol::new("0.0.0.0", "192.168.0.1:7000", config);
     /// ```
     ///
-    pub fn new(host_ip: &str, remote_addr: &str, config: ProtocolConfig) -> Self {
+    /// ```no_run
+    /// extern crate cbor_protocol;
+    /// extern crate file_protocol;
+    ///
+    /// use cbor_protocol::UnixTransport;
+    /// use file_protocol::*;
+    ///
+    /// # fn main() {
+    /// let transport = UnixTransport::bind("/tmp/file-client", "/tmp/file-service").unwrap();
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new(transport, "0.0.0.0:0", config);
+    /// # }
+    /// ```
+    ///
+    pub fn new<T: IntoTransport>(transport: T, remote_addr: &str, config: ProtocolConfig) -> Self {
         // Get a local UDP socket (Bind)
 
-        let c_protocol = CborProtocol::new(format!("{}:0", host_ip), config.chunk_size);
+        let mut c_protocol = CborProtocol::from_transport(transport, config.chunk_size);
+        if let Some((ref key, window)) = config.auth {
+            c_protocol = c_protocol.with_auth(key, window);
+        }
+
+        Self::new_shared(Rc::new(c_protocol), remote_addr, config)
+    }
 
+    /// Create a new file protocol instance which uses an existing UDP socket
+    ///
+    /// Lets one socket carry many transactions at once. Nothing should be received
+    /// through the instance, since messages for the other transactions would arrive too.
+    /// Messages are signed according to how the socket was set up, rather than the config
+    ///
+    /// # Arguments
+    ///
+    /// * socket - The CBOR protocol instance to send messages with
+    /// * remote_addr - The remote IP and port to communicate with
+    /// * config - File protocol configuration
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will panic
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// extern crate cbor_protocol;
+    /// extern crate file_protocol;
+    ///
+    /// use file_protocol::*;
+    /// use std::rc::Rc;
+    ///
+    /// let socket = Rc::new(cbor_protocol::Protocol::new("0.0.0.0:0".to_owned(), 4096));
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new_shared(socket.clone(), "192.168.0.1:7000", config);
+    /// ```
+    ///
+    pub fn new_shared(socket: Rc<CborProtocol>, remote_addr: &str, config: ProtocolConfig) -> Self {
         // Set up the full connection info
         Protocol {
-            cbor_proto: c_protocol,
+            cbor_proto: socket,
             remote_addr: Cell::new(remote_addr.parse::<SocketAddr>().unwrap()),
+            remote_capabilities: RefCell::new(None),
+            window: Cell::new(config.window_size),
+            last_sent: RefCell::new(vec![]),
+            last_chunk: Cell::new(None),
+            feedback_due: Cell::new(false),
+            chunk_gap: Cell::new(Duration::from_millis(0)),
+            progress: RefCell::new(None),
+            reserved: Cell::new(0),
+            outgoing: RefCell::new(None),
+            on_progress: None,
+            background_tasks: false,
+            task: RefCell::new(None),
             config,
         }
     }
 
+    /// Report the progress of file transfers
+    ///
+    /// The callback is given a snapshot of the current transfer each time chunks are
+    /// sent or received, and each time a NAK or ACK is sent or received
+    ///
+    /// # Arguments
+    ///
+    /// * callback - Function to call with each progress update
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "192.168.0.1:7000", config)
+    ///     .with_progress(|progress| {
+    ///         println!("{} of {} chunks", progress.chunks_done(), progress.num_chunks)
+    ///     });
+    /// ```
+    ///
+    pub fn with_progress<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&Progress) + 'static,
+    {
+        self.on_progress = Some(Box::new(callback));
+        self
+    }
+
+    /// Leave slow storage work to the caller, rather than doing it straight away
+    ///
+    /// Preparing a file to be sent and putting a received file back together can take
+    /// a long time for large files. With this set, the transaction moves into the
+    /// `Preparing` or `Finalizing` state instead, and the work is collected with
+    /// `take_task`. Once it has been run, its result is handed back with `finish_task`.
+    /// This lets many transactions share one thread without a large file holding up the rest
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol =
+    ///     FileProtocol::new("0.0.0.0", "192.168.0.1:7000", config).with_background_tasks();
+    /// ```
+    ///
+    pub fn with_background_tasks(mut self) -> Self {
+        self.background_tasks = true;
+        self
+    }
+
+    /// Collect the storage work which the transaction is waiting on, if there is any
+    ///
+    /// Only used with `with_background_tasks`
+    pub fn take_task(&self) -> Option<Task> {
+        self.task.borrow_mut().take()
+    }
+
+    /// Carry on with a transaction once the task it was waiting on has been run
+    ///
+    /// Returns the new transaction state
+    ///
+    /// # Arguments
+    ///
+    /// * result - What the task produced
+    /// * state - Current transaction state
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    /// use std::thread;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol =
+    ///     FileProtocol::new("0.0.0.0", "192.168.0.1:7000", config).with_background_tasks();
+    ///
+    /// let mut state = State::Transmitting;
+    /// if let Some(task) = f_protocol.take_task() {
+    ///     let result = thread::spawn(move || task.run()).join().unwrap();
+    ///     state = f_protocol.finish_task(result, state).unwrap();
+    /// }
+    /// ```
+    ///
+    pub fn finish_task(
+        &self,
+        result: Result<TaskOutput, ProtocolError>,
+        state: State,
+    ) -> Result<State, ProtocolError> {
+        match state {
+            State::Preparing {
+                channel_id,
+                compression,
+            } => match result {
+                Ok(TaskOutput::Prepared {
+                    hash,
+                    num_chunks,
+                    mode,
+                }) => {
+                    // It worked, let the requester know we're ready to send
+                    self.send(messages::import_setup_success(
+                        channel_id,
+                        &hash,
+                        num_chunks,
+                        mode,
+                        compression,
+                    )?)?;
+                    Ok(State::Transmitting)
+                }
+                Ok(output) => Err(ProtocolError::StorageParseError(format!(
+                    "Unexpected result of preparing file: {:?}",
+                    output
+                ))),
+                Err(error) => {
+                    // It failed. Let the requester know that we can't transmit
+                    // the file they want.
+                    self.send(messages::operation_failure(
+                        channel_id,
+                        &format!("{}", error),
+                    )?)?;
+                    Ok(State::Done)
+                }
+            },
+            State::Finalizing {
+                channel_id,
+                hash,
+                path,
+                mode,
+                directory,
+            } => match result {
+                Ok(_) => {
+                    self.send(messages::operation_success(channel_id)?)?;
+                    Ok(State::Done)
+                }
+                Err(ProtocolError::CorruptChunks(chunks)) => {
+                    warn!("Re-requesting corrupt chunks of {}: {:?}", hash, chunks);
+                    // The corrupt chunks have been deleted, so they now show up as missing
+                    let (_, missing) =
+                        storage::validate_file(&self.config.storage_prefix, &hash, None)?;
+                    self.send(messages::nak(channel_id, &hash, &missing)?)?;
+                    self.track(&hash, |tracker| tracker.nak(count_chunks(&missing)));
+                    Ok(State::Holding {
+                        count: 0,
+                        prev_state: Box::new(State::Receiving {
+                            channel_id,
+                            hash,
+                            path,
+                            mode,
+                            directory,
+                        }),
+                    })
+                }
+                Err(e) => {
+                    warn!("Failed to finalize file {} as {}: {}", hash, path, e);
+                    self.send(messages::operation_failure(channel_id, &format!("{}", e))?)?;
+                    Err(e)
+                }
+            },
+            // Nothing was waiting on the task
+            state => Ok(state),
+        }
+    }
+
+    // Run a slow piece of storage work, or leave it for the caller to run if it's
+    // asked to. `state` is the state to wait for the result in
+    fn start_task(&self, task: Task, state: State) -> Result<State, ProtocolError> {
+        if self.background_tasks {
+            *self.task.borrow_mut() = Some(task);
+            Ok(state)
+        } else {
+            self.finish_task(task.run(), state)
+        }
+    }
+
     /// Send CBOR packet to the destination port
     ///
     /// # Arguments
@@ -169,6 +753,31 @@ impl Protocol {
         Ok(())
     }
 
+    /// Ask the remote end to hold off sending messages until `resume_remote` is called
+    ///
+    /// Useful when we're receiving chunks faster than we can store them.
+    /// Messages sent by the remote end in the meantime are queued on its side
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    pub fn pause_remote(&self) -> Result<(), ProtocolError> {
+        self.cbor_proto.send_pause(self.remote_addr.get())?;
+        Ok(())
+    }
+
+    /// Let the remote end know it can carry on sending messages after a `pause_remote`
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    pub fn resume_remote(&self) -> Result<(), ProtocolError> {
+        self.cbor_proto.send_resume(self.remote_addr.get())?;
+        Ok(())
+    }
+
     /// Receive a file protocol message
     ///
     /// # Arguments
@@ -203,11 +812,25 @@ impl Protocol {
     /// ```
     ///
     pub fn recv(&self, timeout: Option<Duration>) -> Result<Value, ProtocolError> {
-        match timeout {
-            Some(value) => Ok(self.cbor_proto.recv_message_timeout(value)?),
-            None => Ok(self.cbor_proto.recv_message()?),
-        }
-    }
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            let result = match deadline {
+                Some(deadline) => self.cbor_proto.recv_message_timeout(cmp::max(
+                    deadline.saturating_duration_since(Instant::now()),
+                    Duration::from_millis(1),
+                )),
+                None => self.cbor_proto.recv_message(),
+            };
+
+            match result {
+                // Flow control is taken care of by the CBOR protocol,
+                // so keep waiting for a real message
+                Err(cbor_protocol::ProtocolError::Paused)
+                | Err(cbor_protocol::ProtocolError::Resumed) => continue,
+                result => return Ok(result?),
+            }
+        }
+    }
 
     /// Generates a new random channel ID for use when initiating a
     /// file transfer.
@@ -266,7 +889,19 @@ impl Protocol {
         hash: &str,
         num_chunks: u32,
     ) -> Result<(), ProtocolError> {
-        self.send(messages::metadata(channel_id, &hash, num_chunks)?)
+        // Let the receiver know how the chunks we'll be sending were stored
+        let compression = storage::load_compression(&self.config.storage_prefix, hash)?;
+
+        // Make sure the receiver will be able to make sense of them
+        self.check_chunk_size()?;
+        if compression != Compression::None {
+            self.require("compression")?;
+        }
+        if self.config.fec_parity > 0 {
+            self.require("fec")?;
+        }
+
+        self.send(messages::metadata(channel_id, hash, num_chunks, compression)?)
     }
 
     /// Request remote target to receive file from host
@@ -337,17 +972,27 @@ impl Protocol {
     /// ```
     ///
     pub fn send_import(&self, channel_id: u32, source_path: &str) -> Result<(), ProtocolError> {
-        self.send(messages::import_request(channel_id, source_path)?)?;
+        self.check_import(0, None)?;
+        self.send(messages::import_request(
+            channel_id,
+            source_path,
+            self.config.compression,
+            0,
+            None,
+        )?)?;
         Ok(())
     }
 
-    /// Prepare a file for transfer
+    /// Request part of a file from a remote target
     ///
-    /// Imports the file into temporary storage and calculates the BLAKE2s hash
+    /// The received file will contain only the requested byte range
     ///
     /// # Arguments
     ///
-    /// * source_path - File to initialize for transfer
+    /// * source_path - File remote target should send
+    /// * offset - Offset of the first byte to send
+    /// * length - Maximum number of bytes to send.
+    ///   If `None`, everything up to the end of the file is sent
     ///
     /// # Errors
     ///
@@ -360,68 +1005,1264 @@ impl Protocol {
     ///
     /// let config = FileProtocolConfig::new(None, 4096, 5);
     /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    /// let channel_id = f_protocol.generate_channel().unwrap();
     ///
-    /// # ::std::fs::File::create("client.txt").unwrap();
-    ///
-    /// let (_hash, _num_chunks, _mode) = f_protocol.initialize_file("client.txt").unwrap();
+    /// f_protocol.send_import_range(channel_id, "service.log", 1024, Some(512));
     /// ```
     ///
-    pub fn initialize_file(&self, source_path: &str) -> Result<(String, u32, u32), ProtocolError> {
-        storage::initialize_file(
-            &self.config.storage_prefix,
+    pub fn send_import_range(
+        &self,
+        channel_id: u32,
+        source_path: &str,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<(), ProtocolError> {
+        self.check_import(offset, length)?;
+        self.send(messages::import_request(
+            channel_id,
             source_path,
-            self.config.chunk_size,
-        )
+            self.config.compression,
+            offset,
+            length,
+        )?)?;
+        Ok(())
     }
 
-    // Verify the integrity of received file data and then transfer into the requested permanent file location.
-    // Notify the connection peer of the results
-    //
-    // Verifies:
-    //     a) All of the chunks of a file have been received
-    //     b) That the calculated hash of said chunks matches the expected hash
-    //
-    fn finalize_file(
+    /// Request remote target to receive a directory tree from host
+    ///
+    /// The directory manifest identified by `hash` is transferred first.
+    /// Once it has been received, the remote target will create the directories
+    /// it describes beneath `target_path`.
+    ///
+    /// # Arguments
+    ///
+    /// * channel_id - Channel ID used for transaction
+    /// * hash - BLAKE2s hash of the directory manifest
+    /// * target_path - Destination directory path
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    ///
+    /// # ::std::fs::create_dir_all("client_dir").unwrap();
+    ///
+    /// let (hash, _num_chunks, _entries) = f_protocol.initialize_directory("client_dir").unwrap();
+    /// let channel_id = f_protocol.generate_channel().unwrap();
+    /// f_protocol.send_export_dir(channel_id, &hash, "final/dir");
+    /// ```
+    ///
+    pub fn send_export_dir(
         &self,
         channel_id: u32,
         hash: &str,
         target_path: &str,
-        mode: Option<u32>,
     ) -> Result<(), ProtocolError> {
-        match storage::finalize_file(&self.config.storage_prefix, hash, target_path, mode) {
-            Ok(_) => {
-                self.send(messages::operation_success(channel_id)?)?;
-                return Ok(());
+        self.send(messages::export_dir_request(channel_id, hash, target_path)?)?;
+        Ok(())
+    }
+
+    /// Request the manifest of a directory tree from a remote target
+    ///
+    /// # Arguments
+    ///
+    /// * channel_id - Channel ID used for transaction
+    /// * source_path - Directory remote target should describe
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    /// let channel_id = f_protocol.generate_channel().unwrap();
+    ///
+    /// f_protocol.send_import_dir(channel_id, "service_dir");
+    /// ```
+    ///
+    pub fn send_import_dir(&self, channel_id: u32, source_path: &str) -> Result<(), ProtocolError> {
+        self.send(messages::import_dir_request(channel_id, source_path)?)?;
+        Ok(())
+    }
+
+    /// Ask a remote target whether it already holds all the chunks of a file
+    ///
+    /// Chunks are kept in the remote target's temporary storage after a file has been
+    /// finalized, so this also detects files which were previously uploaded to a different path.
+    /// If it returns true, the file can be sent with just an export request.
+    /// The remote target will reassemble it from its own chunks.
+    ///
+    /// # Arguments
+    ///
+    /// * hash - BLAKE2s hash of file
+    /// * timeout - Maximum time to wait for a reply. Remote targets which don't
+    ///   reply in time are assumed not to have the file
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    /// use std::time::Duration;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    ///
+    /// let held = f_protocol
+    ///     .remote_has_file("852f1630f4ed2c0bc934d71ada618974", Duration::from_secs(2))
+    ///     .unwrap();
+    /// ```
+    ///
+    pub fn remote_has_file(&self, hash: &str, timeout: Duration) -> Result<bool, ProtocolError> {
+        let channel_id = self.generate_channel()?;
+        self.send(messages::sync(channel_id, hash)?)?;
+
+        let reply = match self.recv(Some(timeout)) {
+            Ok(reply) => reply,
+            Err(ProtocolError::ReceiveTimeout) => return Ok(false),
+            Err(err) => return Err(err),
+        };
+
+        match parsers::parse_message(reply)? {
+            Message::ACK(_, ack_hash) => {
+                info!("<- {{ {}, true }}", ack_hash);
+                Ok(ack_hash == hash)
             }
-            Err(e) => {
-                self.send(messages::operation_failure(channel_id, &format!("{}", e))?)?;
-                return Err(e);
+            Message::NAK(_, nak_hash, _) => {
+                info!("<- {{ {}, false }}", nak_hash);
+                Ok(false)
             }
+            other => Err(unexpected_reply(&other)),
+        }
+    }
+
+    /// Request that a remote target resume an interrupted transaction
+    ///
+    /// The remote target will reply with the information needed to continue
+    /// the transfer of the file with the given hash from where it left off
+    ///
+    /// # Arguments
+    ///
+    /// * channel_id - Channel ID used for transaction
+    /// * hash - BLAKE2s hash of file
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    /// let channel_id = f_protocol.generate_channel().unwrap();
+    ///
+    /// f_protocol.send_resume(channel_id, "852f1630f4ed2c0bc934d71ada618974");
+    /// ```
+    ///
+    pub fn send_resume(&self, channel_id: u32, hash: &str) -> Result<(), ProtocolError> {
+        self.send(messages::resume_request(channel_id, hash)?)?;
+        Ok(())
+    }
+
+    /// Load the state of an interrupted transaction from temporary storage
+    ///
+    /// Returns the state which should be used to continue the transaction once
+    /// a resume request has been sent to the remote target
+    ///
+    /// # Arguments
+    ///
+    /// * hash - BLAKE2s hash of the file being transferred
+    ///
+    /// # Errors
+    ///
+    /// If no saved transaction exists for the given hash, or it cannot be read,
+    /// an error will be returned
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    /// use std::time::Duration;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    /// let hash = "852f1630f4ed2c0bc934d71ada618974";
+    ///
+    /// let state = f_protocol.load_state(hash).unwrap();
+    /// let channel_id = f_protocol.generate_channel().unwrap();
+    /// f_protocol.send_resume(channel_id, hash).unwrap();
+    ///
+    /// let state = match state {
+    ///     State::StartReceive { .. } => {
+    ///         let reply = f_protocol.recv(None).unwrap();
+    ///         f_protocol.process_message(reply, state).unwrap()
+    ///     }
+    ///     other => other,
+    /// };
+    ///
+    /// f_protocol.message_engine(|d| f_protocol.recv(Some(d)), Duration::from_secs(2), state);
+    /// ```
+    ///
+    pub fn load_state(&self, hash: &str) -> Result<State, ProtocolError> {
+        match storage::load_transaction(&self.config.storage_prefix, hash)? {
+            storage::Transaction::Receiving { path, .. } => Ok(State::StartReceive { path }),
+            storage::Transaction::Transmitting { .. } => Ok(State::Transmitting),
+        }
+    }
+
+    /// List the contents of a directory on the remote target
+    ///
+    /// Returns the name, size, modification time and mode of each entry in the directory
+    ///
+    /// # Arguments
+    ///
+    /// * path - Remote directory to list
+    ///
+    /// # Errors
+    ///
+    /// If the remote target is unable to list the directory, or this function encounters
+    /// any other errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    ///
+    /// for entry in f_protocol.remote_list("/home/system/logs").unwrap() {
+    ///     println!("{} {}", entry.name, entry.size);
+    /// }
+    /// ```
+    ///
+    pub fn remote_list(&self, path: &str) -> Result<Vec<FileInfo>, ProtocolError> {
+        let channel_id = self.generate_channel()?;
+        match self.request(channel_id, messages::list_request(channel_id, path)?)? {
+            Message::SuccessList(_, entries) => Ok(entries),
+            other => Err(unexpected_reply(&other)),
+        }
+    }
+
+    /// Get information about a file or directory on the remote target
+    ///
+    /// # Arguments
+    ///
+    /// * path - Remote file to look up
+    ///
+    /// # Errors
+    ///
+    /// If the remote target is unable to find the file, or this function encounters
+    /// any other errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    ///
+    /// let info = f_protocol.remote_stat("/home/system/logs/app.log").unwrap();
+    /// ```
+    ///
+    pub fn remote_stat(&self, path: &str) -> Result<FileInfo, ProtocolError> {
+        let channel_id = self.generate_channel()?;
+        match self.request(channel_id, messages::stat_request(channel_id, path)?)? {
+            Message::SuccessStat(_, info) => Ok(info),
+            other => Err(unexpected_reply(&other)),
+        }
+    }
+
+    /// Delete a file or directory on the remote target
+    ///
+    /// # Arguments
+    ///
+    /// * path - Remote file or directory to delete
+    /// * recursive - Whether to also delete the contents of a directory.
+    ///   If not set, directories must be empty
+    ///
+    /// # Errors
+    ///
+    /// If the remote target is unable to delete the file, or this function encounters
+    /// any other errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    ///
+    /// f_protocol.remote_delete("/home/system/logs/app.log", false).unwrap();
+    /// ```
+    ///
+    pub fn remote_delete(&self, path: &str, recursive: bool) -> Result<(), ProtocolError> {
+        let channel_id = self.generate_channel()?;
+        match self.request(channel_id, messages::delete_request(channel_id, path, recursive)?)? {
+            Message::SuccessReceive(_) => Ok(()),
+            other => Err(unexpected_reply(&other)),
+        }
+    }
+
+    /// Create a directory, and any missing parent directories, on the remote target
+    ///
+    /// # Arguments
+    ///
+    /// * path - Remote directory to create
+    ///
+    /// # Errors
+    ///
+    /// If the remote target is unable to create the directory, or this function encounters
+    /// any other errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    ///
+    /// f_protocol.remote_mkdir("/home/system/logs/archive").unwrap();
+    /// ```
+    ///
+    pub fn remote_mkdir(&self, path: &str) -> Result<(), ProtocolError> {
+        let channel_id = self.generate_channel()?;
+        match self.request(channel_id, messages::mkdir_request(channel_id, path)?)? {
+            Message::SuccessReceive(_) => Ok(()),
+            other => Err(unexpected_reply(&other)),
+        }
+    }
+
+    /// Move a file or directory on the remote target
+    ///
+    /// # Arguments
+    ///
+    /// * source_path - Remote file or directory to move
+    /// * target_path - New remote path. Must not already exist
+    ///
+    /// # Errors
+    ///
+    /// If the remote target is unable to move the file, or this function encounters
+    /// any other errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    ///
+    /// f_protocol
+    ///     .remote_rename("/home/system/logs/app.log", "/home/system/logs/archive/app.log")
+    ///     .unwrap();
+    /// ```
+    ///
+    pub fn remote_rename(&self, source_path: &str, target_path: &str) -> Result<(), ProtocolError> {
+        let channel_id = self.generate_channel()?;
+        match self.request(
+            channel_id,
+            messages::rename_request(channel_id, source_path, target_path)?,
+        )? {
+            Message::SuccessReceive(_) => Ok(()),
+            other => Err(unexpected_reply(&other)),
+        }
+    }
+
+    /// Get the list of transactions which the remote target is currently handling
+    ///
+    /// # Errors
+    ///
+    /// If the remote target doesn't support transaction management, or this function
+    /// encounters any other errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    ///
+    /// for transaction in f_protocol.remote_transactions().unwrap() {
+    ///     println!("{} {}", transaction.channel_id, transaction.path);
+    /// }
+    /// ```
+    ///
+    pub fn remote_transactions(&self) -> Result<Vec<TransactionInfo>, ProtocolError> {
+        let channel_id = self.generate_channel()?;
+        match self.request(channel_id, messages::transactions_request(channel_id)?)? {
+            Message::SuccessTransactions(_, transactions) => Ok(transactions),
+            other => Err(unexpected_reply(&other)),
+        }
+    }
+
+    /// Get the transactions which the remote target has most recently finished,
+    /// newest first
+    ///
+    /// # Arguments
+    ///
+    /// * count - Maximum number of transactions to get
+    ///
+    /// # Errors
+    ///
+    /// If the remote target doesn't support transaction management, or this function
+    /// encounters any other errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    ///
+    /// let failed = f_protocol
+    ///     .remote_history(10)
+    ///     .unwrap()
+    ///     .into_iter()
+    ///     .filter(|transaction| transaction.error.is_some());
+    /// ```
+    ///
+    pub fn remote_history(&self, count: u32) -> Result<Vec<TransactionInfo>, ProtocolError> {
+        let channel_id = self.generate_channel()?;
+        match self.request(channel_id, messages::history_request(channel_id, count)?)? {
+            Message::SuccessTransactions(_, transactions) => Ok(transactions),
+            other => Err(unexpected_reply(&other)),
+        }
+    }
+
+    /// Cancel a transaction which the remote target is currently handling
+    ///
+    /// The other side of the transaction is told that it has failed
+    ///
+    /// # Arguments
+    ///
+    /// * target_channel - Channel of the transaction to cancel
+    ///
+    /// # Errors
+    ///
+    /// If the remote target doesn't have an active transaction on the channel,
+    /// or this function encounters any other errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    ///
+    /// f_protocol.remote_cancel(1234).unwrap();
+    /// ```
+    ///
+    pub fn remote_cancel(&self, target_channel: u32) -> Result<(), ProtocolError> {
+        let channel_id = self.generate_channel()?;
+        match self.request(channel_id, messages::cancel_request(channel_id, target_channel)?)? {
+            Message::SuccessReceive(_) => Ok(()),
+            other => Err(unexpected_reply(&other)),
+        }
+    }
+
+    /// Reply to a request for a list of transactions
+    ///
+    /// # Arguments
+    ///
+    /// * channel_id - Channel the request was received on
+    /// * transactions - The requested transactions
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    pub fn send_transactions(
+        &self,
+        channel_id: u32,
+        transactions: &[TransactionInfo],
+    ) -> Result<(), ProtocolError> {
+        self.send(messages::transactions_success(channel_id, transactions)?)
+    }
+
+    /// Describe what this side of the protocol supports
+    ///
+    /// The optional features are:
+    ///
+    /// - `compression` - File chunks may be compressed
+    /// - `fec` - Parity chunks may be sent, so that lost chunks can be rebuilt
+    /// - `ranges` - Part of a file may be requested, rather than all of it
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    ///
+    /// assert!(f_protocol.capabilities().supports("ranges"));
+    /// ```
+    ///
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::new(PROTOCOL_VERSION, self.config.chunk_size as u32)
+            .with_feature("compression")
+            .with_feature("fec")
+            .with_feature("ranges")
+    }
+
+    /// Exchange hello messages with the remote target, to find out what it supports
+    ///
+    /// Once the remote target has replied, requests which need something it doesn't
+    /// support fail with `ProtocolError::Unsupported`, rather than being sent
+    ///
+    /// # Arguments
+    ///
+    /// * timeout - Maximum time to wait for a reply
+    ///
+    /// # Errors
+    ///
+    /// - Remote targets which are too old to know about hello messages don't reply,
+    ///   so if the timeout is reached, it will return `Err(ProtocolError::ReceiveTimeout)`
+    /// - If this function encounters any other errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use file_protocol::*;
+    /// use std::time::Duration;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    ///
+    /// let remote = f_protocol.hello(Duration::from_secs(2)).unwrap();
+    /// println!("Remote target speaks version {}", remote.version);
+    /// ```
+    ///
+    pub fn hello(&self, timeout: Duration) -> Result<Capabilities, ProtocolError> {
+        let channel_id = self.generate_channel()?;
+        self.send_hello(channel_id)?;
+
+        match parsers::parse_message(self.recv(Some(timeout))?)? {
+            Message::Hello(_, capabilities) => {
+                info!(
+                    "<- {{ {}, hello, {}, {:?}, {} }}",
+                    channel_id,
+                    capabilities.version,
+                    capabilities.features,
+                    capabilities.max_chunk_size
+                );
+                *self.remote_capabilities.borrow_mut() = Some(capabilities.clone());
+                Ok(capabilities)
+            }
+            Message::Failure(channel_id, error_message) => Err(ProtocolError::TransmissionError {
+                channel_id,
+                error_message,
+            }),
+            other => Err(unexpected_reply(&other)),
+        }
+    }
+
+    /// Tell the remote target what we support
+    ///
+    /// # Arguments
+    ///
+    /// * channel_id - Channel the remote target asked on
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    pub fn send_hello(&self, channel_id: u32) -> Result<(), ProtocolError> {
+        self.send(messages::hello(channel_id, &self.capabilities())?)
+    }
+
+    // Check that the remote target supports an optional feature.
+    // Anything goes if we don't know what it supports
+    fn require(&self, feature: &str) -> Result<(), ProtocolError> {
+        match *self.remote_capabilities.borrow() {
+            Some(ref remote) => Ok(remote.require(feature)?),
+            None => Ok(()),
+        }
+    }
+
+    // Check that the remote target can receive chunks of the size we use
+    fn check_chunk_size(&self) -> Result<(), ProtocolError> {
+        match *self.remote_capabilities.borrow() {
+            Some(ref remote) if self.config.chunk_size as u32 > remote.max_chunk_size => {
+                Err(ProtocolError::Unsupported(format!(
+                    "chunks larger than {} bytes",
+                    remote.max_chunk_size
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Check that the remote target can send us a file the way we'd like it
+    fn check_import(&self, offset: u64, length: Option<u64>) -> Result<(), ProtocolError> {
+        self.check_chunk_size()?;
+        if self.config.compression != Compression::None {
+            self.require("compression")?;
+        }
+        if offset > 0 || length.is_some() {
+            self.require("ranges")?;
+        }
+        Ok(())
+    }
+
+    // Send a file system request and wait for the remote target's reply.
+    // The request is sent again if no reply arrives in time, since either may have been lost.
+    // Failure replies are converted into errors
+    fn request(&self, channel_id: u32, message: Vec<u8>) -> Result<Message, ProtocolError> {
+        for _ in 0..=self.config.hold_count {
+            self.send(message.clone())?;
+
+            let deadline = Instant::now() + self.config.request_timeout;
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining == Duration::from_secs(0) {
+                    break;
+                }
+                let reply = match self.recv(Some(remaining)) {
+                    Ok(reply) => reply,
+                    Err(ProtocolError::ReceiveTimeout) => break,
+                    Err(e) => return Err(e),
+                };
+
+                // Late replies to earlier requests aren't the one we're waiting for
+                if parsers::parse_channel_id(&reply)? != channel_id {
+                    continue;
+                }
+
+                return match parsers::parse_message(reply)? {
+                    Message::Failure(channel_id, error_message) => {
+                        info!("<- {{ {}, false, {} }}", channel_id, error_message);
+                        Err(ProtocolError::TransmissionError {
+                            channel_id,
+                            error_message,
+                        })
+                    }
+                    reply => Ok(reply),
+                };
+            }
+        }
+
+        Err(ProtocolError::ReceiveTimeout)
+    }
+
+    /// Prepare a file for transfer
+    ///
+    /// Imports the file into temporary storage and calculates the BLAKE2s hash.
+    /// The chunks are compressed with the configured codec.
+    /// If streaming is enabled, the file is left where it is and only its hash and
+    /// chunk checksums are calculated
+    ///
+    /// # Arguments
+    ///
+    /// * source_path - File to initialize for transfer
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    ///
+    /// # ::std::fs::File::create("client.txt").unwrap();
+    ///
+    /// let (_hash, _num_chunks, _mode) = f_protocol.initialize_file("client.txt").unwrap();
+    /// ```
+    ///
+    pub fn initialize_file(&self, source_path: &str) -> Result<(String, u32, u32), ProtocolError> {
+        storage::initialize_file(
+            &self.config.storage_prefix,
+            source_path,
+            self.config.chunk_size,
+            self.config.compression,
+            self.config.streaming,
+        )
+    }
+
+    /// Prepare a directory for transfer
+    ///
+    /// Walks the directory tree, then imports the resulting manifest into temporary
+    /// storage and calculates its BLAKE2s hash.
+    /// The files within the directory are not imported; each should be transferred
+    /// individually once the manifest has been sent.
+    ///
+    /// Returns the manifest's hash and number of chunks, along with its entries
+    ///
+    /// # Arguments
+    ///
+    /// * source_path - Directory to initialize for transfer
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use file_protocol::*;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    ///
+    /// # ::std::fs::create_dir_all("client_dir").unwrap();
+    ///
+    /// let (_hash, _num_chunks, entries) = f_protocol.initialize_directory("client_dir").unwrap();
+    /// ```
+    ///
+    pub fn initialize_directory(
+        &self,
+        source_path: &str,
+    ) -> Result<(String, u32, Vec<ManifestEntry>), ProtocolError> {
+        let entries = manifest::create(source_path)?;
+        let (hash, num_chunks, _mode) = storage::initialize_manifest(
+            &self.config.storage_prefix,
+            &entries,
+            self.config.chunk_size,
+            self.config.compression,
+        )?;
+
+        Ok((hash, num_chunks, entries))
+    }
+
+    // Verify the integrity of received file data and then transfer into the requested permanent file location.
+    // Notify the connection peer of the results
+    //
+    // Verifies:
+    //     a) All of the chunks of a file have been received
+    //     b) That the calculated hash of said chunks matches the expected hash
+    //
+    // If the file is a directory manifest, the directory tree is created instead.
+    // Returns the state to continue in. If some of the chunks were corrupt, they're
+    // requested again and the file carries on being received
+    fn finish_receive(
+        &self,
+        channel_id: u32,
+        hash: &str,
+        path: &str,
+        mode: Option<u32>,
+        directory: bool,
+    ) -> Result<State, ProtocolError> {
+        self.start_task(
+            Task::Finalize {
+                prefix: self.config.storage_prefix.clone(),
+                hash: hash.to_owned(),
+                path: path.to_owned(),
+                mode,
+                directory,
+                write_roots: self.config.write_roots.clone(),
+            },
+            State::Finalizing {
+                channel_id,
+                hash: hash.to_owned(),
+                path: path.to_owned(),
+                mode,
+                directory,
+            },
+        )
+    }
+
+    /// Let the requester know whether an operation succeeded
+    ///
+    /// # Arguments
+    ///
+    /// * channel_id - Channel the operation was requested on
+    /// * result - Result of the operation. Errors are sent as failure messages
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    pub fn send_result(
+        &self,
+        channel_id: u32,
+        result: Result<(), ProtocolError>,
+    ) -> Result<(), ProtocolError> {
+        match result {
+            Ok(()) => self.send(messages::operation_success(channel_id)?),
+            Err(error) => self.send(messages::operation_failure(
+                channel_id,
+                &format!("{}", error),
+            )?),
+        }
+    }
+
+    // Make sure remote requests are allowed to read the path
+    fn check_read(&self, path: &str) -> Result<(), ProtocolError> {
+        sandbox::check(Path::new(path), &self.config.read_roots)
+    }
+
+    // Make sure remote requests are allowed to write beneath the path
+    fn check_write(&self, path: &str) -> Result<(), ProtocolError> {
+        sandbox::check(Path::new(path), &self.config.write_roots)
+    }
+
+    // Make sure remote requests are allowed to replace, remove or move whatever is at the path.
+    // The write roots themselves have to stay where they are
+    fn check_modify(&self, path: &str) -> Result<(), ProtocolError> {
+        sandbox::check_beneath(Path::new(path), &self.config.write_roots)
+    }
+
+    // Make sure there's room in temporary storage for a file which needs `needed` bytes.
+    // Anything already stored for the file counts towards what it needs
+    fn reserve_storage(&self, hash: Option<&str>, needed: u64) -> Result<(), ProtocolError> {
+        if self.config.storage_limit == 0 {
+            return Ok(());
+        }
+
+        let prefix = &self.config.storage_prefix;
+        let stored = hash
+            .map(|hash| storage::file_usage(prefix, hash))
+            .unwrap_or(0);
+        let needed = needed.saturating_sub(stored);
+        let available = self
+            .config
+            .storage_limit
+            .saturating_sub(storage::storage_usage(prefix)?);
+
+        if needed > available {
+            warn!(
+                "Rejecting transaction: {} bytes needed, {} bytes of storage available",
+                needed, available
+            );
+            return Err(ProtocolError::StorageFull { needed, available });
+        }
+
+        Ok(())
+    }
+
+    // Make sure there's room to receive all of a file's chunks
+    fn reserve_receive(&self, hash: &str) -> Result<(), ProtocolError> {
+        let prefix = &self.config.storage_prefix;
+        // If we haven't been told the file's size, every chunk is checked as it arrives instead
+        let needed = storage::load_meta(prefix, hash)
+            .map(|num_chunks| u64::from(num_chunks) * self.config.chunk_size as u64)
+            .unwrap_or(0);
+        self.reserve_storage(Some(hash), needed)?;
+        self.reserved
+            .set(needed.saturating_sub(storage::file_usage(prefix, hash)));
+        Ok(())
+    }
+
+    // Make sure there's room to store a received chunk. Chunks are taken out of the room
+    // reserved for the file, and storage is only measured again once that runs out
+    fn reserve_chunk(&self, size: u64) -> Result<(), ProtocolError> {
+        if self.config.storage_limit == 0 {
+            return Ok(());
+        }
+
+        let reserved = self.reserved.get();
+        if size <= reserved {
+            self.reserved.set(reserved - size);
+            return Ok(());
+        }
+
+        self.reserved.set(0);
+        self.reserve_storage(None, size)
+    }
+
+    // Make sure there's room to copy part of a file into storage, so it can be sent
+    fn reserve_transmit(
+        &self,
+        path: &str,
+        compression: Compression,
+        offset: u64,
+        length: Option<u64>,
+    ) -> Result<(), ProtocolError> {
+        // Streamed files are read from where they are
+        if self.config.streaming
+            && compression == Compression::None
+            && offset == 0
+            && length.is_none()
+        {
+            return Ok(());
+        }
+
+        let size = match fs::metadata(path) {
+            Ok(meta) => meta.len().saturating_sub(offset),
+            // Setting up the file will fail with a more useful error
+            Err(_) => return Ok(()),
+        };
+
+        self.reserve_storage(None, length.map_or(size, |length| cmp::min(length, size)))
+    }
+
+    // Record that we are receiving a file, then let the sender know which
+    // chunks we still need
+    fn start_receive(
+        &self,
+        channel_id: u32,
+        hash: &str,
+        path: &str,
+        mode: Option<u32>,
+        directory: bool,
+    ) -> Result<State, ProtocolError> {
+        let status = storage::validate_file(&self.config.storage_prefix, hash, None)?;
+
+        storage::store_transaction(
+            &self.config.storage_prefix,
+            hash,
+            &storage::Transaction::Receiving {
+                path: path.to_owned(),
+                mode,
+                directory,
+            },
+        )?;
+
+        // Chunks from any earlier transfer say nothing about how fast this one's will arrive
+        self.last_chunk.set(None);
+        self.feedback_due.set(false);
+        self.chunk_gap.set(Duration::from_millis(0));
+
+        match status {
+            (true, _) => {
+                // We've already got all the file data in temporary storage
+                self.send(messages::ack(channel_id, hash, None)?)?;
+                self.track(hash, |tracker| tracker.remaining = 0);
+
+                Ok(State::ReceivingDone {
+                    channel_id,
+                    hash: hash.to_owned(),
+                    path: path.to_owned(),
+                    mode,
+                    directory,
+                })
+            }
+            (false, chunks) => {
+                // We're missing some number of data chunks of the requrested file
+                self.send(messages::nak(channel_id, hash, &chunks)?)?;
+                self.track(hash, |tracker| tracker.nak(count_chunks(&chunks)));
+
+                Ok(State::Receiving {
+                    channel_id,
+                    hash: hash.to_owned(),
+                    path: path.to_owned(),
+                    mode,
+                    directory,
+                })
+            }
+        }
+    }
+
+    // Start sending the requested chunks of a file to the remote destination.
+    // No more than the current window of chunks will be sent, at no more than
+    // the configured maximum rate. The chunks are sent by `send_pending`
+    fn send_chunks(
+        &self,
+        channel_id: u32,
+        hash: &str,
+        chunks: &[(u32, u32)],
+    ) -> Result<(), ProtocolError> {
+        self.update_window(chunks);
+        self.last_sent.borrow_mut().clear();
+
+        let window = self.window.get() as usize;
+        let mut queue: VecDeque<u32> = chunks
+            .iter()
+            .flat_map(|&(first, last)| first..last)
+            .collect();
+        if window != 0 {
+            queue.truncate(window);
+        }
+
+        // Needed to find the end of the last block of chunks
+        let num_chunks = if self.config.fec_parity > 0 {
+            storage::load_meta(&self.config.storage_prefix, hash)?
+        } else {
+            0
+        };
+
+        // If we're asked for chunks part way through sending the last lot,
+        // carry on at the same rate rather than starting again straight away
+        let now = Instant::now();
+        let start = match *self.outgoing.borrow() {
+            Some(ref outgoing) => cmp::max(outgoing.due, now),
+            None => now,
+        };
+        *self.outgoing.borrow_mut() = Some(Outgoing {
+            channel_id,
+            hash: hash.to_owned(),
+            requested: chunks.to_vec(),
+            chunks: queue,
+            parity: VecDeque::new(),
+            num_chunks,
+            start,
+            messages_sent: 0,
+            bytes_sent: 0,
+            due: start,
+        });
+        Ok(())
+    }
+
+    /// When the next of the chunks being sent is due to go out, if there are any left to send
+    ///
+    /// Whoever is receiving messages for this transaction shouldn't wait any longer than this
+    /// before calling `send_pending`
+    pub fn send_deadline(&self) -> Option<Instant> {
+        self.outgoing.borrow().as_ref().map(|outgoing| outgoing.due)
+    }
+
+    /// Send whichever of the chunks being sent are due to go out
+    ///
+    /// A transfer's chunks are sent a few at a time, in between receiving messages,
+    /// so that the configured maximum rate can be kept to without waiting around
+    ///
+    /// # Errors
+    ///
+    /// If the file can't be read, the receiver is told that it isn't coming and an
+    /// error is returned
+    ///
+    pub fn send_pending(&self) -> Result<(), ProtocolError> {
+        loop {
+            let (channel_id, hash) = match *self.outgoing.borrow() {
+                Some(ref outgoing) if outgoing.due <= Instant::now() => {
+                    (outgoing.channel_id, outgoing.hash.clone())
+                }
+                _ => return Ok(()),
+            };
+
+            if let Err(error) = self.send_next() {
+                *self.outgoing.borrow_mut() = None;
+                // We can't finish sending the file, so there's no point in
+                // the receiver waiting for it
+                self.send(messages::operation_failure(
+                    channel_id,
+                    &format!("{}", error),
+                )?)?;
+                warn!("Failed to send {}: {}", hash, error);
+                return Err(error);
+            }
+        }
+    }
+
+    // Send the next chunk (or parity chunk) of the file being sent, and work out
+    // when the one after it is due
+    fn send_next(&self) -> Result<(), ProtocolError> {
+        let mut outgoing = match self.outgoing.borrow_mut().take() {
+            Some(outgoing) => outgoing,
+            None => return Ok(()),
+        };
+        let channel_id = outgoing.channel_id;
+
+        // A block's parity chunks go out as soon as all of its chunks have been sent
+        let (message, chunk) = match outgoing.parity.pop_front() {
+            Some(message) => (message, None),
+            None => match outgoing.chunks.pop_front() {
+                Some(chunk_index) => {
+                    let chunk = self.load_outgoing(&outgoing.hash, chunk_index)?;
+
+                    // Blocks which are only being partially resent don't get any parity chunks
+                    if self.config.fec_parity > 0 {
+                        let first_chunk = chunk_index - chunk_index % self.config.fec_block;
+                        let end =
+                            cmp::min(first_chunk + self.config.fec_block, outgoing.num_chunks);
+                        if chunk_index + 1 == end
+                            && requested(&outgoing.requested, first_chunk, end)
+                        {
+                            outgoing.parity = self
+                                .parity_messages(channel_id, &outgoing.hash, first_chunk, end)?
+                                .into();
+                        }
+                    }
+
+                    let message = messages::chunk(channel_id, &outgoing.hash, chunk_index, &chunk)?;
+                    (message, Some((chunk_index, chunk.len() as u64)))
+                }
+                None => return Ok(()),
+            },
+        };
+
+        let size = message.len() as u64;
+        // If the receiver has paused us for long enough to fill the queue,
+        // stop here. Whatever wasn't sent will be asked for again
+        if queue_full(self.send(message))? {
+            return Ok(());
+        }
+
+        if let Some((chunk_index, chunk_size)) = chunk {
+            self.last_sent.borrow_mut().push(chunk_index);
+            self.track(&outgoing.hash, |tracker| {
+                tracker.chunks_sent += 1;
+                tracker.bytes += chunk_size;
+                tracker.remaining = tracker.remaining.saturating_sub(1);
+            });
         }
+        outgoing.messages_sent += 1;
+        outgoing.bytes_sent += size;
+        self.pace(outgoing);
+        Ok(())
     }
 
-    // Send all requested chunks of a file to the remote destination
-    fn send_chunks(
+    // Load a chunk of the file being sent. The file is given up on if it can't be read
+    fn load_outgoing(&self, hash: &str, chunk_index: u32) -> Result<Vec<u8>, ProtocolError> {
+        storage::load_chunk(&self.config.storage_prefix, hash, chunk_index).or_else(|e| {
+            warn!("Failed to load chunk {}:{} : {}", hash, chunk_index, e);
+            storage::delete_file(&self.config.storage_prefix, hash)?;
+            Err(match e {
+                // Let the receiver know why the rest of the file isn't coming
+                ProtocolError::SourceChanged(_) => e,
+                _ => ProtocolError::CorruptFile(hash.to_string()),
+            })
+        })
+    }
+
+    // Generate the parity chunk messages for the block of chunks from `first_chunk` up to `end`
+    fn parity_messages(
         &self,
         channel_id: u32,
         hash: &str,
-        chunks: &[(u32, u32)],
-    ) -> Result<(), ProtocolError> {
-        for (first, last) in chunks {
-            for chunk_index in *first..*last {
-                match storage::load_chunk(&self.config.storage_prefix, hash, chunk_index) {
-                    Ok(c) => self.send(messages::chunk(channel_id, hash, chunk_index, &c)?)?,
-                    Err(e) => {
-                        warn!("Failed to load chunk {}:{} : {}", hash, chunk_index, e);
-                        storage::delete_file(&self.config.storage_prefix, hash)?;
-                        return Err(ProtocolError::CorruptFile(hash.to_string()));
-                    }
+        first_chunk: u32,
+        end: u32,
+    ) -> Result<Vec<Vec<u8>>, ProtocolError> {
+        let mut data = vec![];
+        for index in first_chunk..end {
+            data.push(storage::load_chunk(
+                &self.config.storage_prefix,
+                hash,
+                index,
+            )?);
+        }
+
+        let last_size = data.last().map(|chunk| chunk.len()).unwrap_or(0) as u32;
+
+        fec::encode(&data, self.config.fec_parity)
+            .iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                let parity = Parity {
+                    first_chunk,
+                    num_chunks: end - first_chunk,
+                    index: index as u32,
+                    last_size,
                 };
+                messages::parity(channel_id, hash, &parity, chunk)
+            }).collect()
+    }
 
-                thread::sleep(Duration::from_millis(1));
-            }
+    // Work out when the next chunk can be sent, keeping our transmission rate under
+    // the configured limit. Nothing is left to send once all the chunks have gone.
+    // If we fall behind, whatever is overdue is sent straight away
+    fn pace(&self, mut outgoing: Outgoing) {
+        if outgoing.chunks.is_empty() && outgoing.parity.is_empty() {
+            return;
+        }
+
+        outgoing.due = if self.config.max_rate == 0 {
+            // Leave around a millisecond between each chunk
+            outgoing.start + Duration::from_millis(u64::from(outgoing.messages_sent))
+        } else {
+            let target = outgoing.bytes_sent * 1000 / u64::from(self.config.max_rate);
+            outgoing.start + Duration::from_millis(target)
+        };
+        *self.outgoing.borrow_mut() = Some(outgoing);
+    }
+
+    // Adapt the sending window to the link, based on how many of the chunks we
+    // sent in the previous round are now being reported as missing
+    fn update_window(&self, missing: &[(u32, u32)]) {
+        if self.config.window_size == 0 {
+            return;
+        }
+
+        let last_sent = self.last_sent.borrow();
+        if last_sent.is_empty() {
+            return;
+        }
+
+        let lost = last_sent
+            .iter()
+            .filter(|&&index| {
+                missing
+                    .iter()
+                    .any(|&(first, last)| index >= first && index < last)
+            }).count();
+
+        let window = self.window.get();
+        let new_window = if lost * 100 > last_sent.len() * LOSS_THRESHOLD {
+            cmp::max(1, window / 2)
+        } else if lost == 0 {
+            cmp::min(self.config.window_size, window.saturating_mul(2))
+        } else {
+            window
+        };
+
+        if new_window != window {
+            info!(
+                "{} of {} chunks lost. Adjusting send window from {} to {} chunks",
+                lost,
+                last_sent.len(),
+                window,
+                new_window
+            );
+            self.window.set(new_window);
         }
-        Ok(())
+    }
+
+    // Update the progress of the transfer of `hash` and report it.
+    // Nothing is tracked unless someone has asked for progress reports
+    fn track<F>(&self, hash: &str, update: F)
+    where
+        F: FnOnce(&mut Tracker),
+    {
+        let callback = match self.on_progress {
+            Some(ref callback) => callback,
+            None => return,
+        };
+
+        let progress = {
+            let mut tracker = self.progress.borrow_mut();
+
+            // Start counting again whenever we move on to a new file
+            let new_file = match *tracker {
+                Some(ref current) => current.hash != hash,
+                None => true,
+            };
+            if new_file {
+                let num_chunks = match storage::load_meta(&self.config.storage_prefix, hash) {
+                    Ok(num_chunks) => num_chunks,
+                    Err(_) => return,
+                };
+                *tracker = Some(Tracker::new(hash, num_chunks));
+            }
+
+            match *tracker {
+                Some(ref mut current) => {
+                    update(current);
+                    current.snapshot()
+                }
+                None => return,
+            }
+        };
+
+        callback(&progress);
     }
 
     /// Listen for and process file protocol messages
@@ -463,122 +2304,189 @@ impl Protocol {
     where
         F: Fn(Duration) -> Result<Value, ProtocolError>,
     {
-        let mut state = start_state.clone();
+        let mut state = start_state;
         loop {
-            // Listen on UDP port
-            let message = match pump(timeout) {
-                Ok(message) => {
-                    // If we previously timed out, restore the old state
-                    if let State::Holding {
-                        count: _,
-                        prev_state,
-                    } = state
-                    {
-                        state = *prev_state;
-                    }
+            // Send any chunks which are due in between listening for messages.
+            // We only give up waiting for a message once they've all been sent
+            self.send_pending()?;
+            let event = match self.send_deadline() {
+                Some(due) => match pump(cmp::max(
+                    due.saturating_duration_since(Instant::now()),
+                    Duration::from_millis(1),
+                )) {
+                    Err(ProtocolError::ReceiveTimeout) => continue,
+                    event => event,
+                },
+                // Listen on UDP port
+                None => pump(self.wait_time(&state, timeout)),
+            };
+            state = self.process_event(event, state)?;
 
-                    message
-                }
-                Err(ProtocolError::ReceiveTimeout) => match state.clone() {
-                    State::Receiving {
-                        channel_id,
-                        hash,
-                        path,
-                        mode,
-                    } => {
-                        match storage::validate_file(&self.config.storage_prefix, &hash, None) {
-                            Ok((true, _)) => {
-                                self.send(messages::ack(channel_id, &hash, None)?)?;
-                                state = State::ReceivingDone {
-                                    channel_id,
-                                    hash: hash.clone(),
-                                    path: path.clone(),
-                                    mode,
-                                };
-                            }
-                            Ok((false, chunks)) => {
-                                self.send(messages::nak(channel_id, &hash, &chunks)?)?;
-                                state = State::Holding {
-                                    count: 0,
-                                    prev_state: Box::new(state.clone()),
-                                };
-                                continue;
-                            }
-                            Err(e) => return Err(e),
-                        };
+            if state == State::Done {
+                return Ok(());
+            }
+        }
+    }
 
-                        match self.finalize_file(channel_id, &hash, &path, mode) {
-                            Ok(_) => {
-                                return Ok(());
-                            }
-                            Err(e) => {
-                                warn!("Failed to finalize file {} as {}: {}", hash, path, e);
-                                // TODO: Handle finalization failures (ex. corrupted chunk file)
-                                state = State::Holding {
-                                    count: 0,
-                                    prev_state: Box::new(state.clone()),
-                                };
-                                continue;
-                            }
-                        }
-                    }
-                    State::ReceivingDone {
-                        channel_id,
-                        hash,
-                        path,
-                        mode,
-                    } => {
-                        // We've got all the chunks of data we want.
-                        // Stitch it back together and verify the hash of the official file
-                        self.finalize_file(channel_id, &hash, &path, mode)?;
-                        return Ok(());
-                    }
-                    State::Done => {
-                        return Ok(());
-                    }
-                    State::Holding { count, prev_state } => {
-                        if count > self.config.hold_count {
-                            return Ok(());
-                        } else {
-                            state = State::Holding {
-                                count: count + 1,
-                                prev_state,
-                            };
-                            continue;
-                        }
+    /// Process the outcome of waiting for a single file protocol message
+    ///
+    /// Lets the caller decide how messages are received, so that many transactions can be
+    /// handled without a thread for each one. `Err(ProtocolError::ReceiveTimeout)` should
+    /// be given if no message arrived for the transaction in time.
+    ///
+    /// Returns the new transaction state. The transaction is finished once this is `State::Done`
+    ///
+    /// # Arguments
+    ///
+    /// * event - The received message, or the error which occurred instead
+    /// * state - Current transaction state
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// extern crate file_protocol;
+    ///
+    /// use file_protocol::*;
+    /// use std::time::Duration;
+    ///
+    /// let config = FileProtocolConfig::new(None, 4096, 5);
+    /// let f_protocol = FileProtocol::new("0.0.0.0", "0.0.0.0:7000", config);
+    ///
+    /// let mut state = State::Transmitting;
+    /// while state != State::Done {
+    ///     let event = f_protocol.recv(Some(Duration::from_millis(10)));
+    ///     state = f_protocol.process_event(event, state).unwrap();
+    /// }
+    /// ```
+    ///
+    pub fn process_event(
+        &self,
+        event: Result<Value, ProtocolError>,
+        state: State,
+    ) -> Result<State, ProtocolError> {
+        // Nothing else can happen until the storage work we're waiting on is done.
+        // The other side will repeat anything it still needs once we've answered
+        if let State::Preparing { .. } | State::Finalizing { .. } = state {
+            return Ok(state);
+        }
+
+        let message = match event {
+            Ok(message) => message,
+            Err(ProtocolError::ReceiveTimeout) => return self.process_timeout(state),
+            Err(e) => return Err(e),
+        };
+
+        // If we previously timed out, restore the old state
+        let state = match state {
+            State::Holding { prev_state, .. } => *prev_state,
+            state => state,
+        };
+
+        match self.process_message(message, state)? {
+            State::ReceivingDone {
+                channel_id,
+                hash,
+                path,
+                mode,
+                directory,
+            } => {
+                // We've got all the chunks of data we want.
+                // Stitch it back together and verify the hash of the official file
+                self.finish_receive(channel_id, &hash, &path, mode, directory)
+            }
+            state => Ok(state),
+        }
+    }
+
+    // Work out what to do after waiting too long for a message
+    fn process_timeout(&self, state: State) -> Result<State, ProtocolError> {
+        match state.clone() {
+            State::Receiving {
+                channel_id,
+                hash,
+                path,
+                mode,
+                directory,
+            } => {
+                self.feedback_due.set(false);
+                match storage::validate_file(&self.config.storage_prefix, &hash, None)? {
+                    (true, _) => {
+                        self.send(messages::ack(channel_id, &hash, None)?)?;
+                        self.track(&hash, |tracker| tracker.remaining = 0);
                     }
-                    _ => {
-                        state = State::Holding {
+                    (false, chunks) => {
+                        self.send(messages::nak(channel_id, &hash, &chunks)?)?;
+                        self.track(&hash, |tracker| tracker.nak(count_chunks(&chunks)));
+                        return Ok(State::Holding {
                             count: 0,
-                            prev_state: Box::new(state.clone()),
-                        };
-                        continue;
+                            prev_state: Box::new(state),
+                        });
                     }
-                },
-                Err(e) => return Err(e),
-            };
+                };
 
-            match self.process_message(message, state.clone()) {
-                Ok(new_state) => state = new_state,
-                Err(e) => return Err(e),
+                self.finish_receive(channel_id, &hash, &path, mode, directory)
             }
-
-            match state.clone() {
-                State::ReceivingDone {
-                    channel_id,
-                    hash,
-                    path,
-                    mode,
-                } => {
-                    // We've got all the chunks of data we want.
-                    // Stitch it back together and verify the hash of the official file
-                    self.finalize_file(channel_id, &hash, &path, mode)?;
-                    return Ok(());
+            State::ReceivingDone {
+                channel_id,
+                hash,
+                path,
+                mode,
+                directory,
+            } => {
+                // We've got all the chunks of data we want.
+                // Stitch it back together and verify the hash of the official file
+                self.finish_receive(channel_id, &hash, &path, mode, directory)
+            }
+            State::Done => Ok(State::Done),
+            State::Holding { count, prev_state } => {
+                if count > self.config.hold_count {
+                    Ok(State::Done)
+                } else {
+                    Ok(State::Holding {
+                        count: count + 1,
+                        prev_state,
+                    })
                 }
-                State::Done => return Ok(()),
-                _ => continue,
-            };
+            }
+            _ => Ok(State::Holding {
+                count: 0,
+                prev_state: Box::new(state),
+            }),
+        }
+    }
+
+    /// How long to wait for the next message before giving up on it, given the
+    /// transaction's current state
+    ///
+    /// Usually this is `timeout`. While chunks are arriving, the receiver only waits until
+    /// the sender seems to have paused, and then tells it which chunks are still missing.
+    /// This lets the sender move on to its next window of chunks straight away
+    pub fn wait_time(&self, state: &State, timeout: Duration) -> Duration {
+        match (state, self.last_chunk.get()) {
+            (State::Receiving { .. }, Some(last_chunk)) if self.feedback_due.get() => {
+                let delay = cmp::max(FEEDBACK_DELAY, self.chunk_gap.get() * 2);
+                cmp::min(
+                    timeout,
+                    (last_chunk + delay).saturating_duration_since(Instant::now()),
+                )
+            }
+            _ => timeout,
+        }
+    }
+
+    // Note that a chunk has arrived, and how long it's been since the previous one
+    fn chunk_arrived(&self) {
+        let now = Instant::now();
+        if let Some(last_chunk) = self.last_chunk.get() {
+            self.chunk_gap
+                .set((self.chunk_gap.get() * 3 + (now - last_chunk)) / 4);
         }
+        self.last_chunk.set(Some(now));
+        self.feedback_due.set(true);
     }
 
     /// Process a file protocol message
@@ -617,37 +2525,157 @@ impl Protocol {
     ///
     pub fn process_message(&self, message: Value, state: State) -> Result<State, ProtocolError> {
         let parsed_message = parsers::parse_message(message)?;
+
+        // Hashes name directories in temporary storage, so refuse any which could
+        // point somewhere else
+        if let Some((channel_id, hash)) = message_hash(&parsed_message) {
+            if let Err(error) = storage::check_hash(hash) {
+                self.send(messages::operation_failure(
+                    channel_id,
+                    &format!("{}", error),
+                )?)?;
+                return Err(error);
+            }
+        }
+
         let new_state;
         match parsed_message.to_owned() {
             parsed_message => {
                 match &parsed_message {
                     Message::Sync(channel_id, hash) => {
                         info!("<- {{ {}, {} }}", channel_id, hash);
-                        new_state = state.clone();
+                        // Let the requester know whether we already have the whole file,
+                        // in which case it doesn't need to send us any chunks
+                        match storage::validate_file(&self.config.storage_prefix, hash, None) {
+                            Ok((true, _)) => {
+                                let num_chunks =
+                                    storage::load_meta(&self.config.storage_prefix, hash)?;
+                                self.send(messages::ack(*channel_id, hash, Some(num_chunks))?)?;
+                            }
+                            _ => self.send(messages::nak(*channel_id, hash, &[])?)?,
+                        }
+                        new_state = State::Done;
                     }
-                    Message::Metadata(channel_id, hash, num_chunks) => {
-                        info!("<- {{ {}, {}, {} }}", channel_id, hash, num_chunks);
-                        storage::store_meta(&self.config.storage_prefix, &hash, *num_chunks)?;
+                    Message::Metadata(channel_id, hash, num_chunks, compression) => {
+                        info!(
+                            "<- {{ {}, {}, {}, {} }}",
+                            channel_id,
+                            hash,
+                            num_chunks,
+                            compression.name()
+                        );
+                        storage::discard_if_compression_changed(
+                            &self.config.storage_prefix,
+                            hash,
+                            *compression,
+                        )?;
+                        storage::store_meta(
+                            &self.config.storage_prefix,
+                            hash,
+                            *num_chunks,
+                            *compression,
+                        )?;
                         new_state = State::StartReceive {
                             path: hash.to_owned(),
                         };
                     }
-                    Message::ReceiveChunk(channel_id, hash, chunk_num, data) => {
+                    Message::ReceiveChunk(channel_id, hash, chunk_num, data, checksum) => {
                         info!(
                             "<- {{ {}, {}, {}, chunk_data }}",
                             channel_id, hash, chunk_num
                         );
-                        storage::store_chunk(
+                        // The file's metadata might not have told us how much it would need
+                        if let Err(error) = self.reserve_chunk(data.len() as u64) {
+                            self.send_result(*channel_id, Err(error))?;
+                            return Ok(State::Done);
+                        }
+                        match storage::store_chunk(
                             &self.config.storage_prefix,
                             &hash,
                             *chunk_num,
                             &data,
-                        )?;
+                            *checksum,
+                        ) {
+                            // A damaged chunk is dropped, so it will be requested again
+                            // along with any other missing chunks
+                            Err(ProtocolError::CorruptChunks(_)) => {
+                                warn!("Dropping corrupt chunk {} of {}", chunk_num, hash)
+                            }
+                            result => {
+                                result?;
+                                self.chunk_arrived();
+                                self.track(hash, |tracker| {
+                                    tracker.chunks_received += 1;
+                                    tracker.bytes += data.len() as u64;
+                                    tracker.remaining = tracker.remaining.saturating_sub(1);
+                                });
+                            }
+                        }
+                        new_state = state.clone();
+                    }
+                    Message::ReceiveParity(channel_id, hash, parity, data, checksum) => {
+                        info!(
+                            "<- {{ {}, parity, {}, {}, {}, parity_data }}",
+                            channel_id, hash, parity.first_chunk, parity.index
+                        );
+                        // The block has to lie within the file we've been told about
+                        let in_file = storage::load_meta(&self.config.storage_prefix, hash)
+                            .ok()
+                            .and_then(|total| {
+                                parity
+                                    .first_chunk
+                                    .checked_add(parity.num_chunks)
+                                    .map(|end| end <= total)
+                            }).unwrap_or(false);
+
+                        if storage::chunk_checksum(data) != *checksum {
+                            // It's only extra information, so there's nothing to request again
+                            warn!(
+                                "Dropping corrupt parity chunk {} of block {} of {}",
+                                parity.index, parity.first_chunk, hash
+                            );
+                        } else if !in_file {
+                            warn!(
+                                "Dropping parity chunk {} of block {} of {}, which doesn't fit the file",
+                                parity.index, parity.first_chunk, hash
+                            );
+                        } else if let Err(error) = self.reserve_chunk(data.len() as u64) {
+                            self.send_result(*channel_id, Err(error))?;
+                            return Ok(State::Done);
+                        } else {
+                            storage::store_parity(&self.config.storage_prefix, hash, parity, data)?;
+                            self.chunk_arrived();
+
+                            // Rebuild anything we lost from the block, rather than waiting
+                            // to request it again
+                            match storage::recover_block(
+                                &self.config.storage_prefix,
+                                hash,
+                                parity.first_chunk,
+                            ) {
+                                Ok(ref recovered) if !recovered.is_empty() => {
+                                    info!("Rebuilt chunks {:?} of {}", recovered, hash);
+                                    self.track(hash, |tracker| {
+                                        tracker.remaining =
+                                            tracker.remaining.saturating_sub(recovered.len() as u32)
+                                    });
+                                }
+                                Ok(_) => {}
+                                // The missing chunks will be requested again instead
+                                Err(error) => {
+                                    warn!("Failed to rebuild chunks of {}: {}", hash, error)
+                                }
+                            }
+                        }
                         new_state = state.clone();
                     }
                     Message::ACK(_channel_id, ack_hash) => {
                         info!("<- {{ {}, true }}", ack_hash);
                         // TODO: Figure out hash verification here
+                        // The receiver has everything, so there's nothing left to resume
+                        storage::delete_transaction(&self.config.storage_prefix, ack_hash)?;
+                        *self.outgoing.borrow_mut() = None;
+                        self.track(ack_hash, |tracker| tracker.remaining = 0);
                         new_state = State::TransmittingDone;
                     }
                     Message::NAK(channel_id, hash, Some(missing_chunks)) => {
@@ -655,13 +2683,20 @@ impl Protocol {
                             "<- {{ {}, {}, false, {:?} }}",
                             channel_id, hash, missing_chunks
                         );
-                        match self.send_chunks(*channel_id, &hash, &missing_chunks) {
-                            Ok(()) => {}
-                            Err(error) => self.send(messages::operation_failure(
+                        let missing = missing_chunks
+                            .iter()
+                            .map(|&(first, last)| last.saturating_sub(first))
+                            .sum();
+                        self.track(hash, |tracker| tracker.nak(missing));
+                        if let Err(error) = self.send_chunks(*channel_id, hash, missing_chunks) {
+                            // We can't finish sending the file, so there's no point in
+                            // the receiver waiting for it
+                            self.send(messages::operation_failure(
                                 *channel_id,
                                 &format!("{}", error),
-                            )?)?,
-                        };
+                            )?)?;
+                            return Err(error);
+                        }
                         new_state = State::Transmitting;
                     }
                     Message::NAK(channel_id, hash, None) => {
@@ -675,100 +2710,332 @@ impl Protocol {
                             channel_id, hash, path, mode
                         );
                         // The client wants to send us a file.
-                        // See what state the file is currently in on our side
-                        match storage::validate_file(&self.config.storage_prefix, hash, None) {
-                            Ok((true, _)) => {
-                                // We've already got all the file data in temporary storage
-                                self.send(messages::ack(*channel_id, &hash, None)?)?;
-
-                                new_state = State::ReceivingDone {
+                        // Make sure it's allowed to go where it's meant to and that we have
+                        // room for it, then see what state the file is currently in on our side
+                        let allowed = self
+                            .check_modify(path)
+                            .and_then(|()| self.reserve_receive(hash));
+                        new_state = match allowed {
+                            Ok(()) => self.start_receive(*channel_id, hash, path, *mode, false)?,
+                            Err(error) => {
+                                self.send_result(*channel_id, Err(error))?;
+                                State::Done
+                            }
+                        };
+                    }
+                    Message::ReqTransmit(channel_id, path, compression, offset, length) => {
+                        info!(
+                            "<- {{ {}, import, {}, {}, {}, {:?} }}",
+                            channel_id,
+                            path,
+                            compression.name(),
+                            offset,
+                            length
+                        );
+                        // Set up the requested file (or just the requested part of it)
+                        // for transmission, compressed the way the requester asked for
+                        let allowed = self.check_read(path).and_then(|()| {
+                            self.reserve_transmit(path, *compression, *offset, *length)
+                        });
+                        new_state = match allowed {
+                            Ok(()) => self.start_task(
+                                Task::Prepare {
+                                    prefix: self.config.storage_prefix.clone(),
+                                    path: path.to_owned(),
+                                    chunk_size: self.config.chunk_size,
+                                    compression: *compression,
+                                    streaming: self.config.streaming,
+                                    offset: *offset,
+                                    length: *length,
+                                },
+                                State::Preparing {
                                     channel_id: *channel_id,
-                                    hash: hash.to_string(),
-                                    path: path.to_string(),
-                                    mode: *mode,
-                                };
+                                    compression: *compression,
+                                },
+                            )?,
+                            Err(error) => {
+                                // Let the requester know that we can't transmit
+                                // the file they want.
+                                self.send_result(*channel_id, Err(error))?;
+                                State::Done
                             }
-                            Ok((false, chunks)) => {
-                                // We're missing some number of data chunks of the requrested file
-                                self.send(messages::nak(*channel_id, &hash, &chunks)?)?;
-                                new_state = State::Receiving {
+                        };
+                    }
+                    Message::ReqReceiveDir(channel_id, hash, path) => {
+                        info!("<- {{ {}, export_dir, {}, {} }}", channel_id, hash, path);
+                        // The client wants to send us a directory tree.
+                        // Receive its manifest, then recreate the tree at the requested path
+                        let allowed = self
+                            .check_write(path)
+                            .and_then(|()| self.reserve_receive(hash));
+                        new_state = match allowed {
+                            Ok(()) => self.start_receive(*channel_id, hash, path, None, true)?,
+                            Err(error) => {
+                                self.send_result(*channel_id, Err(error))?;
+                                State::Done
+                            }
+                        };
+                    }
+                    Message::ReqTransmitDir(channel_id, path) => {
+                        info!("<- {{ {}, import_dir, {} }}", channel_id, path);
+                        // Set up the manifest of the requested directory for transmission
+                        new_state = match self.check_read(path) {
+                            Ok(()) => self.start_task(
+                                Task::PrepareDirectory {
+                                    prefix: self.config.storage_prefix.clone(),
+                                    path: path.to_owned(),
+                                    chunk_size: self.config.chunk_size,
+                                    compression: self.config.compression,
+                                },
+                                State::Preparing {
                                     channel_id: *channel_id,
-                                    hash: hash.to_string(),
-                                    path: path.to_string(),
-                                    mode: *mode,
-                                };
+                                    compression: self.config.compression,
+                                },
+                            )?,
+                            Err(error) => {
+                                self.send_result(*channel_id, Err(error))?;
+                                State::Done
                             }
-                            Err(e) => return Err(e),
-                        }
+                        };
                     }
-                    Message::ReqTransmit(channel_id, path) => {
-                        info!("<- {{ {}, import, {} }}", channel_id, path);
-                        // Set up the requested file for transmission
-                        match self.initialize_file(path) {
-                            Ok((hash, num_chunks, mode)) => {
-                                // It worked, let the requester know we're ready to send
-                                self.send(messages::import_setup_success(
-                                    *channel_id,
-                                    &hash,
-                                    num_chunks,
-                                    mode,
-                                )?)?;
+                    Message::Resume(channel_id, hash) => {
+                        info!("<- {{ {}, resume, {} }}", channel_id, hash);
+                        // The client wants to pick up an interrupted transaction.
+                        // Figure out which side of it we were on
+                        match storage::load_transaction(&self.config.storage_prefix, hash) {
+                            Ok(storage::Transaction::Receiving {
+                                path,
+                                mode,
+                                directory,
+                            }) => {
+                                // The allowed directories may have changed since the transaction started
+                                let allowed = if directory {
+                                    self.check_write(&path)
+                                } else {
+                                    self.check_modify(&path)
+                                };
+                                new_state = match allowed {
+                                    Ok(()) => self.start_receive(
+                                        *channel_id,
+                                        hash,
+                                        &path,
+                                        mode,
+                                        directory,
+                                    )?,
+                                    Err(error) => {
+                                        self.send_result(*channel_id, Err(error))?;
+                                        State::Done
+                                    }
+                                };
+                            }
+                            Ok(storage::Transaction::Transmitting { path, mode }) => {
+                                // Make sure we still have everything we need to send
+                                let ready = self.check_read(&path).and_then(|()| {
+                                    storage::ready_to_transmit(&self.config.storage_prefix, hash)
+                                });
+                                match ready {
+                                    Ok(true) => {
+                                        let num_chunks =
+                                            storage::load_meta(&self.config.storage_prefix, hash)?;
+                                        let compression = storage::load_compression(
+                                            &self.config.storage_prefix,
+                                            hash,
+                                        )?;
+                                        info!("Resuming transmission of {} ({})", path, hash);
+                                        self.send(messages::import_setup_success(
+                                            *channel_id,
+                                            hash,
+                                            num_chunks,
+                                            mode,
+                                            compression,
+                                        )?)?;
+
+                                        new_state = State::Transmitting;
+                                    }
+                                    Ok(false) => {
+                                        self.send(messages::operation_failure(
+                                            *channel_id,
+                                            "Temporary storage is missing file chunks",
+                                        )?)?;
 
-                                new_state = State::Transmitting;
+                                        new_state = State::Done;
+                                    }
+                                    Err(error) => {
+                                        self.send(messages::operation_failure(
+                                            *channel_id,
+                                            &format!("{}", error),
+                                        )?)?;
+
+                                        new_state = State::Done;
+                                    }
+                                }
                             }
                             Err(error) => {
-                                // It failed. Let the requester know that we can't transmit
-                                // the file they want.
+                                // There's nothing here for us to resume
                                 self.send(messages::operation_failure(
                                     *channel_id,
-                                    &format!("{}", error),
+                                    &format!("No resumable transaction for {}: {}", hash, error),
                                 )?)?;
 
                                 new_state = State::Done;
                             }
                         }
                     }
+                    Message::ReqList(channel_id, path) => {
+                        info!("<- {{ {}, list, {} }}", channel_id, path);
+                        let result = self.check_read(path).and_then(|()| filesystem::list(path));
+                        self.send(match result {
+                            Ok(entries) => messages::list_success(*channel_id, &entries)?,
+                            Err(error) => {
+                                messages::operation_failure(*channel_id, &format!("{}", error))?
+                            }
+                        })?;
+                        new_state = State::Done;
+                    }
+                    Message::ReqStat(channel_id, path) => {
+                        info!("<- {{ {}, stat, {} }}", channel_id, path);
+                        let result = self.check_read(path).and_then(|()| filesystem::stat(path));
+                        self.send(match result {
+                            Ok(info) => messages::stat_success(*channel_id, &info)?,
+                            Err(error) => {
+                                messages::operation_failure(*channel_id, &format!("{}", error))?
+                            }
+                        })?;
+                        new_state = State::Done;
+                    }
+                    Message::ReqDelete(channel_id, path, recursive) => {
+                        info!("<- {{ {}, delete, {}, {} }}", channel_id, path, recursive);
+                        let result = self
+                            .check_modify(path)
+                            .and_then(|()| filesystem::delete(path, *recursive));
+                        self.send_result(*channel_id, result)?;
+                        new_state = State::Done;
+                    }
+                    Message::ReqMkdir(channel_id, path) => {
+                        info!("<- {{ {}, mkdir, {} }}", channel_id, path);
+                        let result = self
+                            .check_modify(path)
+                            .and_then(|()| filesystem::mkdir(path));
+                        self.send_result(*channel_id, result)?;
+                        new_state = State::Done;
+                    }
+                    Message::ReqRename(channel_id, source_path, target_path) => {
+                        info!(
+                            "<- {{ {}, rename, {}, {} }}",
+                            channel_id, source_path, target_path
+                        );
+                        // Moving a file changes both where it was and where it ends up
+                        let result = self
+                            .check_modify(source_path)
+                            .and_then(|()| self.check_modify(target_path))
+                            .and_then(|()| filesystem::rename(source_path, target_path));
+                        self.send_result(*channel_id, result)?;
+                        new_state = State::Done;
+                    }
+                    Message::ReqTransactions(channel_id)
+                    | Message::ReqHistory(channel_id, _)
+                    | Message::ReqCancel(channel_id, _) => {
+                        info!("<- {{ {}, {:?} }}", channel_id, parsed_message);
+                        // Transactions are managed by whatever is running the message engines
+                        self.send(messages::operation_failure(
+                            *channel_id,
+                            "Transaction management is not supported",
+                        )?)?;
+                        new_state = State::Done;
+                    }
+                    Message::SuccessList(channel_id, entries) => {
+                        info!(
+                            "<- {{ {}, true, list, {} entries }}",
+                            channel_id,
+                            entries.len()
+                        );
+                        new_state = State::Done;
+                    }
+                    Message::SuccessStat(channel_id, info) => {
+                        info!("<- {{ {}, true, stat, {:?} }}", channel_id, info);
+                        new_state = State::Done;
+                    }
+                    Message::SuccessTransactions(channel_id, transactions) => {
+                        info!(
+                            "<- {{ {}, true, transactions, {} entries }}",
+                            channel_id,
+                            transactions.len()
+                        );
+                        new_state = State::Done;
+                    }
                     Message::SuccessReceive(channel_id) => {
                         info!("<- {{ {}, true }}", channel_id);
                         new_state = State::Done;
                     }
-                    Message::SuccessTransmit(channel_id, hash, num_chunks, mode) => {
+                    Message::SuccessTransmit(channel_id, hash, num_chunks, mode, compression) => {
                         match mode {
                             Some(value) => info!(
+                                "<- {{ {}, true, {}, {}, {}, {} }}",
+                                channel_id,
+                                hash,
+                                num_chunks,
+                                value,
+                                compression.name()
+                            ),
+                            None => info!(
                                 "<- {{ {}, true, {}, {}, {} }}",
-                                channel_id, hash, num_chunks, value
+                                channel_id,
+                                hash,
+                                num_chunks,
+                                compression.name()
                             ),
-                            None => {
-                                info!("<- {{ {}, true, {}, {} }}", channel_id, hash, num_chunks)
-                            }
                         }
 
-                        // TODO: handle channel_id mismatch
-                        match storage::validate_file(
+                        // Record how the chunks we're about to receive will be compressed
+                        storage::discard_if_compression_changed(
                             &self.config.storage_prefix,
                             hash,
-                            Some(*num_chunks),
-                        ) {
+                            *compression,
+                        )?;
+                        storage::store_meta(
+                            &self.config.storage_prefix,
+                            hash,
+                            *num_chunks,
+                            *compression,
+                        )?;
+
+                        // TODO: handle channel_id mismatch
+                        match storage::validate_file(&self.config.storage_prefix, hash, None) {
                             Ok((true, _)) => {
                                 self.send(messages::ack(*channel_id, &hash, Some(*num_chunks))?)?;
+                                self.track(hash, |tracker| tracker.remaining = 0);
                                 new_state = match state.clone() {
                                     State::StartReceive { path } => State::ReceivingDone {
                                         channel_id: *channel_id,
                                         hash: hash.to_string(),
                                         path: path.to_string(),
                                         mode: *mode,
+                                        directory: false,
                                     },
                                     _ => State::Done,
                                 };
                             }
                             Ok((false, chunks)) => {
+                                if let State::StartReceive { path } = &state {
+                                    storage::store_transaction(
+                                        &self.config.storage_prefix,
+                                        hash,
+                                        &storage::Transaction::Receiving {
+                                            path: path.to_owned(),
+                                            mode: *mode,
+                                            directory: false,
+                                        },
+                                    )?;
+                                }
                                 self.send(messages::nak(*channel_id, &hash, &chunks)?)?;
+                                self.track(hash, |tracker| tracker.nak(count_chunks(&chunks)));
                                 new_state = match state.clone() {
                                     State::StartReceive { path } => State::Receiving {
                                         channel_id: *channel_id,
                                         hash: hash.to_string(),
                                         path: path.to_string(),
                                         mode: *mode,
+                                        directory: false,
                                     },
                                     _ => state.clone(),
                                 };
@@ -776,6 +3043,17 @@ impl Protocol {
                             Err(e) => return Err(e),
                         }
                     }
+                    Message::Hello(channel_id, capabilities) => {
+                        info!(
+                            "<- {{ {}, hello, {}, {:?}, {} }}",
+                            channel_id,
+                            capabilities.version,
+                            capabilities.features,
+                            capabilities.max_chunk_size
+                        );
+                        self.send_hello(*channel_id)?;
+                        new_state = State::Done;
+                    }
                     Message::Failure(channel_id, error_message) => {
                         info!("<- {{ {}, false, {} }}", channel_id, error_message);
                         return Err(ProtocolError::TransmissionError {
@@ -789,3 +3067,57 @@ impl Protocol {
         }
     }
 }
+
+// Check whether a message couldn't be sent because the remote end has paused us
+// and the send queue is full. Any other failure is passed on
+fn queue_full(result: Result<(), ProtocolError>) -> Result<bool, ProtocolError> {
+    match result {
+        Ok(()) => Ok(false),
+        Err(ProtocolError::CborError {
+            err: cbor_protocol::ProtocolError::QueueFull { .. },
+        }) => Ok(true),
+        Err(err) => Err(err),
+    }
+}
+
+// Check whether every chunk from `first` up to `end` is covered by the requested ranges
+fn requested(chunks: &[(u32, u32)], first: u32, end: u32) -> bool {
+    chunks
+        .iter()
+        .any(|&(range_start, range_end)| first >= range_start && end <= range_end)
+}
+
+// Count the chunks covered by a list of missing chunk ranges, given as
+// pairs of `first, last` chunk numbers
+fn count_chunks(chunks: &[u32]) -> u32 {
+    chunks
+        .chunks(2)
+        .map(|range| match range {
+            [first, last] => last.saturating_sub(*first),
+            _ => 0,
+        }).sum()
+}
+
+// Build an error for a reply which doesn't match the request we sent
+fn unexpected_reply(message: &Message) -> ProtocolError {
+    ProtocolError::MessageParseError {
+        err: format!("Unexpected reply: {:?}", message),
+    }
+}
+
+// Find the file hash carried by a message, along with the channel it arrived on
+fn message_hash(message: &Message) -> Option<(u32, &str)> {
+    match message {
+        Message::Sync(channel_id, hash)
+        | Message::Metadata(channel_id, hash, _, _)
+        | Message::ReceiveChunk(channel_id, hash, _, _, _)
+        | Message::ReceiveParity(channel_id, hash, _, _, _)
+        | Message::ACK(channel_id, hash)
+        | Message::NAK(channel_id, hash, _)
+        | Message::ReqReceive(channel_id, hash, _, _)
+        | Message::ReqReceiveDir(channel_id, hash, _)
+        | Message::Resume(channel_id, hash)
+        | Message::SuccessTransmit(channel_id, hash, _, _, _) => Some((*channel_id, hash)),
+        _ => None,
+    }
+}
@@ -0,0 +1,111 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Restricting remote requests to particular parts of the file system
+
+use error::ProtocolError;
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Work out where a path really leads
+///
+/// Relative paths are taken to be relative to the current directory. `.` and `..`
+/// components are removed, and symbolic links are followed wherever they exist,
+/// so that `..` after a link moves up from wherever the link points.
+/// Parts of the path which don't exist yet are kept as they are.
+///
+/// # Errors
+///
+/// Paths which go through a link to something which doesn't exist are rejected,
+/// since creating the missing target could put a file anywhere
+pub fn resolve(path: &Path) -> Result<PathBuf, ProtocolError> {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir()
+            .map_err(|err| ProtocolError::StorageError {
+                action: "get current directory".to_owned(),
+                err,
+            })?.join(path)
+    };
+
+    let mut resolved = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            component => {
+                resolved.push(component);
+
+                match fs::canonicalize(&resolved) {
+                    Ok(real) => resolved = real,
+                    Err(_) => {
+                        let dangling = fs::symlink_metadata(&resolved)
+                            .map(|meta| meta.file_type().is_symlink())
+                            .unwrap_or(false);
+                        if dangling {
+                            return Err(ProtocolError::PathNotAllowed(
+                                path.to_string_lossy().into_owned(),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Check that a path leads to one of the given root directories, or somewhere beneath one
+///
+/// An empty list of roots allows any path
+pub fn check(path: &Path, roots: &[PathBuf]) -> Result<(), ProtocolError> {
+    check_path(path, roots, true)
+}
+
+/// Check that a path leads somewhere beneath one of the given root directories,
+/// rather than to a root itself. Used for anything which would replace, remove or
+/// move whatever is at the path
+///
+/// An empty list of roots allows any path
+pub fn check_beneath(path: &Path, roots: &[PathBuf]) -> Result<(), ProtocolError> {
+    check_path(path, roots, false)
+}
+
+fn check_path(path: &Path, roots: &[PathBuf], include_roots: bool) -> Result<(), ProtocolError> {
+    if roots.is_empty() {
+        return Ok(());
+    }
+
+    let resolved = resolve(path)?;
+    let allowed = roots
+        .iter()
+        .filter_map(|root| resolve(root).ok())
+        .any(|root| resolved.starts_with(&root) && (include_roots || resolved != root));
+
+    if allowed {
+        Ok(())
+    } else {
+        warn!("Rejecting access to {:?} ({:?})", path, resolved);
+        Err(ProtocolError::PathNotAllowed(
+            path.to_string_lossy().into_owned(),
+        ))
+    }
+}
@@ -15,28 +15,109 @@
 //
 
 use blake2_rfc::blake2s::Blake2s;
+use compression::Compression;
 use error::ProtocolError;
-use serde_cbor::{de, to_vec, Value};
+use fec::{self, Parity};
+use flate2::write::{DeflateDecoder, DeflateEncoder};
+use flate2::Compression as Level;
+use flate2::Crc;
+use manifest::{self, ManifestEntry};
+use rand::{self, Rng};
+use sandbox;
+use serde_cbor::{de, to_vec, ObjectKey, Value};
+use std::cmp;
+use std::collections::BTreeMap;
 use std::fs;
 use std::fs::File;
-use std::fs::Permissions;
-use std::io::{BufRead, BufReader, Read, Write};
+use std::fs::{OpenOptions, Permissions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::os::unix::fs::MetadataExt;
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str;
-use std::thread;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use time;
 
 const HASH_SIZE: usize = 16;
+// Number of bytes in each chunk's checksum
+const CHECKSUM_SIZE: usize = 4;
+
+/// Persisted information about an in-progress transaction
+///
+/// Saved alongside the file's chunks so that the transaction can be resumed after
+/// the process handling it has been restarted
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Transaction {
+    /// We are receiving the file and will finalize it into `path`.
+    /// If `directory` is set, the file is a manifest used to recreate a directory tree at `path`
+    Receiving {
+        path: String,
+        mode: Option<u32>,
+        directory: bool,
+    },
+    /// We are transmitting the file, which was read from `path`
+    Transmitting { path: String, mode: u32 },
+}
+
+/// The original file which a streamed file's chunks are read from.
+///
+/// The size and modification time are recorded when the file is prepared, so
+/// that we can tell if the file has been changed before all of its chunks were sent
+#[derive(Clone, Debug, Eq, PartialEq)]
+struct Source {
+    path: String,
+    size: u64,
+    mtime: u64,
+    chunk_size: u64,
+}
+
+/// Make sure a hash is in the form of one which we generated
+///
+/// Hashes are used to name directories in temporary storage, and usually come from
+/// the other side of a transaction, so anything else could point outside of storage
+pub fn check_hash(hash: &str) -> Result<(), ProtocolError> {
+    let hex = hash.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f'));
+
+    if hash.len() != HASH_SIZE * 2 || !hex {
+        return Err(ProtocolError::InvalidHash(hash.to_owned()));
+    }
+    Ok(())
+}
+
+// Directory in temporary storage which holds everything for a file
+fn hash_dir(prefix: &str, hash: &str) -> Result<PathBuf, ProtocolError> {
+    check_hash(hash)?;
+    Ok(Path::new(&format!("{}/storage", prefix)).join(hash))
+}
+
+// Calculate the CRC-32 checksum used to verify a single chunk's contents
+pub fn chunk_checksum(data: &[u8]) -> u32 {
+    let mut crc = Crc::new();
+    crc.update(data);
+    crc.sum()
+}
+
+// Save new chunk in a temporary storage file.
+// If a checksum was sent along with the chunk, the chunk is only saved if it matches.
+// The checksum is stored after the chunk data so that the chunk can be verified when it is loaded,
+// unless the file's other chunks were stored before chunks had checksums
+pub fn store_chunk(
+    prefix: &str,
+    hash: &str,
+    index: u32,
+    data: &[u8],
+    checksum: Option<u32>,
+) -> Result<(), ProtocolError> {
+    let calc_checksum = chunk_checksum(data);
+    if let Some(checksum) = checksum {
+        if checksum != calc_checksum {
+            return Err(ProtocolError::CorruptChunks(vec![index]));
+        }
+    }
 
-// Save new chunk in a temporary storage file
-pub fn store_chunk(prefix: &str, hash: &str, index: u32, data: &[u8]) -> Result<(), ProtocolError> {
     let file_name = format!("{}", index);
-    let storage_path = Path::new(&format!("{}/storage", prefix))
-        .join(hash)
-        .join(file_name);
+    let storage_path = hash_dir(prefix, hash)?.join(file_name);
 
     if let Some(parent) = &storage_path.parent() {
         fs::create_dir_all(parent).map_err(|err| ProtocolError::StorageError {
@@ -50,7 +131,15 @@ pub fn store_chunk(prefix: &str, hash: &str, index: u32, data: &[u8]) -> Result<
         err,
     })?;
 
+    let mut trailer = vec![];
+    if chunks_have_checksums(prefix, hash) {
+        for i in 0..CHECKSUM_SIZE {
+            trailer.push((calc_checksum >> (8 * (CHECKSUM_SIZE - 1 - i))) as u8);
+        }
+    }
+
     file.write_all(data)
+        .and_then(|_| file.write_all(&trailer))
         .map_err(|err| ProtocolError::StorageError {
             action: "write chunk".to_owned(),
             err,
@@ -59,12 +148,22 @@ pub fn store_chunk(prefix: &str, hash: &str, index: u32, data: &[u8]) -> Result<
     Ok(())
 }
 
-pub fn store_meta(prefix: &str, hash: &str, num_chunks: u32) -> Result<(), ProtocolError> {
-    let data = vec![("num_chunks", num_chunks)];
+pub fn store_meta(
+    prefix: &str,
+    hash: &str,
+    num_chunks: u32,
+    compression: Compression,
+) -> Result<(), ProtocolError> {
+    let checksums = chunks_have_checksums(prefix, hash);
+    let data = vec![
+        ("num_chunks", Value::U64(num_chunks.into())),
+        ("compression", Value::String(compression.name().to_owned())),
+        ("checksums", Value::Bool(checksums)),
+    ];
 
     let vec = to_vec(&data)?;
 
-    let file_dir = Path::new(&format!("{}/storage", prefix)).join(hash);
+    let file_dir = hash_dir(prefix, hash)?;
     // Make sure the directory exists
     fs::create_dir_all(file_dir.clone()).map_err(|err| ProtocolError::StorageError {
         action: "create temp storage directory".to_owned(),
@@ -94,12 +193,160 @@ pub fn store_meta(prefix: &str, hash: &str, num_chunks: u32) -> Result<(), Proto
     Ok(())
 }
 
+// Save the state of a transaction so that it can be resumed later
+pub fn store_transaction(
+    prefix: &str,
+    hash: &str,
+    transaction: &Transaction,
+) -> Result<(), ProtocolError> {
+    let mut data = BTreeMap::new();
+    let (direction, path, mode, directory) = match transaction {
+        Transaction::Receiving {
+            path,
+            mode,
+            directory,
+        } => ("receive", path, *mode, *directory),
+        Transaction::Transmitting { path, mode } => ("transmit", path, Some(*mode), false),
+    };
+    data.insert(
+        ObjectKey::String("direction".to_owned()),
+        Value::String(direction.to_owned()),
+    );
+    data.insert(
+        ObjectKey::String("path".to_owned()),
+        Value::String(path.to_owned()),
+    );
+    if let Some(mode) = mode {
+        data.insert(
+            ObjectKey::String("mode".to_owned()),
+            Value::U64(mode.into()),
+        );
+    }
+    data.insert(
+        ObjectKey::String("directory".to_owned()),
+        Value::Bool(directory),
+    );
+
+    let vec = to_vec(&data)?;
+
+    let file_dir = hash_dir(prefix, hash)?;
+    fs::create_dir_all(file_dir.clone()).map_err(|err| ProtocolError::StorageError {
+        action: "create temp storage directory".to_owned(),
+        err,
+    })?;
+
+    let state_path = file_dir.join("transaction");
+    let temp_path = file_dir.join(".transaction.tmp");
+
+    File::create(&temp_path)
+        .map_err(|err| ProtocolError::StorageError {
+            action: format!("create/open {:?} for writing", temp_path),
+            err,
+        })?.write_all(&vec)
+        .map_err(|err| ProtocolError::StorageError {
+            action: format!("write transaction state to {:?}", temp_path),
+            err,
+        })?;
+
+    fs::rename(temp_path.clone(), state_path.clone()).map_err(|err| {
+        ProtocolError::StorageError {
+            action: format!("rename {:?} to {:?}", temp_path, state_path),
+            err,
+        }
+    })?;
+
+    Ok(())
+}
+
+// Load the saved state of an interrupted transaction
+pub fn load_transaction(prefix: &str, hash: &str) -> Result<Transaction, ProtocolError> {
+    let mut data = vec![];
+    let state_path = hash_dir(prefix, hash)?.join("transaction");
+
+    File::open(state_path)
+        .map_err(|err| ProtocolError::StorageError {
+            action: format!("open {} transaction file", hash),
+            err,
+        })?.read_to_end(&mut data)
+        .map_err(|err| ProtocolError::StorageError {
+            action: format!("read {} transaction file", hash),
+            err,
+        })?;
+
+    let state: Value = de::from_slice(&data).map_err(|err| {
+        ProtocolError::StorageParseError(format!(
+            "Unable to parse transaction state for {}: {}",
+            hash, err
+        ))
+    })?;
+
+    let parse_err = || {
+        ProtocolError::StorageParseError(format!("Failed to parse transaction state for {}", hash))
+    };
+
+    // Returned data should be CBOR:
+    // '{"direction": value, "path": value, "mode": value, "directory": value}'
+    let fields = state.as_object().ok_or_else(parse_err)?;
+
+    let path = fields
+        .get(&ObjectKey::String("path".to_owned()))
+        .and_then(|val| val.as_string())
+        .ok_or_else(parse_err)?
+        .to_owned();
+
+    let mode = fields
+        .get(&ObjectKey::String("mode".to_owned()))
+        .and_then(|val| val.as_u64())
+        .map(|val| val as u32);
+
+    let directory = match fields.get(&ObjectKey::String("directory".to_owned())) {
+        Some(Value::Bool(val)) => *val,
+        _ => false,
+    };
+
+    match fields
+        .get(&ObjectKey::String("direction".to_owned()))
+        .and_then(|val| val.as_string())
+        .map(|val| val.as_str())
+    {
+        Some("receive") => Ok(Transaction::Receiving {
+            path,
+            mode,
+            directory,
+        }),
+        Some("transmit") => Ok(Transaction::Transmitting {
+            path,
+            mode: mode.unwrap_or(0o644),
+        }),
+        _ => Err(parse_err()),
+    }
+}
+
+// Remove the saved state of a transaction once it has completed
+pub fn delete_transaction(prefix: &str, hash: &str) -> Result<(), ProtocolError> {
+    let path = hash_dir(prefix, hash)?.join("transaction");
+
+    if path.exists() {
+        fs::remove_file(path).map_err(|err| ProtocolError::StorageError {
+            action: format!("deleting {} transaction file", hash),
+            err,
+        })?;
+    }
+
+    Ok(())
+}
+
 // Load a chunk from its temporary storage file
 pub fn load_chunk(prefix: &str, hash: &str, index: u32) -> Result<Vec<u8>, ProtocolError> {
     let mut data = vec![];
-    let path = Path::new(&format!("{}/storage", prefix))
-        .join(hash)
-        .join(format!("{}", index));
+    let path = hash_dir(prefix, hash)?.join(format!("{}", index));
+
+    // Streamed files don't have chunk files. Read the chunk from the original file instead
+    if !path.exists() {
+        if let Some(source) = load_source(prefix, hash)? {
+            return load_source_chunk(prefix, hash, &source, index);
+        }
+    }
 
     File::open(path)
         .map_err(|err| ProtocolError::StorageError {
@@ -111,15 +358,210 @@ pub fn load_chunk(prefix: &str, hash: &str, index: u32) -> Result<Vec<u8>, Proto
             err,
         })?;
 
+    if !chunks_have_checksums(prefix, hash) {
+        return Ok(data);
+    }
+
+    // Split off the checksum and make sure the chunk hasn't been damaged since it was stored
+    if data.len() < CHECKSUM_SIZE {
+        return Err(ProtocolError::CorruptChunks(vec![index]));
+    }
+
+    let trailer = data.split_off(data.len() - CHECKSUM_SIZE);
+    let checksum = trailer
+        .iter()
+        .fold(0, |sum, &byte| (sum << 8) | u32::from(byte));
+
+    if checksum != chunk_checksum(&data) {
+        return Err(ProtocolError::CorruptChunks(vec![index]));
+    }
+
     Ok(data)
 }
 
-// Load number of chunks in file from metadata
-pub fn load_meta(prefix: &str, hash: &str) -> Result<u32, ProtocolError> {
+// Save a parity chunk until it's needed to rebuild the data chunks of its block.
+// Parity chunks live in their own directory, named `{first chunk}.{parity index}`,
+// and start with the number of data chunks in the block and the size of the last one
+pub fn store_parity(
+    prefix: &str,
+    hash: &str,
+    parity: &Parity,
+    data: &[u8],
+) -> Result<(), ProtocolError> {
+    let parity_path = hash_dir(prefix, hash)?.join("parity");
+
+    fs::create_dir_all(&parity_path).map_err(|err| ProtocolError::StorageError {
+        action: format!("create parity directory {:?}", parity_path),
+        err,
+    })?;
+
+    let mut contents = vec![];
+    for field in &[parity.num_chunks, parity.last_size] {
+        contents.extend((0..4).rev().map(|shift| (field >> (8 * shift)) as u8));
+    }
+    contents.extend_from_slice(data);
+
+    let path = parity_path.join(format!("{}.{}", parity.first_chunk, parity.index));
+    fs::write(&path, &contents).map_err(|err| ProtocolError::StorageError {
+        action: format!("write parity file {:?}", path),
+        err,
+    })
+}
+
+// Rebuild any missing data chunks in the block starting at `first_chunk` from the parity
+// chunks which have been received for it. Returns the indices of the rebuilt chunks.
+// Nothing is rebuilt until enough parity chunks are available
+pub fn recover_block(
+    prefix: &str,
+    hash: &str,
+    first_chunk: u32,
+) -> Result<Vec<u32>, ProtocolError> {
+    let hash_path = hash_dir(prefix, hash)?;
+    let parity_path = hash_path.join("parity");
+    let block_prefix = format!("{}.", first_chunk);
+
+    let entries = fs::read_dir(&parity_path).map_err(|err| ProtocolError::StorageError {
+        action: format!("read {:?} directory", parity_path),
+        err,
+    })?;
+
+    let mut parity: Vec<(u32, Vec<u8>)> = vec![];
+    let mut headers: Vec<(u32, u32)> = vec![];
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let index = match entry.file_name().to_str().and_then(|name| {
+            if name.starts_with(&block_prefix) {
+                name[block_prefix.len()..].parse::<u32>().ok()
+            } else {
+                None
+            }
+        }) {
+            Some(index) => index,
+            None => continue,
+        };
+
+        let mut contents = fs::read(entry.path()).map_err(|err| ProtocolError::StorageError {
+            action: format!("read parity file {:?}", entry.path()),
+            err,
+        })?;
+        if contents.len() < 8 {
+            continue;
+        }
+
+        let data = contents.split_off(8);
+        let num_chunks = contents[0..4]
+            .iter()
+            .fold(0, |sum, &byte| (sum << 8) | u32::from(byte));
+        let last_size = contents[4..8]
+            .iter()
+            .fold(0, |sum, &byte| (sum << 8) | u32::from(byte));
+        headers.push((num_chunks, last_size));
+        parity.push((index, data));
+    }
+
+    let (num_chunks, last_size) = match headers.first() {
+        Some(&header) => header,
+        None => return Ok(vec![]),
+    };
+
+    // Every parity chunk has to describe the same block, and the block has to fit the file.
+    // Parity chunks which can't be trusted are thrown away, and the missing chunks
+    // are requested again instead
+    if let Err(error) = check_block(prefix, hash, first_chunk, &headers, &parity) {
+        for (index, _) in parity {
+            let _ = fs::remove_file(parity_path.join(format!("{}{}", block_prefix, index)));
+        }
+        return Err(error);
+    }
+    let end = first_chunk + num_chunks;
+
+    let missing: Vec<u32> = (first_chunk..end)
+        .filter(|index| !hash_path.join(format!("{}", index)).exists())
+        .collect();
+
+    if missing.len() > parity.len() {
+        // Wait for more parity chunks, or for the missing chunks to be sent again
+        return Ok(vec![]);
+    }
+
+    if !missing.is_empty() {
+        let mut data: Vec<Option<Vec<u8>>> = (first_chunk..end)
+            .map(|index| load_chunk(prefix, hash, index).ok())
+            .collect();
+
+        fec::decode(&mut data, &parity)?;
+
+        for &index in missing.iter() {
+            let offset = (index - first_chunk) as usize;
+            let mut chunk = data[offset].take().unwrap_or_default();
+            if offset + 1 == num_chunks as usize {
+                chunk.truncate(last_size as usize);
+            }
+            store_chunk(prefix, hash, index, &chunk, None)?;
+        }
+    }
+
+    // The block is complete, so its parity chunks aren't needed anymore
+    for (index, _) in parity {
+        let _ = fs::remove_file(parity_path.join(format!("{}{}", block_prefix, index)));
+    }
+
+    Ok(missing)
+}
+
+// Make sure the parity chunks stored for a block agree with each other and with the file.
+// The block's chunks all have to be within the file, and its last chunk can't be larger
+// than the parity chunks, or a different size from the copy we already have
+fn check_block(
+    prefix: &str,
+    hash: &str,
+    first_chunk: u32,
+    headers: &[(u32, u32)],
+    parity: &[(u32, Vec<u8>)],
+) -> Result<(), ProtocolError> {
+    let (num_chunks, last_size) = headers[0];
+    if headers.iter().any(|&header| header != (num_chunks, last_size)) {
+        return Err(ProtocolError::RecoveryError(format!(
+            "Parity chunks of block {} disagree about its size",
+            first_chunk
+        )));
+    }
+
+    let total = load_meta(prefix, hash)?;
+    let end = match first_chunk.checked_add(num_chunks) {
+        Some(end) if num_chunks > 0 && end <= total => end,
+        _ => {
+            return Err(ProtocolError::RecoveryError(format!(
+                "Block {} of {} chunks doesn't fit in a file of {} chunks",
+                first_chunk, num_chunks, total
+            )))
+        }
+    };
+
+    let size = parity[0].1.len();
+    if parity.iter().any(|(_, data)| data.len() != size) || last_size as usize > size {
+        return Err(ProtocolError::RecoveryError(format!(
+            "Parity chunks of block {} have inconsistent sizes",
+            first_chunk
+        )));
+    }
+
+    if let Ok(last) = load_chunk(prefix, hash, end - 1) {
+        if last.len() != last_size as usize {
+            return Err(ProtocolError::RecoveryError(format!(
+                "Parity chunks of block {} don't match the size of chunk {}",
+                first_chunk,
+                end - 1
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// Read a file's metadata
+fn read_meta(prefix: &str, hash: &str) -> Result<Value, ProtocolError> {
     let mut data = vec![];
-    let meta_path = Path::new(&format!("{}/storage", prefix))
-        .join(hash)
-        .join("meta");
+    let meta_path = hash_dir(prefix, hash)?.join("meta");
 
     File::open(meta_path)
         .map_err(|err| ProtocolError::StorageError {
@@ -131,9 +573,26 @@ pub fn load_meta(prefix: &str, hash: &str) -> Result<u32, ProtocolError> {
             err,
         })?;
 
-    let metadata: Value = de::from_slice(&data).map_err(|err| {
+    de::from_slice(&data).map_err(|err| {
         ProtocolError::StorageParseError(format!("Unable to parse metadata for {}: {}", hash, err))
-    })?;
+    })
+}
+
+// Find the value of one of the metadata's entries
+fn meta_entry<'a>(metadata: &'a Value, key: &str) -> Option<&'a Value> {
+    metadata.as_array().and_then(|data| {
+        data.iter()
+            .filter_map(|entry| entry.as_array())
+            .find(|entry| match entry.first() {
+                Some(Value::String(name)) => name == key,
+                _ => false,
+            }).and_then(|entry| entry.get(1))
+    })
+}
+
+// Load number of chunks in file from metadata
+pub fn load_meta(prefix: &str, hash: &str) -> Result<u32, ProtocolError> {
+    let metadata = read_meta(prefix, hash)?;
 
     // Returned data should be CBOR: '[["num_chunks", value]]'
     let num_chunks = metadata
@@ -159,6 +618,55 @@ pub fn load_meta(prefix: &str, hash: &str) -> Result<u32, ProtocolError> {
     Ok(num_chunks as u32)
 }
 
+// Load the compression codec used for the file's chunks from metadata
+pub fn load_compression(prefix: &str, hash: &str) -> Result<Compression, ProtocolError> {
+    let metadata = read_meta(prefix, hash)?;
+
+    // Metadata written before compression was supported won't have an entry for it,
+    // and so the chunks are uncompressed
+    let codec = meta_entry(&metadata, "compression")
+        .and_then(|val| val.as_string())
+        .cloned()
+        .unwrap_or("none".to_owned());
+
+    codec.parse()
+}
+
+// Whether the file's chunk files end with a checksum of their data.
+// Metadata written before chunks had checksums won't have an entry for it. The chunks stored
+// along with it don't have checksums, and neither do any more chunks stored for the same file.
+// Everything stored before there's any metadata is given a checksum
+fn chunks_have_checksums(prefix: &str, hash: &str) -> bool {
+    match read_meta(prefix, hash) {
+        Ok(metadata) => match meta_entry(&metadata, "checksums") {
+            Some(Value::Bool(checksums)) => *checksums,
+            _ => false,
+        },
+        Err(_) => true,
+    }
+}
+
+// Chunks stored using one compression codec can't be mixed with chunks stored using another,
+// so throw away anything already stored for the file if its codec is changing
+pub fn discard_if_compression_changed(
+    prefix: &str,
+    hash: &str,
+    compression: Compression,
+) -> Result<(), ProtocolError> {
+    match load_compression(prefix, hash) {
+        Ok(existing) if existing != compression => {
+            info!(
+                "Discarding stored chunks of {}: compression changed from {} to {}",
+                hash,
+                existing.name(),
+                compression.name()
+            );
+            delete_file(prefix, hash)
+        }
+        _ => Ok(()),
+    }
+}
+
 // Check if all of a files chunks are present in the temporary directory
 pub fn validate_file(
     prefix: &str,
@@ -166,7 +674,8 @@ pub fn validate_file(
     num_chunks: Option<u32>,
 ) -> Result<(bool, Vec<u32>), ProtocolError> {
     let num_chunks = if let Some(num) = num_chunks {
-        store_meta(prefix, hash, num)?;
+        let compression = load_compression(prefix, hash).unwrap_or_default();
+        store_meta(prefix, hash, num, compression)?;
         num
     } else {
         load_meta(prefix, hash)?
@@ -174,7 +683,7 @@ pub fn validate_file(
 
     let mut missing_ranges: Vec<u32> = vec![];
 
-    let hash_path = Path::new(&format!("{}/storage", prefix)).join(hash);
+    let hash_path = hash_dir(prefix, hash)?;
 
     let mut prev_entry: i32 = -1;
 
@@ -246,11 +755,27 @@ pub fn validate_file(
 /// Create temporary folder for chunks
 /// Stream copy file from mutable space to immutable space
 /// Move folder to hash of contents
+/// The hash covers the original contents, even if the chunks are compressed
 pub fn initialize_file(
     prefix: &str,
     source_path: &str,
     chunk_size: usize,
+    compression: Compression,
+    streaming: bool,
 ) -> Result<(String, u32, u32), ProtocolError> {
+    // A compressed stream can't be split at arbitrary points, so compressed
+    // files always need to be copied into storage
+    if streaming {
+        if compression == Compression::None {
+            return initialize_stream(prefix, source_path, chunk_size);
+        }
+        info!(
+            "Copying {} into storage: streaming isn't supported with {} compression",
+            source_path,
+            compression.name()
+        );
+    }
+
     let storage_path = format!("{}/storage", prefix);
 
     fs::metadata(source_path).map_err(|err| ProtocolError::StorageError {
@@ -283,7 +808,7 @@ pub fn initialize_file(
                 let chunk = reader
                     .fill_buf()
                     .map_err(|err| ProtocolError::StorageError {
-                        action: format!("read chunk from source"),
+                        action: "read chunk from source".to_owned(),
                         err,
                     })?;
                 if chunk.len() == 0 {
@@ -305,7 +830,6 @@ pub fn initialize_file(
                 chunk.len()
             };
             reader.consume(length);
-            thread::sleep(Duration::from_millis(2));
         }
     }
     let hash_result = hasher.finalize();
@@ -314,6 +838,16 @@ pub fn initialize_file(
         hash = format!("{}{:02x}", hash, c);
     }
 
+    if compression == Compression::Deflate {
+        compress_file(&temp_path)?;
+    }
+
+    discard_if_compression_changed(prefix, &hash, compression)?;
+
+    // The file might have been streamed before. The chunk files take precedence,
+    // but there's no reason to keep the stale source information around
+    delete_source(prefix, &hash);
+
     let mut output = File::open(&temp_path).map_err(|err| ProtocolError::StorageError {
         action: format!("open temp file {:?}", temp_path),
         err,
@@ -329,7 +863,7 @@ pub fn initialize_file(
                 if n == 0 {
                     break;
                 }
-                store_chunk(prefix, &hash, index, &chunk[0..n])?;
+                store_chunk(prefix, &hash, index, &chunk[0..n], None)?;
                 index = index + 1;
                 offset = offset + n;
             }
@@ -342,21 +876,537 @@ pub fn initialize_file(
         }
     }
 
-    store_meta(prefix, &hash, index)?;
+    store_meta(prefix, &hash, index, compression)?;
 
-    if let Ok(meta) = fs::metadata(source_path) {
-        Ok((hash, index, meta.mode()))
-    } else {
-        Ok((hash, index, 0o644))
+    // The chunks now hold everything we need
+    let _ = fs::remove_file(&temp_path);
+
+    let mode = match fs::metadata(source_path) {
+        Ok(meta) => meta.mode(),
+        Err(_) => 0o644,
+    };
+
+    store_transaction(
+        prefix,
+        &hash,
+        &Transaction::Transmitting {
+            path: source_path.to_owned(),
+            mode,
+        },
+    )?;
+
+    Ok((hash, index, mode))
+}
+
+// Prepare a file for transfer without copying it into storage.
+// The file is read once to calculate its hash and the checksum of each chunk.
+// Chunks are then read straight from the file as they are needed
+fn initialize_stream(
+    prefix: &str,
+    source_path: &str,
+    chunk_size: usize,
+) -> Result<(String, u32, u32), ProtocolError> {
+    let before = fs::metadata(source_path).map_err(|err| ProtocolError::StorageError {
+        action: format!("stat file {}", source_path),
+        err,
+    })?;
+
+    let mut hasher = Blake2s::new(HASH_SIZE);
+    let mut checksums: Vec<u32> = vec![];
+    {
+        let input = File::open(source_path).map_err(|err| ProtocolError::StorageError {
+            action: format!("open {:?}", source_path),
+            err,
+        })?;
+        let mut reader = BufReader::with_capacity(chunk_size * 2, input);
+        let mut crc = Crc::new();
+        let mut chunk_len = 0;
+
+        loop {
+            let length = {
+                let data = reader
+                    .fill_buf()
+                    .map_err(|err| ProtocolError::StorageError {
+                        action: "read chunk from source".to_owned(),
+                        err,
+                    })?;
+                if data.is_empty() {
+                    break;
+                }
+                hasher.update(data);
+
+                // Keep track of where each chunk ends, so we can record its checksum
+                let mut remaining = data;
+                while !remaining.is_empty() {
+                    let length = cmp::min(chunk_size - chunk_len, remaining.len());
+                    crc.update(&remaining[0..length]);
+                    chunk_len += length;
+                    remaining = &remaining[length..];
+
+                    if chunk_len == chunk_size {
+                        checksums.push(crc.sum());
+                        crc.reset();
+                        chunk_len = 0;
+                    }
+                }
+                data.len()
+            };
+            reader.consume(length);
+        }
+
+        if chunk_len > 0 {
+            checksums.push(crc.sum());
+        }
     }
+    let hash_result = hasher.finalize();
+    let mut hash = String::from("");
+    for c in hash_result.as_bytes().iter() {
+        hash = format!("{}{:02x}", hash, c);
+    }
+
+    let source = Source {
+        path: source_path.to_owned(),
+        size: before.len(),
+        mtime: modified_time(&before),
+        chunk_size: chunk_size as u64,
+    };
+
+    // Make sure nothing changed while we were reading the file
+    let after = fs::metadata(source_path).map_err(|err| ProtocolError::StorageError {
+        action: format!("stat file {}", source_path),
+        err,
+    })?;
+    if after.len() != source.size || modified_time(&after) != source.mtime {
+        return Err(ProtocolError::SourceChanged(source_path.to_owned()));
+    }
+
+    discard_if_compression_changed(prefix, &hash, Compression::None)?;
+
+    // Any chunk files left over from a previous copy may have been a different size
+    delete_chunks(prefix, &hash)?;
+
+    store_source(prefix, &hash, &source, &checksums)?;
+
+    let num_chunks = checksums.len() as u32;
+    store_meta(prefix, &hash, num_chunks, Compression::None)?;
+
+    let mode = before.mode();
+
+    store_transaction(
+        prefix,
+        &hash,
+        &Transaction::Transmitting {
+            path: source_path.to_owned(),
+            mode,
+        },
+    )?;
+
+    Ok((hash, num_chunks, mode))
+}
+
+// Modification time of a file, in nanoseconds since the UNIX epoch
+fn modified_time(meta: &fs::Metadata) -> u64 {
+    (meta.mtime() as u64)
+        .wrapping_mul(1_000_000_000)
+        .wrapping_add(meta.mtime_nsec() as u64)
+}
+
+// Save the location of a streamed file, along with the checksum of each of its chunks
+fn store_source(
+    prefix: &str,
+    hash: &str,
+    source: &Source,
+    checksums: &[u32],
+) -> Result<(), ProtocolError> {
+    let mut data = BTreeMap::new();
+    data.insert(
+        ObjectKey::String("path".to_owned()),
+        Value::String(source.path.to_owned()),
+    );
+    data.insert(
+        ObjectKey::String("size".to_owned()),
+        Value::U64(source.size),
+    );
+    data.insert(
+        ObjectKey::String("mtime".to_owned()),
+        Value::U64(source.mtime),
+    );
+    data.insert(
+        ObjectKey::String("chunk_size".to_owned()),
+        Value::U64(source.chunk_size),
+    );
+
+    let vec = to_vec(&data)?;
+
+    // The checksums are kept separately, so that a single one can be looked up
+    // without having to load all of them
+    let mut sums = Vec::with_capacity(checksums.len() * CHECKSUM_SIZE);
+    for checksum in checksums {
+        for i in 0..CHECKSUM_SIZE {
+            sums.push((checksum >> (8 * (CHECKSUM_SIZE - 1 - i))) as u8);
+        }
+    }
+
+    let file_dir = hash_dir(prefix, hash)?;
+    fs::create_dir_all(file_dir.clone()).map_err(|err| ProtocolError::StorageError {
+        action: "create temp storage directory".to_owned(),
+        err,
+    })?;
+
+    for (name, contents) in &[("checksums", &sums), ("source", &vec)] {
+        let final_path = file_dir.join(name);
+        let temp_path = file_dir.join(format!(".{}.tmp", name));
+
+        File::create(&temp_path)
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("create/open {:?} for writing", temp_path),
+                err,
+            })?.write_all(contents)
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("write {} to {:?}", name, temp_path),
+                err,
+            })?;
+
+        fs::rename(temp_path.clone(), final_path.clone()).map_err(|err| {
+            ProtocolError::StorageError {
+                action: format!("rename {:?} to {:?}", temp_path, final_path),
+                err,
+            }
+        })?;
+    }
+
+    Ok(())
+}
+
+// Load the location of a streamed file.
+// Returns `None` if the file's chunks were copied into storage instead
+fn load_source(prefix: &str, hash: &str) -> Result<Option<Source>, ProtocolError> {
+    let mut data = vec![];
+    let source_path = hash_dir(prefix, hash)?.join("source");
+
+    if !source_path.exists() {
+        return Ok(None);
+    }
+
+    File::open(source_path)
+        .map_err(|err| ProtocolError::StorageError {
+            action: format!("open {} source file", hash),
+            err,
+        })?.read_to_end(&mut data)
+        .map_err(|err| ProtocolError::StorageError {
+            action: format!("read {} source file", hash),
+            err,
+        })?;
+
+    let parse_err =
+        || ProtocolError::StorageParseError(format!("Failed to parse source file for {}", hash));
+
+    // Returned data should be CBOR:
+    // '{"path": value, "size": value, "mtime": value, "chunk_size": value}'
+    let source: Value = de::from_slice(&data).map_err(|_| parse_err())?;
+    let fields = source.as_object().ok_or_else(parse_err)?;
+    let number = |key: &str| {
+        fields
+            .get(&ObjectKey::String(key.to_owned()))
+            .and_then(|val| val.as_u64())
+            .ok_or_else(parse_err)
+    };
+
+    Ok(Some(Source {
+        path: fields
+            .get(&ObjectKey::String("path".to_owned()))
+            .and_then(|val| val.as_string())
+            .ok_or_else(parse_err)?
+            .to_owned(),
+        size: number("size")?,
+        mtime: number("mtime")?,
+        chunk_size: number("chunk_size")?,
+    }))
+}
+
+// Remove the location of a streamed file
+fn delete_source(prefix: &str, hash: &str) {
+    let file_dir = match hash_dir(prefix, hash) {
+        Ok(file_dir) => file_dir,
+        Err(_) => return,
+    };
+    let _ = fs::remove_file(file_dir.join("source"));
+    let _ = fs::remove_file(file_dir.join("checksums"));
+}
+
+// Check that a streamed file hasn't been changed since it was prepared
+fn check_source(source: &Source) -> Result<(), ProtocolError> {
+    let meta = fs::metadata(&source.path).map_err(|err| ProtocolError::StorageError {
+        action: format!("stat file {}", source.path),
+        err,
+    })?;
+
+    if meta.len() != source.size || modified_time(&meta) != source.mtime {
+        return Err(ProtocolError::SourceChanged(source.path.to_owned()));
+    }
+
+    Ok(())
+}
+
+// Read a single chunk of a streamed file
+fn load_source_chunk(
+    prefix: &str,
+    hash: &str,
+    source: &Source,
+    index: u32,
+) -> Result<Vec<u8>, ProtocolError> {
+    check_source(source)?;
+
+    let mut checksum = [0u8; CHECKSUM_SIZE];
+    let checksums_path = hash_dir(prefix, hash)?.join("checksums");
+
+    File::open(&checksums_path)
+        .and_then(|mut file| {
+            file.seek(SeekFrom::Start(u64::from(index) * CHECKSUM_SIZE as u64))?;
+            file.read_exact(&mut checksum)
+        }).map_err(|err| ProtocolError::StorageError {
+            action: format!("read checksum of chunk {}", index),
+            err,
+        })?;
+
+    let mut data = vec![];
+    File::open(&source.path)
+        .and_then(|mut file| {
+            file.seek(SeekFrom::Start(u64::from(index) * source.chunk_size))?;
+            file.take(source.chunk_size).read_to_end(&mut data)
+        }).map_err(|err| ProtocolError::StorageError {
+            action: format!("read chunk {} from {}", index, source.path),
+            err,
+        })?;
+
+    // The size and modification time can't catch every change, so
+    // double check that we read the same data that was there originally
+    let checksum = checksum
+        .iter()
+        .fold(0, |sum, &byte| (sum << 8) | u32::from(byte));
+    if checksum != chunk_checksum(&data) {
+        return Err(ProtocolError::SourceChanged(source.path.to_owned()));
+    }
+
+    Ok(data)
+}
+
+// Check whether we still have everything needed to send a file
+pub fn ready_to_transmit(prefix: &str, hash: &str) -> Result<bool, ProtocolError> {
+    match load_source(prefix, hash)? {
+        Some(source) => Ok(check_source(&source).is_ok()),
+        None => validate_file(prefix, hash, None).map(|(result, _)| result),
+    }
+}
+
+// Remove any chunk files from a file's storage directory, leaving the metadata intact
+fn delete_chunks(prefix: &str, hash: &str) -> Result<(), ProtocolError> {
+    let hash_path = hash_dir(prefix, hash)?;
+
+    let entries = match fs::read_dir(&hash_path) {
+        Ok(entries) => entries,
+        // Nothing to clean up
+        Err(_) => return Ok(()),
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let is_chunk = entry
+            .file_name()
+            .to_str()
+            .map(|name| name.parse::<u32>().is_ok())
+            .unwrap_or(false);
+
+        if is_chunk {
+            fs::remove_file(entry.path()).map_err(|err| ProtocolError::StorageError {
+                action: format!("delete chunk file {:?}", entry.path()),
+                err,
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+// Replace the contents of a file with a deflate stream of those contents
+fn compress_file(path: &Path) -> Result<(), ProtocolError> {
+    let compressed_path = path.with_extension("deflate");
+    {
+        let mut input = File::open(path).map_err(|err| ProtocolError::StorageError {
+            action: format!("open {:?}", path),
+            err,
+        })?;
+        let output = File::create(&compressed_path).map_err(|err| ProtocolError::StorageError {
+            action: format!("create/open {:?} for writing", compressed_path),
+            err,
+        })?;
+
+        let mut encoder = DeflateEncoder::new(output, Level::default());
+        io::copy(&mut input, &mut encoder)
+            .and_then(|_| encoder.finish())
+            .and_then(|output| output.sync_all())
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("compress {:?}", path),
+                err,
+            })?;
+    }
+
+    fs::rename(&compressed_path, path).map_err(|err| ProtocolError::StorageError {
+        action: format!("rename {:?} to {:?}", compressed_path, path),
+        err,
+    })
+}
+
+/// Write a directory manifest into a temporary file and prepare it for transfer
+pub fn initialize_manifest(
+    prefix: &str,
+    entries: &[ManifestEntry],
+    chunk_size: usize,
+    compression: Compression,
+) -> Result<(String, u32, u32), ProtocolError> {
+    let storage_path = format!("{}/storage", prefix);
+
+    fs::create_dir_all(&storage_path).map_err(|err| ProtocolError::StorageError {
+        action: format!("create dir {}", storage_path),
+        err,
+    })?;
+
+    let temp_path = Path::new(&storage_path).join(format!(".{}.manifest", time::get_time().nsec));
+    manifest::store(&temp_path, entries)?;
+
+    // The manifest is a temporary file, so it can't be streamed
+    let result = initialize_file(
+        prefix,
+        &temp_path.to_string_lossy(),
+        chunk_size,
+        compression,
+        false,
+    );
+
+    // The chunks now hold everything we need
+    let _ = fs::remove_file(&temp_path);
+
+    result
+}
+
+// Set up part of a file for transmission
+//
+// The requested byte range is copied out into its own file, so the hash and
+// chunks cover only the range. If no length is given, the range runs to the end of the file
+pub fn initialize_range(
+    prefix: &str,
+    source_path: &str,
+    offset: u64,
+    length: Option<u64>,
+    chunk_size: usize,
+    compression: Compression,
+) -> Result<(String, u32, u32), ProtocolError> {
+    let storage_path = format!("{}/storage", prefix);
+
+    let meta = fs::metadata(source_path).map_err(|err| ProtocolError::StorageError {
+        action: format!("stat file {}", source_path),
+        err,
+    })?;
+
+    if offset > meta.len() {
+        return Err(ProtocolError::StorageParseError(format!(
+            "Offset {} is past the end of {} ({} bytes)",
+            offset,
+            source_path,
+            meta.len()
+        )));
+    }
+
+    fs::create_dir_all(&storage_path).map_err(|err| ProtocolError::StorageError {
+        action: format!("create dir {}", storage_path),
+        err,
+    })?;
+
+    let temp_path = Path::new(&storage_path).join(format!(".{}.range", time::get_time().nsec));
+    {
+        let mut input = File::open(source_path).map_err(|err| ProtocolError::StorageError {
+            action: format!("open {}", source_path),
+            err,
+        })?;
+        input
+            .seek(SeekFrom::Start(offset))
+            .map_err(|err| ProtocolError::StorageError {
+                action: format!("seek to {} in {}", offset, source_path),
+                err,
+            })?;
+        let mut output = File::create(&temp_path).map_err(|err| ProtocolError::StorageError {
+            action: format!("create/open {:?} for writing", temp_path),
+            err,
+        })?;
+
+        io::copy(
+            &mut input.take(length.unwrap_or(u64::MAX)),
+            &mut output,
+        ).map_err(|err| ProtocolError::StorageError {
+            action: format!("copy range of {} to {:?}", source_path, temp_path),
+            err,
+        })?;
+    }
+
+    // The range is a temporary file, so it can't be streamed
+    let result = initialize_file(
+        prefix,
+        &temp_path.to_string_lossy(),
+        chunk_size,
+        compression,
+        false,
+    );
+
+    // The chunks now hold everything we need
+    let _ = fs::remove_file(&temp_path);
+
+    let (hash, num_chunks, _) = result?;
+
+    // Report the original file, rather than the temporary copy
+    store_transaction(
+        prefix,
+        &hash,
+        &Transaction::Transmitting {
+            path: source_path.to_owned(),
+            mode: meta.mode(),
+        },
+    )?;
+
+    Ok((hash, num_chunks, meta.mode()))
+}
+
+// Re-assemble a received directory manifest and recreate its directory tree at the target path
+pub fn finalize_directory(
+    prefix: &str,
+    hash: &str,
+    target_path: &str,
+    write_roots: &[PathBuf],
+) -> Result<(), ProtocolError> {
+    check_hash(hash)?;
+    let temp_path = Path::new(&format!("{}/storage", prefix)).join(format!(".{}.manifest", hash));
+
+    // The manifest stays in temporary storage, which isn't up to the client
+    finalize_file(prefix, hash, &temp_path.to_string_lossy(), None, &[])?;
+
+    let entries = manifest::load(&temp_path);
+    let _ = fs::remove_file(&temp_path);
+    let entries = entries?;
+
+    // Links inside the tree could lead anywhere
+    for entry in entries.iter() {
+        sandbox::check(&manifest::entry_path(target_path, entry)?, write_roots)?;
+    }
+
+    manifest::create_directories(target_path, &entries)
 }
 
 // Copy temporary data chunks into permanent file?
+// The file has to end up beneath one of `write_roots`, if any are given
 pub fn finalize_file(
     prefix: &str,
     hash: &str,
     target_path: &str,
     mode: Option<u32>,
+    write_roots: &[PathBuf],
 ) -> Result<(), ProtocolError> {
     // Double check that all the chunks of the file are present and the hash matches up
     let (result, _) = validate_file(prefix, hash, None)?;
@@ -367,29 +1417,107 @@ pub fn finalize_file(
         });
     }
 
-    // Get the total number of chunks we're saving
-    let num_chunks = load_meta(prefix, hash)?;
-
+    // The file is put together next to the target, so that anything already at the
+    // target is left alone unless the whole file comes out intact.
     // Q: Do we want to create the parent directories if they don't exist?
-    let mut file = File::create(target_path).map_err(|err| ProtocolError::StorageError {
-        action: format!("create/open file for writing {}", target_path),
-        err,
-    })?;
+    // The partial file has a name no one can guess, and is never opened through a link,
+    // so nothing already in the directory can redirect what we write
+    check_target_dir(target_path, write_roots)?;
+    let partial_path = partial_path(target_path);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(&partial_path)
+        .map_err(|err| ProtocolError::StorageError {
+            action: format!("create/open file for writing {}", target_path),
+            err,
+        })?;
 
-    if let Some(mode_val) = mode {
-        file.set_permissions(Permissions::from_mode(mode_val))
-            .map_err(|err| ProtocolError::StorageError {
-                action: format!("set target file's mode"),
+    // Without a mode to use, keep the one the target already has
+    let mode = mode.or_else(|| {
+        fs::metadata(target_path)
+            .ok()
+            .map(|meta| meta.permissions().mode())
+    });
+    let result = match mode {
+        Some(mode_val) => file.set_permissions(Permissions::from_mode(mode_val)).map_err(
+            |err| ProtocolError::StorageError {
+                action: "set target file's mode".to_owned(),
                 err,
-            })?;
+            },
+        ),
+        None => Ok(()),
     }
+    .and_then(|()| write_file(prefix, hash, &mut file));
+    drop(file);
+
+    // The directory may have been swapped for a link while the file was being written
+    let result = result.and_then(|()| check_target_dir(target_path, write_roots));
+
+    match result {
+        Ok(()) => fs::rename(&partial_path, target_path).map_err(|err| {
+            let _ = fs::remove_file(&partial_path);
+            ProtocolError::StorageError {
+                action: format!("move finished file to {}", target_path),
+                err,
+            }
+        }),
+        Err(e) => {
+            let _ = fs::remove_file(&partial_path);
+            Err(e)
+        }
+    }
+}
+
+// Where a file is put together before being moved to `target_path`.
+// It's in the same directory, so that the move can't leave the target half-written
+fn partial_path(target_path: &str) -> PathBuf {
+    let target = Path::new(target_path);
+    let suffix: u32 = rand::thread_rng().gen();
+    match target.file_name() {
+        Some(name) => target.with_file_name(format!(
+            ".{}.{:08x}.partial",
+            name.to_string_lossy(),
+            suffix
+        )),
+        None => PathBuf::from(format!("{}.{:08x}.partial", target_path, suffix)),
+    }
+}
+
+// Make sure the directory a file is going into is somewhere it's allowed to go
+fn check_target_dir(target_path: &str, write_roots: &[PathBuf]) -> Result<(), ProtocolError> {
+    let parent = match Path::new(target_path).parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+    sandbox::check(parent, write_roots)
+}
+
+// Write out the contents of a fully-received file, making sure they match its hash
+fn write_file(prefix: &str, hash: &str, file: &mut File) -> Result<(), ProtocolError> {
+    // Get the total number of chunks we're saving
+    let num_chunks = load_meta(prefix, hash)?;
+
+    // Compressed chunks are fed through a decoder, which hands back the original data
+    let mut decoder = match load_compression(prefix, hash)? {
+        Compression::None => None,
+        Compression::Deflate => Some(DeflateDecoder::new(vec![])),
+    };
 
     let mut calc_hash = Blake2s::new(HASH_SIZE);
 
     let mut load_chunk_err = None;
+    let mut corrupt_chunks = vec![];
     for chunk_num in 0..num_chunks {
         let chunk = match load_chunk(prefix, hash, chunk_num) {
             Ok(c) => c,
+            Err(ProtocolError::CorruptChunks(_)) => {
+                warn!("Chunk {} of {} is corrupt, deleting", chunk_num, hash);
+                delete_chunk(prefix, hash, chunk_num)?;
+                corrupt_chunks.push(chunk_num);
+                continue;
+            }
             Err(e) => {
                 warn!(
                     "Error encountered loading chunk {}, deleting : {}",
@@ -401,10 +1529,29 @@ pub fn finalize_file(
             }
         };
 
+        // Once a chunk has been lost, keep checking the rest of the chunks
+        // so that they can all be re-requested at once
+        if load_chunk_err.is_some() || !corrupt_chunks.is_empty() {
+            continue;
+        }
+
+        let data = match decoder {
+            Some(ref mut decoder) => {
+                decoder
+                    .write_all(&chunk)
+                    .map_err(|err| ProtocolError::StorageError {
+                        action: format!("decompress chunk {}", chunk_num),
+                        err,
+                    })?;
+                decoder.get_mut().split_off(0)
+            }
+            None => chunk,
+        };
+
         // Update our verification hash
-        calc_hash.update(&chunk);
+        calc_hash.update(&data);
         // Write the chunk to the destination file
-        file.write_all(&chunk)
+        file.write_all(&data)
             .map_err(|err| ProtocolError::StorageError {
                 action: format!("write chunk {}", chunk_num),
                 err,
@@ -415,6 +1562,27 @@ pub fn finalize_file(
         return Err(e);
     }
 
+    if !corrupt_chunks.is_empty() {
+        return Err(ProtocolError::CorruptChunks(corrupt_chunks));
+    }
+
+    // Flush out whatever the decoder was still holding on to
+    if let Some(decoder) = decoder {
+        let data = decoder
+            .finish()
+            .map_err(|err| ProtocolError::StorageError {
+                action: "decompress file".to_owned(),
+                err,
+            })?;
+
+        calc_hash.update(&data);
+        file.write_all(&data)
+            .map_err(|err| ProtocolError::StorageError {
+                action: "write decompressed data".to_owned(),
+                err,
+            })?;
+    }
+
     let calc_hash_str = calc_hash
         .finalize()
         .as_bytes()
@@ -434,9 +1602,7 @@ pub fn finalize_file(
 }
 
 pub fn delete_chunk(prefix: &str, hash: &str, index: u32) -> Result<(), ProtocolError> {
-    let path = Path::new(&format!("{}/storage", prefix))
-        .join(hash)
-        .join(format!("{}", index));
+    let path = hash_dir(prefix, hash)?.join(format!("{}", index));
 
     fs::remove_file(path).map_err(|err| ProtocolError::StorageError {
         action: format!("deleting chunk file {}", index),
@@ -447,7 +1613,7 @@ pub fn delete_chunk(prefix: &str, hash: &str, index: u32) -> Result<(), Protocol
 }
 
 pub fn delete_file(prefix: &str, hash: &str) -> Result<(), ProtocolError> {
-    let path = Path::new(&format!("{}/storage", prefix)).join(hash);
+    let path = hash_dir(prefix, hash)?;
 
     fs::remove_dir_all(path).map_err(|err| ProtocolError::StorageError {
         action: format!("deleting file {}", hash),
@@ -456,3 +1622,107 @@ pub fn delete_file(prefix: &str, hash: &str) -> Result<(), ProtocolError> {
 
     Ok(())
 }
+
+// Total size of a file, or of everything within a directory
+fn disk_usage(path: &Path) -> io::Result<u64> {
+    let meta = fs::symlink_metadata(path)?;
+    if !meta.is_dir() {
+        return Ok(meta.len());
+    }
+
+    let mut total = 0;
+    for entry in fs::read_dir(path)? {
+        total += disk_usage(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+// Most recent time that a file, or anything within a directory, was modified
+fn last_modified(path: &Path) -> io::Result<SystemTime> {
+    let meta = fs::symlink_metadata(path)?;
+    let mut newest = meta.modified()?;
+    if meta.is_dir() {
+        for entry in fs::read_dir(path)? {
+            newest = cmp::max(newest, last_modified(&entry?.path())?);
+        }
+    }
+    Ok(newest)
+}
+
+/// Total size of everything in temporary storage, in bytes
+pub fn storage_usage(prefix: &str) -> Result<u64, ProtocolError> {
+    let path = PathBuf::from(format!("{}/storage", prefix));
+    if !path.exists() {
+        return Ok(0);
+    }
+
+    disk_usage(&path).map_err(|err| ProtocolError::StorageError {
+        action: format!("measure {:?}", path),
+        err,
+    })
+}
+
+/// Size of a file's temporary storage, in bytes. Zero if there isn't any
+pub fn file_usage(prefix: &str, hash: &str) -> u64 {
+    hash_dir(prefix, hash)
+        .map(|path| disk_usage(&path).unwrap_or(0))
+        .unwrap_or(0)
+}
+
+/// Delete anything in temporary storage which hasn't been modified for at least `max_age`
+///
+/// This cleans up after transfers which failed or were abandoned. The storage of the
+/// files in `keep` is left alone, regardless of its age.
+///
+/// Returns the names of the deleted entries
+pub fn collect_garbage(
+    prefix: &str,
+    max_age: Duration,
+    keep: &[String],
+) -> Result<Vec<String>, ProtocolError> {
+    let storage_path = PathBuf::from(format!("{}/storage", prefix));
+    if !storage_path.exists() {
+        return Ok(vec![]);
+    }
+
+    let entries = fs::read_dir(&storage_path).map_err(|err| ProtocolError::StorageError {
+        action: format!("read {:?} directory", storage_path),
+        err,
+    })?;
+
+    let now = SystemTime::now();
+    let mut deleted = vec![];
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if keep.contains(&name) {
+            continue;
+        }
+
+        // Anything which is being written to right now is still in use.
+        // Entries which disappear while we're looking at them are already gone
+        let path = entry.path();
+        let stale = match last_modified(&path) {
+            Ok(modified) => now
+                .duration_since(modified)
+                .map(|age| age >= max_age)
+                .unwrap_or(false),
+            Err(_) => false,
+        };
+        if !stale {
+            continue;
+        }
+
+        let result = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+
+        match result {
+            Ok(()) => deleted.push(name),
+            Err(err) => warn!("Failed to delete stale storage {:?}: {}", path, err),
+        }
+    }
+
+    Ok(deleted)
+}
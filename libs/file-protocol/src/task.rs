@@ -0,0 +1,163 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Storage work which can take a long time for large files
+
+use compression::Compression;
+use error::ProtocolError;
+use manifest;
+use std::path::PathBuf;
+use storage;
+
+/// A slow piece of storage work which a transaction is waiting on
+///
+/// Tasks don't need anything from the protocol instance which started them,
+/// so they can be run on another thread while other transactions carry on.
+/// The result should be handed back with `Protocol::finish_task`
+#[derive(Clone, Debug)]
+pub enum Task {
+    /// Copy all or part of a file into temporary storage, ready to be sent
+    Prepare {
+        /// Temporary storage directory prefix
+        prefix: String,
+        /// File to send
+        path: String,
+        /// Size of each chunk
+        chunk_size: usize,
+        /// Codec to compress the chunks with
+        compression: Compression,
+        /// Whether the file may be read from where it is rather than copied
+        streaming: bool,
+        /// Where the part of the file to send starts
+        offset: u64,
+        /// How much of the file to send. `None` sends the rest of it
+        length: Option<u64>,
+    },
+    /// Put together the manifest of a directory, ready to be sent
+    PrepareDirectory {
+        /// Temporary storage directory prefix
+        prefix: String,
+        /// Directory to send
+        path: String,
+        /// Size of each chunk
+        chunk_size: usize,
+        /// Codec to compress the chunks with
+        compression: Compression,
+    },
+    /// Put a received file back together at its destination
+    Finalize {
+        /// Temporary storage directory prefix
+        prefix: String,
+        /// Hash of the received file
+        hash: String,
+        /// Where the file should go
+        path: String,
+        /// Mode to give the file
+        mode: Option<u32>,
+        /// Whether the file is a directory manifest whose tree should be recreated at `path`
+        directory: bool,
+        /// Directories which the file, or the directory tree, may be written to
+        write_roots: Vec<PathBuf>,
+    },
+}
+
+/// What a task produced
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TaskOutput {
+    /// The file is ready to be sent
+    Prepared {
+        /// Hash of the file's contents
+        hash: String,
+        /// Number of chunks the file was split into
+        num_chunks: u32,
+        /// Mode of the original file
+        mode: u32,
+    },
+    /// The received file is in place
+    Finalized,
+}
+
+impl Task {
+    /// Carry out the task
+    ///
+    /// # Errors
+    ///
+    /// If this function encounters any errors, it will return an error message string
+    pub fn run(&self) -> Result<TaskOutput, ProtocolError> {
+        match self {
+            Task::Prepare {
+                prefix,
+                path,
+                chunk_size,
+                compression,
+                streaming,
+                offset,
+                length,
+            } => {
+                let (hash, num_chunks, mode) = if *offset == 0 && length.is_none() {
+                    storage::initialize_file(prefix, path, *chunk_size, *compression, *streaming)?
+                } else {
+                    storage::initialize_range(
+                        prefix,
+                        path,
+                        *offset,
+                        *length,
+                        *chunk_size,
+                        *compression,
+                    )?
+                };
+                Ok(TaskOutput::Prepared {
+                    hash,
+                    num_chunks,
+                    mode,
+                })
+            }
+            Task::PrepareDirectory {
+                prefix,
+                path,
+                chunk_size,
+                compression,
+            } => {
+                let entries = manifest::create(path)?;
+                let (hash, num_chunks, _mode) =
+                    storage::initialize_manifest(prefix, &entries, *chunk_size, *compression)?;
+                // The manifest is only ever read, so it doesn't need to carry
+                // a meaningful mode
+                Ok(TaskOutput::Prepared {
+                    hash,
+                    num_chunks,
+                    mode: 0o644,
+                })
+            }
+            Task::Finalize {
+                prefix,
+                hash,
+                path,
+                mode,
+                directory,
+                write_roots,
+            } => {
+                if *directory {
+                    storage::finalize_directory(prefix, hash, path, write_roots)?;
+                } else {
+                    storage::finalize_file(prefix, hash, path, *mode, write_roots)?;
+                }
+                storage::delete_transaction(prefix, hash)?;
+                Ok(TaskOutput::Finalized)
+            }
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+//! Information about the transactions being handled by a remote target
+
+use serde_cbor::Value;
+
+/// Direction of a file transfer, from the point of view of the client
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// The client is sending a file to the remote target
+    Upload,
+    /// The client is receiving a file from the remote target
+    Download,
+    /// Not a file transfer (ex. a file system operation), or not yet known
+    Other,
+}
+
+impl Direction {
+    /// The name used for the direction in messages
+    pub fn name(&self) -> &'static str {
+        match self {
+            Direction::Upload => "upload",
+            Direction::Download => "download",
+            Direction::Other => "other",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "upload" => Some(Direction::Upload),
+            "download" => Some(Direction::Download),
+            "other" => Some(Direction::Other),
+            _ => None,
+        }
+    }
+}
+
+/// Information about a single transaction
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TransactionInfo {
+    /// Channel the transaction is using
+    pub channel_id: u32,
+    /// Direction of the file transfer
+    pub direction: Direction,
+    /// Path of the file on the remote target, if known
+    pub path: String,
+    /// Hash of the file being transferred, if known
+    pub hash: String,
+    /// Number of chunks in the file being transferred, if known
+    pub num_chunks: u32,
+    /// Number of chunks which have been transferred
+    pub chunks_done: u32,
+    /// Seconds since the transaction started. For finished transactions, how long
+    /// the transaction took
+    pub age: u64,
+    /// Why the transaction failed or was cancelled
+    pub error: Option<String>,
+}
+
+impl TransactionInfo {
+    /// Create a record of a newly started transaction
+    pub fn new(channel_id: u32) -> Self {
+        TransactionInfo {
+            channel_id,
+            direction: Direction::Other,
+            path: String::new(),
+            hash: String::new(),
+            num_chunks: 0,
+            chunks_done: 0,
+            age: 0,
+            error: None,
+        }
+    }
+
+    // Messages carry transaction information as
+    // `[ channel_id, direction, path, hash, num_chunks, chunks_done, age, error ]`,
+    // where `error` is null if there wasn't one
+    pub(crate) fn to_value(&self) -> Value {
+        Value::Array(vec![
+            Value::U64(self.channel_id.into()),
+            Value::String(self.direction.name().to_owned()),
+            Value::String(self.path.clone()),
+            Value::String(self.hash.clone()),
+            Value::U64(self.num_chunks.into()),
+            Value::U64(self.chunks_done.into()),
+            Value::U64(self.age),
+            match self.error {
+                Some(ref error) => Value::String(error.clone()),
+                None => Value::Null,
+            },
+        ])
+    }
+
+    pub(crate) fn from_value(value: &Value) -> Option<Self> {
+        let fields = value.as_array()?;
+        if fields.len() != 8 {
+            return None;
+        }
+
+        let error = match fields[7] {
+            Value::String(ref error) => Some(error.to_owned()),
+            Value::Null => None,
+            _ => return None,
+        };
+
+        match (
+            &fields[0], &fields[1], &fields[2], &fields[3], &fields[4], &fields[5], &fields[6],
+        ) {
+            (
+                Value::U64(channel_id),
+                Value::String(direction),
+                Value::String(path),
+                Value::String(hash),
+                Value::U64(num_chunks),
+                Value::U64(chunks_done),
+                Value::U64(age),
+            ) => Some(TransactionInfo {
+                channel_id: *channel_id as u32,
+                direction: Direction::from_name(direction)?,
+                path: path.to_owned(),
+                hash: hash.to_owned(),
+                num_chunks: *num_chunks as u32,
+                chunks_done: *chunks_done as u32,
+                age: *age,
+                error,
+            }),
+            _ => None,
+        }
+    }
+}
@@ -16,6 +16,7 @@
 
 use cbor_protocol;
 use channel_protocol;
+use serde_cbor;
 use std::io;
 
 /// Errors which occur when using ShellProtocol
@@ -32,12 +33,28 @@ pub enum ProtocolError {
         /// The specific channel protocol error
         err: channel_protocol::ProtocolError,
     },
+    /// An error was encountered when creating a message
+    #[fail(display = "Failed to create {} message: {}", message, err)]
+    MessageCreationError {
+        /// Message which failed to be created
+        message: String,
+        /// Underlying serialization error
+        err: serde_cbor::error::Error,
+    },
     /// A general error was encountered when parsing a message
     #[fail(display = "Unable to parse message: {}", err)]
     MessageParseError {
         /// Underlying error encountered
         err: String,
     },
+    /// A spawn option had an invalid value
+    #[fail(display = "Invalid spawn option {}: {}", option, reason)]
+    InvalidOption {
+        /// Name of the option
+        option: String,
+        /// What was wrong with it
+        reason: String,
+    },
     /// A timeout occurred when receiving data
     #[fail(display = "A receive timeout was encountered")]
     ReceiveTimeout,
@@ -54,6 +71,41 @@ pub enum ProtocolError {
         /// Underlying error
         err: io::Error,
     },
+    /// A process was already running when another was asked for
+    #[fail(display = "A process is already running on channel {}", channel_id)]
+    AlreadyRunning {
+        /// Channel the process is running on
+        channel_id: u32,
+    },
+    /// A request was made for a process which isn't running
+    #[fail(display = "No process is running on channel {}", channel_id)]
+    NoProcess {
+        /// Channel the request was made on
+        channel_id: u32,
+    },
+    /// A client asked for a process to be sent a signal which isn't allowed
+    #[fail(display = "Signal {} is not allowed", signal)]
+    InvalidSignal {
+        /// The signal number
+        signal: u32,
+    },
+    /// A client asked to signal a process which was spawned for a client on another host
+    #[fail(
+        display = "The process on channel {} was spawned by another client",
+        channel_id
+    )]
+    NotOwner {
+        /// Channel the process is running on
+        channel_id: u32,
+    },
+    /// An error was encountered when managing a spawned process
+    #[fail(display = "Error {} process: {}", action, err)]
+    ProcessError {
+        /// What was being done to the process
+        action: String,
+        /// Underlying error
+        err: io::Error,
+    },
 }
 
 impl From<cbor_protocol::ProtocolError> for ProtocolError {
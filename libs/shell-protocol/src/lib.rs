@@ -18,6 +18,7 @@ extern crate cbor_protocol;
 extern crate channel_protocol;
 #[macro_use]
 extern crate failure;
+extern crate libc;
 #[macro_use]
 extern crate log;
 extern crate rand;
@@ -25,7 +26,13 @@ extern crate serde_cbor;
 
 pub mod error;
 pub mod messages;
+mod process;
+mod processes;
 mod protocol;
+mod pty;
 
 pub use error::ProtocolError;
+pub use messages::{ProcessInfo, SpawnOptions};
+pub use processes::ProcessList;
 pub use protocol::Protocol as ShellProtocol;
+pub use protocol::PROTOCOL_VERSION;
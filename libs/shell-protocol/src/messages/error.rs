@@ -0,0 +1,70 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use super::*;
+use channel_protocol::ChannelMessage;
+use error::ProtocolError;
+use serde_cbor::ser;
+
+/// CBOR -> Message::Error
+pub fn from_cbor(message: &ChannelMessage) -> Result<Message, ProtocolError> {
+    let message_text = match message.payload.first() {
+        Some(Value::String(text)) => text.to_owned(),
+        _ => {
+            return Err(ProtocolError::MessageParseError {
+                err: "No error message found".to_owned(),
+            })
+        }
+    };
+
+    Ok(Message::Error {
+        channel_id: message.channel_id,
+        message: message_text,
+    })
+}
+
+/// Error -> CBOR
+pub fn to_cbor(channel_id: u32, message: &str) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ {}, error, {} }}", channel_id, message);
+
+    ser::to_vec_packed(&(channel_id, "error", message)).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "error".to_owned(),
+            err,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channel_protocol::parse_message;
+    use serde_cbor::de;
+
+    #[test]
+    fn create_parse_error_message() {
+        let raw = to_cbor(10, "Invalid spawn option cwd: /nowhere is not a directory").unwrap();
+        let parsed = parse_message(de::from_slice(&raw).unwrap()).unwrap();
+
+        assert_eq!(
+            from_cbor(&parsed).unwrap(),
+            Message::Error {
+                channel_id: 10,
+                message: "Invalid spawn option cwd: /nowhere is not a directory".to_owned(),
+            }
+        );
+    }
+}
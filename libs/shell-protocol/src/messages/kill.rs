@@ -0,0 +1,94 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use super::*;
+use channel_protocol::ChannelMessage;
+use error::ProtocolError;
+use serde_cbor::ser;
+
+/// CBOR -> Message::Kill
+///
+/// A message without a signal number asks for the process to be sent `SIGTERM`
+pub fn from_cbor(message: &ChannelMessage) -> Result<Message, ProtocolError> {
+    let signal = match message.payload.first() {
+        Some(Value::U64(signal)) => Some(*signal as u32),
+        None => None,
+        _ => {
+            return Err(ProtocolError::MessageParseError {
+                err: "Invalid kill signal".to_owned(),
+            })
+        }
+    };
+
+    Ok(Message::Kill {
+        channel_id: message.channel_id,
+        signal,
+    })
+}
+
+/// Kill -> CBOR
+pub fn to_cbor(channel_id: u32, signal: Option<u32>) -> Result<Vec<u8>, ProtocolError> {
+    let result = match signal {
+        Some(signal) => {
+            info!("-> {{ {}, kill, {} }}", channel_id, signal);
+            ser::to_vec_packed(&(channel_id, "kill", signal))
+        }
+        None => {
+            info!("-> {{ {}, kill }}", channel_id);
+            ser::to_vec_packed(&(channel_id, "kill"))
+        }
+    };
+
+    result.map_err(|err| ProtocolError::MessageCreationError {
+        message: "kill".to_owned(),
+        err,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channel_protocol::parse_message;
+    use serde_cbor::de;
+
+    #[test]
+    fn create_parse_kill_signal() {
+        let raw = to_cbor(10, Some(9)).unwrap();
+        let parsed = parse_message(de::from_slice(&raw).unwrap()).unwrap();
+
+        assert_eq!(
+            from_cbor(&parsed).unwrap(),
+            Message::Kill {
+                channel_id: 10,
+                signal: Some(9),
+            }
+        );
+    }
+
+    #[test]
+    fn create_parse_kill_default() {
+        let raw = to_cbor(10, None).unwrap();
+        let parsed = parse_message(de::from_slice(&raw).unwrap()).unwrap();
+
+        assert_eq!(
+            from_cbor(&parsed).unwrap(),
+            Message::Kill {
+                channel_id: 10,
+                signal: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use super::*;
+use channel_protocol::ChannelMessage;
+use error::ProtocolError;
+use serde_cbor::{ser, ObjectKey};
+use std::collections::BTreeMap;
+
+/// Information about one of the shell service's processes
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProcessInfo {
+    /// Command which was spawned
+    pub path: String,
+    /// Process ID
+    pub pid: u32,
+    /// Number of seconds since the process was spawned
+    pub run_time: u64,
+    /// What the process is currently doing, for example "running", "sleeping" or "stopped"
+    pub state: String,
+}
+
+impl ProcessInfo {
+    fn to_value(&self) -> Value {
+        let mut info = BTreeMap::new();
+        info.insert(
+            ObjectKey::String("path".to_owned()),
+            Value::String(self.path.to_owned()),
+        );
+        info.insert(
+            ObjectKey::String("pid".to_owned()),
+            Value::U64(u64::from(self.pid)),
+        );
+        info.insert(
+            ObjectKey::String("run_time".to_owned()),
+            Value::U64(self.run_time),
+        );
+        info.insert(
+            ObjectKey::String("state".to_owned()),
+            Value::String(self.state.to_owned()),
+        );
+        Value::Object(info)
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        let info = match value {
+            Value::Object(info) => info,
+            _ => return None,
+        };
+        let get = |name: &str| info.get(&ObjectKey::String(name.to_owned()));
+
+        Some(ProcessInfo {
+            path: get("path")?.as_string()?.to_owned(),
+            pid: get("pid")?.as_u64()? as u32,
+            // Older services only report the path and pid
+            run_time: get("run_time")
+                .and_then(|value| value.as_u64())
+                .unwrap_or(0),
+            state: get("state")
+                .and_then(|value| value.as_string())
+                .map(|state| state.to_owned())
+                .unwrap_or_else(|| "unknown".to_owned()),
+        })
+    }
+}
+
+/// CBOR -> Message::List
+///
+/// A message without any processes is a request for the list
+pub fn from_cbor(message: &ChannelMessage) -> Result<Message, ProtocolError> {
+    let processes = match message.payload.first() {
+        Some(Value::Object(raw_processes)) => {
+            let mut processes = BTreeMap::new();
+            for (key, value) in raw_processes {
+                match (key, ProcessInfo::from_value(value)) {
+                    (ObjectKey::Integer(channel_id), Some(info)) => {
+                        processes.insert(*channel_id as u32, info);
+                    }
+                    _ => {
+                        return Err(ProtocolError::MessageParseError {
+                            err: "Invalid process list entry".to_owned(),
+                        })
+                    }
+                }
+            }
+            Some(processes)
+        }
+        None => None,
+        _ => {
+            return Err(ProtocolError::MessageParseError {
+                err: "Invalid process list".to_owned(),
+            })
+        }
+    };
+
+    Ok(Message::List {
+        channel_id: message.channel_id,
+        processes,
+    })
+}
+
+/// List -> CBOR
+pub fn to_cbor(
+    channel_id: u32,
+    processes: Option<&BTreeMap<u32, ProcessInfo>>,
+) -> Result<Vec<u8>, ProtocolError> {
+    let result = match processes {
+        Some(processes) => {
+            info!(
+                "-> {{ {}, list, {} processes }}",
+                channel_id,
+                processes.len()
+            );
+            let raw_processes: BTreeMap<ObjectKey, Value> = processes
+                .iter()
+                .map(|(channel, info)| (ObjectKey::Integer(i64::from(*channel)), info.to_value()))
+                .collect();
+            ser::to_vec_packed(&(channel_id, "list", raw_processes))
+        }
+        None => {
+            info!("-> {{ {}, list }}", channel_id);
+            ser::to_vec_packed(&(channel_id, "list"))
+        }
+    };
+
+    result.map_err(|err| ProtocolError::MessageCreationError {
+        message: "list".to_owned(),
+        err,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channel_protocol::parse_message;
+    use serde_cbor::de;
+
+    #[test]
+    fn create_parse_list_request() {
+        let raw = to_cbor(16, None).unwrap();
+        let parsed = parse_message(de::from_slice(&raw).unwrap()).unwrap();
+
+        assert_eq!(
+            from_cbor(&parsed).unwrap(),
+            Message::List {
+                channel_id: 16,
+                processes: None,
+            }
+        );
+    }
+
+    #[test]
+    fn create_parse_list_response() {
+        let mut processes = BTreeMap::new();
+        processes.insert(
+            12,
+            ProcessInfo {
+                path: "sh".to_owned(),
+                pid: 45,
+                run_time: 10,
+                state: "sleeping".to_owned(),
+            },
+        );
+        processes.insert(
+            14,
+            ProcessInfo {
+                path: "tail".to_owned(),
+                pid: 50,
+                run_time: 3600,
+                state: "running".to_owned(),
+            },
+        );
+
+        let raw = to_cbor(16, Some(&processes)).unwrap();
+        let parsed = parse_message(de::from_slice(&raw).unwrap()).unwrap();
+
+        assert_eq!(
+            from_cbor(&parsed).unwrap(),
+            Message::List {
+                channel_id: 16,
+                processes: Some(processes),
+            }
+        );
+    }
+}
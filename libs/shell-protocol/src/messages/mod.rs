@@ -14,34 +14,130 @@
 // limitations under the License.
 //
 
-use channel_protocol::ChannelMessage;
+use channel_protocol::{Capabilities, ChannelMessage};
 use error::ProtocolError;
 use serde_cbor::Value;
+use std::collections::BTreeMap;
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum Message {
     Spawn {
         channel_id: u32,
         command: String,
-        args: Option<Vec<String>>,
-        // TODO: Add these options:
-        // - pty - boolean specifying need for a pty
-        // - env - list of environment variables
-        // - cwd - current working directory of process
-        // - uid - uid of process
-        // - gid - gid of processs
-        // - detached - boolean specifying if child process should be detached
+        options: SpawnOptions,
+    },
+    Hello {
+        channel_id: u32,
+        capabilities: Capabilities,
+    },
+    Pid {
+        channel_id: u32,
+        pid: u32,
+    },
+    Stdin {
+        channel_id: u32,
+        data: Option<Vec<u8>>,
+    },
+    Resize {
+        channel_id: u32,
+        columns: u16,
+        rows: u16,
+    },
+    Stdout {
+        channel_id: u32,
+        data: Option<Vec<u8>>,
+    },
+    Stderr {
+        channel_id: u32,
+        data: Option<Vec<u8>>,
+    },
+    Exit {
+        channel_id: u32,
+        code: u32,
+        signal: u32,
+    },
+    Kill {
+        channel_id: u32,
+        signal: Option<u32>,
+    },
+    List {
+        channel_id: u32,
+        processes: Option<BTreeMap<u32, ProcessInfo>>,
+    },
+    Timeout {
+        channel_id: u32,
+        reason: String,
+    },
+    Error {
+        channel_id: u32,
+        message: String,
     },
 }
 
+pub mod error;
+pub mod exit;
+pub mod kill;
+pub mod list;
+pub mod pid;
+pub mod resize;
 pub mod spawn;
+pub mod stream;
+pub mod timeout;
+
+pub use self::list::ProcessInfo;
+pub use self::spawn::SpawnOptions;
 
 pub fn parse_message(message: ChannelMessage) -> Result<Message, ProtocolError> {
     if message.name == "spawn" {
         Ok(spawn::from_cbor(&message)?)
+    } else if message.name == "hello" {
+        Ok(Message::Hello {
+            channel_id: message.channel_id,
+            capabilities: Capabilities::from_payload(&message.payload)?,
+        })
+    } else if message.name == "pid" {
+        Ok(pid::from_cbor(&message)?)
+    } else if message.name == "stdin" {
+        Ok(stream::from_cbor(&message)?)
+    } else if message.name == "resize" {
+        Ok(resize::from_cbor(&message)?)
+    } else if message.name == "stdout" || message.name == "stderr" {
+        Ok(stream::from_cbor(&message)?)
+    } else if message.name == "exit" {
+        Ok(exit::from_cbor(&message)?)
+    } else if message.name == "kill" {
+        Ok(kill::from_cbor(&message)?)
+    } else if message.name == "list" {
+        Ok(list::from_cbor(&message)?)
+    } else if message.name == "timeout" {
+        Ok(timeout::from_cbor(&message)?)
+    } else if message.name == "error" {
+        Ok(error::from_cbor(&message)?)
     } else {
         Err(ProtocolError::MessageParseError {
             err: "No message found".to_owned(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_cbor::de;
+
+    #[test]
+    fn parse_hello_message() {
+        let capabilities = Capabilities::new(1, 4096);
+
+        let raw = capabilities.to_cbor(12).unwrap();
+        let parsed = channel_protocol::parse_message(de::from_slice(&raw).unwrap()).unwrap();
+
+        assert_eq!(
+            parse_message(parsed).unwrap(),
+            Message::Hello {
+                channel_id: 12,
+                capabilities,
+            }
+        );
+    }
+}
@@ -0,0 +1,81 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use super::*;
+use channel_protocol::ChannelMessage;
+use error::ProtocolError;
+use serde_cbor::ser;
+
+/// CBOR -> Message::Resize
+pub fn from_cbor(message: &ChannelMessage) -> Result<Message, ProtocolError> {
+    let columns = match message.payload.first() {
+        Some(Value::U64(columns)) => *columns as u16,
+        _ => {
+            return Err(ProtocolError::MessageParseError {
+                err: "No resize columns found".to_owned(),
+            })
+        }
+    };
+
+    let rows = match message.payload.get(1) {
+        Some(Value::U64(rows)) => *rows as u16,
+        _ => {
+            return Err(ProtocolError::MessageParseError {
+                err: "No resize rows found".to_owned(),
+            })
+        }
+    };
+
+    Ok(Message::Resize {
+        channel_id: message.channel_id,
+        columns,
+        rows,
+    })
+}
+
+/// Resize -> CBOR
+pub fn to_cbor(channel_id: u32, columns: u16, rows: u16) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ {}, resize, {}, {} }}", channel_id, columns, rows);
+
+    ser::to_vec_packed(&(channel_id, "resize", columns, rows)).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "resize".to_owned(),
+            err,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channel_protocol::parse_message;
+    use serde_cbor::de;
+
+    #[test]
+    fn create_parse_resize_message() {
+        let raw = to_cbor(10, 80, 24).unwrap();
+        let parsed = parse_message(de::from_slice(&raw).unwrap()).unwrap();
+
+        assert_eq!(
+            from_cbor(&parsed).unwrap(),
+            Message::Resize {
+                channel_id: 10,
+                columns: 80,
+                rows: 24,
+            }
+        );
+    }
+}
@@ -21,10 +21,27 @@ use serde_cbor::{ser, ObjectKey};
 use std::collections::BTreeMap;
 use std::process::{Child, Command, Stdio};
 
+/// Options controlling how a process is spawned
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SpawnOptions {
+    /// Arguments to pass to the process
+    pub args: Option<Vec<String>>,
+    /// Whether the process needs a pseudo-terminal
+    pub pty: bool,
+    /// Environment variables to add to the process's environment, as `KEY=val` entries
+    pub env: Option<Vec<String>>,
+    /// Working directory of the process
+    pub cwd: Option<String>,
+    /// User to run the process as
+    pub uid: Option<u32>,
+    /// Group to run the process as
+    pub gid: Option<u32>,
+    /// Whether the process should be started in a new session, away from the service's
+    pub detached: bool,
+}
+
 /// CBOR -> Message::Spawn
 pub fn from_cbor(message: &ChannelMessage) -> Result<Message, ProtocolError> {
-    let mut args: Option<Vec<String>> = None;
-
     let command = match message.payload.get(0) {
         Some(Value::String(command)) => command,
         _ => {
@@ -35,44 +52,153 @@ pub fn from_cbor(message: &ChannelMessage) -> Result<Message, ProtocolError> {
     };
 
     // Parse out options
-    match message.payload.get(1) {
-        Some(Value::Object(raw_options)) => {
-            // Parse out command arguments
-            args = match raw_options.get(&ObjectKey::String("args".to_owned())) {
-                Some(Value::Array(args)) => Some(
-                    args.to_vec()
-                        .iter()
-                        .map(|s| s.as_string().unwrap().to_owned())
-                        .collect(),
-                ),
-                _ => None,
-            };
-        }
-        _ => {}
+    let options = match message.payload.get(1) {
+        Some(Value::Object(raw_options)) => parse_options(raw_options)?,
+        Some(_) => return Err(invalid_option("options", "must be a map")),
+        None => SpawnOptions::default(),
     };
 
     Ok(Message::Spawn {
         channel_id: message.channel_id,
         command: command.to_owned(),
-        args: args,
+        options,
     })
 }
 
+fn parse_options(raw_options: &BTreeMap<ObjectKey, Value>) -> Result<SpawnOptions, ProtocolError> {
+    let get = |name: &str| raw_options.get(&ObjectKey::String(name.to_owned()));
+    let mut options = SpawnOptions::default();
+
+    if let Some(value) = get("args") {
+        options.args = Some(parse_strings("args", value)?);
+    }
+    if let Some(value) = get("pty") {
+        options.pty = parse_bool("pty", value)?;
+    }
+    if let Some(value) = get("env") {
+        let env = parse_strings("env", value)?;
+        for entry in &env {
+            match entry.find('=') {
+                Some(index) if index > 0 => {}
+                _ => {
+                    return Err(invalid_option(
+                        "env",
+                        &format!("{:?} is not of the form KEY=val", entry),
+                    ))
+                }
+            }
+        }
+        options.env = Some(env);
+    }
+    if let Some(value) = get("cwd") {
+        options.cwd = match value {
+            Value::String(cwd) => Some(cwd.to_owned()),
+            _ => return Err(invalid_option("cwd", "must be a string")),
+        };
+    }
+    if let Some(value) = get("uid") {
+        options.uid = Some(parse_id("uid", value)?);
+    }
+    if let Some(value) = get("gid") {
+        options.gid = Some(parse_id("gid", value)?);
+    }
+    if let Some(value) = get("detached") {
+        options.detached = parse_bool("detached", value)?;
+    }
+
+    // Otherwise the process would keep our group
+    if options.uid.is_some() && options.gid.is_none() {
+        return Err(invalid_option("uid", "must be given along with a gid"));
+    }
+
+    Ok(options)
+}
+
+fn parse_strings(option: &str, value: &Value) -> Result<Vec<String>, ProtocolError> {
+    match value {
+        Value::Array(values) => values
+            .iter()
+            .map(|value| match value {
+                Value::String(value) => Ok(value.to_owned()),
+                _ => Err(invalid_option(option, "must be a list of strings")),
+            })
+            .collect(),
+        _ => Err(invalid_option(option, "must be a list of strings")),
+    }
+}
+
+fn parse_bool(option: &str, value: &Value) -> Result<bool, ProtocolError> {
+    match value {
+        Value::Bool(value) => Ok(*value),
+        _ => Err(invalid_option(option, "must be a boolean")),
+    }
+}
+
+fn parse_id(option: &str, value: &Value) -> Result<u32, ProtocolError> {
+    match value {
+        Value::U64(id) if *id <= u64::from(u32::MAX) => Ok(*id as u32),
+        _ => Err(invalid_option(
+            option,
+            "must be a non-negative 32-bit number",
+        )),
+    }
+}
+
+fn invalid_option(option: &str, reason: &str) -> ProtocolError {
+    ProtocolError::InvalidOption {
+        option: option.to_owned(),
+        reason: reason.to_owned(),
+    }
+}
+
 /// Spawn -> CBOR
-pub fn to_cbor(channel_id: u32, command: &str, args: Option<&[String]>) -> Result<Vec<u8>, String> {
+pub fn to_cbor(
+    channel_id: u32,
+    command: &str,
+    options: &SpawnOptions,
+) -> Result<Vec<u8>, ProtocolError> {
     info!("-> {{ {}, spawn, {} }}", channel_id, command);
-    let mut options = BTreeMap::new();
-    if let Some(args) = args {
-        let args_vec = args
-            .to_vec()
-            .iter()
-            .map(|s| Value::String(s.to_owned()))
-            .collect();
-        options.insert(ObjectKey::String("args".to_owned()), Value::Array(args_vec));
+    let strings = |values: &[String]| {
+        Value::Array(
+            values
+                .iter()
+                .map(|value| Value::String(value.to_owned()))
+                .collect(),
+        )
+    };
+
+    let mut raw_options = BTreeMap::new();
+    let mut insert = |name: &str, value: Value| {
+        raw_options.insert(ObjectKey::String(name.to_owned()), value);
+    };
+    if let Some(ref args) = options.args {
+        insert("args", strings(args));
+    }
+    if options.pty {
+        insert("pty", Value::Bool(true));
+    }
+    if let Some(ref env) = options.env {
+        insert("env", strings(env));
+    }
+    if let Some(ref cwd) = options.cwd {
+        insert("cwd", Value::String(cwd.to_owned()));
+    }
+    if let Some(uid) = options.uid {
+        insert("uid", Value::U64(u64::from(uid)));
+    }
+    if let Some(gid) = options.gid {
+        insert("gid", Value::U64(u64::from(gid)));
+    }
+    if options.detached {
+        insert("detached", Value::Bool(true));
     }
 
-    ser::to_vec_packed(&(channel_id, "spawn", command, options))
-        .map_err(|_err| "Error creating spawn message".to_owned())
+    ser::to_vec_packed(&(channel_id, "spawn", command, raw_options)).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "spawn".to_owned(),
+            err,
+        }
+    })
 }
 
 /// Perform a spawn action
@@ -95,7 +221,7 @@ mod tests {
         let channel_id = 10;
         let command = "/bin/pwd";
 
-        let raw = to_cbor(channel_id, command, None).unwrap();
+        let raw = to_cbor(channel_id, command, &SpawnOptions::default()).unwrap();
         let parsed = parse_message(de::from_slice(&raw).unwrap()).unwrap();
         let msg = from_cbor(&parsed);
 
@@ -104,7 +230,7 @@ mod tests {
             Message::Spawn {
                 channel_id: channel_id,
                 command: command.to_owned(),
-                args: None
+                options: SpawnOptions::default(),
             }
         );
     }
@@ -113,9 +239,12 @@ mod tests {
     fn create_parse_spawn_single_arg() {
         let channel_id = 10;
         let command = "/bin/sleep";
-        let args: Vec<String> = vec!["100".to_owned()];
+        let options = SpawnOptions {
+            args: Some(vec!["100".to_owned()]),
+            ..Default::default()
+        };
 
-        let raw = to_cbor(channel_id, command, Some(&args)).unwrap();
+        let raw = to_cbor(channel_id, command, &options).unwrap();
         let parsed = parse_message(de::from_slice(&raw).unwrap()).unwrap();
         let msg = from_cbor(&parsed);
 
@@ -124,7 +253,7 @@ mod tests {
             Message::Spawn {
                 channel_id: channel_id,
                 command: command.to_owned(),
-                args: Some(args)
+                options,
             }
         );
     }
@@ -133,9 +262,35 @@ mod tests {
     fn create_parse_spawn_multi_args() {
         let channel_id = 10;
         let command = "/usr/bin/echo";
-        let args: Vec<String> = vec!["hello".to_owned(), "world".to_owned()];
+        let options = SpawnOptions {
+            args: Some(vec!["hello".to_owned(), "world".to_owned()]),
+            ..Default::default()
+        };
 
-        let raw = to_cbor(channel_id, command, Some(&args)).unwrap();
+        let raw = to_cbor(channel_id, command, &options).unwrap();
+        let parsed = parse_message(de::from_slice(&raw).unwrap()).unwrap();
+        let msg = from_cbor(&parsed);
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::Spawn {
+                channel_id,
+                command: command.to_owned(),
+                options,
+            }
+        );
+    }
+
+    #[test]
+    fn create_parse_spawn_pty() {
+        let channel_id = 10;
+        let command = "/bin/sh";
+        let options = SpawnOptions {
+            pty: true,
+            ..Default::default()
+        };
+
+        let raw = to_cbor(channel_id, command, &options).unwrap();
         let parsed = parse_message(de::from_slice(&raw).unwrap()).unwrap();
         let msg = from_cbor(&parsed);
 
@@ -144,8 +299,77 @@ mod tests {
             Message::Spawn {
                 channel_id: channel_id,
                 command: command.to_owned(),
-                args: Some(args)
+                options,
+            }
+        );
+    }
+
+    #[test]
+    fn create_parse_spawn_all_options() {
+        let channel_id = 10;
+        let command = "/usr/sbin/logrotate";
+        let options = SpawnOptions {
+            args: Some(vec!["-v".to_owned()]),
+            pty: false,
+            env: Some(vec!["HOME=/home/kubos".to_owned(), "EMPTY=".to_owned()]),
+            cwd: Some("/var/log".to_owned()),
+            uid: Some(1000),
+            gid: Some(100),
+            detached: true,
+        };
+
+        let raw = to_cbor(channel_id, command, &options).unwrap();
+        let parsed = parse_message(de::from_slice(&raw).unwrap()).unwrap();
+        let msg = from_cbor(&parsed);
+
+        assert_eq!(
+            msg.unwrap(),
+            Message::Spawn {
+                channel_id,
+                command: command.to_owned(),
+                options,
             }
         );
     }
+
+    // Build a spawn message with a single raw option
+    fn parse_option(name: &str, value: Value) -> Result<Message, ProtocolError> {
+        let mut options = BTreeMap::new();
+        options.insert(ObjectKey::String(name.to_owned()), value);
+
+        let raw = ser::to_vec_packed(&(10, "spawn", "/bin/true", options)).unwrap();
+        from_cbor(&parse_message(de::from_slice(&raw).unwrap()).unwrap())
+    }
+
+    fn assert_invalid(result: Result<Message, ProtocolError>, expected: &str) {
+        match result {
+            Err(ProtocolError::InvalidOption { option, .. }) => assert_eq!(option, expected),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn parse_spawn_invalid_options() {
+        assert_invalid(
+            parse_option("args", Value::Array(vec![Value::U64(1)])),
+            "args",
+        );
+        assert_invalid(
+            parse_option(
+                "env",
+                Value::Array(vec![Value::String("NO_VALUE".to_owned())]),
+            ),
+            "env",
+        );
+        assert_invalid(
+            parse_option("env", Value::Array(vec![Value::String("=val".to_owned())])),
+            "env",
+        );
+        assert_invalid(parse_option("cwd", Value::U64(1)), "cwd");
+        assert_invalid(parse_option("uid", Value::String("root".to_owned())), "uid");
+        assert_invalid(parse_option("uid", Value::U64(1000)), "uid");
+        assert_invalid(parse_option("gid", Value::I64(-1)), "gid");
+        assert_invalid(parse_option("gid", Value::U64(1 << 32)), "gid");
+        assert_invalid(parse_option("detached", Value::U64(1)), "detached");
+    }
 }
@@ -0,0 +1,134 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Data sent to a process's stdin or received from its stdout and stderr.
+// These messages only differ in their names
+
+use super::*;
+use channel_protocol::ChannelMessage;
+use error::ProtocolError;
+use serde_cbor::ser;
+
+/// CBOR -> Message::Stdin, Message::Stdout or Message::Stderr
+///
+/// A message without any data means the pipe has been closed, or for stdin,
+/// that it should be closed. Data may be sent as a text string as well as bytes
+pub fn from_cbor(message: &ChannelMessage) -> Result<Message, ProtocolError> {
+    let data = match message.payload.first() {
+        Some(Value::Bytes(data)) => Some(data.to_owned()),
+        Some(Value::String(data)) => Some(data.as_bytes().to_vec()),
+        None => None,
+        _ => {
+            return Err(ProtocolError::MessageParseError {
+                err: format!("Invalid {} data", message.name),
+            })
+        }
+    };
+
+    let channel_id = message.channel_id;
+    match message.name.as_str() {
+        "stdin" => Ok(Message::Stdin { channel_id, data }),
+        "stdout" => Ok(Message::Stdout { channel_id, data }),
+        "stderr" => Ok(Message::Stderr { channel_id, data }),
+        _ => Err(ProtocolError::MessageParseError {
+            err: format!("{} is not a stream", message.name),
+        }),
+    }
+}
+
+/// Stdin, Stdout or Stderr -> CBOR
+///
+/// The data is sent as bytes, since a process's output doesn't have to be text
+pub fn to_cbor(channel_id: u32, name: &str, data: Option<&[u8]>) -> Result<Vec<u8>, ProtocolError> {
+    let result = match data {
+        Some(data) => {
+            info!("-> {{ {}, {}, {} bytes }}", channel_id, name, data.len());
+            ser::to_vec_packed(&(channel_id, name, Value::Bytes(data.to_vec())))
+        }
+        None => {
+            info!("-> {{ {}, {} }}", channel_id, name);
+            ser::to_vec_packed(&(channel_id, name))
+        }
+    };
+
+    result.map_err(|err| ProtocolError::MessageCreationError {
+        message: name.to_owned(),
+        err,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channel_protocol::parse_message;
+    use serde_cbor::de;
+
+    #[test]
+    fn create_parse_stdout_data() {
+        let raw = to_cbor(10, "stdout", Some(b"hello\n")).unwrap();
+        let parsed = parse_message(de::from_slice(&raw).unwrap()).unwrap();
+
+        assert_eq!(
+            from_cbor(&parsed).unwrap(),
+            Message::Stdout {
+                channel_id: 10,
+                data: Some(b"hello\n".to_vec()),
+            }
+        );
+    }
+
+    #[test]
+    fn create_parse_stderr_binary_data() {
+        let raw = to_cbor(10, "stderr", Some(&[0xff, 0x00, 0xc3])).unwrap();
+        let parsed = parse_message(de::from_slice(&raw).unwrap()).unwrap();
+
+        assert_eq!(
+            from_cbor(&parsed).unwrap(),
+            Message::Stderr {
+                channel_id: 10,
+                data: Some(vec![0xff, 0x00, 0xc3]),
+            }
+        );
+    }
+
+    #[test]
+    fn create_parse_stdin_closed() {
+        let raw = to_cbor(10, "stdin", None).unwrap();
+        let parsed = parse_message(de::from_slice(&raw).unwrap()).unwrap();
+
+        assert_eq!(
+            from_cbor(&parsed).unwrap(),
+            Message::Stdin {
+                channel_id: 10,
+                data: None,
+            }
+        );
+    }
+
+    #[test]
+    fn parse_stdin_text() {
+        let raw = ser::to_vec_packed(&(10, "stdin", "ls\n")).unwrap();
+        let parsed = parse_message(de::from_slice(&raw).unwrap()).unwrap();
+
+        assert_eq!(
+            from_cbor(&parsed).unwrap(),
+            Message::Stdin {
+                channel_id: 10,
+                data: Some(b"ls\n".to_vec()),
+            }
+        );
+    }
+}
@@ -0,0 +1,70 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use super::*;
+use channel_protocol::ChannelMessage;
+use error::ProtocolError;
+use serde_cbor::ser;
+
+/// CBOR -> Message::Timeout
+pub fn from_cbor(message: &ChannelMessage) -> Result<Message, ProtocolError> {
+    let reason = match message.payload.first() {
+        Some(Value::String(reason)) => reason.to_owned(),
+        _ => {
+            return Err(ProtocolError::MessageParseError {
+                err: "No timeout reason found".to_owned(),
+            })
+        }
+    };
+
+    Ok(Message::Timeout {
+        channel_id: message.channel_id,
+        reason,
+    })
+}
+
+/// Timeout -> CBOR
+pub fn to_cbor(channel_id: u32, reason: &str) -> Result<Vec<u8>, ProtocolError> {
+    info!("-> {{ {}, timeout, {} }}", channel_id, reason);
+
+    ser::to_vec_packed(&(channel_id, "timeout", reason)).map_err(|err| {
+        ProtocolError::MessageCreationError {
+            message: "timeout".to_owned(),
+            err,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channel_protocol::parse_message;
+    use serde_cbor::de;
+
+    #[test]
+    fn create_parse_timeout_message() {
+        let raw = to_cbor(10, "idle").unwrap();
+        let parsed = parse_message(de::from_slice(&raw).unwrap()).unwrap();
+
+        assert_eq!(
+            from_cbor(&parsed).unwrap(),
+            Message::Timeout {
+                channel_id: 10,
+                reason: "idle".to_owned(),
+            }
+        );
+    }
+}
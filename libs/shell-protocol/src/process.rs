@@ -0,0 +1,468 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// A process spawned on behalf of a shell client, along with the output
+// which it has produced but which hasn't been sent to the client yet
+
+use error::ProtocolError;
+use libc;
+use messages::SpawnOptions;
+use processes::ProcessList;
+use pty::{self, Pty};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::ptr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Instant;
+
+// Largest amount of output to read from a pipe at a time
+const OUTPUT_CHUNK: usize = 1024;
+
+// What typing Ctrl-D sends
+const EOT: u8 = 4;
+
+/// Output read from one of a process's pipes. `None` means the pipe has been closed
+#[derive(Debug, Eq, PartialEq)]
+pub enum Output {
+    Stdout(Option<Vec<u8>>),
+    Stderr(Option<Vec<u8>>),
+}
+
+// Input waiting to be written to a process's stdin
+enum Input {
+    Data(Vec<u8>),
+    Close,
+}
+
+pub struct ProcessHandler {
+    pub channel_id: u32,
+    child: Child,
+    output: Receiver<Output>,
+    stdin: Option<Sender<Input>>,
+    pty: Option<Pty>,
+    stdout_open: bool,
+    stderr_open: bool,
+    detached: bool,
+    last_output: Instant,
+    processes: ProcessList,
+}
+
+impl ProcessHandler {
+    /// Spawn a process and start collecting its output
+    ///
+    /// If a pseudo-terminal is requested, everything the process writes to it
+    /// is reported as stdout. The process is added to the process list, on behalf of
+    /// the client on the `owner` host, until it has finished or the handler is dropped.
+    /// Nothing is spawned if a detached process is still running on the channel
+    pub fn spawn(
+        channel_id: u32,
+        command: String,
+        options: SpawnOptions,
+        processes: &ProcessList,
+        owner: IpAddr,
+    ) -> Result<Self, ProtocolError> {
+        if processes.pid(channel_id).is_some() {
+            return Err(ProtocolError::AlreadyRunning { channel_id });
+        }
+
+        let spawn_error = |err| ProtocolError::SpawnError {
+            cmd: command.to_owned(),
+            err,
+        };
+
+        let mut cmd = Command::new(&command);
+        cmd.args(options.args.unwrap_or(vec![]));
+
+        for entry in options.env.unwrap_or(vec![]) {
+            let mut parts = entry.splitn(2, '=');
+            if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
+                cmd.env(key, value);
+            }
+        }
+        if let Some(cwd) = options.cwd {
+            // Catch this here, otherwise it looks like the command doesn't exist
+            if !Path::new(&cwd).is_dir() {
+                return Err(ProtocolError::InvalidOption {
+                    option: "cwd".to_owned(),
+                    reason: format!("{} is not a directory", cwd),
+                });
+            }
+            cmd.current_dir(cwd);
+        }
+
+        let terminal = if options.pty {
+            Some(attach_pty(&mut cmd).map_err(&spawn_error)?)
+        } else {
+            cmd.stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped());
+            None
+        };
+
+        // A pseudo-terminal already needs a session of its own
+        if options.detached && !options.pty {
+            unsafe {
+                cmd.pre_exec(new_session);
+            }
+        }
+
+        // Change user last, once nothing else needs our privileges
+        if options.uid.is_some() || options.gid.is_some() {
+            let (uid, gid) = (options.uid, options.gid);
+            unsafe {
+                cmd.pre_exec(move || change_user(uid, gid));
+            }
+        }
+
+        let mut child = cmd.spawn().map_err(&spawn_error)?;
+        // Close our copies of the terminal, so that reading from the controlling
+        // side fails once the process is finished with it
+        drop(cmd);
+
+        // Reading and writing block, so each pipe gets its own thread
+        let (sender, receiver) = mpsc::channel();
+        let (stdin, pty): (Option<Sender<Input>>, Option<Pty>) = match terminal {
+            Some((pty, reader, writer)) => {
+                thread::spawn(move || read_output(reader, &sender, Output::Stdout));
+                (Some(spawn_writer(channel_id, writer, true)), Some(pty))
+            }
+            None => {
+                if let Some(stdout) = child.stdout.take() {
+                    let sender = sender.clone();
+                    thread::spawn(move || read_output(stdout, &sender, Output::Stdout));
+                }
+                if let Some(stderr) = child.stderr.take() {
+                    thread::spawn(move || read_output(stderr, &sender, Output::Stderr));
+                }
+                (
+                    child
+                        .stdin
+                        .take()
+                        .map(|stdin| spawn_writer(channel_id, stdin, false)),
+                    None,
+                )
+            }
+        };
+
+        if let Err(err) = processes.add(channel_id, &command, child.id(), owner) {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(err);
+        }
+
+        Ok(ProcessHandler {
+            channel_id,
+            child,
+            output: receiver,
+            stdin,
+            stderr_open: pty.is_none(),
+            pty,
+            stdout_open: true,
+            detached: options.detached,
+            last_output: Instant::now(),
+            processes: processes.clone(),
+        })
+    }
+
+    /// The process's pid
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Fetch the next piece of output, if there is any waiting
+    pub fn try_output(&mut self) -> Option<Output> {
+        let output = self.output.try_recv().ok();
+        match output {
+            Some(Output::Stdout(None)) => self.stdout_open = false,
+            Some(Output::Stderr(None)) => self.stderr_open = false,
+            _ => {}
+        }
+        if output.is_some() {
+            self.last_output = Instant::now();
+        }
+        output
+    }
+
+    /// When the process last produced any output
+    pub fn last_output(&self) -> Instant {
+        self.last_output
+    }
+
+    /// Whether the process was spawned to carry on without its session
+    pub fn detached(&self) -> bool {
+        self.detached
+    }
+
+    /// Queue data to be written to the process's stdin, or close it if there isn't any
+    ///
+    /// The writing is done in the background, so a process which isn't reading its
+    /// stdin can't hold up the session. Failures to write are only logged, and
+    /// any data sent after one is refused
+    pub fn write_stdin(&mut self, data: Option<&[u8]>) -> Result<(), ProtocolError> {
+        let input = match data {
+            Some(data) => Input::Data(data.to_vec()),
+            None => Input::Close,
+        };
+        let sent = match self.stdin {
+            Some(ref stdin) => stdin.send(input).is_ok(),
+            None => false,
+        };
+        if data.is_none() {
+            self.stdin = None;
+        }
+
+        if sent {
+            Ok(())
+        } else {
+            Err(ProtocolError::ProcessError {
+                action: "writing to stdin of".to_owned(),
+                err: io::Error::new(io::ErrorKind::BrokenPipe, "stdin has been closed"),
+            })
+        }
+    }
+
+    /// Change the size of the process's terminal
+    pub fn resize(&self, columns: u16, rows: u16) -> Result<(), ProtocolError> {
+        match self.pty {
+            Some(ref pty) => pty.resize(columns, rows),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "process has no terminal",
+            )),
+        }
+        .map_err(|err| ProtocolError::ProcessError {
+            action: "resizing the terminal of".to_owned(),
+            err,
+        })
+    }
+
+    /// Check whether the process has finished
+    ///
+    /// The process isn't treated as finished until all of its output has been collected,
+    /// so that the exit status is always the last thing reported
+    pub fn try_exit(&mut self) -> Result<Option<ExitStatus>, ProtocolError> {
+        if self.stdout_open || self.stderr_open {
+            return Ok(None);
+        }
+
+        self.child
+            .try_wait()
+            .map_err(|err| ProtocolError::ProcessError {
+                action: "checking the status of".to_owned(),
+                err,
+            })
+    }
+
+    /// Stop the process straight away and wait for it to finish
+    ///
+    /// Any output which hasn't been collected yet is thrown away
+    pub fn kill(&mut self) -> Result<ExitStatus, ProtocolError> {
+        // This only fails if the process has already exited, which is fine
+        let _ = self.child.kill();
+
+        self.child
+            .wait()
+            .map_err(|err| ProtocolError::ProcessError {
+                action: "waiting for".to_owned(),
+                err,
+            })
+    }
+}
+
+// A process shouldn't outlive its session, unless it was spawned detached. Then it's left
+// running without anyone to collect its output. It stays in the process list until it
+// finishes, so that other sessions can still find it and signal it
+impl Drop for ProcessHandler {
+    fn drop(&mut self) {
+        let channel_id = self.channel_id;
+        let pid = self.child.id();
+
+        if let Ok(None) = self.child.try_wait() {
+            if self.detached {
+                // Somebody still has to reap the process once it's done
+                let processes = self.processes.clone();
+                thread::spawn(move || {
+                    let mut status = 0;
+                    unsafe { libc::waitpid(pid as libc::pid_t, &mut status, 0) };
+                    processes.remove(channel_id, pid);
+                });
+                return;
+            }
+
+            warn!("{}: killing process {}", channel_id, pid);
+            let _ = self.kill();
+        }
+
+        self.processes.remove(channel_id, pid);
+    }
+}
+
+/// Exit code and signal of a finished process, as reported to the client
+pub fn exit_details(status: &ExitStatus) -> (u32, u32) {
+    (
+        status.code().unwrap_or(0) as u32,
+        status.signal().unwrap_or(0) as u32,
+    )
+}
+
+// Give the process a new terminal to use as its stdio. Returns the terminal's
+// controlling side, along with handles for reading from and writing to it
+fn attach_pty(cmd: &mut Command) -> io::Result<(Pty, File, File)> {
+    let (pty, terminal) = Pty::open()?;
+    let reader = pty.try_clone()?;
+    let writer = pty.try_clone()?;
+
+    cmd.stdin(terminal.try_clone()?)
+        .stdout(terminal.try_clone()?)
+        .stderr(terminal);
+    unsafe {
+        cmd.pre_exec(pty::make_controlling);
+    }
+
+    Ok((pty, reader, writer))
+}
+
+// Start a new session, so that the process isn't affected by signals
+// sent to the service's process group
+fn new_session() -> io::Result<()> {
+    if unsafe { libc::setsid() } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Switch the process to the requested user and group. Root's supplementary
+// groups are dropped before the user changes, so that the process doesn't keep them
+fn change_user(uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    unsafe {
+        if uid.is_some() && libc::geteuid() == 0 && libc::setgroups(0, ptr::null()) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if let Some(gid) = gid {
+            if libc::setgid(gid) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(uid) = uid {
+            if libc::setuid(uid) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+// Start a thread which writes input to a process's stdin until it's closed.
+// A terminal can't be closed without hanging up on the process,
+// so end-of-file is sent through it instead
+fn spawn_writer<W: Write + Send + 'static>(channel_id: u32, pipe: W, pty: bool) -> Sender<Input> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        if let Err(err) = write_input(pipe, &receiver, pty) {
+            warn!("{}: failed to write to stdin: {}", channel_id, err);
+        }
+    });
+    sender
+}
+
+// The pipe is closed once this returns, because it's dropped
+fn write_input<W: Write>(mut pipe: W, receiver: &Receiver<Input>, pty: bool) -> io::Result<()> {
+    // The sender goes away along with the process's handler
+    while let Ok(input) = receiver.recv() {
+        match input {
+            Input::Data(data) => pipe.write_all(&data)?,
+            Input::Close => {
+                if pty {
+                    pipe.write_all(&[EOT])?;
+                    pipe.flush()?;
+                }
+                break;
+            }
+        }
+        pipe.flush()?;
+    }
+    Ok(())
+}
+
+// Pass on everything read from a pipe until it's closed
+fn read_output<R: Read>(mut pipe: R, sender: &Sender<Output>, wrap: fn(Option<Vec<u8>>) -> Output) {
+    let mut buffer = [0; OUTPUT_CHUNK];
+
+    loop {
+        let len = match pipe.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+
+        // Nobody is listening any more once the process has been detached from its session,
+        // but keep reading anyway so that the process can still write to the pipe
+        let _ = sender.send(wrap(Some(buffer[..len].to_vec())));
+    }
+
+    let _ = sender.send(wrap(None));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn read_binary_output() {
+        let (sender, receiver) = mpsc::channel();
+        let data: &[u8] = &[b'c', b'a', b'f', 0xc3, 0xff, 0x00];
+
+        read_output(data, &sender, Output::Stdout);
+
+        let output: Vec<Output> = receiver.try_iter().collect();
+        assert_eq!(
+            output,
+            vec![Output::Stdout(Some(data.to_vec())), Output::Stdout(None)]
+        );
+    }
+
+    #[test]
+    fn write_stdin_does_not_block() {
+        let processes = ProcessList::new();
+        let mut handler = ProcessHandler::spawn(
+            1,
+            "sleep".to_owned(),
+            SpawnOptions {
+                args: Some(vec!["10".to_owned()]),
+                ..Default::default()
+            },
+            &processes,
+            IpAddr::from([127, 0, 0, 1]),
+        )
+        .unwrap();
+
+        // Far more than fits in the pipe, and the process never reads any of it
+        let data = vec![b'x'; 1024 * 1024];
+        let start = Instant::now();
+        handler.write_stdin(Some(&data)).unwrap();
+        handler.write_stdin(None).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        assert!(handler.write_stdin(Some(b"more")).is_err());
+    }
+}
@@ -0,0 +1,174 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Processes spawned by every session of a shell service, so that any session
+// can list them, and signal the ones spawned for the same client
+
+use error::ProtocolError;
+use messages::ProcessInfo;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+struct Entry {
+    path: String,
+    pid: u32,
+    started: Instant,
+    // Host of the client whose session spawned the process
+    owner: IpAddr,
+}
+
+/// Processes which are currently running, by the channel they were spawned on
+///
+/// Clones share the same list
+#[derive(Clone, Default)]
+pub struct ProcessList {
+    entries: Arc<Mutex<HashMap<u32, Entry>>>,
+}
+
+impl ProcessList {
+    /// Create an empty process list
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // A detached process may still be running on the channel, in which case
+    // it's left where it is, so that it can still be found
+    pub(crate) fn add(
+        &self,
+        channel_id: u32,
+        path: &str,
+        pid: u32,
+        owner: IpAddr,
+    ) -> Result<(), ProtocolError> {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.contains_key(&channel_id) {
+            return Err(ProtocolError::AlreadyRunning { channel_id });
+        }
+        entries.insert(
+            channel_id,
+            Entry {
+                path: path.to_owned(),
+                pid,
+                started: Instant::now(),
+                owner,
+            },
+        );
+        Ok(())
+    }
+
+    // The channel may have been reused by the time a detached process finishes,
+    // so only remove the entry if it's still for the same process
+    pub(crate) fn remove(&self, channel_id: u32, pid: u32) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.get(&channel_id).map(|entry| entry.pid) == Some(pid) {
+            entries.remove(&channel_id);
+        }
+    }
+
+    /// Pid of the process which was spawned on a channel
+    pub fn pid(&self, channel_id: u32) -> Option<u32> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&channel_id)
+            .map(|entry| entry.pid)
+    }
+
+    /// Pid of the process which was spawned on a channel, if it was spawned for
+    /// a client on the given host
+    pub fn owned_pid(&self, channel_id: u32, owner: IpAddr) -> Option<u32> {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&channel_id)
+            .filter(|entry| entry.owner == owner)
+            .map(|entry| entry.pid)
+    }
+
+    /// Describe all of the processes
+    pub fn list(&self) -> BTreeMap<u32, ProcessInfo> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(channel_id, entry)| {
+                (
+                    *channel_id,
+                    ProcessInfo {
+                        path: entry.path.to_owned(),
+                        pid: entry.pid,
+                        run_time: entry.started.elapsed().as_secs(),
+                        state: state(entry.pid),
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+// Look up what a process is doing
+fn state(pid: u32) -> String {
+    // The state comes straight after the command name, which is in brackets
+    let code = fs::read_to_string(format!("/proc/{}/stat", pid))
+        .ok()
+        .and_then(|stat| {
+            stat.rfind(')')
+                .and_then(|end| stat[end + 1..].trim_start().chars().next())
+        });
+
+    match code {
+        Some('R') => "running",
+        Some('S') | Some('D') | Some('I') => "sleeping",
+        Some('T') | Some('t') => "stopped",
+        Some('Z') => "zombie",
+        _ => "unknown",
+    }
+    .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn track_processes() {
+        let processes = ProcessList::new();
+        let shared = processes.clone();
+
+        let owner = "127.0.0.1".parse().unwrap();
+        processes.add(12, "sh", process::id(), owner).unwrap();
+        assert_eq!(shared.pid(12), Some(process::id()));
+        assert_eq!(shared.owned_pid(12, owner), Some(process::id()));
+        assert_eq!(shared.owned_pid(12, "127.0.0.2".parse().unwrap()), None);
+
+        // A reused channel doesn't hide the process which is already on it
+        assert!(shared.add(12, "cat", 1, owner).is_err());
+        assert_eq!(shared.list()[&12].path, "sh");
+
+        let list = shared.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[&12].path, "sh");
+        assert_ne!(list[&12].state, "unknown");
+
+        processes.remove(12, process::id());
+        assert_eq!(shared.pid(12), None);
+        assert!(shared.list().is_empty());
+    }
+}
@@ -14,26 +14,109 @@
 // limitations under the License.
 //
 
-use channel_protocol::{ChannelMessage, ChannelProtocol};
+use cbor_protocol::IntoTransport;
+use channel_protocol::{Capabilities, ChannelMessage, ChannelProtocol};
 use error::ProtocolError;
-use messages;
-use std::process::{Command, Stdio};
-use std::time::Duration;
+use libc;
+use messages::{self, SpawnOptions};
+use process::{self, Output, ProcessHandler};
+use processes::ProcessList;
+use std::cell::RefCell;
+use std::cmp;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// Version of the shell protocol reported in hello messages
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// How often to check for output while a process is running
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// Signals which clients may send to processes
+const ALLOWED_SIGNALS: [libc::c_int; 10] = [
+    libc::SIGHUP,
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGKILL,
+    libc::SIGUSR1,
+    libc::SIGUSR2,
+    libc::SIGTERM,
+    libc::SIGCONT,
+    libc::SIGSTOP,
+    libc::SIGTSTP,
+];
 
 pub struct Protocol {
     pub channel_protocol: ChannelProtocol,
+    // Host of the client, which owns the processes this session spawns
+    client: IpAddr,
+    process: RefCell<Option<ProcessHandler>>,
+    processes: ProcessList,
+    idle_timeout: Option<Duration>,
+    lifetime: Option<Duration>,
 }
 
 impl Protocol {
-    pub fn new(host_ip: &str, remote_addr: &str) -> Self {
+    pub fn new<T: IntoTransport>(transport: T, remote_addr: &str) -> Self {
         // Set up the full connection info
         Protocol {
-            channel_protocol: ChannelProtocol::new(host_ip, remote_addr, 4096),
+            channel_protocol: ChannelProtocol::new(transport, remote_addr, 4096),
+            client: remote_addr
+                .parse::<SocketAddr>()
+                .map(|addr| addr.ip())
+                .unwrap_or_else(|_| IpAddr::from([0, 0, 0, 0])),
+            process: RefCell::new(None),
+            processes: ProcessList::new(),
+            idle_timeout: None,
+            lifetime: None,
+        }
+    }
+
+    /// Sign all sent messages, and only accept received messages which are signed.
+    /// Messages are signed as the service, so clients must sign theirs as clients
+    pub fn with_auth(self, key: &[u8], window: Duration) -> Self {
+        Protocol {
+            channel_protocol: self.channel_protocol.with_service_auth(key, window),
+            ..self
+        }
+    }
+
+    /// Share a list of processes with other sessions, so that each of them can
+    /// list the processes spawned by the others, and signal the ones spawned
+    /// for a client on the same host
+    pub fn with_processes(self, processes: ProcessList) -> Self {
+        Protocol { processes, ..self }
+    }
+
+    /// Limit how long a session with a running process may last
+    ///
+    /// # Arguments
+    ///
+    /// * idle_timeout - How long the client and the process may both go without sending anything
+    /// * lifetime - How long the session may last in total
+    ///
+    /// Once either limit is reached the process is killed, or left running if it
+    /// was spawned detached, and the session ends
+    pub fn with_session_limits(
+        self,
+        idle_timeout: Option<Duration>,
+        lifetime: Option<Duration>,
+    ) -> Self {
+        Protocol {
+            idle_timeout,
+            lifetime,
+            ..self
         }
     }
 
     /// Listen for and process shell protocol messages
     ///
+    /// While a spawned process is running, its output is sent back to the client
+    /// as it arrives. Once the process has exited and its exit status has been sent,
+    /// this function returns. It also returns if no process is running and no
+    /// message arrives before the timeout, or if the session outlives its limits
+    ///
     /// # Arguments
     ///
     /// * pump - Function which returns the next message for processing
@@ -63,17 +146,44 @@ impl Protocol {
     where
         F: Fn(Duration) -> Result<ChannelMessage, ProtocolError>,
     {
+        let started = Instant::now();
+        let mut last_message = started;
+
         loop {
-            let message = match pump(timeout) {
-                Ok(message) => message,
+            // Don't leave a running process's output waiting for too long
+            let wait = match *self.process.borrow() {
+                Some(_) => cmp::min(timeout, POLL_INTERVAL),
+                None => timeout,
+            };
+
+            match pump(wait) {
+                Ok(message) => {
+                    last_message = Instant::now();
+                    // A bad request shouldn't cut off a running process,
+                    // but the client needs to know that it failed
+                    let channel_id = message.channel_id;
+                    if let Err(e) = self.process_message(message) {
+                        warn!("{}: {}", channel_id, e);
+                        self.channel_protocol
+                            .send(messages::error::to_cbor(channel_id, &format!("{}", e))?)?;
+                    }
+                }
                 Err(ProtocolError::ReceiveTimeout) => {
-                    // TODO when do we end this?
-                    continue;
+                    // Nothing left to do in this session
+                    if self.process.borrow().is_none() {
+                        return Ok(());
+                    }
                 }
                 Err(e) => return Err(e),
-            };
+            }
+
+            if self.send_output()? {
+                return Ok(());
+            }
 
-            self.process_message(message)?;
+            if let Some(reason) = self.expired(started, last_message) {
+                return self.expire(reason);
+            }
         }
     }
 
@@ -84,25 +194,838 @@ impl Protocol {
             messages::Message::Spawn {
                 channel_id,
                 command,
-                args,
+                options,
+            } => {
+                info!("{}: spawning command {} {:?}", channel_id, command, options);
+                self.spawn(channel_id, command, options)?;
+            }
+            messages::Message::Stdin { channel_id, data } => match *self.process.borrow_mut() {
+                Some(ref mut handler) => {
+                    handler.write_stdin(data.as_deref())?;
+                }
+                None => return Err(ProtocolError::NoProcess { channel_id }),
+            },
+            messages::Message::Resize {
+                channel_id,
+                columns,
+                rows,
+            } => match *self.process.borrow() {
+                Some(ref handler) => handler.resize(columns, rows)?,
+                None => return Err(ProtocolError::NoProcess { channel_id }),
+            },
+            messages::Message::Kill { channel_id, signal } => {
+                let signal = signal.unwrap_or(libc::SIGTERM as u32);
+                info!("{}: sending signal {}", channel_id, signal);
+                self.kill(channel_id, signal)?;
+            }
+            messages::Message::List {
+                channel_id,
+                processes: None,
             } => {
-                info!("{}: spawning command {} {:?}", channel_id, command, args);
-                self.spawn(command, args)?;
+                let processes = self.processes.list();
+                self.channel_protocol
+                    .send(messages::list::to_cbor(channel_id, Some(&processes))?)?;
             }
+            messages::Message::Hello {
+                channel_id,
+                capabilities,
+            } => {
+                info!(
+                    "{}: remote speaks version {} with features {:?}",
+                    channel_id, capabilities.version, capabilities.features
+                );
+                self.channel_protocol
+                    .send_hello(channel_id, &Self::capabilities())?;
+            }
+            // Everything else is only sent by the shell service
+            other => warn!("Ignoring unexpected message: {:?}", other),
+        }
+
+        Ok(())
+    }
+
+    /// What this end of the shell protocol supports
+    pub fn capabilities() -> Capabilities {
+        Capabilities::new(PROTOCOL_VERSION, 4096)
+    }
+
+    fn spawn(
+        &self,
+        channel_id: u32,
+        command: String,
+        options: SpawnOptions,
+    ) -> Result<(), ProtocolError> {
+        // Each session only looks after one process
+        if self.process.borrow().is_some() {
+            return Err(ProtocolError::AlreadyRunning { channel_id });
         }
 
+        let handler =
+            ProcessHandler::spawn(channel_id, command, options, &self.processes, self.client)?;
+        self.channel_protocol
+            .send(messages::pid::to_cbor(channel_id, handler.id())?)?;
+        *self.process.borrow_mut() = Some(handler);
         Ok(())
     }
 
-    fn spawn(&self, command: String, args: Option<Vec<String>>) -> Result<(), ProtocolError> {
-        match Command::new(command.to_owned())
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .args(args.unwrap_or(vec![]))
-            .spawn()
+    // Signal the process which was spawned on a channel.
+    // It may belong to another session, so look it up in the shared list.
+    // Only processes spawned for the same client host can be signalled
+    fn kill(&self, channel_id: u32, signal: u32) -> Result<(), ProtocolError> {
+        if !ALLOWED_SIGNALS
+            .iter()
+            .any(|&allowed| allowed as u32 == signal)
         {
-            Ok(_) => Ok(()),
-            Err(err) => Err(ProtocolError::SpawnError { cmd: command, err }),
+            return Err(ProtocolError::InvalidSignal { signal });
+        }
+
+        if self.processes.pid(channel_id).is_none() {
+            return Err(ProtocolError::NoProcess { channel_id });
+        }
+        let pid = self
+            .processes
+            .owned_pid(channel_id, self.client)
+            .ok_or(ProtocolError::NotOwner { channel_id })?;
+
+        if unsafe { libc::kill(pid as libc::pid_t, signal as libc::c_int) } != 0 {
+            return Err(ProtocolError::ProcessError {
+                action: "signalling".to_owned(),
+                err: io::Error::last_os_error(),
+            });
+        }
+        Ok(())
+    }
+
+    // Pass on any output from the running process to the client, followed by its
+    // exit status once it has finished. Returns whether the process has finished
+    fn send_output(&self) -> Result<bool, ProtocolError> {
+        let mut running = self.process.borrow_mut();
+        let (channel_id, status) = match *running {
+            Some(ref mut handler) => {
+                let channel_id = handler.channel_id;
+                while let Some(output) = handler.try_output() {
+                    let (name, data) = match output {
+                        Output::Stdout(data) => ("stdout", data),
+                        Output::Stderr(data) => ("stderr", data),
+                    };
+                    self.channel_protocol.send(messages::stream::to_cbor(
+                        channel_id,
+                        name,
+                        data.as_deref(),
+                    )?)?;
+                }
+
+                match handler.try_exit()? {
+                    Some(status) => (channel_id, status),
+                    None => return Ok(false),
+                }
+            }
+            None => return Ok(false),
+        };
+
+        info!("{}: process exited with {}", channel_id, status);
+        // Done with the process, which takes it off the process list
+        *running = None;
+
+        let (code, signal) = process::exit_details(&status);
+        self.channel_protocol
+            .send(messages::exit::to_cbor(channel_id, code, signal)?)?;
+        Ok(true)
+    }
+
+    // Check whether the session has gone on for too long, returning which limit was reached
+    fn expired(&self, started: Instant, last_message: Instant) -> Option<&'static str> {
+        let running = self.process.borrow();
+        let handler = running.as_ref()?;
+        let last_activity = cmp::max(last_message, handler.last_output());
+
+        if self
+            .lifetime
+            .is_some_and(|limit| started.elapsed() >= limit)
+        {
+            Some("lifetime")
+        } else if self
+            .idle_timeout
+            .is_some_and(|limit| last_activity.elapsed() >= limit)
+        {
+            Some("idle")
+        } else {
+            None
+        }
+    }
+
+    // Stop the running process, or leave it to carry on by itself if it was spawned
+    // detached, then let the client know why the session is ending
+    fn expire(&self, reason: &str) -> Result<(), ProtocolError> {
+        let mut handler = match self.process.borrow_mut().take() {
+            Some(handler) => handler,
+            None => return Ok(()),
+        };
+        let channel_id = handler.channel_id;
+
+        if handler.detached() {
+            info!(
+                "{}: session reached its {} limit, leaving process {} running",
+                channel_id,
+                reason,
+                handler.id()
+            );
+        } else {
+            info!(
+                "{}: session reached its {} limit, killing process {}",
+                channel_id,
+                reason,
+                handler.id()
+            );
+            let status = handler.kill();
+            drop(handler);
+
+            let (code, signal) = process::exit_details(&status?);
+            self.channel_protocol
+                .send(messages::exit::to_cbor(channel_id, code, signal)?)?;
+        }
+
+        self.channel_protocol
+            .send(messages::timeout::to_cbor(channel_id, reason)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cbor_protocol::UnixTransport;
+    use libc;
+    use messages::Message;
+    use serde_cbor::{ser, ObjectKey, Value};
+    use std::collections::BTreeMap;
+    use std::thread;
+    use std::thread::JoinHandle;
+
+    // Start a shell session on one end of a socket pair, returning the other end
+    fn start_session() -> (ChannelProtocol, JoinHandle<Result<(), ProtocolError>>) {
+        start_shared_session(ProcessList::new())
+    }
+
+    fn start_shared_session(
+        processes: ProcessList,
+    ) -> (ChannelProtocol, JoinHandle<Result<(), ProtocolError>>) {
+        start_limited_session(processes, None, None)
+    }
+
+    fn start_limited_session(
+        processes: ProcessList,
+        idle_timeout: Option<Duration>,
+        lifetime: Option<Duration>,
+    ) -> (ChannelProtocol, JoinHandle<Result<(), ProtocolError>>) {
+        // There's only one peer on the other end, so the remote address doesn't matter
+        start_session_for("0.0.0.0:0", processes, idle_timeout, lifetime)
+    }
+
+    // The remote address is only used to decide which host the session's client is on
+    fn start_session_for(
+        remote_addr: &'static str,
+        processes: ProcessList,
+        idle_timeout: Option<Duration>,
+        lifetime: Option<Duration>,
+    ) -> (ChannelProtocol, JoinHandle<Result<(), ProtocolError>>) {
+        let (client, service) = UnixTransport::pair().unwrap();
+
+        let engine = thread::spawn(move || {
+            let s_protocol = Protocol::new(service, remote_addr)
+                .with_processes(processes)
+                .with_session_limits(idle_timeout, lifetime);
+            s_protocol.message_engine(
+                |d| Ok(s_protocol.channel_protocol.recv_message(Some(d))?),
+                Duration::from_secs(1),
+            )
+        });
+
+        (ChannelProtocol::new(client, "0.0.0.0:0", 4096), engine)
+    }
+
+    fn recv(c_protocol: &ChannelProtocol) -> Message {
+        let message = c_protocol
+            .recv_message(Some(Duration::from_secs(5)))
+            .unwrap();
+        messages::parse_message(message).unwrap()
+    }
+
+    // Wait for the process to be created, returning its pid
+    fn expect_pid(c_protocol: &ChannelProtocol, expected_channel: u32) -> u32 {
+        match recv(c_protocol) {
+            Message::Pid { channel_id, pid } => {
+                assert_eq!(channel_id, expected_channel);
+                assert!(pid > 0);
+                pid
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    // Run a shell command with the given options and collect its stdout
+    fn run_shell(channel_id: u32, script: &str, options: SpawnOptions) -> (u32, String) {
+        let (c_protocol, engine) = start_session();
+
+        let options = SpawnOptions {
+            args: Some(vec!["-c".to_owned(), script.to_owned()]),
+            ..options
+        };
+        c_protocol
+            .send(messages::spawn::to_cbor(channel_id, "sh", &options).unwrap())
+            .unwrap();
+
+        let pid = expect_pid(&c_protocol, channel_id);
+        let (stdout, stderr, code, _) = collect_output(&c_protocol);
+        assert_eq!((stderr.as_slice(), code), (&b""[..], 0));
+        assert!(engine.join().unwrap().is_ok());
+
+        (pid, String::from_utf8(stdout).unwrap())
+    }
+
+    // Wait for the session to say why it's ending
+    fn expect_timeout(c_protocol: &ChannelProtocol, expected_channel: u32) -> String {
+        match recv(c_protocol) {
+            Message::Timeout { channel_id, reason } => {
+                assert_eq!(channel_id, expected_channel);
+                reason
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    // Wait for a request to be refused, returning the reason
+    fn expect_error(c_protocol: &ChannelProtocol, expected_channel: u32) -> String {
+        match recv(c_protocol) {
+            Message::Error {
+                channel_id,
+                message,
+            } => {
+                assert_eq!(channel_id, expected_channel);
+                message
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+    }
+
+    // Gather up the process's output until it exits.
+    // Returns stdout, stderr, the exit code and the exit signal
+    fn collect_output(c_protocol: &ChannelProtocol) -> (Vec<u8>, Vec<u8>, u32, u32) {
+        let mut stdout = vec![];
+        let mut stderr = vec![];
+        let mut stdout_closed = false;
+        loop {
+            match recv(c_protocol) {
+                Message::Stdout {
+                    data: Some(data), ..
+                } => stdout.extend(data),
+                Message::Stderr {
+                    data: Some(data), ..
+                } => stderr.extend(data),
+                Message::Stdout { data: None, .. } => stdout_closed = true,
+                // There's no separate stderr with a terminal, so this won't always be sent
+                Message::Stderr { data: None, .. } => {}
+                Message::Exit { code, signal, .. } => {
+                    assert!(stdout_closed);
+                    return (stdout, stderr, code, signal);
+                }
+                other => panic!("Unexpected message: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn spawn_and_collect_output() {
+        let (c_protocol, engine) = start_session();
+
+        let options = SpawnOptions {
+            args: Some(vec![
+                "-c".to_owned(),
+                "echo hello; echo oops >&2; exit 3".to_owned(),
+            ]),
+            ..Default::default()
+        };
+        c_protocol
+            .send(messages::spawn::to_cbor(5, "sh", &options).unwrap())
+            .unwrap();
+
+        expect_pid(&c_protocol, 5);
+        assert_eq!(
+            collect_output(&c_protocol),
+            (b"hello\n".to_vec(), b"oops\n".to_vec(), 3, 0)
+        );
+
+        // The session is over once the exit status has been sent
+        assert!(engine.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn write_and_close_stdin() {
+        let (c_protocol, engine) = start_session();
+
+        c_protocol
+            .send(messages::spawn::to_cbor(6, "cat", &SpawnOptions::default()).unwrap())
+            .unwrap();
+        expect_pid(&c_protocol, 6);
+
+        c_protocol
+            .send(messages::stream::to_cbor(6, "stdin", Some(b"to the cat\n")).unwrap())
+            .unwrap();
+        c_protocol
+            .send(messages::stream::to_cbor(6, "stdin", None).unwrap())
+            .unwrap();
+
+        assert_eq!(
+            collect_output(&c_protocol),
+            (b"to the cat\n".to_vec(), vec![], 0, 0)
+        );
+        assert!(engine.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn binary_data_unchanged() {
+        let (c_protocol, engine) = start_session();
+
+        c_protocol
+            .send(messages::spawn::to_cbor(15, "cat", &SpawnOptions::default()).unwrap())
+            .unwrap();
+        expect_pid(&c_protocol, 15);
+
+        // Not valid UTF-8, so none of this should be replaced along the way
+        let data = vec![0xff, 0xfe, 0x00, 0xc3, b'\n'];
+        c_protocol
+            .send(messages::stream::to_cbor(15, "stdin", Some(&data)).unwrap())
+            .unwrap();
+        c_protocol
+            .send(messages::stream::to_cbor(15, "stdin", None).unwrap())
+            .unwrap();
+
+        assert_eq!(collect_output(&c_protocol), (data, vec![], 0, 0));
+        assert!(engine.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn interactive_pty_session() {
+        let (c_protocol, engine) = start_session();
+
+        let options = SpawnOptions {
+            pty: true,
+            ..Default::default()
+        };
+        c_protocol
+            .send(messages::spawn::to_cbor(7, "sh", &options).unwrap())
+            .unwrap();
+        expect_pid(&c_protocol, 7);
+
+        // The process should see its terminal's new size
+        c_protocol
+            .send(messages::resize::to_cbor(7, 100, 30).unwrap())
+            .unwrap();
+        c_protocol
+            .send(messages::stream::to_cbor(7, "stdin", Some(b"stty size; tty\n")).unwrap())
+            .unwrap();
+        c_protocol
+            .send(messages::stream::to_cbor(7, "stdin", None).unwrap())
+            .unwrap();
+
+        // Everything arrives as stdout, including the terminal's echo of our input
+        let (stdout, stderr, code, signal) = collect_output(&c_protocol);
+        let stdout = String::from_utf8_lossy(&stdout);
+        assert!(stdout.contains("30 100"), "Unexpected output: {:?}", stdout);
+        assert!(
+            stdout.contains("/dev/pts/"),
+            "Unexpected output: {:?}",
+            stdout
+        );
+        assert_eq!(stderr, b"");
+        assert_eq!((code, signal), (0, 0));
+        assert!(engine.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn spawn_with_env_and_cwd() {
+        let options = SpawnOptions {
+            env: Some(vec!["GREETING=hello there".to_owned()]),
+            cwd: Some("/".to_owned()),
+            ..Default::default()
+        };
+
+        let (_, stdout) = run_shell(8, "echo $GREETING; pwd", options);
+        assert_eq!(stdout, "hello there\n/\n");
+    }
+
+    #[test]
+    fn spawn_as_user() {
+        // Switching to our own user is always allowed
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let options = SpawnOptions {
+            uid: Some(uid),
+            gid: Some(gid),
+            ..Default::default()
+        };
+
+        let (_, stdout) = run_shell(9, "id -u; id -g; id -G", options);
+        let lines: Vec<&str> = stdout.lines().collect();
+        assert_eq!(lines[..2], [&uid.to_string()[..], &gid.to_string()[..]]);
+        // Only root can drop its supplementary groups
+        if uid == 0 {
+            assert_eq!(lines[2], gid.to_string());
+        }
+    }
+
+    #[test]
+    fn spawn_detached() {
+        let options = SpawnOptions {
+            detached: true,
+            ..Default::default()
+        };
+
+        // The process should be the leader of its own session
+        let (pid, stdout) = run_shell(10, "cut -d' ' -f6 /proc/$$/stat", options);
+        assert_eq!(stdout, format!("{}\n", pid));
+    }
+
+    #[test]
+    fn spawn_bad_cwd() {
+        let (c_protocol, engine) = start_session();
+
+        let options = SpawnOptions {
+            cwd: Some("/does/not/exist".to_owned()),
+            ..Default::default()
+        };
+        c_protocol
+            .send(messages::spawn::to_cbor(11, "pwd", &options).unwrap())
+            .unwrap();
+
+        assert_eq!(
+            expect_error(&c_protocol, 11),
+            "Invalid spawn option cwd: /does/not/exist is not a directory"
+        );
+
+        // Nothing is spawned, and the session ends once it's been idle for a while
+        assert!(engine.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn spawn_bad_options() {
+        let (c_protocol, engine) = start_session();
+
+        let mut options = BTreeMap::new();
+        options.insert(
+            ObjectKey::String("env".to_owned()),
+            Value::Array(vec![Value::String("NO_VALUE".to_owned())]),
+        );
+        c_protocol
+            .send(ser::to_vec_packed(&(14, "spawn", "env", options)).unwrap())
+            .unwrap();
+
+        assert_eq!(
+            expect_error(&c_protocol, 14),
+            "Invalid spawn option env: \"NO_VALUE\" is not of the form KEY=val"
+        );
+        assert!(engine.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn failed_requests_reported() {
+        let (c_protocol, engine) = start_session();
+
+        c_protocol
+            .send(messages::kill::to_cbor(16, None).unwrap())
+            .unwrap();
+        assert_eq!(
+            expect_error(&c_protocol, 16),
+            "No process is running on channel 16"
+        );
+        c_protocol
+            .send(messages::stream::to_cbor(16, "stdin", Some(b"hello")).unwrap())
+            .unwrap();
+        assert_eq!(
+            expect_error(&c_protocol, 16),
+            "No process is running on channel 16"
+        );
+
+        // A process without a terminal can't be resized
+        c_protocol
+            .send(messages::spawn::to_cbor(16, "cat", &SpawnOptions::default()).unwrap())
+            .unwrap();
+        expect_pid(&c_protocol, 16);
+        c_protocol
+            .send(messages::resize::to_cbor(16, 100, 30).unwrap())
+            .unwrap();
+        assert_eq!(
+            expect_error(&c_protocol, 16),
+            "Error resizing the terminal of process: process has no terminal"
+        );
+
+        c_protocol
+            .send(messages::stream::to_cbor(16, "stdin", None).unwrap())
+            .unwrap();
+        assert_eq!(collect_output(&c_protocol), (vec![], vec![], 0, 0));
+        assert!(engine.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn second_spawn_refused() {
+        let processes = ProcessList::new();
+        let (c_protocol, engine) = start_shared_session(processes.clone());
+
+        let options = SpawnOptions {
+            args: Some(vec!["30".to_owned()]),
+            ..Default::default()
+        };
+        c_protocol
+            .send(messages::spawn::to_cbor(12, "sleep", &options).unwrap())
+            .unwrap();
+        let pid = expect_pid(&c_protocol, 12);
+
+        // The process which is already running is left alone
+        c_protocol
+            .send(messages::spawn::to_cbor(12, "sleep", &options).unwrap())
+            .unwrap();
+        assert_eq!(
+            expect_error(&c_protocol, 12),
+            "A process is already running on channel 12"
+        );
+        c_protocol
+            .send(messages::kill::to_cbor(12, None).unwrap())
+            .unwrap();
+
+        let (_, _, _, signal) = collect_output(&c_protocol);
+        assert_eq!(signal, libc::SIGTERM as u32);
+        assert!(engine.join().unwrap().is_ok());
+        assert!(processes.list().is_empty());
+        assert_ne!(unsafe { libc::kill(pid as libc::pid_t, 0) }, 0);
+    }
+
+    #[test]
+    fn ended_session_kills_process() {
+        let processes = ProcessList::new();
+        let (client, service) = UnixTransport::pair().unwrap();
+        let c_protocol = ChannelProtocol::new(client, "0.0.0.0:0", 4096);
+        let s_protocol = Protocol::new(service, "0.0.0.0:0").with_processes(processes.clone());
+
+        let options = SpawnOptions {
+            args: Some(vec!["30".to_owned()]),
+            ..Default::default()
+        };
+        c_protocol
+            .send(messages::spawn::to_cbor(13, "sleep", &options).unwrap())
+            .unwrap();
+        let message = s_protocol
+            .channel_protocol
+            .recv_message(Some(Duration::from_secs(1)))
+            .unwrap();
+        s_protocol.process_message(message).unwrap();
+        let pid = expect_pid(&c_protocol, 13);
+
+        // However the session ends, the process doesn't outlive it
+        drop(s_protocol);
+        assert_eq!(processes.pid(13), None);
+        assert_ne!(unsafe { libc::kill(pid as libc::pid_t, 0) }, 0);
+    }
+
+    #[test]
+    fn list_and_kill() {
+        let processes = ProcessList::new();
+
+        // Start a process which won't finish by itself
+        let (c_protocol, engine) = start_shared_session(processes.clone());
+        let options = SpawnOptions {
+            args: Some(vec!["30".to_owned()]),
+            ..Default::default()
+        };
+        c_protocol
+            .send(messages::spawn::to_cbor(20, "sleep", &options).unwrap())
+            .unwrap();
+        let pid = expect_pid(&c_protocol, 20);
+
+        // Find it from another session
+        let (other_protocol, other_engine) = start_shared_session(processes);
+        other_protocol
+            .send(messages::list::to_cbor(21, None).unwrap())
+            .unwrap();
+        match recv(&other_protocol) {
+            Message::List {
+                channel_id,
+                processes: Some(processes),
+            } => {
+                assert_eq!(channel_id, 21);
+                assert_eq!(processes.len(), 1);
+                assert_eq!(processes[&20].path, "sleep");
+                assert_eq!(processes[&20].pid, pid);
+                assert_ne!(processes[&20].state, "unknown");
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+
+        // And stop it, using the default signal
+        other_protocol
+            .send(messages::kill::to_cbor(20, None).unwrap())
+            .unwrap();
+
+        let (_, _, code, signal) = collect_output(&c_protocol);
+        assert_eq!((code, signal), (0, libc::SIGTERM as u32));
+        assert!(engine.join().unwrap().is_ok());
+
+        // It shouldn't be listed any more
+        other_protocol
+            .send(messages::list::to_cbor(22, None).unwrap())
+            .unwrap();
+        match recv(&other_protocol) {
+            Message::List {
+                processes: Some(processes),
+                ..
+            } => assert!(processes.is_empty()),
+            other => panic!("Unexpected message: {:?}", other),
+        }
+
+        // The other session ends once it's been idle for a while
+        assert!(other_engine.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn kill_checks_owner_and_signal() {
+        let processes = ProcessList::new();
+
+        let (c_protocol, engine) =
+            start_session_for("127.0.0.1:7100", processes.clone(), None, None);
+        let options = SpawnOptions {
+            args: Some(vec!["30".to_owned()]),
+            ..Default::default()
+        };
+        c_protocol
+            .send(messages::spawn::to_cbor(23, "sleep", &options).unwrap())
+            .unwrap();
+        let pid = expect_pid(&c_protocol, 23);
+
+        // A client on another host can see the process, but not signal it
+        let (other_protocol, other_engine) =
+            start_session_for("127.0.0.2:7100", processes.clone(), None, None);
+        other_protocol
+            .send(messages::kill::to_cbor(23, Some(libc::SIGKILL as u32)).unwrap())
+            .unwrap();
+        assert!(expect_error(&other_protocol, 23).contains("another client"));
+        assert_eq!(processes.pid(23), Some(pid));
+
+        // Nor can it start another process on the same channel while this one is running
+        other_protocol
+            .send(messages::spawn::to_cbor(23, "true", &Default::default()).unwrap())
+            .unwrap();
+        assert!(expect_error(&other_protocol, 23).contains("already running"));
+        assert_eq!(processes.pid(23), Some(pid));
+
+        // A client on the same host, from another port, can signal it,
+        // but only with one of the allowed signals
+        let (same_protocol, same_engine) =
+            start_session_for("127.0.0.1:7101", processes.clone(), None, None);
+        same_protocol
+            .send(messages::kill::to_cbor(23, Some(31)).unwrap())
+            .unwrap();
+        assert!(expect_error(&same_protocol, 23).contains("not allowed"));
+        assert_eq!(processes.pid(23), Some(pid));
+
+        same_protocol
+            .send(messages::kill::to_cbor(23, Some(libc::SIGINT as u32)).unwrap())
+            .unwrap();
+        let (_, _, code, signal) = collect_output(&c_protocol);
+        assert_eq!((code, signal), (0, libc::SIGINT as u32));
+
+        assert!(engine.join().unwrap().is_ok());
+        assert!(other_engine.join().unwrap().is_ok());
+        assert!(same_engine.join().unwrap().is_ok());
+        assert!(processes.list().is_empty());
+    }
+
+    #[test]
+    fn idle_timeout_kills_process() {
+        let processes = ProcessList::new();
+        let (c_protocol, engine) =
+            start_limited_session(processes.clone(), Some(Duration::from_millis(200)), None);
+
+        let options = SpawnOptions {
+            args: Some(vec!["30".to_owned()]),
+            ..Default::default()
+        };
+        c_protocol
+            .send(messages::spawn::to_cbor(30, "sleep", &options).unwrap())
+            .unwrap();
+        expect_pid(&c_protocol, 30);
+
+        match recv(&c_protocol) {
+            Message::Exit { code, signal, .. } => {
+                assert_eq!((code, signal), (0, libc::SIGKILL as u32))
+            }
+            other => panic!("Unexpected message: {:?}", other),
+        }
+        assert_eq!(expect_timeout(&c_protocol, 30), "idle");
+        assert!(engine.join().unwrap().is_ok());
+        assert!(processes.list().is_empty());
+    }
+
+    #[test]
+    fn lifetime_limits_busy_session() {
+        let (c_protocol, engine) =
+            start_limited_session(ProcessList::new(), None, Some(Duration::from_secs(1)));
+
+        // Output counts as activity, but it can't keep the session going forever
+        let options = SpawnOptions {
+            args: Some(vec![
+                "-c".to_owned(),
+                "while true; do echo tick; sleep 0.1; done".to_owned(),
+            ]),
+            ..Default::default()
+        };
+        c_protocol
+            .send(messages::spawn::to_cbor(31, "sh", &options).unwrap())
+            .unwrap();
+        expect_pid(&c_protocol, 31);
+
+        loop {
+            match recv(&c_protocol) {
+                Message::Stdout { .. } => {}
+                Message::Exit { signal, .. } => {
+                    assert_eq!(signal, libc::SIGKILL as u32);
+                    break;
+                }
+                other => panic!("Unexpected message: {:?}", other),
+            }
+        }
+        assert_eq!(expect_timeout(&c_protocol, 31), "lifetime");
+        assert!(engine.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn timeout_leaves_detached_process_running() {
+        let processes = ProcessList::new();
+        let (c_protocol, engine) =
+            start_limited_session(processes.clone(), Some(Duration::from_millis(200)), None);
+
+        let options = SpawnOptions {
+            args: Some(vec!["30".to_owned()]),
+            detached: true,
+            ..Default::default()
+        };
+        c_protocol
+            .send(messages::spawn::to_cbor(32, "sleep", &options).unwrap())
+            .unwrap();
+        let pid = expect_pid(&c_protocol, 32);
+
+        // There's no exit status, since the process is still going
+        assert_eq!(expect_timeout(&c_protocol, 32), "idle");
+        assert!(engine.join().unwrap().is_ok());
+        assert_eq!(processes.pid(32), Some(pid));
+
+        // It's dropped from the list once it finishes
+        unsafe {
+            libc::kill(pid as libc::pid_t, libc::SIGTERM);
+        }
+        for _ in 0..50 {
+            if processes.pid(32).is_none() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
         }
+        assert_eq!(processes.pid(32), None);
     }
 }
@@ -0,0 +1,118 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+// Pseudo-terminals for interactive processes
+
+use libc;
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+/// The controlling side of a pseudo-terminal
+pub struct Pty {
+    master: File,
+}
+
+impl Pty {
+    /// Allocate a new pseudo-terminal
+    ///
+    /// Returns the controlling side, along with the terminal itself
+    /// for the process to use as its stdio
+    pub fn open() -> io::Result<(Pty, File)> {
+        let master = unsafe {
+            // Other processes we start mustn't inherit the controlling side
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // Make sure the descriptor is closed if anything below fails
+            File::from_raw_fd(fd)
+        };
+
+        let path = unsafe {
+            if libc::grantpt(master.as_raw_fd()) != 0 || libc::unlockpt(master.as_raw_fd()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // `ptsname` returns a static buffer, which other threads may be using
+            let mut name = [0 as libc::c_char; 128];
+            let result = libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len());
+            if result != 0 {
+                return Err(io::Error::from_raw_os_error(result));
+            }
+            CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned()
+        };
+
+        let terminal = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(path)?;
+
+        Ok((Pty { master }, terminal))
+    }
+
+    /// Get another handle to the controlling side, for reading or writing
+    pub fn try_clone(&self) -> io::Result<File> {
+        self.master.try_clone()
+    }
+
+    /// Change the size of the terminal
+    pub fn resize(&self, columns: u16, rows: u16) -> io::Result<()> {
+        let size = libc::winsize {
+            ws_row: rows,
+            ws_col: columns,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        };
+
+        if unsafe { libc::ioctl(self.master.as_raw_fd(), libc::TIOCSWINSZ, &size) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Make the terminal on stdin the controlling terminal of a new session
+///
+/// Only to be called in a newly forked child, before the command is run
+pub fn make_controlling() -> io::Result<()> {
+    unsafe {
+        if libc::setsid() < 0 || libc::ioctl(0, libc::TIOCSCTTY, 0) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn controlling_side_not_inherited() {
+        let (pty, terminal) = Pty::open().unwrap();
+
+        let flags = unsafe { libc::fcntl(pty.master.as_raw_fd(), libc::F_GETFD) };
+        assert!(flags >= 0);
+        assert_ne!(flags & libc::FD_CLOEXEC, 0);
+
+        let flags = unsafe { libc::fcntl(terminal.as_raw_fd(), libc::F_GETFD) };
+        assert!(flags >= 0);
+        assert_ne!(flags & libc::FD_CLOEXEC, 0);
+    }
+}
@@ -19,27 +19,43 @@ extern crate file_protocol;
 extern crate kubos_system;
 #[macro_use]
 extern crate log;
+#[macro_use]
 extern crate failure;
 extern crate serde_cbor;
 extern crate simplelog;
 
-use file_protocol::{FileProtocol, FileProtocolConfig, ProtocolError, State};
+mod transactions;
+
+use cbor_protocol::{SlipTransport, UnixTransport};
+use file_protocol::{
+    FileProtocol, FileProtocolConfig, Message, ProtocolError, State, Task, TaskOutput,
+};
 use kubos_system::Config as ServiceConfig;
+use std::cell::RefCell;
+use std::cmp;
 use std::collections::HashMap;
-use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
-use std::sync::{Arc, Mutex};
+use std::fs;
+use std::mem;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use transactions::Transactions;
+
+// How often to check on storage work which is being done in the background
+const TASK_POLL: Duration = Duration::from_millis(10);
+
+// The outcome of a transaction's storage work, along with its channel
+type TaskDone = (u32, Result<TaskOutput, ProtocolError>);
 
 // We need this in this lib.rs file so we can build integration tests
 pub fn recv_loop(config: ServiceConfig) -> Result<(), failure::Error> {
     // Get and bind our UDP listening socket
     let host = config.hosturl();
 
-    // Extract our local IP address so we can spawn child sockets later
-    let mut host_parts = host.split(':').map(|val| val.to_owned());
-    let host_ip = host_parts.next().unwrap();
-
     // Get the storage directory prefix that we'll be using for our
     // temporary/intermediate storage location
     let prefix = match config.get("storage_dir") {
@@ -58,9 +74,80 @@ pub fn recv_loop(config: ServiceConfig) -> Result<(), failure::Error> {
         None => 5,
     } as u16;
 
-    let f_config = FileProtocolConfig::new(prefix, chunk_size, hold_count);
+    // Get the maximum number of chunks to send in response to a single NAK
+    let window_size = match config.get("window_size") {
+        Some(val) => val.as_integer().unwrap_or(0),
+        None => 0,
+    } as u32;
+
+    // Get the maximum transmission rate, in bytes per second
+    let max_rate = match config.get("max_rate") {
+        Some(val) => val.as_integer().unwrap_or(0),
+        None => 0,
+    } as u32;
+
+    // Whether files should be sent straight from their original location,
+    // rather than being copied into temporary storage first
+    let streaming = match config.get("streaming") {
+        Some(val) => val.as_bool().unwrap_or(false),
+        None => false,
+    };
+
+    // Get the number of parity chunks to send after each block of chunks,
+    // so clients can rebuild lost chunks without requesting them again
+    let fec_block = match config.get("fec_block") {
+        Some(val) => val.as_integer().unwrap_or(16),
+        None => 16,
+    } as u32;
+    let fec_parity = match config.get("fec_parity") {
+        Some(val) => val.as_integer().unwrap_or(0),
+        None => 0,
+    } as u32;
+
+    // Get the maximum total size of temporary storage, in bytes
+    let max_storage = match config.get("max_storage") {
+        Some(val) => val.as_integer().unwrap_or(0),
+        None => 0,
+    } as u64;
+
+    // Get how long, in seconds, temporary files can go untouched before they're removed
+    let storage_max_age = match config.get("storage_max_age") {
+        Some(val) => val.as_integer().unwrap_or(86400),
+        None => 86400,
+    } as u64;
+
+    // Get the directories which clients may read from and write to
+    let read_roots = path_list(&config, "read_roots");
+    let write_roots = path_list(&config, "write_roots");
+
+    let f_config = FileProtocolConfig::new(prefix, chunk_size, hold_count)
+        .with_window_size(window_size)
+        .with_max_rate(max_rate)
+        .with_streaming(streaming)
+        .with_fec(fec_block, fec_parity)
+        .with_storage_limit(max_storage)
+        .with_read_roots(&read_roots)
+        .with_write_roots(&write_roots);
+
+    // Get the key used to sign messages, and how old a signed message may be, in seconds
+    let auth_key = config
+        .get("auth_key")
+        .and_then(|val| val.as_str().map(|key| key.to_owned()));
+    let auth_window = match config.get("auth_window") {
+        Some(val) => val.as_integer().unwrap_or(30),
+        None => 30,
+    } as u64;
 
-    let c_protocol = cbor_protocol::Protocol::new(host.clone(), chunk_size);
+    // Signed messages can only come from those holding the key,
+    // so any of them may cancel a transaction
+    let authenticated = auth_key.is_some();
+
+    let mut c_protocol = open_protocol(&config, host, chunk_size)?;
+    if let Some(key) = auth_key {
+        c_protocol =
+            c_protocol.with_service_auth(key.as_bytes(), Duration::from_secs(auth_window));
+    }
+    let c_protocol = Rc::new(c_protocol);
 
     let timeout = config
         .get("timeout")
@@ -69,26 +156,131 @@ pub fn recv_loop(config: ServiceConfig) -> Result<(), failure::Error> {
                 .and_then(|num| Some(Duration::from_secs(num as u64)))
         }).unwrap_or(Duration::from_secs(2));
 
-    // Setup map of channel IDs to thread channels
-    let raw_threads: HashMap<u32, Sender<serde_cbor::Value>> = HashMap::new();
-    // Create thread sharable wrapper
-    let threads = Arc::new(Mutex::new(raw_threads));
+    // Get the number of finished transactions to remember
+    let history_size = match config.get("history_size") {
+        Some(val) => val.as_integer().unwrap_or(20),
+        None => 20,
+    } as usize;
+
+    // Get the maximum number of transactions to handle at once
+    let max_transactions = match config.get("max_transactions") {
+        Some(val) => val.as_integer().unwrap_or(32),
+        None => 32,
+    } as usize;
+
+    // Setup record of the transactions being handled
+    let transactions = Rc::new(RefCell::new(Transactions::new(history_size)));
+
+    // The protocol engine of each active transaction, by channel.
+    // They all share our socket, so every message arrives here
+    let mut engines: HashMap<u32, Engine> = HashMap::new();
+
+    // Periodically clear out files left behind by transactions which never finished
+    let gc_interval = if storage_max_age > 0 {
+        Some(cmp::max(
+            Duration::from_secs(storage_max_age) / 4,
+            Duration::from_secs(1),
+        ))
+    } else {
+        None
+    };
+    let mut next_gc = Instant::now();
+    let gc_running = Arc::new(AtomicBool::new(false));
+
+    // Preparing and finalizing large files is done on threads of its own,
+    // so that the other transactions don't have to wait for it
+    let (task_sender, task_receiver) = mpsc::channel::<TaskDone>();
 
     loop {
-        // Listen on UDP port
-        let (source, first_message) = match c_protocol.recv_message_peer() {
-            Ok((source, first_message)) => (source, first_message),
+        // Carry on with any transactions whose storage work has finished.
+        // Cancelled transactions won't be found, so their results are dropped
+        while let Ok((channel_id, result)) = task_receiver.try_recv() {
+            step(
+                &mut engines,
+                &transactions,
+                &task_sender,
+                channel_id,
+                timeout,
+                |protocol, state| protocol.finish_task(result, state),
+            );
+        }
+
+        // Send the next few chunks of any transfers which are due some
+        let now = Instant::now();
+        let sending: Vec<u32> = engines
+            .iter()
+            .filter(|(_, engine)| {
+                engine
+                    .protocol
+                    .send_deadline()
+                    .is_some_and(|due| due <= now)
+            })
+            .map(|(channel_id, _)| *channel_id)
+            .collect();
+        for channel_id in sending {
+            send_pending(&mut engines, &transactions, channel_id, timeout);
+        }
+
+        // Let any transactions which have waited too long for a message decide what to do next.
+        // Transactions which are still sending chunks aren't waiting for anything yet
+        let expired: Vec<u32> = engines
+            .iter()
+            .filter(|(_, engine)| {
+                !engine.busy()
+                    && engine.protocol.send_deadline().is_none()
+                    && engine.deadline <= now
+            })
+            .map(|(channel_id, _)| *channel_id)
+            .collect();
+        for channel_id in expired {
+            step(
+                &mut engines,
+                &transactions,
+                &task_sender,
+                channel_id,
+                timeout,
+                |protocol, state| protocol.process_event(Err(ProtocolError::ReceiveTimeout), state),
+            );
+        }
+
+        if let Some(interval) = gc_interval {
+            if next_gc <= now {
+                clean_storage(
+                    f_config.storage_prefix(),
+                    Duration::from_secs(storage_max_age),
+                    &transactions,
+                    &gc_running,
+                );
+                next_gc = now + interval;
+            }
+        }
+
+        // Listen on UDP port, until the next time something else needs doing
+        let wake = engines
+            .values()
+            .map(Engine::wake)
+            .chain(gc_interval.map(|_| next_gc))
+            .min();
+        let received = match wake {
+            Some(wake) => c_protocol.recv_message_peer_timeout(cmp::max(
+                wake.saturating_duration_since(Instant::now()),
+                Duration::from_millis(1),
+            )),
+            None => c_protocol.recv_message_peer(),
+        };
+        let (source, message) = match received {
+            Ok((source, message)) => (source, message),
+            // Flow control frames are handled by the CBOR protocol
+            Err(cbor_protocol::ProtocolError::Timeout)
+            | Err(cbor_protocol::ProtocolError::Paused)
+            | Err(cbor_protocol::ProtocolError::Resumed) => continue,
             Err(e) => {
                 warn!("Error receiving message: {:?}", e);
                 continue;
             }
         };
 
-        let config_ref = f_config.clone();
-        let host_ref = host_ip.clone();
-        let timeout_ref = timeout.clone();
-
-        let channel_id = match file_protocol::parse_channel_id(&first_message) {
+        let channel_id = match file_protocol::parse_channel_id(&message) {
             Ok(channel_id) => channel_id,
             Err(e) => {
                 warn!("Error parsing channel ID: {:?}", e);
@@ -96,56 +288,366 @@ pub fn recv_loop(config: ServiceConfig) -> Result<(), failure::Error> {
             }
         };
 
-        if !threads.lock().unwrap().contains_key(&channel_id) {
-            let (sender, receiver): (
-                Sender<serde_cbor::Value>,
-                Receiver<serde_cbor::Value>,
-            ) = mpsc::channel();
-            threads.lock().unwrap().insert(channel_id, sender.clone());
-            // Break the processing work off into its own thread so we can
-            // listen for requests from other clients
-            let shared_threads = threads.clone();
-            thread::spawn(move || {
-                let state = State::Holding {
-                    count: 0,
-                    prev_state: Box::new(State::Done),
-                };
-
-                // Set up the file system processor with the reply socket information
-                let f_protocol = FileProtocol::new(&host_ref, &format!("{}", source), config_ref);
-
-                // Listen, process, and react to the remaining messages in the
-                // requested operation
-                match f_protocol.message_engine(
-                    |d| match receiver.recv_timeout(d) {
-                        Ok(v) => Ok(v),
-                        Err(RecvTimeoutError::Timeout) => Err(ProtocolError::ReceiveTimeout),
-                        Err(e) => Err(ProtocolError::ReceiveError {
-                            err: format!("Error {:?}", e),
-                        }),
-                    },
-                    timeout_ref,
-                    state,
+        // Transaction management requests are about the other channels' transactions,
+        // so they're answered straight away rather than starting a transaction
+        let parsed = file_protocol::parse_message(message.clone());
+        match parsed {
+            // So are questions about what we support
+            Ok(Message::Hello(channel_id, ref remote)) => {
+                info!(
+                    "Client {} speaks file protocol version {} ({:?})",
+                    source, remote.version, remote.features
+                );
+                let f_protocol = FileProtocol::new_shared(
+                    c_protocol.clone(),
+                    &format!("{}", source),
+                    f_config.clone(),
+                );
+                if let Err(e) = f_protocol.send_hello(channel_id) {
+                    warn!("Failed to answer hello: {}", e);
+                }
+                continue;
+            }
+            Ok(ref request @ Message::ReqTransactions(_))
+            | Ok(ref request @ Message::ReqHistory(_, _))
+            | Ok(ref request @ Message::ReqCancel(_, _)) => {
+                let f_protocol = FileProtocol::new_shared(
+                    c_protocol.clone(),
+                    &format!("{}", source),
+                    f_config.clone(),
+                );
+                if let Err(e) = manage_transactions(
+                    &f_protocol,
+                    &mut engines,
+                    &transactions,
+                    source,
+                    authenticated,
+                    request,
                 ) {
-                    Err(e) => warn!("Encountered errors while processing transaction: {}", e),
-                    _ => {}
+                    warn!("Failed to answer transaction request: {}", e);
                 }
+                continue;
+            }
+            _ => {}
+        }
 
-                // Remove ourselves from threads list if we are finished
-                shared_threads.lock().unwrap().remove(&channel_id);
-            });
+        // The other side may not have noticed yet that a transaction was cancelled or refused
+        if transactions.borrow().closed(channel_id) {
+            continue;
         }
 
-        if let Some(sender) = threads.lock().unwrap().get(&channel_id) {
-            match sender.send(first_message) {
-                Err(e) => warn!("Error when sending to channel {}: {:?}", channel_id, e),
-                _ => {}
-            };
+        if !engines.contains_key(&channel_id) {
+            // Set up the file system processor with the reply address
+            let f_protocol = FileProtocol::new_shared(
+                c_protocol.clone(),
+                &format!("{}", source),
+                f_config.clone(),
+            );
+
+            // Let the other side know if it's asked for something we don't understand,
+            // rather than leaving it waiting for a reply
+            if let Err(ref e) = parsed {
+                let error = ProtocolError::UnsupportedRequest(format!("{}", e));
+                warn!("Refusing transaction on channel {}: {}", channel_id, error);
+                transactions
+                    .borrow_mut()
+                    .refuse(channel_id, format!("{}", error));
+                if let Err(e) = f_protocol.send_result(channel_id, Err(error)) {
+                    warn!("Failed to report refusal: {}", e);
+                }
+                continue;
+            }
+
+            // Turn new transactions away once we're handling as many as we're allowed
+            if max_transactions > 0 && engines.len() >= max_transactions {
+                let error = ProtocolError::TooManyTransactions(max_transactions);
+                warn!("Refusing transaction on channel {}: {}", channel_id, error);
+                transactions
+                    .borrow_mut()
+                    .refuse(channel_id, format!("{}", error));
+                if let Err(e) = f_protocol.send_result(channel_id, Err(error)) {
+                    warn!("Failed to report refusal: {}", e);
+                }
+                continue;
+            }
+
+            transactions.borrow_mut().insert(channel_id);
+            let progress_transactions = transactions.clone();
+            engines.insert(
+                channel_id,
+                Engine {
+                    protocol: f_protocol
+                        .with_progress(move |progress| {
+                            progress_transactions
+                                .borrow_mut()
+                                .update_progress(channel_id, progress)
+                        }).with_background_tasks(),
+                    state: State::Holding {
+                        count: 0,
+                        prev_state: Box::new(State::Done),
+                    },
+                    deadline: now + timeout,
+                    peer: source,
+                },
+            );
         }
 
-        if !threads.lock().unwrap().contains_key(&channel_id) {
-            warn!("No sender found for {}", channel_id);
-            threads.lock().unwrap().remove(&channel_id);
+        if let Ok(ref parsed) = parsed {
+            transactions.borrow_mut().observe(channel_id, parsed);
+        }
+
+        // Process, and react to, the message
+        step(
+            &mut engines,
+            &transactions,
+            &task_sender,
+            channel_id,
+            timeout,
+            |protocol, state| protocol.process_event(Ok(message), state),
+        );
+    }
+}
+
+// Get a config option which holds a list of paths.
+// A single path is treated as a list of one, and a missing option as an empty list
+fn path_list(config: &ServiceConfig, key: &str) -> Vec<String> {
+    match config.get(key) {
+        Some(ref val) if val.is_str() => vec![val.as_str().unwrap().to_owned()],
+        Some(val) => val
+            .as_array()
+            .map(|paths| {
+                paths
+                    .iter()
+                    .filter_map(|path| path.as_str().map(|path| path.to_owned()))
+                    .collect()
+            }).unwrap_or_default(),
+        None => vec![],
+    }
+}
+
+// Open the link which messages are sent and received over.
+// This is the UDP socket bound to the service's address, unless the config asks for
+// a Unix socket or a serial line. Those only connect two points, so every message
+// on them comes from, and goes to, the one client on the other end
+fn open_protocol(
+    config: &ServiceConfig,
+    host: String,
+    chunk_size: usize,
+) -> Result<cbor_protocol::Protocol, failure::Error> {
+    let setting = |key| {
+        config
+            .get(key)
+            .and_then(|val| val.as_str().map(|val| val.to_owned()))
+            .ok_or_else(|| format_err!("The {} option is needed for this transport", key))
+    };
+    let transport = config
+        .get("transport")
+        .and_then(|val| val.as_str().map(|val| val.to_owned()))
+        .unwrap_or_else(|| "udp".to_owned());
+
+    let protocol = match transport.as_str() {
+        "udp" => cbor_protocol::Protocol::new(host, chunk_size),
+        "unix" => {
+            // Clear away the socket left behind by a previous run
+            let path = setting("socket_path")?;
+            let _ = fs::remove_file(&path);
+            let socket = UnixTransport::bind(&path, setting("peer_path")?)?;
+            cbor_protocol::Protocol::from_transport(socket, chunk_size)
+        }
+        "slip" | "kiss" => {
+            let baud_rate = match config.get("baud_rate") {
+                Some(val) => val.as_integer().unwrap_or(115200),
+                None => 115200,
+            } as usize;
+            let mut link = SlipTransport::open(&setting("device")?, baud_rate)?;
+            if transport == "kiss" {
+                link = link.with_kiss();
+            }
+            cbor_protocol::Protocol::from_transport(link, chunk_size)
+        }
+        other => bail!("Unknown transport: {}", other),
+    };
+    Ok(protocol)
+}
+
+// An active transaction
+struct Engine {
+    protocol: FileProtocol,
+    state: State,
+    // When the transaction gives up waiting for its next message
+    deadline: Instant,
+    // Who the transaction is with
+    peer: SocketAddr,
+}
+
+impl Engine {
+    // When the transaction next needs something done
+    fn wake(&self) -> Instant {
+        if self.busy() {
+            return Instant::now() + TASK_POLL;
+        }
+        self.protocol.send_deadline().unwrap_or(self.deadline)
+    }
+
+    // Whether the transaction is waiting for its storage work to finish,
+    // rather than for a message
+    fn busy(&self) -> bool {
+        matches!(self.state, State::Preparing { .. } | State::Finalizing { .. })
+    }
+}
+
+// Move a transaction's engine on, by passing it a received message, a timeout or
+// the result of its storage work. Any new storage work it needs is started on a
+// thread of its own, which reports back through `tasks`.
+// The transaction is moved into the history once the engine is done with it
+fn step<F>(
+    engines: &mut HashMap<u32, Engine>,
+    transactions: &RefCell<Transactions>,
+    tasks: &Sender<TaskDone>,
+    channel_id: u32,
+    timeout: Duration,
+    process: F,
+) where
+    F: FnOnce(&FileProtocol, State) -> Result<State, ProtocolError>,
+{
+    let error = match engines.get_mut(&channel_id) {
+        Some(engine) => {
+            let state = mem::replace(&mut engine.state, State::Done);
+            match process(&engine.protocol, state) {
+                Ok(State::Done) => None,
+                Ok(state) => {
+                    engine.deadline = Instant::now() + engine.protocol.wait_time(&state, timeout);
+                    engine.state = state;
+                    if let Some(task) = engine.protocol.take_task() {
+                        run_task(tasks, channel_id, task);
+                    }
+                    return;
+                }
+                Err(e) => {
+                    warn!("Encountered errors while processing transaction: {}", e);
+                    Some(format!("{}", e))
+                }
+            }
+        }
+        None => return,
+    };
+
+    engines.remove(&channel_id);
+    transactions.borrow_mut().finish(channel_id, error);
+}
+
+// Run a transaction's storage work in the background
+fn run_task(tasks: &Sender<TaskDone>, channel_id: u32, task: Task) {
+    let tasks = tasks.clone();
+    thread::spawn(move || {
+        // The service only stops listening for results when it shuts down
+        let _ = tasks.send((channel_id, task.run()));
+    });
+}
+
+// Send whatever is due of the chunks a transaction is sending.
+// The transaction is moved into the history if the rest of the file can't be sent
+fn send_pending(
+    engines: &mut HashMap<u32, Engine>,
+    transactions: &RefCell<Transactions>,
+    channel_id: u32,
+    timeout: Duration,
+) {
+    let error = match engines.get_mut(&channel_id) {
+        Some(engine) => match engine.protocol.send_pending() {
+            Ok(()) => {
+                engine.deadline = Instant::now() + timeout;
+                return;
+            }
+            Err(e) => {
+                warn!("Encountered errors while processing transaction: {}", e);
+                format!("{}", e)
+            }
+        },
+        None => return,
+    };
+
+    engines.remove(&channel_id);
+    transactions.borrow_mut().finish(channel_id, Some(error));
+}
+
+// Remove temporary files which haven't been touched for a while,
+// unless they belong to an active transaction.
+// Storage is scanned in the background, and only one scan is run at a time
+fn clean_storage(
+    prefix: &str,
+    max_age: Duration,
+    transactions: &RefCell<Transactions>,
+    running: &Arc<AtomicBool>,
+) {
+    if running.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    let active: Vec<String> = transactions
+        .borrow()
+        .active()
+        .into_iter()
+        .map(|info| info.hash)
+        .filter(|hash| !hash.is_empty())
+        .collect();
+
+    let prefix = prefix.to_owned();
+    let running = running.clone();
+    thread::spawn(move || {
+        match file_protocol::collect_garbage(&prefix, max_age, &active) {
+            Ok(ref removed) if !removed.is_empty() => {
+                info!("Removed stale temporary files: {:?}", removed)
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to clean up temporary storage: {}", e),
+        }
+        running.store(false, Ordering::SeqCst);
+    });
+}
+
+// Answer a request to list or cancel transactions.
+// Unless messages are authenticated, only the host a transaction is with may cancel it.
+// Any port on that host will do, since the stuck client is still holding its own
+fn manage_transactions(
+    f_protocol: &FileProtocol,
+    engines: &mut HashMap<u32, Engine>,
+    transactions: &RefCell<Transactions>,
+    source: SocketAddr,
+    authenticated: bool,
+    request: &Message,
+) -> Result<(), ProtocolError> {
+    match request {
+        Message::ReqTransactions(channel_id) => {
+            let active = transactions.borrow().active();
+            f_protocol.send_transactions(*channel_id, &active)
+        }
+        Message::ReqHistory(channel_id, count) => {
+            let history = transactions.borrow().history(*count as usize);
+            f_protocol.send_transactions(*channel_id, &history)
+        }
+        Message::ReqCancel(channel_id, target_channel) => {
+            let allowed = engines
+                .get(target_channel)
+                .is_some_and(|engine| authenticated || engine.peer.ip() == source.ip());
+            let result = if allowed && transactions.borrow_mut().cancel(*target_channel) {
+                info!("Cancelling transaction on channel {}", target_channel);
+                // Let the other side know that it shouldn't wait for us
+                if let Some(engine) = engines.remove(target_channel) {
+                    if let Err(e) = engine
+                        .protocol
+                        .send_result(*target_channel, Err(ProtocolError::Cancelled))
+                    {
+                        warn!("Failed to report cancellation: {}", e);
+                    }
+                }
+                transactions.borrow_mut().finish(*target_channel, None);
+                Ok(())
+            } else {
+                Err(ProtocolError::NoTransaction(*target_channel))
+            };
+            f_protocol.send_result(*channel_id, result)
         }
+        _ => Ok(()),
     }
 }
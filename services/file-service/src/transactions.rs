@@ -0,0 +1,183 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use file_protocol::{Direction, Message, Progress, ProtocolError, TransactionInfo};
+use std::collections::{HashMap, VecDeque};
+use std::time::Instant;
+
+// A transaction which is currently being processed
+struct Active {
+    info: TransactionInfo,
+    start: Instant,
+}
+
+/// Record of the transactions the service is handling, and the ones it has recently finished
+pub struct Transactions {
+    active: HashMap<u32, Active>,
+    history: VecDeque<TransactionInfo>,
+    history_size: usize,
+    // Recently cancelled or refused channels, whose stray messages shouldn't start new transactions
+    closed: VecDeque<u32>,
+}
+
+impl Transactions {
+    /// Create an empty record, which remembers up to `history_size` finished transactions
+    pub fn new(history_size: usize) -> Self {
+        Transactions {
+            active: HashMap::new(),
+            history: VecDeque::new(),
+            history_size,
+            closed: VecDeque::new(),
+        }
+    }
+
+    /// Whether the channel's transaction was recently cancelled or refused
+    pub fn closed(&self, channel_id: u32) -> bool {
+        self.closed.contains(&channel_id)
+    }
+
+    /// Record a new transaction
+    pub fn insert(&mut self, channel_id: u32) {
+        self.active.insert(
+            channel_id,
+            Active {
+                info: TransactionInfo::new(channel_id),
+                start: Instant::now(),
+            },
+        );
+    }
+
+    /// Record a transaction which was turned away before it started
+    pub fn refuse(&mut self, channel_id: u32, error: String) {
+        let mut info = TransactionInfo::new(channel_id);
+        info.error = Some(error);
+        self.history.push_front(info);
+        self.history.truncate(self.history_size);
+        self.close(channel_id);
+    }
+
+    // Ignore anything else which arrives for a channel
+    fn close(&mut self, channel_id: u32) {
+        self.closed.push_front(channel_id);
+        self.closed.truncate(self.history_size);
+    }
+
+    /// Fill in what we can learn about a transaction from one of its messages
+    pub fn observe(&mut self, channel_id: u32, message: &Message) {
+        let info = match self.active.get_mut(&channel_id) {
+            Some(active) => &mut active.info,
+            None => return,
+        };
+
+        match message {
+            Message::Metadata(_, hash, num_chunks, _) => {
+                info.direction = Direction::Upload;
+                info.hash = hash.to_owned();
+                info.num_chunks = *num_chunks;
+            }
+            Message::ReqReceive(_, hash, path, _) | Message::ReqReceiveDir(_, hash, path) => {
+                info.direction = Direction::Upload;
+                info.hash = hash.to_owned();
+                info.path = path.to_owned();
+            }
+            Message::ReqTransmit(_, path, ..) | Message::ReqTransmitDir(_, path) => {
+                info.direction = Direction::Download;
+                info.path = path.to_owned();
+            }
+            Message::Resume(_, hash) => info.hash = hash.to_owned(),
+            Message::ReqList(_, path)
+            | Message::ReqStat(_, path)
+            | Message::ReqDelete(_, path, _)
+            | Message::ReqMkdir(_, path)
+            | Message::ReqRename(_, path, _) => info.path = path.to_owned(),
+            _ => {}
+        }
+    }
+
+    /// Record the progress of a transaction's file transfer
+    pub fn update_progress(&mut self, channel_id: u32, progress: &Progress) {
+        let info = match self.active.get_mut(&channel_id) {
+            Some(active) => &mut active.info,
+            None => return,
+        };
+
+        info.hash = progress.hash.clone();
+        info.num_chunks = progress.num_chunks;
+        info.chunks_done = progress.chunks_done();
+
+        // Resumed transactions don't say which way the file is going
+        if info.direction == Direction::Other {
+            if progress.chunks_received > 0 {
+                info.direction = Direction::Upload;
+            } else if progress.chunks_sent > 0 {
+                info.direction = Direction::Download;
+            }
+        }
+    }
+
+    /// Mark a transaction as cancelled, so that any further messages for it are ignored.
+    /// The caller is responsible for stopping its processing and calling `finish`
+    ///
+    /// Returns false if there's no active transaction on the channel
+    pub fn cancel(&mut self, channel_id: u32) -> bool {
+        match self.active.get_mut(&channel_id) {
+            Some(active) => active.info.error = Some(format!("{}", ProtocolError::Cancelled)),
+            None => return false,
+        }
+
+        self.close(channel_id);
+        true
+    }
+
+    /// Move a transaction into the history once it's done
+    pub fn finish(&mut self, channel_id: u32, error: Option<String>) {
+        let active = match self.active.remove(&channel_id) {
+            Some(active) => active,
+            None => return,
+        };
+
+        let mut info = active.info;
+        info.age = active.start.elapsed().as_secs();
+        // A cancellation is more useful to know about than the failure it caused
+        if info.error.is_none() {
+            info.error = error;
+        }
+
+        self.history.push_front(info);
+        self.history.truncate(self.history_size);
+    }
+
+    /// Information about each active transaction, ordered by channel
+    pub fn active(&self) -> Vec<TransactionInfo> {
+        let mut transactions: Vec<TransactionInfo> = self
+            .active
+            .values()
+            .map(|active| {
+                let mut info = active.info.clone();
+                info.age = active.start.elapsed().as_secs();
+                info
+            }).collect();
+
+        transactions.sort_by_key(|info| info.channel_id);
+        transactions
+    }
+
+    /// Information about up to `count` of the most recently finished transactions,
+    /// newest first
+    pub fn history(&self, count: usize) -> Vec<TransactionInfo> {
+        self.history.iter().take(count).cloned().collect()
+    }
+}
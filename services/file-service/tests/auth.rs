@@ -0,0 +1,181 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+extern crate file_protocol;
+extern crate file_service;
+extern crate kubos_system;
+extern crate tempfile;
+
+use file_protocol::{FileProtocol, FileProtocolConfig, Message, ProtocolError, State};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Start a service which only accepts messages signed with the given key
+fn auth_service_new(port: u16, key: &str) {
+    let config = format!(
+        r#"
+        [file-transfer-service]
+        storage_dir = "service"
+        chunk_size = 4096
+        hold_count = 5
+        timeout = 1
+        auth_key = "{}"
+        [file-transfer-service.addr]
+        ip = "127.0.0.1"
+        port = {}
+        "#,
+        key, port
+    );
+
+    thread::spawn(move || {
+        recv_loop(ServiceConfig::new_from_str(
+            "file-transfer-service",
+            &config,
+        )).unwrap();
+    });
+
+    thread::sleep(Duration::new(1, 0));
+}
+
+// Upload a file, signing messages with the given key
+fn signed_upload(
+    port: u16,
+    key: Option<&str>,
+    source: &str,
+    dest: &str,
+) -> (String, Result<(), ProtocolError>) {
+    let mut f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 2);
+    if let Some(key) = key {
+        f_config = f_config.with_auth(key.as_bytes(), Duration::from_secs(30));
+    }
+    let f_protocol = FileProtocol::new("127.0.0.1", &format!("127.0.0.1:{}", port), f_config);
+
+    let (hash, num_chunks, mode) = f_protocol.initialize_file(source).unwrap();
+    let channel = f_protocol.generate_channel().unwrap();
+    f_protocol
+        .send_metadata(channel, &hash, num_chunks)
+        .unwrap();
+    f_protocol.send_export(channel, &hash, dest, mode).unwrap();
+
+    let result = f_protocol.message_engine(
+        |d| f_protocol.recv(Some(d)),
+        Duration::from_secs(1),
+        State::Transmitting,
+    );
+    (hash, result)
+}
+
+// Clients using the service's key are answered as normal
+#[test]
+fn matching_key() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7140;
+
+    // Several full chunks, so that the largest messages are signed
+    let contents: Vec<u8> = (0..3 * 4096).map(|i| (i * 7 % 251) as u8).collect();
+    File::create(&source).unwrap().write_all(&contents).unwrap();
+
+    auth_service_new(service_port, "secret");
+
+    let (hash, result) = signed_upload(service_port, Some("secret"), &source, &dest);
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    assert!(result.is_ok(), "Upload failed: {:?}", result);
+    assert_eq!(fs::read(&dest).unwrap(), contents);
+}
+
+// Messages which aren't signed with the service's key are ignored
+#[test]
+fn missing_or_wrong_key() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7141;
+
+    File::create(&source)
+        .unwrap()
+        .write_all("missing_or_wrong_key".as_bytes())
+        .unwrap();
+
+    auth_service_new(service_port, "secret");
+
+    for key in [None, Some("guess")].iter() {
+        // The client just gives up waiting for a reply, so the service's
+        // storage is the only sign that nothing got through
+        let (hash, _) = signed_upload(service_port, *key, &source, &dest);
+        fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+
+        assert!(!Path::new(&format!("service/storage/{}", hash)).exists());
+        assert!(!Path::new(&dest).exists());
+    }
+}
+
+// Any client holding the key may cancel a transaction, wherever it's sending from
+#[test]
+fn authenticated_cancel() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7142;
+
+    File::create(&source)
+        .unwrap()
+        .write_all("authenticated_cancel".as_bytes())
+        .unwrap();
+
+    auth_service_new(service_port, "secret");
+
+    let signed_client = |host_ip: &str| {
+        let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 2)
+            .with_auth(b"secret", Duration::from_secs(30));
+        FileProtocol::new(host_ip, &format!("127.0.0.1:{}", service_port), f_config)
+    };
+
+    // Start the upload, but don't send any of the file yet
+    let f_protocol = signed_client("127.0.0.1");
+    let (hash, num_chunks, mode) = f_protocol.initialize_file(&source).unwrap();
+    let channel = f_protocol.generate_channel().unwrap();
+    f_protocol.send_metadata(channel, &hash, num_chunks).unwrap();
+    f_protocol.send_export(channel, &hash, &dest, mode).unwrap();
+    let reply = f_protocol.recv(Some(Duration::from_secs(1))).unwrap();
+    match file_protocol::parse_message(reply) {
+        Ok(Message::NAK(..)) => {}
+        other => panic!("Unexpected reply: {:?}", other),
+    }
+
+    let result = signed_client("127.0.0.2").remote_cancel(channel);
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    assert!(result.is_ok(), "Cancel failed: {:?}", result);
+    assert!(!Path::new(&dest).exists());
+}
@@ -19,9 +19,10 @@ extern crate blake2_rfc;
 extern crate file_protocol;
 
 use common::blake2_rfc::blake2s::Blake2s;
-use file_protocol::{FileProtocol, FileProtocolConfig, ProtocolError, State};
+use file_protocol::{manifest, FileProtocol, FileProtocolConfig, ProtocolError, State};
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::Path;
 use std::time::Duration;
 
 #[macro_export]
@@ -121,6 +122,123 @@ pub fn upload(
     Ok(hash.to_owned())
 }
 
+pub fn resume(
+    host_ip: &str,
+    remote_addr: &str,
+    hash: &str,
+    prefix: Option<String>,
+    chunk_size: u32,
+) -> Result<(), ProtocolError> {
+    let hold_count = 5;
+    let f_config = FileProtocolConfig::new(prefix, chunk_size as usize, hold_count);
+    let f_protocol = FileProtocol::new(host_ip, remote_addr, f_config);
+
+    // figure out which side of the transfer we were on
+    let state = f_protocol.load_state(hash)?;
+
+    let channel = f_protocol.generate_channel()?;
+
+    // ask the remote target to pick up where it left off
+    f_protocol.send_resume(channel, hash)?;
+
+    let state = match state {
+        State::StartReceive { .. } => {
+            let reply = f_protocol.recv(None)?;
+            f_protocol.process_message(reply, state)?
+        }
+        other => other,
+    };
+
+    f_protocol.message_engine(|d| f_protocol.recv(Some(d)), Duration::from_secs(2), state)
+}
+
+pub fn upload_dir(
+    host_ip: &str,
+    remote_addr: &str,
+    source_path: &str,
+    target_path: &str,
+    prefix: Option<String>,
+    chunk_size: u32,
+) -> Result<(), ProtocolError> {
+    let hold_count = 5;
+    let f_config = FileProtocolConfig::new(prefix.clone(), chunk_size as usize, hold_count);
+    let f_protocol = FileProtocol::new(host_ip, remote_addr, f_config);
+
+    // walk the tree and send its manifest so the directories get created
+    let (hash, num_chunks, entries) = f_protocol.initialize_directory(source_path)?;
+
+    let channel = f_protocol.generate_channel()?;
+    f_protocol.send_metadata(channel, &hash, num_chunks)?;
+    f_protocol.send_export_dir(channel, &hash, target_path)?;
+
+    f_protocol.message_engine(
+        |d| f_protocol.recv(Some(d)),
+        Duration::from_secs(2),
+        State::Transmitting,
+    )?;
+
+    // then send each file on its own
+    for entry in entries.iter().filter(|entry| !entry.is_dir) {
+        upload(
+            host_ip,
+            remote_addr,
+            &manifest::entry_path(source_path, entry)?.to_string_lossy(),
+            &manifest::entry_path(target_path, entry)?.to_string_lossy(),
+            prefix.clone(),
+            chunk_size,
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn download_dir(
+    host_ip: &str,
+    remote_addr: &str,
+    source_path: &str,
+    target_path: &str,
+    prefix: Option<String>,
+    chunk_size: u32,
+) -> Result<(), ProtocolError> {
+    let hold_count = 5;
+    let f_config = FileProtocolConfig::new(prefix.clone(), chunk_size as usize, hold_count);
+    let f_protocol = FileProtocol::new(host_ip, remote_addr, f_config);
+
+    let channel = f_protocol.generate_channel()?;
+
+    // ask for the manifest of the remote tree
+    f_protocol.send_import_dir(channel, source_path)?;
+
+    let manifest_path = format!("{}.manifest", target_path);
+
+    let reply = f_protocol.recv(None)?;
+    let state = f_protocol.process_message(
+        reply,
+        State::StartReceive {
+            path: manifest_path.clone(),
+        },
+    )?;
+
+    f_protocol.message_engine(|d| f_protocol.recv(Some(d)), Duration::from_secs(2), state)?;
+
+    let entries = manifest::load(Path::new(&manifest_path))?;
+    manifest::create_directories(target_path, &entries)?;
+
+    // then fetch each file on its own
+    for entry in entries.iter().filter(|entry| !entry.is_dir) {
+        download(
+            host_ip,
+            remote_addr,
+            &manifest::entry_path(source_path, entry)?.to_string_lossy(),
+            &manifest::entry_path(target_path, entry)?.to_string_lossy(),
+            prefix.clone(),
+            chunk_size,
+        )?;
+    }
+
+    Ok(())
+}
+
 pub fn create_test_file(name: &str, contents: &[u8]) -> String {
     let mut file = File::create(name).unwrap();
     file.write_all(contents).unwrap();
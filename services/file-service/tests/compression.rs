@@ -0,0 +1,146 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+extern crate cbor_protocol;
+extern crate file_protocol;
+extern crate file_service;
+extern crate kubos_system;
+extern crate tempfile;
+
+mod common;
+
+use common::*;
+use file_protocol::{Compression, FileProtocol, FileProtocolConfig, State};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// NOTE: Each test's file contents must be unique. Otherwise the hash is the same, so
+// the same storage directory is used across all of them, creating conflicts
+
+// Upload a highly compressible file using deflate
+#[test]
+fn upload_compressed() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7030;
+
+    let contents = [30; 50000];
+
+    let hash = create_test_file(&source, &contents);
+
+    service_new!(service_port, 4096);
+
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5)
+        .with_compression(Compression::Deflate);
+    let f_protocol = FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+
+    // The hash is still calculated over the original contents
+    let (file_hash, num_chunks, mode) = f_protocol.initialize_file(&source).unwrap();
+    assert_eq!(file_hash, hash);
+    // Uncompressed, this would take 13 chunks
+    assert_eq!(num_chunks, 1);
+
+    let channel = f_protocol.generate_channel().unwrap();
+    f_protocol.send_metadata(channel, &hash, num_chunks).unwrap();
+    f_protocol.send_export(channel, &hash, &dest, mode).unwrap();
+
+    let result = f_protocol.message_engine(
+        |d| f_protocol.recv(Some(d)),
+        Duration::from_secs(2),
+        State::Transmitting,
+    );
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    // Verify the final file's contents
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(&contents[..], dest_contents.as_slice());
+}
+
+// Download a highly compressible file, requesting deflate
+#[test]
+fn download_compressed() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7031;
+
+    let contents = [31; 50000];
+
+    let hash = create_test_file(&source, &contents);
+
+    service_new!(service_port, 4096);
+
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5)
+        .with_compression(Compression::Deflate);
+    let f_protocol = FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+
+    let channel = f_protocol.generate_channel().unwrap();
+    f_protocol.send_import(channel, &source).unwrap();
+
+    let reply = f_protocol.recv(None).unwrap();
+    let state = f_protocol
+        .process_message(
+            reply,
+            State::StartReceive {
+                path: dest.to_string(),
+            },
+        ).unwrap();
+
+    let result =
+        f_protocol.message_engine(|d| f_protocol.recv(Some(d)), Duration::from_secs(2), state);
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
+
+    // The service should have stored the file as a single compressed chunk
+    assert!(fs::metadata(format!("service/storage/{}/0", hash)).is_ok());
+    assert!(fs::metadata(format!("service/storage/{}/1", hash)).is_err());
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    // Verify the final file's contents
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(&contents[..], dest_contents.as_slice());
+}
@@ -0,0 +1,321 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+extern crate cbor_protocol;
+extern crate file_protocol;
+extern crate file_service;
+extern crate kubos_system;
+extern crate tempfile;
+
+mod common;
+
+use common::*;
+use file_protocol::{FileProtocol, FileProtocolConfig, ProtocolError, State};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// NOTE: Each test's file contents must be unique. Otherwise the hash is the same, so
+// the same storage directory is used across all of them, creating conflicts
+
+// Start a service with some extra config options
+fn custom_service_new(port: u16, options: &'static str) {
+    thread::spawn(move || {
+        recv_loop(ServiceConfig::new_from_str(
+            "file-transfer-service",
+            &format!(
+                r#"
+                [file-transfer-service]
+                storage_dir = "service"
+                chunk_size = 4096
+                hold_count = 5
+                {}
+                [file-transfer-service.addr]
+                ip = "127.0.0.1"
+                port = {}
+                "#,
+                options, port
+            ),
+        )).unwrap();
+    });
+
+    thread::sleep(Duration::new(1, 0));
+}
+
+// Upload a file slowly enough that the transaction stays active for a while
+fn slow_upload(service_port: u16, source_path: &str, target_path: &str) -> Result<(), String> {
+    let f_config =
+        FileProtocolConfig::new(Some("client".to_owned()), 4096, 5).with_max_rate(8192);
+    let f_protocol = FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+
+    let result: Result<(), ProtocolError> = (|| {
+        let (hash, num_chunks, mode) = f_protocol.initialize_file(source_path)?;
+        let channel = f_protocol.generate_channel()?;
+        f_protocol.send_metadata(channel, &hash, num_chunks)?;
+        f_protocol.send_export(channel, &hash, target_path, mode)?;
+        f_protocol.message_engine(
+            |d| f_protocol.recv(Some(d)),
+            Duration::from_secs(2),
+            State::Transmitting,
+        )
+    })();
+
+    result.map_err(|err| format!("{}", err))
+}
+
+// New transactions are refused while the service is handling as many as it's allowed,
+// and accepted again once there's room
+#[test]
+fn refuse_over_limit() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let slow_source = format!("{}/slow_source", test_dir_str);
+    let slow_dest = format!("{}/slow_dest", test_dir_str);
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7120;
+
+    let slow_contents: Vec<u8> = (0..24000).map(|val| (val % 223) as u8).collect();
+    let slow_hash = create_test_file(&slow_source, &slow_contents);
+    let contents = "refuse_over_limit".as_bytes();
+    let hash = create_test_file(&source, contents);
+
+    // Only handle one transaction at a time
+    custom_service_new(service_port, "max_transactions = 1");
+
+    let slow = {
+        let (source, dest) = (slow_source.clone(), slow_dest.clone());
+        thread::spawn(move || slow_upload(service_port, &source, &dest))
+    };
+
+    thread::sleep(Duration::from_millis(1000));
+
+    let result = upload(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some("client".to_owned()),
+        4096,
+    );
+
+    let error = result.unwrap_err().to_string();
+    assert!(
+        error.contains("Too many transactions in progress (limit is 1)"),
+        "Unexpected error: {}",
+        error
+    );
+
+    // The transaction which was already running isn't affected
+    assert_eq!(slow.join().unwrap(), Ok(()));
+    assert_eq!(fs::read(&slow_dest).unwrap(), slow_contents);
+
+    // Give the service a moment to finish up
+    thread::sleep(Duration::from_millis(500));
+
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+    let f_protocol = FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+    let history = f_protocol.remote_history(5).unwrap();
+    let refused = history
+        .iter()
+        .filter_map(|info| info.error.as_ref())
+        .any(|error| error.contains("Too many transactions"));
+    assert!(refused);
+
+    let result = upload(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some("client".to_owned()),
+        4096,
+    );
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", slow_hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", slow_hash)).unwrap();
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    assert_eq!(fs::read(dest).unwrap(), contents);
+}
+
+// Other clients are still answered while the service is slowly sending a file
+#[test]
+fn hello_during_slow_download() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7121;
+
+    let contents: Vec<u8> = (0..24000).map(|val| (val % 227) as u8).collect();
+    let hash = create_test_file(&source, &contents);
+
+    custom_service_new(service_port, "max_rate = 8192");
+
+    let (events, order) = mpsc::channel();
+
+    let slow = {
+        let (source, dest, events) = (source.clone(), dest.clone(), events.clone());
+        thread::spawn(move || {
+            let result = download(
+                "127.0.0.1",
+                &format!("127.0.0.1:{}", service_port),
+                &source,
+                &dest,
+                Some("client".to_owned()),
+                4096,
+            )
+            .map_err(|err| format!("{}", err));
+            events.send("download finished").unwrap();
+            result
+        })
+    };
+
+    thread::sleep(Duration::from_millis(1000));
+
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+    let f_protocol = FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+    assert!(f_protocol.hello(Duration::from_secs(2)).is_ok());
+    events.send("hello answered").unwrap();
+
+    // The hello didn't have to wait for the download
+    assert_eq!(order.recv().unwrap(), "hello answered");
+    assert_eq!(order.recv().unwrap(), "download finished");
+
+    // The download still finishes, at the rate it was limited to
+    assert_eq!(slow.join().unwrap(), Ok(()));
+    assert_eq!(fs::read(&dest).unwrap(), contents);
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+}
+
+// Other transfers keep moving while the service is setting up a large file to be sent
+#[test]
+fn upload_during_slow_prepare() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let large_source = format!("{}/large_source", test_dir_str);
+    let large_dest = format!("{}/large_dest", test_dir_str);
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7122;
+
+    // Large enough that hashing and chunking it takes a while
+    let large_contents: Vec<u8> = (0..16_000_000u32).map(|val| (val % 229) as u8).collect();
+    create_test_file(&large_source, &large_contents);
+    let contents = "upload_during_slow_prepare".as_bytes();
+    create_test_file(&source, contents);
+
+    custom_service_new(service_port, "");
+
+    let (events, order) = mpsc::channel();
+
+    let large = {
+        let (source, dest, events) = (large_source.clone(), large_dest.clone(), events.clone());
+        thread::spawn(move || -> Result<String, String> {
+            let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+            let f_protocol = FileProtocol::new(
+                "127.0.0.1",
+                &format!("127.0.0.1:{}", service_port),
+                f_config,
+            );
+
+            let result: Result<String, ProtocolError> = (|| {
+                let channel = f_protocol.generate_channel()?;
+                f_protocol.send_import(channel, &source)?;
+
+                // The service only replies once the file is ready to be sent
+                let reply = f_protocol.recv(None)?;
+                events.send("large file prepared").unwrap();
+
+                let state =
+                    f_protocol.process_message(reply, State::StartReceive { path: dest })?;
+                let hash = match state {
+                    State::Receiving { ref hash, .. } | State::ReceivingDone { ref hash, .. } => {
+                        hash.clone()
+                    }
+                    ref other => panic!("Unexpected state {:?}", other),
+                };
+                f_protocol.message_engine(
+                    |d| f_protocol.recv(Some(d)),
+                    Duration::from_secs(2),
+                    state,
+                )?;
+                Ok(hash)
+            })();
+
+            result.map_err(|err| format!("{}", err))
+        })
+    };
+
+    // Give the service a moment to start preparing the large file
+    thread::sleep(Duration::from_millis(100));
+
+    let result = upload(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some("client".to_owned()),
+        4096,
+    );
+    events.send("small file uploaded").unwrap();
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+    let hash = result.unwrap();
+    assert_eq!(fs::read(&dest).unwrap(), contents);
+
+    // The small file didn't have to wait for the large one
+    assert_eq!(order.recv().unwrap(), "small file uploaded");
+    assert_eq!(order.recv().unwrap(), "large file prepared");
+
+    let large_hash = large.join().unwrap().unwrap();
+    assert_eq!(fs::read(&large_dest).unwrap(), large_contents);
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("client/storage/{}", large_hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", large_hash)).unwrap();
+}
@@ -0,0 +1,124 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+extern crate cbor_protocol;
+extern crate file_protocol;
+extern crate file_service;
+extern crate kubos_system;
+extern crate tempfile;
+
+mod common;
+
+use common::*;
+use file_protocol::{FileProtocol, FileProtocolConfig, State};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// NOTE: Each test's file contents must be unique. Otherwise the hash is the same, so
+// the same storage directory is used across all of them, creating conflicts
+
+// Upload the same file to a second path.
+// The service should build the second copy from the chunks it already has
+#[test]
+fn upload_duplicate() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let dest_copy = format!("{}/dest_copy", test_dir_str);
+    let service_port = 7050;
+
+    let contents = [50; 20000];
+
+    create_test_file(&source, &contents);
+
+    service_new!(service_port, 4096);
+
+    let result = upload(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some("client".to_owned()),
+        4096,
+    );
+    assert!(result.is_ok());
+    let hash = result.unwrap();
+
+    // Throw away our own copy of the chunks, so that the upload can only succeed
+    // if the service doesn't ask for any of them
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+    let f_protocol = FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+
+    let held = f_protocol
+        .remote_has_file(&hash, Duration::from_secs(2))
+        .unwrap();
+    assert!(held);
+
+    let channel = f_protocol.generate_channel().unwrap();
+    f_protocol
+        .send_export(channel, &hash, &dest_copy, 0o644)
+        .unwrap();
+
+    let result = f_protocol.message_engine(
+        |d| f_protocol.recv(Some(d)),
+        Duration::from_secs(2),
+        State::Transmitting,
+    );
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    // Verify the final file's contents
+    let dest_contents = fs::read(dest_copy).unwrap();
+    assert_eq!(&contents[..], dest_contents.as_slice());
+}
+
+// Ask about a file which the service has never seen
+#[test]
+fn sync_unknown_file() {
+    let service_port = 7051;
+
+    service_new!(service_port, 4096);
+
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+    let f_protocol = FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+
+    let held = f_protocol
+        .remote_has_file("0123456789abcdef0123456789abcdef", Duration::from_secs(2))
+        .unwrap();
+    assert!(!held);
+}
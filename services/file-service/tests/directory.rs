@@ -0,0 +1,153 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+extern crate cbor_protocol;
+extern crate file_protocol;
+extern crate file_service;
+extern crate kubos_system;
+extern crate tempfile;
+
+mod common;
+
+use common::*;
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// NOTE: Each test's file contents must be unique. Otherwise the hash is the same, so
+// the same storage directory is used across all of them, creating conflicts
+
+// Build a small nested tree in the given directory
+fn create_test_tree(root: &str, seed: u8) {
+    fs::create_dir_all(format!("{}/logs/old", root)).unwrap();
+    fs::create_dir_all(format!("{}/empty", root)).unwrap();
+    fs::set_permissions(format!("{}/logs", root), fs::Permissions::from_mode(0o750)).unwrap();
+
+    create_test_file(&format!("{}/app.bin", root), &[seed; 6000]);
+    create_test_file(&format!("{}/logs/today.log", root), &[seed + 1; 100]);
+    create_test_file(
+        &format!("{}/logs/old/yesterday.log", root),
+        &[seed + 2; 5000],
+    );
+}
+
+// Check that the destination tree matches the source tree
+fn verify_test_tree(source: &str, dest: &str) {
+    for file in &["app.bin", "logs/today.log", "logs/old/yesterday.log"] {
+        let source_contents = fs::read(format!("{}/{}", source, file)).unwrap();
+        let dest_contents = fs::read(format!("{}/{}", dest, file)).unwrap();
+        assert_eq!(source_contents, dest_contents);
+    }
+
+    assert!(Path::new(&format!("{}/empty", dest)).is_dir());
+
+    let mode = fs::metadata(format!("{}/logs", dest))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o750);
+}
+
+// Upload a nested directory tree
+#[test]
+fn upload_directory() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7020;
+
+    create_test_tree(&source, 20);
+
+    service_new!(service_port, 4096);
+
+    let result = upload_dir(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some("client".to_owned()),
+        4096,
+    );
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
+
+    verify_test_tree(&source, &dest);
+}
+
+// Download a nested directory tree
+#[test]
+fn download_directory() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7021;
+
+    create_test_tree(&source, 30);
+
+    service_new!(service_port, 4096);
+
+    let result = download_dir(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some("client".to_owned()),
+        4096,
+    );
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
+
+    verify_test_tree(&source, &dest);
+}
+
+// Request a directory which doesn't exist
+#[test]
+fn download_directory_missing() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7022;
+
+    service_new!(service_port, 4096);
+
+    let result = download_dir(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some("client".to_owned()),
+        4096,
+    );
+
+    assert!(result.is_err());
+    assert!(!Path::new(&dest).exists());
+}
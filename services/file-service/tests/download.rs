@@ -203,9 +203,10 @@ fn download_multi_complete() {
     assert_eq!(&contents[..], dest_contents.as_slice());
 }
 
-// Download. Create hash mismatch.
+// Download. Corrupt one of the chunks the client has already received.
+// Only that chunk should be sent again
 #[test]
-fn download_bad_hash() {
+fn download_corrupt_chunk() {
     let test_dir = TempDir::new().expect("Failed to create test dir");
     let test_dir_str = test_dir.path().to_str().unwrap();
     let source = format!("{}/source", test_dir_str);
@@ -229,7 +230,7 @@ fn download_bad_hash() {
     );
     assert!(result.is_ok());
 
-    // Tweak the chunk contents so the future hash calculation will fail
+    // Tweak the chunk contents so that it fails its integrity check
     fs::write(format!("client/storage/{}/0", hash), "bad data".as_bytes()).unwrap();
 
     let result = download(
@@ -240,12 +241,19 @@ fn download_bad_hash() {
         Some("client".to_owned()),
         4096,
     );
-    assert_eq!("File hash mismatch", format!("{}", result.unwrap_err()));
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
 
     // Cleanup the temporary files so that the test can be repeatable
-    // The client folder is cleaned up by the protocol as a result
-    // of the hash mismatch
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
     fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    // Verify the final file's contents
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(contents, dest_contents.as_slice());
 }
 
 // Download a single file in 5 simultaneous client instances
@@ -0,0 +1,244 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+extern crate cbor_protocol;
+extern crate file_protocol;
+extern crate file_service;
+extern crate kubos_system;
+extern crate serde_cbor;
+extern crate tempfile;
+
+mod common;
+
+use common::*;
+use file_protocol::{FileProtocol, FileProtocolConfig, ProtocolError, State};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use serde_cbor::Value;
+use std::cell::{Cell, RefCell};
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// NOTE: Each test's file contents must be unique. Otherwise the hash is the same, so
+// the same storage directory is used across all of them, creating conflicts
+
+// Start a service which sends two parity chunks after every four chunks
+fn fec_service_new(port: u16) {
+    thread::spawn(move || {
+        recv_loop(ServiceConfig::new_from_str(
+            "file-transfer-service",
+            &format!(
+                r#"
+                [file-transfer-service]
+                storage_dir = "service"
+                chunk_size = 4096
+                hold_count = 5
+                fec_block = 4
+                fec_parity = 2
+                [file-transfer-service.addr]
+                ip = "127.0.0.1"
+                port = {}
+                "#,
+                port
+            ),
+        )).unwrap();
+    });
+
+    thread::sleep(Duration::new(1, 0));
+}
+
+// Get the index of a file chunk message
+fn chunk_index(message: &Value) -> Option<u64> {
+    match message {
+        Value::Array(items) => match (items.get(1), items.get(2), items.get(3)) {
+            (Some(Value::String(_)), Some(Value::U64(index)), Some(Value::Bytes(_))) => {
+                Some(*index)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// Download a file, dropping the first copy of each of the `lost` chunks.
+// Also returns the number of times any of the dropped chunks were sent again
+fn lossy_download(
+    service_port: u16,
+    source_path: &str,
+    target_path: &str,
+    lost: &[u64],
+) -> (Result<(), ProtocolError>, u32) {
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+    let f_protocol = FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+
+    let channel = f_protocol.generate_channel().unwrap();
+    f_protocol.send_import(channel, source_path).unwrap();
+
+    let reply = f_protocol.recv(None).unwrap();
+    let state = f_protocol
+        .process_message(
+            reply,
+            State::StartReceive {
+                path: target_path.to_owned(),
+            },
+        ).unwrap();
+
+    let dropped = RefCell::new(vec![]);
+    let resent = Cell::new(0);
+
+    let result = f_protocol.message_engine(
+        |d| loop {
+            let message = f_protocol.recv(Some(d))?;
+            if let Some(index) = chunk_index(&message) {
+                if lost.contains(&index) {
+                    if !dropped.borrow().contains(&index) {
+                        dropped.borrow_mut().push(index);
+                        continue;
+                    }
+                    resent.set(resent.get() + 1);
+                }
+            }
+            return Ok(message);
+        },
+        Duration::from_secs(2),
+        state,
+    );
+
+    (result, resent.get())
+}
+
+// Lose a chunk from a full block and the short final chunk.
+// Both should be rebuilt from the parity chunks without being requested again
+#[test]
+fn download_rebuilt_chunks() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7080;
+
+    // Ten chunks, so the last block only has two
+    let contents: Vec<u8> = (0..40000).map(|val| (val % 233) as u8).collect();
+    let hash = create_test_file(&source, &contents);
+
+    fec_service_new(service_port);
+
+    let (result, resent) = lossy_download(service_port, &source, &dest, &[1, 9]);
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
+    assert_eq!(resent, 0);
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    // Verify the final file's contents
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(contents, dest_contents);
+}
+
+// Lose more chunks from a block than there are parity chunks.
+// The missing chunks should be requested again instead
+#[test]
+fn download_too_many_lost() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7081;
+
+    let contents: Vec<u8> = (0..40000).map(|val| (val % 229) as u8).collect();
+    let hash = create_test_file(&source, &contents);
+
+    fec_service_new(service_port);
+
+    let (result, resent) = lossy_download(service_port, &source, &dest, &[4, 5, 6]);
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
+    assert_eq!(resent, 3);
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    // Verify the final file's contents
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(contents, dest_contents);
+}
+
+// Upload a file with parity chunks
+#[test]
+fn upload_with_parity() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7082;
+
+    let contents: Vec<u8> = (0..40000).map(|val| (val % 227) as u8).collect();
+    let hash = create_test_file(&source, &contents);
+
+    service_new!(service_port, 4096);
+
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5).with_fec(4, 2);
+    let f_protocol = FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+
+    let (_, num_chunks, mode) = f_protocol.initialize_file(&source).unwrap();
+
+    let channel = f_protocol.generate_channel().unwrap();
+    f_protocol
+        .send_metadata(channel, &hash, num_chunks)
+        .unwrap();
+    f_protocol.send_export(channel, &hash, &dest, mode).unwrap();
+
+    let result = f_protocol.message_engine(
+        |d| f_protocol.recv(Some(d)),
+        Duration::from_secs(2),
+        State::Transmitting,
+    );
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    // Verify the final file's contents
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(contents, dest_contents);
+}
@@ -0,0 +1,166 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+extern crate cbor_protocol;
+extern crate file_protocol;
+extern crate file_service;
+extern crate kubos_system;
+extern crate tempfile;
+
+mod common;
+
+use common::*;
+use file_protocol::{FileProtocol, FileProtocolConfig};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn new_client(service_port: u16) -> FileProtocol {
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+    FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    )
+}
+
+// List a directory and look up a single file
+#[test]
+fn list_and_stat() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let service_port = 7040;
+
+    create_test_file(&format!("{}/b.txt", test_dir_str), &[40; 100]);
+    create_test_file(&format!("{}/a.txt", test_dir_str), &[41; 10]);
+    fs::create_dir(format!("{}/logs", test_dir_str)).unwrap();
+
+    service_new!(service_port, 4096);
+
+    let f_protocol = new_client(service_port);
+
+    let entries = f_protocol.remote_list(test_dir_str).unwrap();
+    let names: Vec<&str> = entries.iter().map(|entry| entry.name.as_str()).collect();
+    assert_eq!(names, vec!["a.txt", "b.txt", "logs"]);
+    assert_eq!(entries[0].size, 10);
+    assert_eq!(entries[1].size, 100);
+    assert!(!entries[1].is_dir);
+    assert!(entries[2].is_dir);
+    assert!(entries[1].mtime > 0);
+
+    let path = format!("{}/b.txt", test_dir_str);
+    let info = f_protocol.remote_stat(&path).unwrap();
+    assert_eq!(info.name, path);
+    assert_eq!(info.size, 100);
+    assert!(!info.is_dir);
+
+    // Things which don't exist should be reported as failures
+    assert!(
+        f_protocol
+            .remote_stat(&format!("{}/missing", test_dir_str))
+            .is_err()
+    );
+    assert!(
+        f_protocol
+            .remote_list(&format!("{}/missing", test_dir_str))
+            .is_err()
+    );
+}
+
+// Create a directory, move a file into it and then clean everything up
+#[test]
+fn mkdir_rename_delete() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let archive = format!("{}/archive/old", test_dir_str);
+    let moved = format!("{}/source", archive);
+    let service_port = 7041;
+
+    create_test_file(&source, &[42; 100]);
+
+    service_new!(service_port, 4096);
+
+    let f_protocol = new_client(service_port);
+
+    f_protocol.remote_mkdir(&archive).unwrap();
+    assert!(Path::new(&archive).is_dir());
+
+    f_protocol.remote_rename(&source, &moved).unwrap();
+    assert!(!Path::new(&source).exists());
+    assert!(Path::new(&moved).exists());
+
+    // Directories which aren't empty can only be removed recursively
+    let archive_root = format!("{}/archive", test_dir_str);
+    assert!(f_protocol.remote_delete(&archive_root, false).is_err());
+    assert!(Path::new(&moved).exists());
+
+    f_protocol.remote_delete(&moved, false).unwrap();
+    assert!(!Path::new(&moved).exists());
+
+    f_protocol.remote_delete(&archive_root, true).unwrap();
+    assert!(!Path::new(&archive_root).exists());
+}
+
+// Moving a file shouldn't overwrite an existing one
+#[test]
+fn rename_existing_target() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7042;
+
+    create_test_file(&source, &[43; 100]);
+    create_test_file(&dest, &[44; 100]);
+
+    service_new!(service_port, 4096);
+
+    let f_protocol = new_client(service_port);
+
+    assert!(f_protocol.remote_rename(&source, &dest).is_err());
+
+    assert_eq!(fs::read(&source).unwrap(), vec![43; 100]);
+    assert_eq!(fs::read(&dest).unwrap(), vec![44; 100]);
+}
+
+// Listings which don't fit in a single message are split up and put back together
+#[test]
+fn list_large_directory() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let service_port = 7043;
+
+    let mut expected: Vec<String> = (0..300)
+        .map(|index| format!("a-file-with-a-fairly-long-name-{:03}.log", index))
+        .collect();
+    for name in expected.iter() {
+        fs::write(format!("{}/{}", test_dir_str, name), [45; 10]).unwrap();
+    }
+    expected.sort();
+
+    service_new!(service_port, 4096);
+
+    let f_protocol = new_client(service_port);
+
+    let entries = f_protocol.remote_list(test_dir_str).unwrap();
+    let names: Vec<String> = entries.into_iter().map(|entry| entry.name).collect();
+    assert_eq!(names, expected);
+}
@@ -0,0 +1,108 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+extern crate cbor_protocol;
+extern crate file_protocol;
+extern crate file_service;
+extern crate kubos_system;
+extern crate serde_cbor;
+extern crate tempfile;
+
+mod common;
+
+use file_protocol::{
+    parse_message, FileProtocol, FileProtocolConfig, Message, ProtocolError, PROTOCOL_VERSION,
+};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use serde_cbor::ser;
+use std::thread;
+use std::time::Duration;
+
+fn new_client(service_port: u16, chunk_size: usize) -> FileProtocol {
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), chunk_size, 5);
+    FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    )
+}
+
+// The service should report what it supports
+#[test]
+fn hello_capabilities() {
+    let service_port = 7150;
+
+    service_new!(service_port, 4096);
+
+    let f_protocol = new_client(service_port, 4096);
+
+    let remote = f_protocol.hello(Duration::from_secs(2)).unwrap();
+    assert_eq!(remote.version, PROTOCOL_VERSION);
+    assert_eq!(remote.max_chunk_size, 4096);
+    assert!(remote.supports("compression"));
+    assert!(remote.supports("fec"));
+    assert!(remote.supports("ranges"));
+}
+
+// Once we know the service can't handle our chunk size, requests should fail
+// up front rather than being sent
+#[test]
+fn hello_chunk_size_too_large() {
+    let service_port = 7151;
+
+    service_new!(service_port, 4096);
+
+    let f_protocol = new_client(service_port, 8192);
+
+    f_protocol.hello(Duration::from_secs(2)).unwrap();
+
+    let channel_id = f_protocol.generate_channel().unwrap();
+    match f_protocol.send_import(channel_id, "/tmp/hello-test") {
+        Err(ProtocolError::Unsupported(feature)) => {
+            assert_eq!(feature, "chunks larger than 4096 bytes")
+        }
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
+
+// Requests the service doesn't understand should be refused with a clear error
+#[test]
+fn unknown_request() {
+    let service_port = 7152;
+
+    service_new!(service_port, 4096);
+
+    let f_protocol = new_client(service_port, 4096);
+
+    let channel_id = f_protocol.generate_channel().unwrap();
+    f_protocol
+        .send(ser::to_vec_packed(&(channel_id, "frobnicate", "x")).unwrap())
+        .unwrap();
+
+    let reply = f_protocol.recv(Some(Duration::from_secs(2))).unwrap();
+    match parse_message(reply).unwrap() {
+        Message::Failure(reply_channel, error) => {
+            assert_eq!(reply_channel, channel_id);
+            assert!(
+                error.contains("Unsupported request"),
+                "Unexpected error: {}",
+                error
+            );
+        }
+        other => panic!("Unexpected reply: {:?}", other),
+    }
+}
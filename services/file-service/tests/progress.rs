@@ -0,0 +1,153 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+extern crate cbor_protocol;
+extern crate file_protocol;
+extern crate file_service;
+extern crate kubos_system;
+extern crate tempfile;
+
+mod common;
+
+use common::*;
+use file_protocol::{FileProtocol, FileProtocolConfig, Progress, State};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::cell::RefCell;
+use std::fs;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// NOTE: Each test's file contents must be unique. Otherwise the hash is the same, so
+// the same storage directory is used across all of them, creating conflicts
+
+// Create a client which records every progress update it's given
+fn new_client(service_port: u16) -> (FileProtocol, Rc<RefCell<Vec<Progress>>>) {
+    let reports = Rc::new(RefCell::new(vec![]));
+    let recorder = reports.clone();
+
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+    let f_protocol = FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    ).with_progress(move |progress| recorder.borrow_mut().push(progress.clone()));
+
+    (f_protocol, reports)
+}
+
+// Progress should be reported for each chunk we send
+#[test]
+fn upload_progress() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7090;
+
+    let contents: Vec<u8> = (0..20000).map(|val| (val % 239) as u8).collect();
+    let hash = create_test_file(&source, &contents);
+
+    service_new!(service_port, 4096);
+
+    let (f_protocol, reports) = new_client(service_port);
+
+    let (hash_sent, num_chunks, mode) = f_protocol.initialize_file(&source).unwrap();
+    let channel = f_protocol.generate_channel().unwrap();
+    f_protocol
+        .send_metadata(channel, &hash, num_chunks)
+        .unwrap();
+    f_protocol.send_export(channel, &hash, &dest, mode).unwrap();
+    f_protocol
+        .message_engine(
+            |d| f_protocol.recv(Some(d)),
+            Duration::from_secs(2),
+            State::Transmitting,
+        ).unwrap();
+
+    assert_eq!(hash_sent, hash);
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    let reports = reports.borrow();
+    let last = reports.last().unwrap();
+    assert_eq!(last.hash, hash);
+    assert_eq!(last.num_chunks, 5);
+    assert_eq!(last.chunks_sent, 5);
+    assert_eq!(last.chunks_received, 0);
+    assert_eq!(last.nak_rounds, 1);
+    assert_eq!(last.bytes, 20000);
+    assert_eq!(last.remaining, 0);
+    assert_eq!(last.chunks_done(), 5);
+    assert_eq!(last.eta, Some(Duration::from_secs(0)));
+
+    // Each chunk should have been reported as it was sent
+    let sent: Vec<u32> = reports.iter().map(|report| report.chunks_sent).collect();
+    assert!((1..6).all(|count| sent.contains(&count)));
+
+    assert_eq!(fs::read(dest).unwrap(), contents);
+}
+
+// Progress should be reported for each chunk we receive
+#[test]
+fn download_progress() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7091;
+
+    let contents: Vec<u8> = (0..10000).map(|val| (val % 233) as u8).collect();
+    let hash = create_test_file(&source, &contents);
+
+    service_new!(service_port, 4096);
+
+    let (f_protocol, reports) = new_client(service_port);
+
+    let channel = f_protocol.generate_channel().unwrap();
+    f_protocol.send_import(channel, &source).unwrap();
+    let reply = f_protocol.recv(None).unwrap();
+    let state = f_protocol
+        .process_message(reply, State::StartReceive { path: dest.clone() })
+        .unwrap();
+    f_protocol
+        .message_engine(|d| f_protocol.recv(Some(d)), Duration::from_secs(2), state)
+        .unwrap();
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    let reports = reports.borrow();
+
+    // Our first NAK asks for everything
+    assert_eq!(reports[0].nak_rounds, 1);
+    assert_eq!(reports[0].remaining, 3);
+
+    let last = reports.last().unwrap();
+    assert_eq!(last.hash, hash);
+    assert_eq!(last.num_chunks, 3);
+    assert_eq!(last.chunks_sent, 0);
+    assert_eq!(last.chunks_received, 3);
+    assert_eq!(last.bytes, 10000);
+    assert_eq!(last.remaining, 0);
+
+    assert_eq!(fs::read(dest).unwrap(), contents);
+}
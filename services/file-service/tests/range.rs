@@ -0,0 +1,151 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+extern crate cbor_protocol;
+extern crate file_protocol;
+extern crate file_service;
+extern crate kubos_system;
+extern crate tempfile;
+
+mod common;
+
+use common::*;
+use file_protocol::{FileProtocol, FileProtocolConfig, ProtocolError, State};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// NOTE: Each test's file contents must be unique. Otherwise the hash is the same, so
+// the same storage directory is used across all of them, creating conflicts
+
+fn download_range(
+    service_port: u16,
+    source_path: &str,
+    target_path: &str,
+    offset: u64,
+    length: Option<u64>,
+) -> Result<(), ProtocolError> {
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+    let f_protocol = FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+
+    let channel = f_protocol.generate_channel()?;
+    f_protocol.send_import_range(channel, source_path, offset, length)?;
+
+    let reply = f_protocol.recv(None)?;
+    let state = f_protocol.process_message(
+        reply,
+        State::StartReceive {
+            path: target_path.to_owned(),
+        },
+    )?;
+
+    f_protocol.message_engine(|d| f_protocol.recv(Some(d)), Duration::from_secs(2), state)
+}
+
+// Download a region from the middle of a file
+#[test]
+fn download_middle() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7070;
+
+    let contents: Vec<u8> = (0..20000).map(|val| (val % 251) as u8).collect();
+    create_test_file(&source, &contents);
+
+    // The transferred file is identified by the hash of just the range
+    let expected = &contents[5000..14000];
+    let hash = create_test_file(&format!("{}/expected", test_dir_str), expected);
+
+    service_new!(service_port, 4096);
+
+    let result = download_range(service_port, &source, &dest, 5000, Some(9000));
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    // Verify the final file's contents
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(expected, dest_contents.as_slice());
+}
+
+// Download everything from an offset to the end of the file
+#[test]
+fn download_tail() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7071;
+
+    let contents: Vec<u8> = (0..20000).map(|val| (val % 241) as u8).collect();
+    create_test_file(&source, &contents);
+
+    let expected = &contents[17000..];
+    let hash = create_test_file(&format!("{}/expected", test_dir_str), expected);
+
+    service_new!(service_port, 4096);
+
+    let result = download_range(service_port, &source, &dest, 17000, None);
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    // Verify the final file's contents
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(expected, dest_contents.as_slice());
+}
+
+// Request a range which starts past the end of the file
+#[test]
+fn download_past_end() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7072;
+
+    create_test_file(&source, &[72; 1000]);
+
+    service_new!(service_port, 4096);
+
+    let result = download_range(service_port, &source, &dest, 5000, Some(10));
+
+    assert!(result.is_err());
+    assert!(fs::metadata(&dest).is_err());
+}
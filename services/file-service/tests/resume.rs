@@ -0,0 +1,186 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+extern crate cbor_protocol;
+extern crate file_protocol;
+extern crate file_service;
+extern crate kubos_system;
+extern crate tempfile;
+
+mod common;
+
+use common::*;
+use file_protocol::{FileProtocol, FileProtocolConfig, ProtocolError, State};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// NOTE: Each test's file contents must be unique. Otherwise the hash is the same, so
+// the same storage directory is used across all of them, creating conflicts
+
+// Resume an upload whose client went away after sending the export request
+#[test]
+fn resume_upload() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7010;
+
+    let contents = [10; 9000];
+
+    create_test_file(&source, &contents);
+
+    service_new!(service_port, 4096);
+
+    // Start the upload, but stop before sending any chunks
+    let hash = {
+        let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+        let f_protocol =
+            FileProtocol::new("127.0.0.1", &format!("127.0.0.1:{}", service_port), f_config);
+
+        let (hash, num_chunks, mode) = f_protocol.initialize_file(&source).unwrap();
+        let channel = f_protocol.generate_channel().unwrap();
+        f_protocol.send_metadata(channel, &hash, num_chunks).unwrap();
+        f_protocol.send_export(channel, &hash, &dest, mode).unwrap();
+        hash
+    };
+
+    // Give the service a moment to record the transaction
+    thread::sleep(Duration::from_millis(100));
+    assert!(Path::new(&format!("service/storage/{}/transaction", hash)).exists());
+
+    let result = resume(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        &hash,
+        Some("client".to_owned()),
+        4096,
+    );
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
+
+    // Neither side should have anything left to resume
+    assert!(!Path::new(&format!("client/storage/{}/transaction", hash)).exists());
+    assert!(!Path::new(&format!("service/storage/{}/transaction", hash)).exists());
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    // Verify the final file's contents
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(&contents[..], dest_contents.as_slice());
+}
+
+// Resume a download whose client went away after the import request was accepted
+#[test]
+fn resume_download() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7011;
+
+    let contents = [11; 9000];
+
+    let hash = create_test_file(&source, &contents);
+
+    service_new!(service_port, 4096);
+
+    // Start the download, but stop before receiving any chunks
+    {
+        let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+        let f_protocol =
+            FileProtocol::new("127.0.0.1", &format!("127.0.0.1:{}", service_port), f_config);
+
+        let channel = f_protocol.generate_channel().unwrap();
+        f_protocol.send_import(channel, &source).unwrap();
+        let reply = f_protocol.recv(None).unwrap();
+        let state = f_protocol
+            .process_message(
+                reply,
+                State::StartReceive {
+                    path: dest.to_owned(),
+                },
+            ).unwrap();
+
+        match state {
+            State::Receiving { hash: state_hash, .. } => assert_eq!(state_hash, hash),
+            other => panic!("Unexpected state: {:?}", other),
+        }
+    }
+
+    let result = resume(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        &hash,
+        Some("client".to_owned()),
+        4096,
+    );
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    // Verify the final file's contents
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(&contents[..], dest_contents.as_slice());
+}
+
+// Attempt to resume a transfer the service knows nothing about
+#[test]
+fn resume_unknown() {
+    let service_port = 7012;
+
+    service_new!(service_port, 4096);
+
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+    let f_protocol =
+        FileProtocol::new("127.0.0.1", &format!("127.0.0.1:{}", service_port), f_config);
+
+    let channel = f_protocol.generate_channel().unwrap();
+    f_protocol
+        .send_resume(channel, "00000000000000000000000000000000")
+        .unwrap();
+
+    let result = f_protocol.message_engine(
+        |d| f_protocol.recv(Some(d)),
+        Duration::from_secs(2),
+        State::Transmitting,
+    );
+
+    match result {
+        Err(ProtocolError::TransmissionError { error_message, .. }) => {
+            assert!(error_message.starts_with("No resumable transaction"))
+        }
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
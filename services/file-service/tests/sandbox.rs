@@ -0,0 +1,300 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+extern crate cbor_protocol;
+extern crate file_protocol;
+extern crate file_service;
+extern crate kubos_system;
+extern crate serde_cbor;
+extern crate tempfile;
+
+mod common;
+
+use common::*;
+use file_protocol::{parse_message, FileProtocol, FileProtocolConfig, Message};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use serde_cbor::{ser, Value};
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// NOTE: Each test's file contents must be unique. Otherwise the hash is the same, so
+// the same storage directory is used across all of them, creating conflicts
+
+// Start a service which only lets clients read from and write to the given directories
+fn sandbox_service_new(port: u16, read_root: &str, write_root: &str) {
+    let config = format!(
+        r#"
+        [file-transfer-service]
+        storage_dir = "service"
+        chunk_size = 4096
+        hold_count = 5
+        read_roots = ["{}"]
+        write_roots = ["{}"]
+        [file-transfer-service.addr]
+        ip = "127.0.0.1"
+        port = {}
+        "#,
+        read_root, write_root, port
+    );
+
+    thread::spawn(move || {
+        recv_loop(ServiceConfig::new_from_str(
+            "file-transfer-service",
+            &config,
+        )).unwrap();
+    });
+
+    thread::sleep(Duration::new(1, 0));
+}
+
+// Upload a file, returning the error message if it fails
+fn try_upload(service_port: u16, source: &str, dest: &str) -> Result<(), String> {
+    upload(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        source,
+        dest,
+        Some("client".to_owned()),
+        4096,
+    ).map(|_| ())
+    .map_err(|err| format!("{}", err))
+}
+
+fn assert_not_allowed(result: Result<(), String>) {
+    let error = result.unwrap_err();
+    assert!(
+        error.contains("is not allowed"),
+        "Unexpected error: {}",
+        error
+    );
+}
+
+// Files can only be uploaded beneath the write roots, however the path is written
+#[test]
+fn upload_outside_write_root() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let allowed = format!("{}/allowed", test_dir_str);
+    let outside = format!("{}/outside", test_dir_str);
+    let service_port = 7130;
+
+    fs::create_dir(&allowed).unwrap();
+    fs::create_dir(&outside).unwrap();
+    symlink(&outside, format!("{}/link", allowed)).unwrap();
+    symlink(
+        format!("{}/missing", outside),
+        format!("{}/dangling", allowed),
+    ).unwrap();
+
+    let contents = "upload_outside_write_root".as_bytes();
+    let hash = create_test_file(&source, contents);
+
+    sandbox_service_new(service_port, &allowed, &allowed);
+
+    assert_not_allowed(try_upload(
+        service_port,
+        &source,
+        &format!("{}/dest", outside),
+    ));
+    assert_not_allowed(try_upload(
+        service_port,
+        &source,
+        &format!("{}/../outside/dest", allowed),
+    ));
+    assert_not_allowed(try_upload(
+        service_port,
+        &source,
+        &format!("{}/link/dest", allowed),
+    ));
+    assert_not_allowed(try_upload(
+        service_port,
+        &source,
+        &format!("{}/dangling", allowed),
+    ));
+
+    assert!(fs::read_dir(&outside).unwrap().next().is_none());
+
+    let dest = format!("{}/dest", allowed);
+    assert_eq!(try_upload(service_port, &source, &dest), Ok(()));
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    assert_eq!(fs::read(dest).unwrap(), contents);
+}
+
+// Files can only be downloaded or inspected beneath the read roots
+#[test]
+fn download_outside_read_root() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let allowed = format!("{}/allowed", test_dir_str);
+    let secret = format!("{}/secret", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7131;
+
+    fs::create_dir(&allowed).unwrap();
+    create_test_file(&secret, "download_outside_read_root".as_bytes());
+    symlink(&secret, format!("{}/link", allowed)).unwrap();
+
+    sandbox_service_new(service_port, &allowed, &allowed);
+
+    for source in [secret.clone(), format!("{}/link", allowed)].iter() {
+        let result = download(
+            "127.0.0.1",
+            &format!("127.0.0.1:{}", service_port),
+            source,
+            &dest,
+            Some("client".to_owned()),
+            4096,
+        )
+        .map_err(|err| format!("{}", err));
+        assert_not_allowed(result);
+    }
+    assert!(!Path::new(&dest).exists());
+
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+    let f_protocol = FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+
+    assert_not_allowed(
+        f_protocol
+            .remote_stat(&secret)
+            .map(|_| ())
+            .map_err(|err| format!("{}", err)),
+    );
+    assert_not_allowed(
+        f_protocol
+            .remote_list(test_dir_str)
+            .map(|_| ())
+            .map_err(|err| format!("{}", err)),
+    );
+    assert!(f_protocol.remote_list(&allowed).is_ok());
+}
+
+// Files can only be moved, created or removed beneath the write roots
+#[test]
+fn manage_outside_write_root() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let allowed = format!("{}/allowed", test_dir_str);
+    let readable = format!("{}/readable", test_dir_str);
+    let service_port = 7132;
+
+    fs::create_dir(&allowed).unwrap();
+    fs::create_dir(&readable).unwrap();
+    create_test_file(
+        &format!("{}/file", readable),
+        "manage_outside_write_root".as_bytes(),
+    );
+
+    sandbox_service_new(service_port, &readable, &allowed);
+
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+    let f_protocol = FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+    let not_allowed = |result: Result<(), file_protocol::ProtocolError>| {
+        assert_not_allowed(result.map_err(|err| format!("{}", err)))
+    };
+
+    not_allowed(f_protocol.remote_mkdir(&format!("{}/dir", readable)));
+    not_allowed(f_protocol.remote_delete(&format!("{}/file", readable), false));
+    not_allowed(
+        f_protocol.remote_rename(&format!("{}/file", readable), &format!("{}/file", allowed)),
+    );
+    assert!(Path::new(&format!("{}/file", readable)).exists());
+
+    assert!(f_protocol.remote_mkdir(&format!("{}/dir", allowed)).is_ok());
+    assert!(f_protocol
+        .remote_rename(&format!("{}/dir", allowed), &format!("{}/moved", allowed))
+        .is_ok());
+    assert!(f_protocol
+        .remote_delete(&format!("{}/moved", allowed), false)
+        .is_ok());
+
+    // The write root itself can be listed and written into, but not removed or moved
+    not_allowed(f_protocol.remote_delete(&allowed, true));
+    not_allowed(f_protocol.remote_delete(&format!("{}/.", allowed), true));
+    not_allowed(f_protocol.remote_rename(&allowed, &format!("{}/moved", allowed)));
+    assert!(f_protocol.remote_list(&readable).is_ok());
+    assert!(Path::new(&allowed).is_dir());
+}
+
+// Hashes come from the client, so they mustn't be able to point outside of
+// temporary storage, where the read and write roots aren't checked
+#[test]
+fn hash_outside_storage() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let allowed = format!("{}/allowed", test_dir_str);
+    // Right next to the service's temporary storage
+    let victim = "service/hash_outside_storage";
+    let service_port = 7133;
+
+    fs::create_dir(&allowed).unwrap();
+    fs::create_dir_all(format!("{}/kept", victim)).unwrap();
+
+    sandbox_service_new(service_port, &allowed, &allowed);
+
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+    let f_protocol = FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+
+    let hash = "../hash_outside_storage";
+    let dest = format!("{}/dest", allowed);
+    let requests = vec![
+        ser::to_vec_packed(&(1, "export", hash, &dest)).unwrap(),
+        ser::to_vec_packed(&(2, hash, 0, Value::Bytes(b"oops".to_vec()))).unwrap(),
+        ser::to_vec_packed(&(3, hash, false, 0, 1)).unwrap(),
+        ser::to_vec_packed(&(4, "resume", hash)).unwrap(),
+    ];
+
+    for request in requests {
+        f_protocol.send(request).unwrap();
+        let reply = f_protocol.recv(Some(Duration::from_secs(2))).unwrap();
+        match parse_message(reply).unwrap() {
+            Message::Failure(_, error) => assert!(
+                error.contains("Invalid file hash"),
+                "Unexpected error: {}",
+                error
+            ),
+            other => panic!("Unexpected reply: {:?}", other),
+        }
+    }
+
+    // Nothing was written next to the temporary storage, and nothing there was removed
+    assert!(Path::new(&format!("{}/kept", victim)).exists());
+    assert!(!Path::new(&format!("{}/0", victim)).exists());
+    assert!(!Path::new(&dest).exists());
+
+    fs::remove_dir_all(victim).unwrap();
+}
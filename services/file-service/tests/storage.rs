@@ -0,0 +1,256 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+extern crate cbor_protocol;
+extern crate file_protocol;
+extern crate file_service;
+extern crate kubos_system;
+extern crate serde_cbor;
+extern crate tempfile;
+
+mod common;
+
+use common::*;
+use file_protocol::{parse_message, FileProtocol, FileProtocolConfig, Message};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use serde_cbor::{ser, Value};
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// NOTE: Each test's file contents must be unique. Otherwise the hash is the same, so
+// the same storage directory is used across all of them, creating conflicts
+
+// Start a service with its own storage directory, so that the amount of
+// temporary storage in use isn't affected by other tests
+fn storage_service_new(port: u16, storage_dir: &str, options: &str) {
+    let config = format!(
+        r#"
+        [file-transfer-service]
+        storage_dir = "{}"
+        chunk_size = 4096
+        hold_count = 5
+        {}
+        [file-transfer-service.addr]
+        ip = "127.0.0.1"
+        port = {}
+        "#,
+        storage_dir, options, port
+    );
+
+    thread::spawn(move || {
+        recv_loop(ServiceConfig::new_from_str("file-transfer-service", &config)).unwrap();
+    });
+
+    thread::sleep(Duration::new(1, 0));
+}
+
+// Uploading a file which fits within the storage limit works as normal
+#[test]
+fn upload_within_limit() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_dir = format!("{}/service", test_dir_str);
+    let service_port = 7110;
+
+    let contents = [11; 5000];
+
+    create_test_file(&source, &contents);
+
+    storage_service_new(service_port, &service_dir, "max_storage = 1048576");
+
+    let result = upload(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some("client".to_owned()),
+        4096,
+    );
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
+
+    let hash = result.unwrap();
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+
+    // Verify the final file's contents
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(&contents[..], dest_contents.as_slice());
+}
+
+// Uploading a file which is too big for the service's temporary storage is rejected
+#[test]
+fn upload_over_limit() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_dir = format!("{}/service", test_dir_str);
+    let service_port = 7111;
+
+    let contents = [12; 12000];
+
+    let hash = create_test_file(&source, &contents);
+
+    storage_service_new(service_port, &service_dir, "max_storage = 8192");
+
+    let result = upload(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some("client".to_owned()),
+        4096,
+    );
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+
+    let error = result.unwrap_err().to_string();
+    assert!(
+        error.contains("Not enough temporary storage"),
+        "Unexpected error: {}",
+        error
+    );
+
+    // Nothing should have been written
+    assert!(!Path::new(&dest).exists());
+    assert!(!Path::new(&format!("{}/storage/{}/0", service_dir, hash)).exists());
+}
+
+// Chunks beyond what a file's metadata said it needed still count towards the storage limit
+#[test]
+fn upload_understated_size() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let dest = format!("{}/dest", test_dir_str);
+    let service_dir = format!("{}/service", test_dir_str);
+    let service_port = 7114;
+
+    storage_service_new(service_port, &service_dir, "max_storage = 10000");
+
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+    let f_protocol = FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+
+    // Claim the file only has a single chunk
+    let hash = "0123456789abcdef0123456789abcdef";
+    f_protocol
+        .send(ser::to_vec_packed(&(1, hash, 1)).unwrap())
+        .unwrap();
+    f_protocol
+        .send(ser::to_vec_packed(&(1, "export", hash, &dest)).unwrap())
+        .unwrap();
+    match parse_message(f_protocol.recv(Some(Duration::from_secs(2))).unwrap()).unwrap() {
+        Message::NAK(..) => {}
+        other => panic!("Unexpected reply: {:?}", other),
+    }
+
+    for chunk_num in 0..4 {
+        let chunk = (1, hash, chunk_num, Value::Bytes(vec![14; 4096]));
+        f_protocol
+            .send(ser::to_vec_packed(&chunk).unwrap())
+            .unwrap();
+    }
+
+    match parse_message(f_protocol.recv(Some(Duration::from_secs(2))).unwrap()).unwrap() {
+        Message::Failure(_, error) => assert!(
+            error.contains("Not enough temporary storage"),
+            "Unexpected error: {}",
+            error
+        ),
+        other => panic!("Unexpected reply: {:?}", other),
+    }
+
+    // Only the chunks which fit were kept
+    let storage = format!("{}/storage/{}", service_dir, hash);
+    assert!(Path::new(&format!("{}/1", storage)).exists());
+    assert!(!Path::new(&format!("{}/2", storage)).exists());
+    assert!(!Path::new(&format!("{}/3", storage)).exists());
+}
+
+// Downloading a file which the service would have to copy into
+// too little temporary storage is rejected
+#[test]
+fn download_over_limit() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_dir = format!("{}/service", test_dir_str);
+    let service_port = 7112;
+
+    let contents = [13; 12000];
+
+    create_test_file(&source, &contents);
+
+    storage_service_new(service_port, &service_dir, "max_storage = 8192");
+
+    let result = download(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some("client".to_owned()),
+        4096,
+    );
+
+    let error = result.unwrap_err().to_string();
+    assert!(
+        error.contains("Not enough temporary storage"),
+        "Unexpected error: {}",
+        error
+    );
+    assert!(!Path::new(&dest).exists());
+}
+
+// Temporary files which haven't been touched for a while are removed,
+// without disturbing newer ones
+#[test]
+fn stale_storage_removed() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let service_dir = format!("{}/service", test_dir_str);
+    let service_port = 7113;
+
+    let stale = format!("{}/storage/stale", service_dir);
+    fs::create_dir_all(&stale).unwrap();
+    create_test_file(&format!("{}/0", stale), "stale_storage_removed".as_bytes());
+    thread::sleep(Duration::new(3, 0));
+
+    let fresh = format!("{}/storage/fresh", service_dir);
+    fs::create_dir_all(&fresh).unwrap();
+    create_test_file(&format!("{}/0", fresh), "stale_storage_kept".as_bytes());
+
+    storage_service_new(service_port, &service_dir, "storage_max_age = 3");
+
+    assert!(!Path::new(&stale).exists());
+    assert!(Path::new(&fresh).exists());
+}
@@ -0,0 +1,200 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+extern crate cbor_protocol;
+extern crate file_protocol;
+extern crate file_service;
+extern crate kubos_system;
+extern crate tempfile;
+
+mod common;
+
+use common::*;
+use file_protocol::{FileProtocol, FileProtocolConfig, State};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// NOTE: Each test's file contents must be unique. Otherwise the hash is the same, so
+// the same storage directory is used across all of them, creating conflicts
+
+fn streaming_client(service_port: u16) -> FileProtocol {
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5).with_streaming(true);
+    FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    )
+}
+
+// Upload a file without copying it into the client's storage
+#[test]
+fn upload_streamed() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7060;
+
+    let contents = [60; 10000];
+
+    let hash = create_test_file(&source, &contents);
+
+    service_new!(service_port, 4096);
+
+    let f_protocol = streaming_client(service_port);
+
+    let (file_hash, num_chunks, mode) = f_protocol.initialize_file(&source).unwrap();
+    assert_eq!(file_hash, hash);
+    assert_eq!(num_chunks, 3);
+    // None of the chunks should have been copied
+    assert!(!Path::new(&format!("client/storage/{}/0", hash)).exists());
+
+    let channel = f_protocol.generate_channel().unwrap();
+    f_protocol
+        .send_metadata(channel, &hash, num_chunks)
+        .unwrap();
+    f_protocol.send_export(channel, &hash, &dest, mode).unwrap();
+
+    let result = f_protocol.message_engine(
+        |d| f_protocol.recv(Some(d)),
+        Duration::from_secs(2),
+        State::Transmitting,
+    );
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    // Verify the final file's contents
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(&contents[..], dest_contents.as_slice());
+}
+
+// Change the source file after it has been prepared for upload.
+// The transfer should fail rather than sending a mix of old and new data
+#[test]
+fn upload_streamed_modified() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7061;
+
+    let hash = create_test_file(&source, &[61; 10000]);
+
+    service_new!(service_port, 4096);
+
+    let f_protocol = streaming_client(service_port);
+
+    let (_, num_chunks, mode) = f_protocol.initialize_file(&source).unwrap();
+
+    // Same size, different contents
+    fs::write(&source, &[62; 10000][..]).unwrap();
+
+    let channel = f_protocol.generate_channel().unwrap();
+    f_protocol
+        .send_metadata(channel, &hash, num_chunks)
+        .unwrap();
+    f_protocol.send_export(channel, &hash, &dest, mode).unwrap();
+
+    let result = f_protocol.message_engine(
+        |d| f_protocol.recv(Some(d)),
+        Duration::from_secs(2),
+        State::Transmitting,
+    );
+
+    assert_eq!(
+        format!("Source file {} was modified during the transfer", source),
+        format!("{}", result.unwrap_err())
+    );
+    assert!(!Path::new(&dest).exists());
+
+    // Cleanup the temporary files so that the test can be repeatable
+    // The client folder is cleaned up by the protocol as a result of the failure
+    let _ = fs::remove_dir_all(format!("service/storage/{}", hash));
+}
+
+// Download a file from a service which streams the files it sends
+#[test]
+fn download_streamed() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7062;
+
+    let contents = [63; 10000];
+
+    let hash = create_test_file(&source, &contents);
+
+    thread::spawn(move || {
+        recv_loop(ServiceConfig::new_from_str(
+            "file-transfer-service",
+            &format!(
+                r#"
+                [file-transfer-service]
+                storage_dir = "service"
+                chunk_size = 4096
+                hold_count = 5
+                streaming = true
+                [file-transfer-service.addr]
+                ip = "127.0.0.1"
+                port = {}
+                "#,
+                service_port
+            ),
+        )).unwrap();
+    });
+
+    thread::sleep(Duration::new(1, 0));
+
+    let result = download(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        &source,
+        &dest,
+        Some("client".to_owned()),
+        4096,
+    );
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
+
+    // The service should have read the chunks straight from the source file
+    assert!(!Path::new(&format!("service/storage/{}/0", hash)).exists());
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    // Verify the final file's contents
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(&contents[..], dest_contents.as_slice());
+}
@@ -0,0 +1,201 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+extern crate cbor_protocol;
+extern crate file_protocol;
+extern crate file_service;
+extern crate kubos_system;
+extern crate tempfile;
+
+mod common;
+
+use common::*;
+use file_protocol::{
+    Direction, FileProtocol, FileProtocolConfig, Message, ProtocolError, State,
+};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// NOTE: Each test's file contents must be unique. Otherwise the hash is the same, so
+// the same storage directory is used across all of them, creating conflicts
+
+fn new_client(service_port: u16) -> FileProtocol {
+    new_client_on("127.0.0.1", service_port)
+}
+
+fn new_client_on(host_ip: &str, service_port: u16) -> FileProtocol {
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+    FileProtocol::new(
+        host_ip,
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    )
+}
+
+// Upload a file slowly enough that there's time to inspect the transaction
+fn slow_upload(service_port: u16, source_path: &str, target_path: &str) -> Result<(), String> {
+    let f_config =
+        FileProtocolConfig::new(Some("client".to_owned()), 4096, 5).with_max_rate(8192);
+    let f_protocol = FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+
+    let result: Result<(), ProtocolError> = (|| {
+        let (hash, num_chunks, mode) = f_protocol.initialize_file(source_path)?;
+        let channel = f_protocol.generate_channel()?;
+        f_protocol.send_metadata(channel, &hash, num_chunks)?;
+        f_protocol.send_export(channel, &hash, target_path, mode)?;
+        f_protocol.message_engine(
+            |d| f_protocol.recv(Some(d)),
+            Duration::from_secs(2),
+            State::Transmitting,
+        )
+    })();
+
+    result.map_err(|err| format!("{}", err))
+}
+
+// Look at an upload while it's in progress, and then once it's finished
+#[test]
+fn list_active_and_history() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7100;
+
+    let contents: Vec<u8> = (0..24000).map(|val| (val % 229) as u8).collect();
+    let hash = create_test_file(&source, &contents);
+
+    service_new!(service_port, 4096);
+
+    let upload = {
+        let (source, dest) = (source.clone(), dest.clone());
+        thread::spawn(move || slow_upload(service_port, &source, &dest))
+    };
+
+    thread::sleep(Duration::from_millis(1500));
+
+    let f_protocol = new_client(service_port);
+
+    let active = f_protocol.remote_transactions().unwrap();
+    assert_eq!(active.len(), 1);
+    assert_eq!(active[0].direction, Direction::Upload);
+    assert_eq!(active[0].path, dest);
+    assert_eq!(active[0].hash, hash);
+    assert_eq!(active[0].num_chunks, 6);
+    assert!(active[0].chunks_done > 0 && active[0].chunks_done < 6);
+    assert_eq!(active[0].error, None);
+
+    assert_eq!(upload.join().unwrap(), Ok(()));
+
+    // Give the service a moment to finish up
+    thread::sleep(Duration::from_millis(500));
+
+    assert!(f_protocol.remote_transactions().unwrap().is_empty());
+
+    let history = f_protocol.remote_history(5).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].channel_id, active[0].channel_id);
+    assert_eq!(history[0].chunks_done, 6);
+    assert_eq!(history[0].error, None);
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    assert_eq!(fs::read(dest).unwrap(), contents);
+}
+
+// Cancel an upload part way through
+#[test]
+fn cancel_upload() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7101;
+
+    let contents: Vec<u8> = (0..24000).map(|val| (val % 227) as u8).collect();
+    let hash = create_test_file(&source, &contents);
+
+    service_new!(service_port, 4096);
+
+    // Start the upload, but don't send any of the file yet
+    let f_protocol = new_client(service_port);
+    let (_, num_chunks, mode) = f_protocol.initialize_file(&source).unwrap();
+    let channel = f_protocol.generate_channel().unwrap();
+    f_protocol.send_metadata(channel, &hash, num_chunks).unwrap();
+    f_protocol.send_export(channel, &hash, &dest, mode).unwrap();
+    let reply = f_protocol.recv(Some(Duration::from_secs(1))).unwrap();
+    match file_protocol::parse_message(reply) {
+        Ok(Message::NAK(..)) => {}
+        other => panic!("Unexpected reply: {:?}", other),
+    }
+
+    // Another host may not cancel it
+    let other = new_client_on("127.0.0.2", service_port);
+    assert!(other.remote_cancel(channel).is_err());
+    let active = other.remote_transactions().unwrap();
+    assert_eq!(active.len(), 1);
+    assert_eq!(active[0].channel_id, channel);
+
+    // But the uploader's host may, from a port other than the one the upload is using
+    let canceller = new_client(service_port);
+    canceller.remote_cancel(channel).unwrap();
+
+    // The uploader should be told that the transfer isn't going to finish
+    let reply = f_protocol.recv(Some(Duration::from_secs(1))).unwrap();
+    match file_protocol::parse_message(reply) {
+        Ok(Message::Failure(..)) => {}
+        other => panic!("Unexpected reply: {:?}", other),
+    }
+
+    assert!(other.remote_transactions().unwrap().is_empty());
+
+    let history = other.remote_history(5).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].channel_id, channel);
+    assert_eq!(
+        history[0].error,
+        Some("Transaction was cancelled".to_owned())
+    );
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    assert!(fs::metadata(&dest).is_err());
+}
+
+// Only active transactions can be cancelled
+#[test]
+fn cancel_unknown_channel() {
+    let service_port = 7102;
+
+    service_new!(service_port, 4096);
+
+    let f_protocol = new_client(service_port);
+
+    assert!(f_protocol.remote_cancel(12345).is_err());
+    assert!(f_protocol.remote_history(5).unwrap().is_empty());
+}
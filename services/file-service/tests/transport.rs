@@ -0,0 +1,89 @@
+//
+// Copyright (C) 2018 Kubos Corporation
+//
+// Licensed under the Apache License, Version 2.0 (the "License")
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+// http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+extern crate cbor_protocol;
+extern crate file_protocol;
+extern crate file_service;
+extern crate kubos_system;
+extern crate tempfile;
+
+use cbor_protocol::UnixTransport;
+use file_protocol::{FileProtocol, FileProtocolConfig, State};
+use file_service::recv_loop;
+use kubos_system::Config as ServiceConfig;
+use std::fs::{self, File};
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// The service can be configured to talk over a Unix socket instead of UDP
+#[test]
+fn upload_over_unix_socket() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_socket = format!("{}/service.sock", test_dir_str);
+    let client_socket = format!("{}/client.sock", test_dir_str);
+
+    let contents: Vec<u8> = (0..10000).map(|i| (i * 13 % 241) as u8).collect();
+    File::create(&source).unwrap().write_all(&contents).unwrap();
+
+    let config = format!(
+        r#"
+        [file-transfer-service]
+        storage_dir = "service"
+        chunk_size = 4096
+        hold_count = 5
+        transport = "unix"
+        socket_path = "{}"
+        peer_path = "{}"
+        "#,
+        service_socket, client_socket
+    );
+    thread::spawn(move || {
+        recv_loop(ServiceConfig::new_from_str(
+            "file-transfer-service",
+            &config,
+        )).unwrap();
+    });
+    thread::sleep(Duration::new(1, 0));
+
+    // There's only one peer on the other end, so the remote address doesn't matter
+    let socket = UnixTransport::bind(&client_socket, &service_socket).unwrap();
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5);
+    let f_protocol = FileProtocol::new(socket, "0.0.0.0:0", f_config);
+
+    let (hash, num_chunks, mode) = f_protocol.initialize_file(&source).unwrap();
+    let channel = f_protocol.generate_channel().unwrap();
+    f_protocol
+        .send_metadata(channel, &hash, num_chunks)
+        .unwrap();
+    f_protocol.send_export(channel, &hash, &dest, mode).unwrap();
+    let result = f_protocol.message_engine(
+        |d| f_protocol.recv(Some(d)),
+        Duration::from_secs(2),
+        State::Transmitting,
+    );
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    assert!(result.is_ok(), "Upload failed: {:?}", result);
+    assert_eq!(fs::read(&dest).unwrap(), contents);
+}
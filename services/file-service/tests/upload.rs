@@ -24,7 +24,7 @@ extern crate tempfile;
 mod common;
 
 use common::*;
-use file_protocol::ProtocolError;
+use file_protocol::{FileProtocol, FileProtocolConfig, State};
 use file_service::recv_loop;
 use kubos_system::Config as ServiceConfig;
 use rand::{thread_rng, Rng};
@@ -214,9 +214,10 @@ fn upload_multi_complete() {
     assert_eq!(&contents[..], dest_contents.as_slice());
 }
 
-// Upload. Create hash mismatch.
+// Upload. Corrupt one of the chunks the service has already received.
+// Only that chunk should be sent again
 #[test]
-fn upload_bad_hash() {
+fn upload_corrupt_chunk() {
     let test_dir = TempDir::new().expect("Failed to create test dir");
     let test_dir_str = test_dir.path().to_str().unwrap();
     let source = format!("{}/source", test_dir_str);
@@ -241,7 +242,7 @@ fn upload_bad_hash() {
     assert!(result.is_ok());
     let hash = result.unwrap();
 
-    // Tweak the chunk contents so the future hash calculation will fail
+    // Tweak the chunk contents so that it fails its integrity check
     fs::write(format!("service/storage/{}/0", hash), "bad data".as_bytes()).unwrap();
 
     let result = upload(
@@ -253,21 +254,19 @@ fn upload_bad_hash() {
         4096,
     );
 
-    assert_eq!(
-        "File hash mismatch",
-        match result.unwrap_err() {
-            ProtocolError::TransmissionError {
-                channel_id: _,
-                error_message,
-            } => error_message,
-            _ => "".to_owned(),
-        }
-    );
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
 
     // Cleanup the temporary files so that the test can be repeatable
-    // The service storage folder is deleted by the protocol as a
-    // result of the hash mismatch
     fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    // Verify the final file's contents
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(contents, dest_contents.as_slice());
 }
 
 // Upload a single file in 5 simultaneous client instances
@@ -434,3 +433,75 @@ fn upload_single_after_bad_input() {
     let dest_contents = fs::read(dest).unwrap();
     assert_eq!(&contents[..], dest_contents.as_slice());
 }
+
+// Upload a multi-chunk file using a small, rate-limited sending window
+#[test]
+fn upload_windowed() {
+    let test_dir = TempDir::new().expect("Failed to create test dir");
+    let test_dir_str = test_dir.path().to_str().unwrap();
+    let source = format!("{}/source", test_dir_str);
+    let dest = format!("{}/dest", test_dir_str);
+    let service_port = 7008;
+
+    // Ten chunks
+    let contents = [8; 40000];
+
+    let hash = create_test_file(&source, &contents);
+
+    // The service waits far longer than the test for each message, so the file
+    // only gets through if the service asks for more chunks as soon as each window arrives
+    thread::spawn(move || {
+        recv_loop(ServiceConfig::new_from_str(
+            "file-transfer-service",
+            &format!(
+                r#"
+                [file-transfer-service]
+                storage_dir = "service"
+                chunk_size = 4096
+                hold_count = 5
+                timeout = 3600
+                [file-transfer-service.addr]
+                ip = "127.0.0.1"
+                port = {}
+                "#,
+                service_port
+            ),
+        )).unwrap();
+    });
+    thread::sleep(Duration::new(1, 0));
+
+    let f_config = FileProtocolConfig::new(Some("client".to_owned()), 4096, 5)
+        .with_window_size(2)
+        .with_max_rate(40000);
+    let f_protocol = FileProtocol::new(
+        "127.0.0.1",
+        &format!("127.0.0.1:{}", service_port),
+        f_config,
+    );
+
+    let (_, num_chunks, mode) = f_protocol.initialize_file(&source).unwrap();
+    let channel = f_protocol.generate_channel().unwrap();
+    f_protocol.send_metadata(channel, &hash, num_chunks).unwrap();
+    f_protocol.send_export(channel, &hash, &dest, mode).unwrap();
+
+    // The file takes five NAK rounds, since only two chunks can be sent at a time
+    let result = f_protocol.message_engine(
+        |d| f_protocol.recv(Some(d)),
+        Duration::from_secs(2),
+        State::Transmitting,
+    );
+
+    if let Err(err) = &result {
+        println!("Error: {}", err);
+    }
+
+    assert!(result.is_ok());
+
+    // Cleanup the temporary files so that the test can be repeatable
+    fs::remove_dir_all(format!("client/storage/{}", hash)).unwrap();
+    fs::remove_dir_all(format!("service/storage/{}", hash)).unwrap();
+
+    // Verify the final file's contents
+    let dest_contents = fs::read(dest).unwrap();
+    assert_eq!(&contents[..], dest_contents.as_slice());
+}
@@ -21,6 +21,9 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::net::{SocketAddr, UdpSocket};
 
+// The largest payload a UDP datagram can carry, so that queries are never cut short
+const MAX_DATAGRAM: usize = 65507;
+
 /// Context struct used by a service to provide Juniper context,
 /// subsystem access and persistent storage.
 pub struct Context<T> {
@@ -134,6 +137,8 @@ where
     /// Starts the service's GraphQL/UDP server. This function runs
     /// without return.
     ///
+    /// Each query must fit in a single UDP datagram, so may be up to 65507 bytes long.
+    ///
     /// # Panics
     ///
     /// The UDP interface will panic if the ip address and port provided
@@ -145,7 +150,7 @@ where
         let socket = UdpSocket::bind(&addr).unwrap();
         println!("Listening on: {}", socket.local_addr().unwrap());
 
-        let mut buf = [0; 4096];
+        let mut buf = vec![0; MAX_DATAGRAM];
         loop {
             // Wait for an incoming message
             let (size, peer) = socket
@@ -26,7 +26,7 @@ extern crate simplelog;
 
 use channel_protocol::ChannelMessage;
 use kubos_system::Config as ServiceConfig;
-use shell_protocol::{ProtocolError, ShellProtocol};
+use shell_protocol::{ProcessList, ProtocolError, ShellProtocol};
 use std::collections::HashMap;
 use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
 use std::sync::{Arc, Mutex};
@@ -42,7 +42,21 @@ pub fn recv_loop(config: ServiceConfig) -> Result<(), failure::Error> {
     let mut host_parts = host.split(':').map(|val| val.to_owned());
     let host_ip = host_parts.next().unwrap();
 
-    let c_protocol = cbor_protocol::Protocol::new(host.clone(), 4096);
+    // Get the key used to sign messages, and how old a signed message may be, in seconds
+    let auth = config.get("auth_key").and_then(|val| {
+        val.as_str().map(|key| {
+            let window = match config.get("auth_window") {
+                Some(val) => val.as_integer().unwrap_or(30),
+                None => 30,
+            } as u64;
+            (key.to_owned(), Duration::from_secs(window))
+        })
+    });
+
+    let mut c_protocol = cbor_protocol::Protocol::new(host.clone(), 4096);
+    if let Some((ref key, window)) = auth {
+        c_protocol = c_protocol.with_service_auth(key.as_bytes(), window);
+    }
 
     let timeout = config
         .get("timeout")
@@ -51,15 +65,30 @@ pub fn recv_loop(config: ServiceConfig) -> Result<(), failure::Error> {
                 .and_then(|num| Some(Duration::from_secs(num as u64)))
         }).unwrap_or(Duration::from_secs(2));
 
+    // Sessions with a running process are only cut short if these are configured
+    let seconds = |key| {
+        config
+            .get(key)
+            .and_then(|val| val.as_integer())
+            .map(|num| Duration::from_secs(num as u64))
+    };
+    let idle_timeout = seconds("idle_timeout");
+    let session_lifetime = seconds("session_lifetime");
+
     // Setup map of channel IDs to thread channels
     let raw_threads: HashMap<u32, Sender<ChannelMessage>> = HashMap::new();
     // Create thread sharable wrapper
     let threads = Arc::new(Mutex::new(raw_threads));
+    // Every session can see the processes spawned by the others
+    let processes = ProcessList::new();
 
     loop {
         // Listen on UDP port
         let (source, first_message) = match c_protocol.recv_message_peer() {
             Ok((source, first_message)) => (source, first_message),
+            // Flow control frames are handled by the CBOR protocol
+            Err(cbor_protocol::ProtocolError::Paused)
+            | Err(cbor_protocol::ProtocolError::Resumed) => continue,
             Err(e) => {
                 warn!("Failed to receive message: {}", e);
                 continue;
@@ -68,6 +97,8 @@ pub fn recv_loop(config: ServiceConfig) -> Result<(), failure::Error> {
 
         let host_ref = host_ip.clone();
         let timeout_ref = timeout.clone();
+        let auth_ref = auth.clone();
+        let processes_ref = processes.clone();
 
         let parsed_message = match channel_protocol::parse_message(first_message) {
             Ok(parsed_message) => parsed_message,
@@ -86,7 +117,12 @@ pub fn recv_loop(config: ServiceConfig) -> Result<(), failure::Error> {
             // listen for requests from other clients
             let shared_threads = threads.clone();
             thread::spawn(move || {
-                let s_protocol = ShellProtocol::new(&host_ref, &format!("{}", source));
+                let mut s_protocol = ShellProtocol::new(host_ref.as_str(), &format!("{}", source))
+                    .with_processes(processes_ref)
+                    .with_session_limits(idle_timeout, session_lifetime);
+                if let Some((key, window)) = auth_ref {
+                    s_protocol = s_protocol.with_auth(key.as_bytes(), window);
+                }
 
                 // Listen, process, and react to the remaining messages in the
                 // requested operation